@@ -0,0 +1,108 @@
+//! Measures SET/GET throughput against a running server with an increasing
+//! number of client threads, to show how the sharded keyspace scales with cores.
+//!
+//! Usage: cargo run --release --example benchmark -- [port] [requests per thread]
+
+use std::{
+    env::args,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    thread,
+    time::Instant,
+};
+
+const PIPELINE: usize = 32;
+const KEY_SPACE: usize = 100_000;
+
+fn command(arguments: &[&str]) -> Vec<u8> {
+    let mut message = format!("*{}\r\n", arguments.len()).into_bytes();
+    for argument in arguments {
+        message.extend(format!("${}\r\n{}\r\n", argument.len(), argument).into_bytes());
+    }
+    message
+}
+
+/// Reads one reply, skipping the payload of bulk strings.
+fn read_reply(reader: &mut BufReader<TcpStream>) {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .expect("[ERROR] Could not read reply");
+    if let Some(length) = line.strip_prefix('$') {
+        let length: i64 = length
+            .trim_end()
+            .parse()
+            .expect("[ERROR] Invalid bulk length");
+        if length >= 0 {
+            let mut payload = vec![0; length as usize + 2];
+            reader
+                .read_exact(&mut payload)
+                .expect("[ERROR] Could not read bulk string");
+        }
+    }
+}
+
+fn run_client(port: u16, requests: usize, seed: usize) {
+    let mut stream =
+        TcpStream::connect(("127.0.0.1", port)).expect("[ERROR] Could not connect to server");
+    stream.set_nodelay(true).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut state = seed as u64 * 2654435761 + 1;
+    let mut sent = 0;
+    while sent < requests {
+        let batch = PIPELINE.min(requests - sent);
+        let mut message = vec![];
+        for index in 0..batch {
+            // xorshift keeps the key choice cheap and spread across shards
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let key = format!("key:{}", state as usize % KEY_SPACE);
+            if index % 2 == 0 {
+                message.extend(command(&["SET", &key, "value"]));
+            } else {
+                message.extend(command(&["GET", &key]));
+            }
+        }
+        stream.write_all(&message).unwrap();
+        for _ in 0..batch {
+            read_reply(&mut reader);
+        }
+        sent += batch;
+    }
+}
+
+fn main() {
+    let arguments: Vec<String> = args().collect();
+    let port = arguments
+        .get(1)
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(6379);
+    let requests = arguments
+        .get(2)
+        .and_then(|requests| requests.parse().ok())
+        .unwrap_or(100_000);
+    let max_threads = thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(4)
+        .max(4);
+
+    println!("threads  requests/s");
+    let mut threads = 1;
+    while threads <= max_threads {
+        let start = Instant::now();
+        let clients: Vec<_> = (0..threads)
+            .map(|seed| thread::spawn(move || run_client(port, requests, seed)))
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!(
+            "{:>7}  {:>10.0}",
+            threads,
+            (threads * requests) as f64 / elapsed
+        );
+        threads *= 2;
+    }
+}
@@ -0,0 +1,716 @@
+//! Inspects and converts RDB files with the same decoder and encoder the server
+//! uses, so running it over real dumps doubles as a format conformance check.
+//!
+//! Usage: cargo run --example rdb_tool -- <command>
+//!   check <file.rdb>...            validate checksum, structure and a re-encode
+//!   dump <file.rdb>                one line per key: type, encoding, size, TTL
+//!   to-json <file.rdb> [out.json]  export the dataset as JSON (stdout by default)
+//!   from-json <in.json> <out.rdb>  build an RDB file from such an export
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use redis_starter_rust::{
+    hash::Hash,
+    rdb::{self, Entry, Snapshot},
+    sorted_set::SortedSet,
+    storage::RedisValue,
+    stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId},
+};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    env::args,
+    fmt::Write as _,
+    fs,
+    process::exit,
+};
+
+const USAGE: &str = "Usage:
+  rdb_tool check <file.rdb>...
+  rdb_tool dump <file.rdb>
+  rdb_tool to-json <file.rdb> [out.json]
+  rdb_tool from-json <in.json> <out.rdb>";
+
+fn main() {
+    let arguments: Vec<String> = args().skip(1).collect();
+    let result = match arguments
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["check", paths @ ..] if !paths.is_empty() => check(paths),
+        ["dump", path] => dump(path),
+        ["to-json", path] => to_json(path).map(|json| print!("{json}")),
+        ["to-json", path, out] => {
+            to_json(path).and_then(|json| fs::write(out, json).map_err(|e| format!("{out}: {e}")))
+        }
+        ["from-json", path, out] => from_json(path, out),
+        _ => {
+            eprintln!("{USAGE}");
+            exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("[ERROR] : {e}");
+        exit(1);
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{path}: {e}"))
+}
+
+/// Validates each file: header, every value, the trailing checksum, and that
+/// encoding the dataset again decodes to the same thing.
+fn check(paths: &[&str]) -> Result<(), String> {
+    let mut failed = 0;
+    for path in paths {
+        match check_file(path) {
+            Ok(summary) => println!("[OK] : {path}: {summary}"),
+            Err(e) => {
+                println!("[ERROR] : {path}: {e}");
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{failed} of {} files failed the check", paths.len())),
+    }
+}
+
+fn check_file(path: &str) -> Result<String, String> {
+    let data = read(path)?;
+    let snapshot = rdb::decode(&data).map_err(|e| e.to_string())?;
+    // The header was validated by decoding.
+    let version: u32 = std::str::from_utf8(&data[5..9]).unwrap().parse().unwrap();
+
+    let checksum = if version < 5 {
+        "no checksum".to_string()
+    } else {
+        let (body, stored) = data.split_at(data.len() - 8);
+        let stored = u64::from_le_bytes(stored.try_into().unwrap());
+        if stored == 0 {
+            "checksum disabled".to_string()
+        } else if stored == rdb::crc64(0, body) {
+            format!("checksum {stored:016x} verified")
+        } else {
+            return Err("unexpected data after the checksum".to_string());
+        }
+    };
+
+    let exported = snapshot_to_json(&snapshot);
+    let reencoded = rdb::decode(&rdb::encode(&snapshot)).map_err(|e| format!("re-encoded: {e}"))?;
+    if snapshot_to_json(&reencoded) != exported {
+        return Err("dataset changed after re-encoding".to_string());
+    }
+
+    let keys: usize = snapshot.databases.iter().map(|(_, entries)| entries.len()).sum();
+    let databases = snapshot.databases.len();
+    let plural = if databases == 1 { "" } else { "s" };
+    Ok(format!(
+        "RDB version {version}, {keys} keys in {databases} database{plural}, {checksum}, re-encoding preserved it"
+    ))
+}
+
+fn dump(path: &str) -> Result<(), String> {
+    let data = read(path)?;
+    let now = chrono::Utc::now().timestamp_millis();
+    println!("db\tkey\ttype\tencoding\tsize\tttl_ms");
+    rdb::decode_with(&data, |db, entry, encoding| {
+        let ttl = match entry.deadline {
+            Some(deadline) => (deadline - now).max(0).to_string(),
+            None => "-1".to_string(),
+        };
+        println!(
+            "{db}\t{}\t{}\t{encoding}\t{}\t{ttl}",
+            entry.key.escape_debug(),
+            entry.value.type_name(),
+            size(&entry.value)
+        );
+    })
+    .map_err(|e| format!("{path}: {e}"))?;
+    Ok(())
+}
+
+/// Bytes of a string, elements of anything else.
+fn size(value: &RedisValue) -> usize {
+    match value {
+        RedisValue::String(value) => value.len(),
+        RedisValue::List(list) => list.len(),
+        RedisValue::Set(set) => set.len(),
+        RedisValue::SortedSet(set) => set.len(),
+        RedisValue::Hash(hash) => hash.len(),
+        RedisValue::Stream(stream) => stream.len(),
+    }
+}
+
+fn to_json(path: &str) -> Result<String, String> {
+    let snapshot = rdb::decode(&read(path)?).map_err(|e| format!("{path}: {e}"))?;
+    Ok(snapshot_to_json(&snapshot))
+}
+
+fn from_json(path: &str, out: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let json = Json::parse(&text).map_err(|e| format!("{path}: {e}"))?;
+    let snapshot = snapshot_from_json(&json).map_err(|e| format!("{path}: {e}"))?;
+    fs::write(out, rdb::encode(&snapshot)).map_err(|e| format!("{out}: {e}"))
+}
+
+// Export. Byte strings become JSON strings when they are valid UTF-8 and
+// {"base64": "..."} objects otherwise. Keys, set members and hash fields are
+// sorted so exports of the same dataset compare equal.
+
+fn snapshot_to_json(snapshot: &Snapshot) -> String {
+    let mut out = String::from("{\n  \"aux\": {");
+    for (index, (field, value)) in snapshot.aux.iter().enumerate() {
+        let separator = if index == 0 { "" } else { ", " };
+        write!(out, "{separator}{}: {}", quote(field), bytes(value)).unwrap();
+    }
+    out.push_str("},\n  \"functions\": [");
+    let functions: Vec<String> = snapshot.functions.iter().map(|code| bytes(code)).collect();
+    out.push_str(&functions.join(", "));
+    out.push_str("],\n  \"databases\": [");
+    for (index, (db, entries)) in snapshot.databases.iter().enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        write!(out, "{separator}\n    {{\"index\": {db}, \"keys\": [").unwrap();
+        let mut entries: Vec<&Entry> = entries.iter().collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        for (index, entry) in entries.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(out, "{separator}\n      {}", entry_to_json(entry)).unwrap();
+        }
+        out.push_str("\n    ]}");
+    }
+    out.push_str("\n  ]\n}\n");
+    out
+}
+
+fn entry_to_json(entry: &Entry) -> String {
+    let mut out = format!(
+        "{{\"key\": {}, \"type\": \"{}\"",
+        quote(&entry.key),
+        entry.value.type_name()
+    );
+    if let Some(deadline) = entry.deadline {
+        write!(out, ", \"expires_at\": {deadline}").unwrap();
+    }
+    let value = match &entry.value {
+        RedisValue::String(value) => bytes(value),
+        RedisValue::List(list) => array(list.iter().map(|element| bytes(element))),
+        RedisValue::Set(set) => {
+            let mut members: Vec<&Vec<u8>> = set.iter().collect();
+            members.sort();
+            array(members.into_iter().map(|member| bytes(member)))
+        }
+        RedisValue::SortedSet(set) => array(
+            set.iter()
+                .map(|(member, score)| format!("[{}, {}]", bytes(member), score_to_json(score))),
+        ),
+        RedisValue::Hash(hash) => {
+            let mut fields: Vec<(&Vec<u8>, &Vec<u8>)> = hash.iter().collect();
+            fields.sort();
+            array(fields.into_iter().map(|(field, value)| {
+                match hash.expires_at(field) {
+                    Some(deadline) => format!("[{}, {}, {deadline}]", bytes(field), bytes(value)),
+                    None => format!("[{}, {}]", bytes(field), bytes(value)),
+                }
+            }))
+        }
+        RedisValue::Stream(stream) => stream_to_json(stream),
+    };
+    write!(out, ", \"value\": {value}}}").unwrap();
+    out
+}
+
+fn stream_to_json(stream: &Stream) -> String {
+    let entries = array(stream.entries.iter().map(|(id, fields)| {
+        let fields = array(
+            fields
+                .iter()
+                .map(|(field, value)| format!("[{}, {}]", bytes(field), bytes(value))),
+        );
+        format!("{{\"id\": \"{id}\", \"fields\": {fields}}}")
+    }));
+    let groups = array(stream.groups.iter().map(|group| {
+        let pending = array(group.pending.iter().map(|(id, pending)| {
+            format!(
+                "{{\"id\": \"{id}\", \"consumer\": {}, \"delivery_time\": {}, \"delivery_count\": {}}}",
+                bytes(&pending.consumer),
+                pending.delivery_time,
+                pending.delivery_count
+            )
+        }));
+        let consumers = array(group.consumers.iter().map(|consumer| {
+            format!(
+                "{{\"name\": {}, \"seen_time\": {}, \"active_time\": {}}}",
+                bytes(&consumer.name),
+                consumer.seen_time,
+                consumer.active_time
+            )
+        }));
+        format!(
+            "{{\"name\": {}, \"last_id\": \"{}\", \"entries_read\": {}, \"pending\": {pending}, \"consumers\": {consumers}}}",
+            bytes(&group.name),
+            group.last_id,
+            group.entries_read
+        )
+    }));
+    format!(
+        "{{\"last_id\": \"{}\", \"first_id\": \"{}\", \"max_deleted_id\": \"{}\", \"entries_added\": {}, \"entries\": {entries}, \"groups\": {groups}}}",
+        stream.last_id, stream.first_id, stream.max_deleted_id, stream.entries_added
+    )
+}
+
+/// JSON has no infinities or NaN, so those are written as strings.
+fn score_to_json(score: f64) -> String {
+    if score.is_nan() {
+        "\"nan\"".to_string()
+    } else if score.is_infinite() {
+        format!("\"{}\"", if score > 0.0 { "inf" } else { "-inf" })
+    } else {
+        score.to_string()
+    }
+}
+
+fn array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(", "))
+}
+
+fn bytes(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) => quote(text),
+        Err(_) => format!("{{\"base64\": \"{}\"}}", BASE64.encode(value)),
+    }
+}
+
+fn quote(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Import, the reverse of the export above.
+
+fn snapshot_from_json(json: &Json) -> Result<Snapshot, String> {
+    let mut snapshot = Snapshot::default();
+    for (field, value) in json.get("aux")?.as_object()? {
+        snapshot.aux.push((field.clone(), value.as_bytes()?));
+    }
+    for code in json.get("functions")?.as_array()? {
+        snapshot.functions.push(code.as_bytes()?);
+    }
+    for db in json.get("databases")?.as_array()? {
+        let index = db.get("index")?.as_u64()? as usize;
+        let entries = db
+            .get("keys")?
+            .as_array()?
+            .iter()
+            .map(|entry| entry_from_json(entry).map_err(|e| format!("db {index}: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        snapshot.databases.push((index, entries));
+    }
+    Ok(snapshot)
+}
+
+fn entry_from_json(json: &Json) -> Result<Entry, String> {
+    let key = json.get("key")?.as_str()?.to_string();
+    let deadline = match json.get("expires_at") {
+        Ok(deadline) => Some(deadline.as_i64()?),
+        Err(_) => None,
+    };
+    let value = json.get("value")?;
+    let value = match json.get("type")?.as_str()? {
+        "string" => RedisValue::String(value.as_bytes()?),
+        "list" => RedisValue::List(
+            value
+                .as_array()?
+                .iter()
+                .map(Json::as_bytes)
+                .collect::<Result<VecDeque<_>, _>>()?,
+        ),
+        "set" => RedisValue::Set(
+            value
+                .as_array()?
+                .iter()
+                .map(Json::as_bytes)
+                .collect::<Result<HashSet<_>, _>>()?,
+        ),
+        "zset" => {
+            let mut set = SortedSet::new();
+            for pair in value.as_array()? {
+                match pair.as_array()?.as_slice() {
+                    [member, score] => set.insert(member.as_bytes()?, score.as_score()?),
+                    _ => return Err(format!("{key}: sorted set members are [member, score]")),
+                };
+            }
+            RedisValue::SortedSet(set)
+        }
+        "hash" => {
+            let mut hash = Hash::new();
+            for field in value.as_array()? {
+                let (field, value, deadline) = match field.as_array()?.as_slice() {
+                    [field, value] => (field.as_bytes()?, value.as_bytes()?, None),
+                    [field, value, deadline] => {
+                        (field.as_bytes()?, value.as_bytes()?, Some(deadline.as_i64()?))
+                    }
+                    _ => return Err(format!("{key}: hash fields are [field, value, deadline?]")),
+                };
+                hash.insert(field.clone(), value);
+                hash.set_expires_at(&field, deadline);
+            }
+            RedisValue::Hash(hash)
+        }
+        "stream" => RedisValue::Stream(stream_from_json(value).map_err(|e| format!("{key}: {e}"))?),
+        other => return Err(format!("{key}: unknown type '{other}'")),
+    };
+    Ok(Entry {
+        key,
+        value,
+        deadline,
+    })
+}
+
+fn stream_from_json(json: &Json) -> Result<Stream, String> {
+    let mut stream = Stream {
+        last_id: json.get("last_id")?.as_stream_id()?,
+        first_id: json.get("first_id")?.as_stream_id()?,
+        max_deleted_id: json.get("max_deleted_id")?.as_stream_id()?,
+        entries_added: json.get("entries_added")?.as_u64()?,
+        ..Stream::default()
+    };
+    for entry in json.get("entries")?.as_array()? {
+        let mut fields = Vec::new();
+        for pair in entry.get("fields")?.as_array()? {
+            match pair.as_array()?.as_slice() {
+                [field, value] => fields.push((field.as_bytes()?, value.as_bytes()?)),
+                _ => return Err("stream fields are [field, value]".to_string()),
+            }
+        }
+        stream
+            .entries
+            .insert(entry.get("id")?.as_stream_id()?, fields);
+    }
+    for group in json.get("groups")?.as_array()? {
+        let mut pending = BTreeMap::new();
+        for entry in group.get("pending")?.as_array()? {
+            pending.insert(
+                entry.get("id")?.as_stream_id()?,
+                PendingEntry {
+                    delivery_time: entry.get("delivery_time")?.as_i64()?,
+                    delivery_count: entry.get("delivery_count")?.as_u64()?,
+                    consumer: entry.get("consumer")?.as_bytes()?,
+                },
+            );
+        }
+        let consumers = group
+            .get("consumers")?
+            .as_array()?
+            .iter()
+            .map(|consumer| {
+                Ok(Consumer {
+                    name: consumer.get("name")?.as_bytes()?,
+                    seen_time: consumer.get("seen_time")?.as_i64()?,
+                    active_time: consumer.get("active_time")?.as_i64()?,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        stream.groups.push(ConsumerGroup {
+            name: group.get("name")?.as_bytes()?,
+            last_id: group.get("last_id")?.as_stream_id()?,
+            entries_read: group.get("entries_read")?.as_i64()?,
+            pending,
+            consumers,
+        });
+    }
+    Ok(stream)
+}
+
+/// A parsed JSON document. Numbers keep their text so 64-bit integers survive.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool,
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            position: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.position != text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, name: &str) -> Result<&Json, String> {
+        self.as_object()?
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| format!("missing \"{name}\""))
+    }
+
+    fn as_object(&self) -> Result<&[(String, Json)], String> {
+        match self {
+            Json::Object(fields) => Ok(fields),
+            other => Err(format!("expected an object, got {}", other.kind())),
+        }
+    }
+
+    fn as_array(&self) -> Result<&Vec<Json>, String> {
+        match self {
+            Json::Array(items) => Ok(items),
+            other => Err(format!("expected an array, got {}", other.kind())),
+        }
+    }
+
+    fn as_str(&self) -> Result<&str, String> {
+        match self {
+            Json::String(text) => Ok(text),
+            other => Err(format!("expected a string, got {}", other.kind())),
+        }
+    }
+
+    /// A string, or the {"base64": "..."} form of binary data.
+    fn as_bytes(&self) -> Result<Vec<u8>, String> {
+        match self {
+            Json::String(text) => Ok(text.clone().into_bytes()),
+            Json::Object(_) => BASE64
+                .decode(self.get("base64")?.as_str()?)
+                .map_err(|e| format!("invalid base64: {e}")),
+            other => Err(format!("expected a string, got {}", other.kind())),
+        }
+    }
+
+    fn as_number<T: std::str::FromStr>(&self) -> Result<T, String> {
+        match self {
+            Json::Number(text) => text.parse().map_err(|_| format!("number {text} out of range")),
+            other => Err(format!("expected a number, got {}", other.kind())),
+        }
+    }
+
+    fn as_u64(&self) -> Result<u64, String> {
+        self.as_number()
+    }
+
+    fn as_i64(&self) -> Result<i64, String> {
+        self.as_number()
+    }
+
+    fn as_score(&self) -> Result<f64, String> {
+        match self {
+            Json::String(text) => match text.as_str() {
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                "nan" => Ok(f64::NAN),
+                _ => Err(format!("invalid score \"{text}\"")),
+            },
+            _ => self.as_number(),
+        }
+    }
+
+    fn as_stream_id(&self) -> Result<StreamId, String> {
+        let text = self.as_str()?;
+        text.split_once('-')
+            .and_then(|(ms, seq)| {
+                Some(StreamId {
+                    ms: ms.parse().ok()?,
+                    seq: seq.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| format!("invalid stream id \"{text}\""))
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool => "a boolean",
+            Json::Number(_) => "a number",
+            Json::String(_) => "a string",
+            Json::Array(_) => "an array",
+            Json::Object(_) => "an object",
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{message} at byte {}", self.position)
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.position).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error("invalid literal"));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool),
+            Some(b'f') => self.literal("false", Json::Bool),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.position;
+                while matches!(
+                    self.peek(),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.position += 1;
+                }
+                let number = std::str::from_utf8(&self.text[start..self.position]).unwrap();
+                Ok(Json::Number(number.to_string()))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            let name = self.string()?;
+            self.expect(b':')?;
+            fields.push((name, self.value()?));
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let byte = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+            self.position += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.position += 1;
+                    match escape {
+                        b'"' | b'\\' | b'/' => out.push(escape),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0C),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // Characters outside the BMP come as a surrogate pair.
+                            if (0xD800..0xDC00).contains(&code) {
+                                if !self.text[self.position..].starts_with(b"\\u") {
+                                    return Err(self.error("unpaired surrogate"));
+                                }
+                                self.position += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            let c = char::from_u32(code)
+                                .ok_or_else(|| self.error("invalid \\u escape"))?;
+                            out.extend_from_slice(c.to_string().as_bytes());
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                byte => out.push(byte),
+            }
+        }
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8 in string"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .text
+            .get(self.position..self.position + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.position += 4;
+        Ok(digits)
+    }
+}
@@ -0,0 +1,535 @@
+#!/usr/bin/env python3
+"""Writes the RDB fixtures in this directory.
+
+Each file reproduces, byte for byte in layout, what the Redis release it is
+named after writes for the data listed in README.md: the same opcodes, value
+types and container encodings (zipmap, ziplist, intset, quicklist, listpack,
+stream listpacks), built here with ports of the Redis encoders so the corpus
+can be regenerated without a Redis installation:
+
+    python3 fixtures/rdb/generate.py
+"""
+
+import os
+import struct
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+OPCODE_SLOT_INFO = 0xF4
+OPCODE_FUNCTION2 = 0xF5
+OPCODE_MODULE_AUX = 0xF7
+OPCODE_IDLE = 0xF8
+OPCODE_FREQ = 0xF9
+OPCODE_AUX = 0xFA
+OPCODE_RESIZEDB = 0xFB
+OPCODE_EXPIRETIME_MS = 0xFC
+OPCODE_EXPIRETIME = 0xFD
+OPCODE_SELECTDB = 0xFE
+OPCODE_EOF = 0xFF
+
+TYPE_STRING = 0
+TYPE_LIST = 1
+TYPE_SET = 2
+TYPE_ZSET = 3
+TYPE_HASH = 4
+TYPE_ZSET_2 = 5
+TYPE_MODULE_2 = 7
+TYPE_HASH_ZIPMAP = 9
+TYPE_LIST_ZIPLIST = 10
+TYPE_SET_INTSET = 11
+TYPE_ZSET_ZIPLIST = 12
+TYPE_HASH_ZIPLIST = 13
+TYPE_LIST_QUICKLIST = 14
+TYPE_STREAM_LISTPACKS = 15
+TYPE_HASH_LISTPACK = 16
+TYPE_ZSET_LISTPACK = 17
+TYPE_LIST_QUICKLIST_2 = 18
+TYPE_STREAM_LISTPACKS_2 = 19
+TYPE_SET_LISTPACK = 20
+TYPE_STREAM_LISTPACKS_3 = 21
+TYPE_HASH_METADATA = 24
+TYPE_HASH_LISTPACK_EX = 25
+
+# Fixed timestamps keep the generated files stable.
+NOW_MS = 1_700_000_000_000
+FAR_FUTURE_MS = 4_102_444_800_000  # 2100-01-01
+
+
+def crc64(data):
+    """CRC-64/Jones as used by Redis, reflected, polynomial 0xad93d23594c935a9."""
+    poly = 0x95AC9329AC4BC9B5  # the reflected polynomial
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ poly if crc & 1 else crc >> 1
+    return crc
+
+
+def lzf_compress(data):
+    """A greedy LZF compressor, enough to produce valid back references."""
+    out = bytearray()
+    literals = bytearray()
+    table = {}
+    i = 0
+
+    def flush():
+        while literals:
+            chunk = literals[:32]
+            del literals[:32]
+            out.append(len(chunk) - 1)
+            out.extend(chunk)
+
+    while i < len(data):
+        key = data[i:i + 3]
+        candidate = table.get(key) if len(key) == 3 else None
+        table[key] = i
+        if candidate is not None and 0 < i - candidate <= 8192:
+            length = 3
+            while (i + length < len(data) and length < 264
+                   and data[candidate + length] == data[i + length]):
+                length += 1
+            flush()
+            offset = i - candidate - 1
+            if length - 2 < 7:
+                out.append(((length - 2) << 5) | (offset >> 8))
+            else:
+                out.append((7 << 5) | (offset >> 8))
+                out.append(length - 2 - 7)
+            out.append(offset & 0xFF)
+            i += length
+        else:
+            literals.append(data[i])
+            i += 1
+    flush()
+    return bytes(out)
+
+
+def length(n):
+    if n < 1 << 6:
+        return bytes([n])
+    if n < 1 << 14:
+        return bytes([0x40 | (n >> 8), n & 0xFF])
+    if n <= 0xFFFFFFFF:
+        return b"\x80" + struct.pack(">I", n)
+    return b"\x81" + struct.pack(">Q", n)
+
+
+def string(value, compress=True):
+    if isinstance(value, str):
+        value = value.encode()
+    try:
+        integer = int(value)
+        canonical = str(integer).encode() == value
+    except ValueError:
+        canonical = False
+    if canonical and len(value) <= 11:
+        if -(1 << 7) <= integer < 1 << 7:
+            return b"\xc0" + struct.pack("<b", integer)
+        if -(1 << 15) <= integer < 1 << 15:
+            return b"\xc1" + struct.pack("<h", integer)
+        if -(1 << 31) <= integer < 1 << 31:
+            return b"\xc2" + struct.pack("<i", integer)
+    if compress and len(value) > 20:
+        compressed = lzf_compress(value)
+        if len(compressed) <= len(value) - 4:
+            return b"\xc3" + length(len(compressed)) + length(len(value)) + compressed
+    return length(len(value)) + value
+
+
+def as_int(value):
+    if isinstance(value, int):
+        return value
+    if isinstance(value, str):
+        value = value.encode()
+    try:
+        integer = int(value)
+    except ValueError:
+        return None
+    return integer if str(integer).encode() == value else None
+
+
+def as_bytes(value):
+    if isinstance(value, int):
+        return str(value).encode()
+    return value.encode() if isinstance(value, str) else value
+
+
+def listpack(entries):
+    body = bytearray()
+    for entry in entries:
+        integer = as_int(entry)
+        if integer is not None and -(1 << 63) <= integer < 1 << 63:
+            if 0 <= integer <= 127:
+                encoded = bytes([integer])
+            elif -4096 <= integer <= 4095:
+                value = integer & 0x1FFF
+                encoded = bytes([0xC0 | (value >> 8), value & 0xFF])
+            elif -(1 << 15) <= integer < 1 << 15:
+                encoded = b"\xf1" + struct.pack("<h", integer)
+            elif -(1 << 23) <= integer < 1 << 23:
+                encoded = b"\xf2" + struct.pack("<i", integer)[:3]
+            elif -(1 << 31) <= integer < 1 << 31:
+                encoded = b"\xf3" + struct.pack("<i", integer)
+            else:
+                encoded = b"\xf4" + struct.pack("<q", integer)
+        else:
+            value = as_bytes(entry)
+            if len(value) < 64:
+                encoded = bytes([0x80 | len(value)]) + value
+            elif len(value) < 4096:
+                encoded = bytes([0xE0 | (len(value) >> 8), len(value) & 0xFF]) + value
+            else:
+                encoded = b"\xf0" + struct.pack("<I", len(value)) + value
+        body += encoded + backlen(len(encoded))
+    total = 6 + len(body) + 1
+    return struct.pack("<IH", total, min(len(entries), 65535)) + bytes(body) + b"\xff"
+
+
+def backlen(n):
+    if n <= 127:
+        return bytes([n])
+    if n < 16383:
+        return bytes([n >> 7, (n & 127) | 128])
+    if n < 2097151:
+        return bytes([n >> 14, ((n >> 7) & 127) | 128, (n & 127) | 128])
+    if n < 268435455:
+        return bytes([n >> 21, ((n >> 14) & 127) | 128, ((n >> 7) & 127) | 128, (n & 127) | 128])
+    return bytes([n >> 28, ((n >> 21) & 127) | 128, ((n >> 14) & 127) | 128,
+                  ((n >> 7) & 127) | 128, (n & 127) | 128])
+
+
+def ziplist(entries):
+    body = bytearray()
+    prevlen = 0
+    tail = 10
+    for entry in entries:
+        header = bytes([prevlen]) if prevlen < 254 else b"\xfe" + struct.pack("<I", prevlen)
+        integer = as_int(entry)
+        if integer is not None and -(1 << 63) <= integer < 1 << 63:
+            if 0 <= integer <= 12:
+                encoded = bytes([0xF1 + integer])
+            elif -(1 << 7) <= integer < 1 << 7:
+                encoded = b"\xfe" + struct.pack("<b", integer)
+            elif -(1 << 15) <= integer < 1 << 15:
+                encoded = b"\xc0" + struct.pack("<h", integer)
+            elif -(1 << 23) <= integer < 1 << 23:
+                encoded = b"\xf0" + struct.pack("<i", integer)[:3]
+            elif -(1 << 31) <= integer < 1 << 31:
+                encoded = b"\xd0" + struct.pack("<i", integer)
+            else:
+                encoded = b"\xe0" + struct.pack("<q", integer)
+        else:
+            value = as_bytes(entry)
+            if len(value) <= 63:
+                encoded = bytes([len(value)]) + value
+            elif len(value) <= 16383:
+                encoded = bytes([0x40 | (len(value) >> 8), len(value) & 0xFF]) + value
+            else:
+                encoded = b"\x80" + struct.pack(">I", len(value)) + value
+        tail = 10 + len(body)
+        entry_bytes = header + encoded
+        body += entry_bytes
+        prevlen = len(entry_bytes)
+    total = 10 + len(body) + 1
+    return struct.pack("<IIH", total, tail, len(entries)) + bytes(body) + b"\xff"
+
+
+def intset(values):
+    values = sorted(values)
+    if all(-(1 << 15) <= v < 1 << 15 for v in values):
+        width, fmt = 2, "<h"
+    elif all(-(1 << 31) <= v < 1 << 31 for v in values):
+        width, fmt = 4, "<i"
+    else:
+        width, fmt = 8, "<q"
+    return struct.pack("<II", width, len(values)) + b"".join(struct.pack(fmt, v) for v in values)
+
+
+def zipmap(pairs, free=0):
+    def zm_length(n):
+        return bytes([n]) if n < 254 else b"\xfe" + struct.pack("<I", n)
+
+    out = bytearray([min(len(pairs), 254)])
+    for field, value in pairs:
+        field, value = as_bytes(field), as_bytes(value)
+        out += zm_length(len(field)) + field
+        out += zm_length(len(value)) + bytes([free]) + value + b"\x00" * free
+    return bytes(out) + b"\xff"
+
+
+def module_type_id(name, version):
+    """A module type id: the 9 character name in 6 bit symbols, then a 10 bit version."""
+    charset = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_"
+    module_id = 0
+    for char in name.encode():
+        module_id = (module_id << 6) | charset.index(char)
+    return (module_id << 10) | version
+
+
+def stream_id(ms, seq):
+    return struct.pack(">QQ", ms, seq)
+
+
+def stream_node(master, entries, deleted=()):
+    """A stream listpack node: the master entry, then every entry as a diff
+    from the node's first id, with shared field names elided."""
+    master_fields = [field for field, _ in entries[0][1]]
+    live = len(entries) - len(deleted)
+    items = [live, len(deleted), len(master_fields), *master_fields, 0]
+    for (ms, seq), pairs in entries:
+        same = [field for field, _ in pairs] == master_fields
+        flags = (2 if same else 0) | (1 if (ms, seq) in deleted else 0)
+        start = len(items)
+        items += [flags, ms - master[0], seq - master[1]]
+        if same:
+            items += [value for _, value in pairs]
+        else:
+            items.append(len(pairs))
+            for field, value in pairs:
+                items += [field, value]
+        items.append(len(items) - start)
+    return string(stream_id(*master), compress=False) + string(listpack(items))
+
+
+def stream(version, nodes, length_, last_id, groups, first_id=None, max_deleted=(0, 0),
+           entries_added=None):
+    out = length(len(nodes))
+    for node in nodes:
+        out += stream_node(*node)
+    out += length(length_) + length(last_id[0]) + length(last_id[1])
+    if version >= 2:
+        out += length(first_id[0]) + length(first_id[1])
+        out += length(max_deleted[0]) + length(max_deleted[1])
+        out += length(entries_added)
+    out += length(len(groups))
+    for group in groups:
+        out += string(group["name"])
+        out += length(group["last_id"][0]) + length(group["last_id"][1])
+        if version >= 2:
+            out += length(group["entries_read"])
+        out += length(len(group["pending"]))
+        for (ms, seq), (delivery_time, count, _) in group["pending"].items():
+            out += stream_id(ms, seq) + struct.pack("<q", delivery_time) + length(count)
+        out += length(len(group["consumers"]))
+        for name, seen_time, active_time in group["consumers"]:
+            out += string(name) + struct.pack("<q", seen_time)
+            if version >= 3:
+                out += struct.pack("<q", active_time)
+            owned = [id_ for id_, (_, _, owner) in group["pending"].items() if owner == name]
+            out += length(len(owned))
+            for ms, seq in owned:
+                out += stream_id(ms, seq)
+    return out
+
+
+class Rdb:
+    def __init__(self, version, redis_version):
+        self.version = version
+        self.out = bytearray(b"REDIS%04d" % version)
+        # Auxiliary fields and the trailing checksum came with versions 7 and 5.
+        if version >= 7:
+            self.aux("redis-ver", redis_version)
+            self.aux("redis-bits", "64")
+            self.aux("ctime", str(NOW_MS // 1000))
+            self.aux("used-mem", "1048576")
+
+    def aux(self, field, value):
+        self.out += bytes([OPCODE_AUX]) + string(field) + string(value)
+
+    def select(self, db, keys, expires):
+        self.out += bytes([OPCODE_SELECTDB]) + length(db)
+        if self.version >= 7:
+            self.out += bytes([OPCODE_RESIZEDB]) + length(keys) + length(expires)
+
+    def key(self, value_type, key, payload, expire_ms=None, expire_s=None, idle=None, freq=None):
+        if expire_ms is not None:
+            self.out += bytes([OPCODE_EXPIRETIME_MS]) + struct.pack("<q", expire_ms)
+        if expire_s is not None:
+            self.out += bytes([OPCODE_EXPIRETIME]) + struct.pack("<i", expire_s)
+        if idle is not None:
+            self.out += bytes([OPCODE_IDLE]) + length(idle)
+        if freq is not None:
+            self.out += bytes([OPCODE_FREQ, freq])
+        self.out += bytes([value_type]) + string(key) + payload
+
+    def raw(self, data):
+        self.out += data
+
+    def write(self, name):
+        self.out += bytes([OPCODE_EOF])
+        if self.version >= 5:
+            self.out += struct.pack("<Q", crc64(self.out))
+        with open(os.path.join(HERE, name), "wb") as file:
+            file.write(self.out)
+
+
+def strings(values):
+    return length(len(values)) + b"".join(string(value) for value in values)
+
+
+def redis_2_4():
+    rdb = Rdb(4, "2.4.18")
+    rdb.select(0, 2, 0)
+    # Values are followed by free bytes left over from in-place updates.
+    rdb.key(TYPE_HASH_ZIPMAP, "hash:zipmap",
+            string(zipmap([("name", "redis"), ("version", "2.4"), ("big", "x" * 300)], free=2)))
+    rdb.key(TYPE_STRING, "string:expires-s", string("until 2038"), expire_s=2**31 - 1)
+    rdb.write("redis-2.4-zipmap.rdb")
+
+
+def redis_2_6():
+    rdb = Rdb(6, "2.6.17")
+    rdb.select(0, 10, 1)
+    rdb.key(TYPE_STRING, "string:plain", string("hello world"))
+    rdb.key(TYPE_STRING, "string:int", string("-1234567"))
+    rdb.key(TYPE_STRING, "string:lzf", string("abcabcabcabcabcabcabcabcabcabcabcabc"))
+    rdb.key(TYPE_STRING, "string:expires-ms", string("until 2100"), expire_ms=FAR_FUTURE_MS)
+    rdb.key(TYPE_LIST_ZIPLIST, "list:ziplist",
+            string(ziplist(["a", 0, 12, 13, -100, 1000, 100000, 2**31, "x" * 70])))
+    rdb.key(TYPE_SET_INTSET, "set:intset16", string(intset([1, 2, 3, -4])))
+    rdb.key(TYPE_SET_INTSET, "set:intset64", string(intset([1, 2**40, -(2**35)])))
+    rdb.key(TYPE_ZSET_ZIPLIST, "zset:ziplist",
+            string(ziplist(["one", 1, "two", "2.5", "three", "-3"])))
+    rdb.key(TYPE_HASH_ZIPLIST, "hash:ziplist", string(ziplist(["field", "value", "n", 42])))
+    rdb.select(1, 3, 0)
+    rdb.key(TYPE_LIST, "list:plain", strings(["first", "second", "third"]))
+    rdb.key(TYPE_SET, "set:plain", strings(["x", "y", "z"]))
+    # Scores as length prefixed strings, with 253-255 for NaN and the infinities.
+    rdb.key(TYPE_ZSET, "zset:plain",
+            length(3) + string("low") + b"\xff" + string("mid") + b"\x041.25"
+            + string("high") + b"\xfe")
+    rdb.write("redis-2.6-ziplist-intset.rdb")
+
+
+def redis_3_2():
+    rdb = Rdb(7, "3.2.13")
+    rdb.aux("aof-preamble", "0")
+    rdb.select(0, 3, 0)
+    first = ziplist(["node1-%d" % i for i in range(5)] + ["y" * 300, 7])
+    second = ziplist([-5, "tail"])
+    rdb.key(TYPE_LIST_QUICKLIST, "list:quicklist", length(2) + string(first) + string(second))
+    rdb.key(TYPE_HASH, "hash:plain",
+            length(2) + string("a") + string("1") + string("b") + string("2"))
+    rdb.key(TYPE_ZSET_2, "zset:binary",
+            length(2) + string("pi") + struct.pack("<d", 3.14159) + string("neg")
+            + struct.pack("<d", -0.5))
+    rdb.write("redis-3.2-quicklist.rdb")
+
+
+def redis_5_0():
+    rdb = Rdb(9, "5.0.14")
+    rdb.aux("repl-stream-db", "0")
+    rdb.aux("repl-id", "a" * 40)
+    rdb.aux("repl-offset", "0")
+    # A module's auxiliary data, which loaders without the module skip.
+    rdb.raw(bytes([OPCODE_MODULE_AUX]) + length(module_type_id("scripting", 1)) + length(2)
+            + length(2)
+            + length(1) + length(7) + length(5) + string("opaque") + length(3)
+            + struct.pack("<f", 1.5) + length(4) + struct.pack("<d", 2.5) + length(0))
+    rdb.select(0, 2, 0)
+    entries = [
+        ((NOW_MS, 0), [("temp", "21.5"), ("unit", "c")]),
+        ((NOW_MS, 1), [("temp", "22"), ("unit", "c")]),
+        ((NOW_MS + 5, 0), [("humidity", "40")]),
+    ]
+    group = {
+        "name": "readers",
+        "last_id": (NOW_MS, 1),
+        "pending": {(NOW_MS, 0): (NOW_MS + 100, 2, "alice"),
+                    (NOW_MS, 1): (NOW_MS + 200, 1, "bob")},
+        "consumers": [("alice", NOW_MS + 100, None), ("bob", NOW_MS + 200, None)],
+    }
+    rdb.key(TYPE_STREAM_LISTPACKS, "stream:v1",
+            stream(1, [((NOW_MS, 0), entries, {(NOW_MS, 1)})], 2, (NOW_MS + 5, 0), [group]),
+            idle=30)
+    rdb.key(TYPE_SET, "set:lfu", strings(["only"]), freq=5)
+    rdb.write("redis-5.0-stream.rdb")
+
+
+def redis_7_0():
+    rdb = Rdb(10, "7.0.15")
+    rdb.aux("aof-base", "0")
+    rdb.raw(bytes([OPCODE_FUNCTION2]) + string(
+        "#!lua name=mylib\nredis.register_function('echo', function(keys, args) "
+        "return args[1] end)"))
+    rdb.select(0, 5, 0)
+    # Quicklist nodes: a packed listpack, a plain node for an oversized element, a listpack.
+    rdb.key(TYPE_LIST_QUICKLIST_2, "list:quicklist2",
+            length(3) + length(2) + string(listpack(["a", "b", 1, -2000, 70000, 2**40]))
+            + length(1) + string("P" * 1000) + length(2) + string(listpack(["z" * 100])))
+    rdb.key(TYPE_HASH_LISTPACK, "hash:listpack",
+            string(listpack(["field1", "value1", "count", 10, "long", "v" * 5000])))
+    rdb.key(TYPE_ZSET_LISTPACK, "zset:listpack",
+            string(listpack(["a", 1, "b", "1.5", "c", "inf", "d", "-inf"])))
+    entries = [((NOW_MS + i, 0), [("n", str(i))]) for i in range(3)]
+    group = {
+        "name": "g",
+        "last_id": (NOW_MS + 2, 0),
+        "entries_read": 3,
+        "pending": {(NOW_MS + 2, 0): (NOW_MS, 1, "c1")},
+        "consumers": [("c1", NOW_MS, None), ("idle", NOW_MS - 1000, None)],
+    }
+    rdb.key(TYPE_STREAM_LISTPACKS_2, "stream:v2",
+            stream(2, [((NOW_MS, 0), entries)], 3, (NOW_MS + 2, 0), [group],
+                   first_id=(NOW_MS, 0), max_deleted=(NOW_MS - 1, 0), entries_added=4))
+    rdb.key(TYPE_STREAM_LISTPACKS_2, "stream:empty",
+            stream(2, [], 0, (NOW_MS, 7), [], first_id=(0, 0), max_deleted=(NOW_MS, 7),
+                   entries_added=8))
+    rdb.write("redis-7.0-listpack.rdb")
+
+
+def redis_7_2():
+    rdb = Rdb(11, "7.2.5")
+    rdb.select(0, 3, 0)
+    rdb.key(TYPE_SET_LISTPACK, "set:listpack", string(listpack(["apple", "banana", 7])))
+    rdb.key(TYPE_SET_INTSET, "set:intset32", string(intset([100000, -100000, 3])))
+    # More than one node, as with stream-node-max-entries 2.
+    node1 = [((NOW_MS, 0), [("k", "v0")]), ((NOW_MS, 1), [("k", "v1")])]
+    node2 = [((NOW_MS + 10, 0), [("k", "v2"), ("extra", "e")])]
+    groups = [
+        {"name": "g1", "last_id": (NOW_MS + 10, 0), "entries_read": 3,
+         "pending": {(NOW_MS, 1): (NOW_MS + 50, 3, "worker")},
+         "consumers": [("worker", NOW_MS + 60, NOW_MS + 50)]},
+        {"name": "g2", "last_id": (0, 0), "entries_read": 0, "pending": {}, "consumers": []},
+    ]
+    rdb.key(TYPE_STREAM_LISTPACKS_3, "stream:v3",
+            stream(3, [((NOW_MS, 0), node1), ((NOW_MS + 10, 0), node2)], 3, (NOW_MS + 10, 0),
+                   groups, first_id=(NOW_MS, 0), max_deleted=(0, 0), entries_added=3))
+    rdb.write("redis-7.2-set-listpack.rdb")
+
+
+def redis_7_4():
+    rdb = Rdb(12, "7.4.1")
+    rdb.raw(bytes([OPCODE_SLOT_INFO]) + length(866) + length(1) + length(0))
+    rdb.select(0, 2, 0)
+    # Field, value, absolute deadline triples; 0 means the field doesn't expire.
+    rdb.key(TYPE_HASH_LISTPACK_EX, "hash:listpack-ex",
+            struct.pack("<q", FAR_FUTURE_MS)
+            + string(listpack(["f1", "v1", FAR_FUTURE_MS, "f2", "v2", 0])))
+    # Deadlines relative to the smallest one, plus one; 0 means none.
+    rdb.key(TYPE_HASH_METADATA, "hash:metadata",
+            struct.pack("<q", FAR_FUTURE_MS) + length(2)
+            + length(1) + string("soon") + string("a")
+            + length(0) + string("never") + string("b"))
+    rdb.write("redis-7.4-hash-field-expiry.rdb")
+
+
+def module_value():
+    rdb = Rdb(11, "7.2.5")
+    rdb.select(0, 1, 0)
+    # "ReJSON-RL" encoding version 3, which no loader without the module can read.
+    rdb.key(TYPE_MODULE_2, "json:doc",
+            length(module_type_id("ReJSON-RL", 3)) + length(5) + string("{}") + length(0))
+    rdb.write("unsupported-module-value.rdb")
+
+
+if __name__ == "__main__":
+    redis_2_4()
+    redis_2_6()
+    redis_3_2()
+    redis_5_0()
+    redis_7_0()
+    redis_7_2()
+    redis_7_4()
+    module_value()
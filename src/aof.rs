@@ -0,0 +1,552 @@
+use crate::rdb::{self, Snapshot};
+use crate::resp_parser::{self, to_command_array, RespRequest};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// When appended writes are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every write, before it is acknowledged.
+    Always,
+    /// Once a second, in the background.
+    Everysec,
+    /// Whenever the operating system flushes its buffers.
+    No,
+}
+
+impl Fsync {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Some(Fsync::Always),
+            "everysec" => Some(Fsync::Everysec),
+            "no" => Some(Fsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fsync::Always => "always",
+            Fsync::Everysec => "everysec",
+            Fsync::No => "no",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileType {
+    Base,
+    Incr,
+}
+
+#[derive(Debug, Clone)]
+struct ManifestEntry {
+    name: String,
+    seq: u64,
+}
+
+/// The Redis 7 multi-part layout: a base file holding a snapshot of the
+/// dataset, followed by incremental files with the writes made since. The
+/// manifest names them, one `file <name> seq <n> type <b|i>` line each.
+#[derive(Debug, Clone, Default)]
+struct Manifest {
+    base: Option<ManifestEntry>,
+    incrs: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    fn path(dir: &Path, filename: &str) -> PathBuf {
+        dir.join(format!("{}.manifest", filename))
+    }
+
+    /// Reads the manifest, or returns None if there is none.
+    fn read(dir: &Path, filename: &str) -> Result<Option<Self>, String> {
+        let text = match fs::read_to_string(Self::path(dir, filename)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut manifest = Manifest::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let field = |key: &str| {
+                words
+                    .chunks(2)
+                    .find(|pair| pair[0] == key)
+                    .and_then(|pair| pair.get(1).copied())
+            };
+            let (Some(name), Some(seq), Some(file_type)) = (field("file"), field("seq"), field("type"))
+            else {
+                return Err(format!("Invalid AOF manifest line: {}", line));
+            };
+            let seq = seq
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid AOF manifest line: {}", line))?;
+            let entry = ManifestEntry {
+                name: name.to_string(),
+                seq,
+            };
+            match file_type {
+                "b" => manifest.base = Some(entry),
+                "i" => manifest.incrs.push(entry),
+                // History files are leftovers of a rewrite, waiting to be deleted.
+                "h" => {}
+                _ => return Err(format!("Invalid AOF manifest line: {}", line)),
+            }
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Replaces the manifest atomically.
+    fn write(&self, dir: &Path, filename: &str) -> io::Result<()> {
+        let mut text = String::new();
+        if let Some(base) = &self.base {
+            text += &format!("file {} seq {} type b\n", base.name, base.seq);
+        }
+        for incr in &self.incrs {
+            text += &format!("file {} seq {} type i\n", incr.name, incr.seq);
+        }
+        let path = Self::path(dir, filename);
+        let temp = dir.join(format!("temp-{}.manifest", filename));
+        let mut file = File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)
+    }
+
+    fn next_seq(&self, file_type: FileType) -> u64 {
+        let last = match file_type {
+            FileType::Base => self.base.as_ref().map(|base| base.seq),
+            FileType::Incr => self.incrs.last().map(|incr| incr.seq),
+        };
+        last.unwrap_or(0) + 1
+    }
+
+    fn files(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+}
+
+fn file_name(filename: &str, seq: u64, file_type: FileType) -> String {
+    match file_type {
+        FileType::Base => format!("{}.{}.base.rdb", filename, seq),
+        FileType::Incr => format!("{}.{}.incr.aof", filename, seq),
+    }
+}
+
+/// The open append-only file: writes are appended to the last incremental file.
+#[derive(Debug)]
+pub struct AppendOnlyFile {
+    dir: PathBuf,
+    filename: String,
+    manifest: Manifest,
+    incr: File,
+    /// Database the appended commands currently apply to.
+    selected_db: Option<usize>,
+    pub fsync: Fsync,
+    /// Whether writes were appended since the last fsync.
+    unsynced: bool,
+    /// Number of incremental files that a running rewrite will replace.
+    rewritten_incrs: Option<usize>,
+}
+
+impl AppendOnlyFile {
+    /// Opens the AOF in `dir`, creating the directory and a first incremental
+    /// file if needed. Returns whether a base file already existed.
+    pub fn open(dir: PathBuf, filename: &str, fsync: Fsync) -> Result<(Self, bool), String> {
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let mut manifest = Manifest::read(&dir, filename)?.unwrap_or_default();
+        let has_base = manifest.base.is_some();
+        if manifest.incrs.is_empty() {
+            let seq = manifest.next_seq(FileType::Incr);
+            manifest.incrs.push(ManifestEntry {
+                name: file_name(filename, seq, FileType::Incr),
+                seq,
+            });
+        }
+        let incr = open_for_append(&dir.join(&manifest.incrs.last().unwrap().name))
+            .map_err(|e| e.to_string())?;
+        manifest.write(&dir, filename).map_err(|e| e.to_string())?;
+        let aof = Self {
+            dir,
+            filename: filename.to_string(),
+            manifest,
+            incr,
+            selected_db: None,
+            fsync,
+            unsynced: false,
+            rewritten_incrs: None,
+        };
+        Ok((aof, has_base))
+    }
+
+    /// Appends `commands`, executed against database `db`.
+    pub fn append(&mut self, db: usize, commands: &[Vec<Vec<u8>>]) -> io::Result<()> {
+        let mut payload = vec![];
+        if self.selected_db != Some(db) {
+            payload.extend(to_command_array(&[
+                b"SELECT".to_vec(),
+                db.to_string().into_bytes(),
+            ]));
+            self.selected_db = Some(db);
+        }
+        for command in commands {
+            payload.extend(to_command_array(command));
+        }
+        self.incr.write_all(&payload)?;
+        match self.fsync {
+            Fsync::Always => self.incr.sync_data(),
+            Fsync::Everysec | Fsync::No => {
+                self.unsynced = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// A handle to fsync outside the lock, if `everysec` writes are pending.
+    pub fn take_unsynced(&mut self) -> Option<io::Result<File>> {
+        if self.fsync != Fsync::Everysec || !self.unsynced {
+            return None;
+        }
+        self.unsynced = false;
+        Some(self.incr.try_clone())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.incr.sync_data()
+    }
+
+    /// Starts a rewrite: later writes go to a new incremental file, which is
+    /// all that survives next to the new base. Must be called while the
+    /// dataset is being snapshotted, so the two split writes exactly.
+    pub fn start_rewrite(&mut self) -> io::Result<RewriteTarget> {
+        let seq = self.manifest.next_seq(FileType::Incr);
+        let name = file_name(&self.filename, seq, FileType::Incr);
+        self.incr = open_for_append(&self.dir.join(&name))?;
+        self.selected_db = None;
+        self.rewritten_incrs = Some(self.manifest.incrs.len());
+        self.manifest.incrs.push(ManifestEntry { name, seq });
+        self.manifest.write(&self.dir, &self.filename)?;
+        Ok(RewriteTarget {
+            dir: self.dir.clone(),
+            filename: self.filename.clone(),
+            seq: self.manifest.next_seq(FileType::Base),
+        })
+    }
+
+    /// Completes a rewrite whose base was written, dropping the files it replaces.
+    pub fn finish_rewrite(&mut self, base: &str, seq: u64) -> io::Result<()> {
+        let replaced = self.rewritten_incrs.take().unwrap_or(0);
+        let old = self.manifest.clone();
+        self.manifest.base = Some(ManifestEntry {
+            name: base.to_string(),
+            seq,
+        });
+        self.manifest.incrs.drain(..replaced);
+        self.manifest.write(&self.dir, &self.filename)?;
+        remove_unlisted(&self.dir, &old, &self.manifest);
+        Ok(())
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewritten_incrs = None;
+    }
+}
+
+/// Where a rewrite writes its base file.
+#[derive(Debug)]
+pub struct RewriteTarget {
+    pub dir: PathBuf,
+    pub filename: String,
+    pub seq: u64,
+}
+
+impl RewriteTarget {
+    /// A rewrite while the AOF is off only leaves a base file behind.
+    pub fn offline(dir: PathBuf, filename: &str) -> Result<Self, String> {
+        let manifest = Manifest::read(&dir, filename)?.unwrap_or_default();
+        Ok(Self {
+            seq: manifest.next_seq(FileType::Base),
+            dir,
+            filename: filename.to_string(),
+        })
+    }
+
+    /// Writes the snapshot as the new base file and returns its name.
+    pub fn write_base(&self, snapshot: &Snapshot) -> io::Result<String> {
+        fs::create_dir_all(&self.dir)?;
+        let name = file_name(&self.filename, self.seq, FileType::Base);
+        let temp = self.dir.join(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let written = File::create(&temp).and_then(|mut file| {
+            file.write_all(&rdb::encode(snapshot))?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp, self.dir.join(&name))) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        Ok(name)
+    }
+
+    /// Makes the base written while the AOF was off the whole AOF.
+    pub fn finish_offline(&self, base: &str) -> io::Result<()> {
+        let old = Manifest::read(&self.dir, &self.filename)
+            .map_err(io::Error::other)?
+            .unwrap_or_default();
+        let manifest = Manifest {
+            base: Some(ManifestEntry {
+                name: base.to_string(),
+                seq: self.seq,
+            }),
+            incrs: vec![],
+        };
+        manifest.write(&self.dir, &self.filename)?;
+        remove_unlisted(&self.dir, &old, &manifest);
+        Ok(())
+    }
+}
+
+fn open_for_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn remove_unlisted(dir: &Path, old: &Manifest, current: &Manifest) {
+    for entry in old.files() {
+        if !current.files().any(|kept| kept.name == entry.name) {
+            if let Err(e) = fs::remove_file(dir.join(&entry.name)) {
+                println!("[ERROR] : Could not remove {}: {}", entry.name, e);
+            }
+        }
+    }
+}
+
+/// Replays the AOF in `dir`: the base file goes to `load_base` if it is an RDB
+/// file, and every command of the AOF files to `apply`, in order. Returns false
+/// if there is no AOF. A last file cut short by a crash is truncated to its
+/// last complete command when `load_truncated` is set, and an error otherwise.
+pub fn load(
+    dir: &Path,
+    filename: &str,
+    load_truncated: bool,
+    mut load_base: impl FnMut(Snapshot) -> Result<(), String>,
+    mut apply: impl FnMut(RespRequest),
+) -> Result<bool, String> {
+    let Some(manifest) = Manifest::read(dir, filename)? else {
+        return Ok(false);
+    };
+    let count = manifest.files().count();
+    for (index, entry) in manifest.files().enumerate() {
+        let path = dir.join(&entry.name);
+        let data = fs::read(&path).map_err(|e| format!("{}: {}", entry.name, e))?;
+        if data.starts_with(b"REDIS") {
+            let snapshot = rdb::decode(&data).map_err(|e| format!("{}: {}", entry.name, e))?;
+            load_base(snapshot)?;
+            continue;
+        }
+
+        let mut position = 0;
+        while position < data.len() {
+            if data[position] != b'*' {
+                return Err(format!(
+                    "Bad file format reading the append only file {}",
+                    entry.name
+                ));
+            }
+            let Some((request, consumed)) = resp_parser::handle_resp_request(&data[position..])
+            else {
+                break;
+            };
+            apply(request);
+            position += consumed;
+        }
+        if position < data.len() {
+            if index + 1 < count || !load_truncated {
+                return Err(format!(
+                    "Unexpected end of file reading the append only file {}",
+                    entry.name
+                ));
+            }
+            println!(
+                "[ERROR] : !!! Warning: short read while loading the AOF file {}!!!",
+                entry.name
+            );
+            println!(
+                "[ERROR] : AOF {} loaded anyway because aof-load-truncated is enabled, truncating it to {} bytes",
+                entry.name, position
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len(position as u64))
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RedisValue;
+
+    /// An empty directory of its own for each test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn command(parts: &[&str]) -> Vec<Vec<u8>> {
+        parts.iter().map(|part| part.as_bytes().to_vec()).collect()
+    }
+
+    /// Loads the AOF in `dir`, returning how many base files were loaded and
+    /// the name and keys of every replayed command.
+    fn replay(dir: &Path, load_truncated: bool) -> Result<(usize, Vec<String>), String> {
+        let mut bases = 0;
+        let mut commands = vec![];
+        load(
+            dir,
+            "appendonly.aof",
+            load_truncated,
+            |_| {
+                bases += 1;
+                Ok(())
+            },
+            |request| {
+                let mut parts = vec![request.name.clone()];
+                parts.extend(request.keys().into_iter().cloned());
+                commands.push(parts.join(" "));
+            },
+        )?;
+        Ok((bases, commands))
+    }
+
+    #[test]
+    fn parses_manifest_lines() {
+        let dir = scratch("manifest");
+        fs::write(
+            dir.join("appendonly.aof.manifest"),
+            "# written by hand\n\
+             file appendonly.aof.3.base.rdb seq 3 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type h\n\
+             seq 4 type i file appendonly.aof.4.incr.aof\n\
+             file appendonly.aof.5.incr.aof seq 5 type i\n",
+        )
+        .unwrap();
+        let manifest = Manifest::read(&dir, "appendonly.aof").unwrap().unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 3);
+        let incrs: Vec<_> = manifest
+            .incrs
+            .iter()
+            .map(|incr| incr.name.as_str())
+            .collect();
+        assert_eq!(
+            incrs,
+            vec!["appendonly.aof.4.incr.aof", "appendonly.aof.5.incr.aof"]
+        );
+        assert_eq!(manifest.next_seq(FileType::Base), 4);
+        assert_eq!(manifest.next_seq(FileType::Incr), 6);
+
+        fs::write(
+            dir.join("appendonly.aof.manifest"),
+            "file appendonly.aof.1.incr.aof seq one type i\n",
+        )
+        .unwrap();
+        assert!(Manifest::read(&dir, "appendonly.aof").is_err());
+        assert!(Manifest::read(&dir, "missing.aof").unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_appended_commands() {
+        let dir = scratch("replay");
+        assert_eq!(replay(&dir, false), Ok((0, vec![])));
+        let (mut aof, has_base) =
+            AppendOnlyFile::open(dir.clone(), "appendonly.aof", Fsync::Always).unwrap();
+        assert!(!has_base);
+        aof.append(0, &[command(&["SET", "a", "1"])]).unwrap();
+        aof.append(0, &[command(&["HSET", "h", "f", "v"])]).unwrap();
+        aof.append(2, &[command(&["DEL", "b"]), command(&["SET", "c", "2"])])
+            .unwrap();
+        drop(aof);
+
+        assert_eq!(
+            replay(&dir, false),
+            Ok((
+                0,
+                vec![
+                    "SELECT".to_string(),
+                    "SET a".to_string(),
+                    "HSET h".to_string(),
+                    "SELECT".to_string(),
+                    "DEL b".to_string(),
+                    "SET c".to_string(),
+                ]
+            ))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rewrite_replaces_earlier_files() {
+        let dir = scratch("rewrite");
+        let (mut aof, _) = AppendOnlyFile::open(dir.clone(), "appendonly.aof", Fsync::No).unwrap();
+        aof.append(0, &[command(&["SET", "a", "1"])]).unwrap();
+        let target = aof.start_rewrite().unwrap();
+        aof.append(0, &[command(&["SET", "b", "2"])]).unwrap();
+        let snapshot = Snapshot {
+            databases: vec![(
+                0,
+                vec![rdb::Entry {
+                    key: "a".to_string(),
+                    value: RedisValue::String(b"1".to_vec()),
+                    deadline: None,
+                }],
+            )],
+            ..Default::default()
+        };
+        let base = target.write_base(&snapshot).unwrap();
+        aof.finish_rewrite(&base, target.seq).unwrap();
+        drop(aof);
+
+        assert!(!dir.join("appendonly.aof.1.incr.aof").exists());
+        assert!(dir.join(&base).exists());
+        assert_eq!(
+            replay(&dir, false),
+            Ok((1, vec!["SELECT".to_string(), "SET b".to_string()]))
+        );
+        let (_, has_base) = AppendOnlyFile::open(dir.clone(), "appendonly.aof", Fsync::No).unwrap();
+        assert!(has_base);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_cut_short_tail_only_when_allowed() {
+        let dir = scratch("truncated");
+        let (mut aof, _) =
+            AppendOnlyFile::open(dir.clone(), "appendonly.aof", Fsync::Always).unwrap();
+        aof.append(0, &[command(&["SET", "a", "1"])]).unwrap();
+        drop(aof);
+        let incr = dir.join("appendonly.aof.1.incr.aof");
+        let complete = fs::metadata(&incr).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&incr).unwrap();
+        file.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nb").unwrap();
+        drop(file);
+
+        assert!(replay(&dir, false).is_err());
+        assert_eq!(
+            replay(&dir, true),
+            Ok((0, vec!["SELECT".to_string(), "SET a".to_string()]))
+        );
+        assert_eq!(fs::metadata(&incr).unwrap().len(), complete);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
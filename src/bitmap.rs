@@ -0,0 +1,679 @@
+use crate::resp_parser::{
+    string_to_simple_resp, to_error, to_integer, wrong_arguments, Command, Content, RespRequest,
+};
+use crate::storage::{Keyspace, RedisValue};
+
+/// Strings are capped at 512MB, so bit offsets must stay below 2^32.
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8;
+
+#[derive(Debug, Clone, Copy)]
+enum Unit {
+    Byte,
+    Bit,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BitfieldOp {
+    Get,
+    Set(i64),
+    Incrby(i64),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BitfieldCall {
+    op: BitfieldOp,
+    signed: bool,
+    bits: u32,
+    offset: u64,
+    overflow: Overflow,
+}
+
+pub fn handle_bitmap_command(request: &RespRequest, storage: &mut Keyspace) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Setbit => setbit(storage, arguments),
+        Command::Getbit => getbit(storage, arguments),
+        Command::Bitcount => bitcount(storage, arguments),
+        Command::Bitpos => bitpos(storage, arguments),
+        Command::Bitop => bitop(storage, arguments),
+        Command::Bitfield => bitfield(storage, arguments, false),
+        Command::BitfieldRo => bitfield(storage, arguments, true),
+        _ => to_error("ERR unknown bitmap command"),
+    }
+}
+
+fn parse_integer(argument: &Content) -> Result<i64, Vec<u8>> {
+    argument
+        .content
+        .parse::<i64>()
+        .map_err(|_| to_error("ERR value is not an integer or out of range"))
+}
+
+fn parse_bit_offset(argument: &Content) -> Result<u64, Vec<u8>> {
+    match argument.content.parse::<u64>() {
+        Ok(offset) if offset < MAX_BIT_OFFSET => Ok(offset),
+        _ => Err(to_error("ERR bit offset is not an integer or out of range")),
+    }
+}
+
+fn parse_unit(argument: Option<&Content>) -> Result<Unit, Vec<u8>> {
+    match argument {
+        None => Ok(Unit::Byte),
+        Some(unit) if unit.content.eq_ignore_ascii_case("BYTE") => Ok(Unit::Byte),
+        Some(unit) if unit.content.eq_ignore_ascii_case("BIT") => Ok(Unit::Bit),
+        Some(_) => Err(to_error("ERR syntax error")),
+    }
+}
+
+/// Returns the string stored at `key`, creating it or zero-padding it so that
+/// it holds at least `min_len` bytes. Any existing expiry is preserved.
+fn string_for_write<'a>(
+    storage: &'a mut Keyspace,
+    key: &String,
+    min_len: usize,
+) -> Result<&'a mut Vec<u8>, Vec<u8>> {
+    match storage.get_string(key) {
+        Ok(Some(_)) => {}
+        Ok(None) => storage.update(key.clone(), RedisValue::String(vec![]), i64::MAX),
+        Err(error) => return Err(to_error(&error.to_string())),
+    }
+    let value = storage.get_string_mut(key).unwrap().unwrap();
+    if value.len() < min_len {
+        value.resize(min_len, 0);
+    }
+    Ok(value)
+}
+
+fn string_for_read<'a>(
+    storage: &'a Keyspace,
+    key: &String,
+) -> Result<Option<&'a Vec<u8>>, Vec<u8>> {
+    storage
+        .get_string(key)
+        .map_err(|error| to_error(&error.to_string()))
+}
+
+/// Resolves a possibly negative `start`/`end` pair into an inclusive range of
+/// bit positions, or `None` when the range is empty.
+fn resolve_bit_range(start: i64, end: i64, unit: Unit, byte_len: usize) -> Option<(u64, u64)> {
+    let len = match unit {
+        Unit::Byte => byte_len as i64,
+        Unit::Bit => byte_len as i64 * 8,
+    };
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+
+    if start > end {
+        return None;
+    }
+    match unit {
+        Unit::Byte => Some((start as u64 * 8, end as u64 * 8 + 7)),
+        Unit::Bit => Some((start as u64, end as u64)),
+    }
+}
+
+/// Masks off the bits of byte `index` that fall outside `[low, high]`.
+fn mask_byte(byte: u8, index: u64, low: u64, high: u64) -> u8 {
+    let mut byte = byte;
+    if index == low / 8 {
+        byte &= 0xFF >> (low % 8);
+    }
+    if index == high / 8 {
+        byte &= 0xFF << (7 - high % 8);
+    }
+    byte
+}
+
+fn setbit(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 3 {
+        return wrong_arguments("setbit");
+    }
+    let offset = match parse_bit_offset(&arguments[1]) {
+        Ok(offset) => offset,
+        Err(message) => return message,
+    };
+    let on = match arguments[2].content.as_str() {
+        "0" => false,
+        "1" => true,
+        _ => return to_error("ERR bit is not an integer or out of range"),
+    };
+
+    let byte_index = (offset / 8) as usize;
+    let shift = 7 - (offset % 8);
+    let value = match string_for_write(storage, &arguments[0].content, byte_index + 1) {
+        Ok(value) => value,
+        Err(message) => return message,
+    };
+
+    let previous = (value[byte_index] >> shift) & 1;
+    if on {
+        value[byte_index] |= 1 << shift;
+    } else {
+        value[byte_index] &= !(1 << shift);
+    }
+    to_integer(previous as i64)
+}
+
+fn getbit(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 2 {
+        return wrong_arguments("getbit");
+    }
+    let offset = match parse_bit_offset(&arguments[1]) {
+        Ok(offset) => offset,
+        Err(message) => return message,
+    };
+
+    let value = match string_for_read(storage, &arguments[0].content) {
+        Ok(value) => value,
+        Err(message) => return message,
+    };
+    let bit = value
+        .and_then(|value| value.get((offset / 8) as usize))
+        .map(|byte| (byte >> (7 - offset % 8)) & 1)
+        .unwrap_or(0);
+    to_integer(bit as i64)
+}
+
+fn bitcount(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("bitcount");
+    }
+    let empty = vec![];
+    let value = match string_for_read(storage, &arguments[0].content) {
+        Ok(value) => value.unwrap_or(&empty),
+        Err(message) => return message,
+    };
+
+    let range = match arguments.len() {
+        1 => resolve_bit_range(0, -1, Unit::Byte, value.len()),
+        3 | 4 => {
+            let start = match parse_integer(&arguments[1]) {
+                Ok(start) => start,
+                Err(message) => return message,
+            };
+            let end = match parse_integer(&arguments[2]) {
+                Ok(end) => end,
+                Err(message) => return message,
+            };
+            let unit = match parse_unit(arguments.get(3)) {
+                Ok(unit) => unit,
+                Err(message) => return message,
+            };
+            resolve_bit_range(start, end, unit, value.len())
+        }
+        _ => return to_error("ERR syntax error"),
+    };
+
+    let Some((low, high)) = range else {
+        return to_integer(0);
+    };
+    let count: u32 = (low / 8..=high / 8)
+        .map(|index| mask_byte(value[index as usize], index, low, high).count_ones())
+        .sum();
+    to_integer(count as i64)
+}
+
+fn bitpos(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 2 {
+        return wrong_arguments("bitpos");
+    }
+    let bit = match arguments[1].content.as_str() {
+        "0" => false,
+        "1" => true,
+        _ => return to_error("ERR The bit argument must be 1 or 0."),
+    };
+    if arguments.len() > 5 {
+        return to_error("ERR syntax error");
+    }
+    let start = match arguments.get(2).map(parse_integer).transpose() {
+        Ok(start) => start.unwrap_or(0),
+        Err(message) => return message,
+    };
+    let end = match arguments.get(3).map(parse_integer).transpose() {
+        Ok(end) => end,
+        Err(message) => return message,
+    };
+    let unit = match parse_unit(arguments.get(4)) {
+        Ok(unit) => unit,
+        Err(message) => return message,
+    };
+
+    let value = match string_for_read(storage, &arguments[0].content) {
+        Ok(Some(value)) => value,
+        Ok(None) => return to_integer(if bit { -1 } else { 0 }),
+        Err(message) => return message,
+    };
+    let Some((low, high)) = resolve_bit_range(start, end.unwrap_or(-1), unit, value.len()) else {
+        return to_integer(-1);
+    };
+
+    for index in low / 8..=high / 8 {
+        let byte = if bit {
+            value[index as usize]
+        } else {
+            !value[index as usize]
+        };
+        let byte = mask_byte(byte, index, low, high);
+        if byte != 0 {
+            return to_integer((index * 8 + byte.leading_zeros() as u64) as i64);
+        }
+    }
+
+    // Looking for a clear bit in an open-ended range of set bits behaves as if
+    // the string were padded with zeros on the right.
+    if !bit && end.is_none() {
+        to_integer(high as i64 + 1)
+    } else {
+        to_integer(-1)
+    }
+}
+
+fn bitop(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 3 {
+        return wrong_arguments("bitop");
+    }
+    let operation = arguments[0].content.to_ascii_uppercase();
+    if !["AND", "OR", "XOR", "NOT"].contains(&operation.as_str()) {
+        return to_error("ERR syntax error");
+    }
+    if operation == "NOT" && arguments.len() != 3 {
+        return to_error("ERR BITOP NOT must be called with a single source key.");
+    }
+
+    let mut sources: Vec<Vec<u8>> = vec![];
+    for key in &arguments[2..] {
+        match string_for_read(storage, &key.content) {
+            Ok(value) => sources.push(value.cloned().unwrap_or_default()),
+            Err(message) => return message,
+        }
+    }
+    let max_len = sources.iter().map(Vec::len).max().unwrap_or(0);
+
+    let result: Vec<u8> = (0..max_len)
+        .map(|index| {
+            let mut bytes = sources
+                .iter()
+                .map(|source| source.get(index).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            match operation.as_str() {
+                "AND" => bytes.fold(first, |acc, byte| acc & byte),
+                "OR" => bytes.fold(first, |acc, byte| acc | byte),
+                "XOR" => bytes.fold(first, |acc, byte| acc ^ byte),
+                _ => !first,
+            }
+        })
+        .collect();
+
+    let destination = arguments[1].content.clone();
+    if result.is_empty() {
+        storage.remove(&destination);
+    } else {
+        storage.update(destination, RedisValue::String(result), i64::MAX);
+    }
+    to_integer(max_len as i64)
+}
+
+fn parse_bitfield_type(argument: &Content) -> Result<(bool, u32), Vec<u8>> {
+    let invalid = || {
+        to_error("ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.")
+    };
+    let content = argument.content.as_str();
+    let signed = match content.chars().next() {
+        Some('i') | Some('I') => true,
+        Some('u') | Some('U') => false,
+        _ => return Err(invalid()),
+    };
+    let bits = content[1..].parse::<u32>().map_err(|_| invalid())?;
+    if bits == 0 || (signed && bits > 64) || (!signed && bits > 63) {
+        return Err(invalid());
+    }
+    Ok((signed, bits))
+}
+
+fn parse_bitfield_offset(argument: &Content, bits: u32) -> Result<u64, Vec<u8>> {
+    let invalid = || to_error("ERR bit offset is not an integer or out of range");
+    let offset = match argument.content.strip_prefix('#') {
+        Some(multiplier) => multiplier
+            .parse::<u64>()
+            .ok()
+            .and_then(|multiplier| multiplier.checked_mul(bits as u64)),
+        None => argument.content.parse::<u64>().ok(),
+    }
+    .ok_or_else(invalid)?;
+    offset
+        .checked_add(bits as u64)
+        .filter(|end| *end <= MAX_BIT_OFFSET)
+        .ok_or_else(invalid)?;
+    Ok(offset)
+}
+
+fn parse_bitfield(arguments: &[Content], readonly: bool) -> Result<Vec<BitfieldCall>, Vec<u8>> {
+    let mut calls = vec![];
+    let mut overflow = Overflow::Wrap;
+    let mut index = 1;
+
+    while index < arguments.len() {
+        let subcommand = arguments[index].content.to_ascii_uppercase();
+        let remaining = arguments.len() - index - 1;
+
+        if subcommand == "OVERFLOW" && remaining >= 1 {
+            overflow = match arguments[index + 1].content.to_ascii_uppercase().as_str() {
+                "WRAP" => Overflow::Wrap,
+                "SAT" => Overflow::Sat,
+                "FAIL" => Overflow::Fail,
+                _ => return Err(to_error("ERR Invalid OVERFLOW type specified")),
+            };
+            index += 2;
+            continue;
+        }
+
+        let op = match subcommand.as_str() {
+            "GET" if remaining >= 2 => BitfieldOp::Get,
+            "SET" if remaining >= 3 => BitfieldOp::Set(parse_integer(&arguments[index + 3])?),
+            "INCRBY" if remaining >= 3 => BitfieldOp::Incrby(parse_integer(&arguments[index + 3])?),
+            _ => return Err(to_error("ERR syntax error")),
+        };
+        if readonly && !matches!(op, BitfieldOp::Get) {
+            return Err(to_error("ERR BITFIELD_RO only supports the GET subcommand"));
+        }
+
+        let (signed, bits) = parse_bitfield_type(&arguments[index + 1])?;
+        let offset = parse_bitfield_offset(&arguments[index + 2], bits)?;
+        calls.push(BitfieldCall {
+            op,
+            signed,
+            bits,
+            offset,
+            overflow,
+        });
+        index += if matches!(op, BitfieldOp::Get) { 3 } else { 4 };
+    }
+
+    Ok(calls)
+}
+
+fn read_bits(value: &[u8], offset: u64, bits: u32) -> u64 {
+    (offset..offset + bits as u64).fold(0, |acc, position| {
+        let bit = value
+            .get((position / 8) as usize)
+            .map(|byte| (byte >> (7 - position % 8)) & 1)
+            .unwrap_or(0);
+        (acc << 1) | bit as u64
+    })
+}
+
+fn write_bits(value: &mut [u8], offset: u64, bits: u32, field: u64) {
+    for (index, position) in (offset..offset + bits as u64).enumerate() {
+        let bit = (field >> (bits as usize - 1 - index)) & 1;
+        let byte = &mut value[(position / 8) as usize];
+        let shift = 7 - position % 8;
+        if bit == 1 {
+            *byte |= 1 << shift;
+        } else {
+            *byte &= !(1 << shift);
+        }
+    }
+}
+
+fn sign_extend(field: u64, bits: u32) -> i64 {
+    if bits < 64 && field & (1 << (bits - 1)) != 0 {
+        (field | (u64::MAX << bits)) as i64
+    } else {
+        field as i64
+    }
+}
+
+/// Applies `increment` to a field of the given width, returning `None` when
+/// the result overflows under `OVERFLOW FAIL`.
+fn apply_overflow(current: i64, increment: i64, call: &BitfieldCall) -> Option<i64> {
+    let bits = call.bits;
+    let (min, max) = if call.signed {
+        let max = if bits == 64 {
+            i64::MAX as i128
+        } else {
+            (1i128 << (bits - 1)) - 1
+        };
+        (-max - 1, max)
+    } else {
+        (0, (1i128 << bits) - 1)
+    };
+    let current_wide = if call.signed {
+        current as i128
+    } else {
+        current as u64 as i128
+    };
+    let sum = current_wide + increment as i128;
+
+    if sum >= min && sum <= max {
+        return Some(sum as i64);
+    }
+    match call.overflow {
+        Overflow::Fail => None,
+        Overflow::Sat => Some(if sum > max { max as i64 } else { min as i64 }),
+        Overflow::Wrap => {
+            let wrapped = (current as u64).wrapping_add(increment as u64);
+            if call.signed {
+                Some(sign_extend(wrapped & field_mask(bits), bits))
+            } else {
+                Some((wrapped & field_mask(bits)) as i64)
+            }
+        }
+    }
+}
+
+fn field_mask(bits: u32) -> u64 {
+    if bits == 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+fn bitfield(storage: &mut Keyspace, arguments: &[Content], readonly: bool) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments(if readonly { "bitfield_ro" } else { "bitfield" });
+    }
+    let calls = match parse_bitfield(arguments, readonly) {
+        Ok(calls) => calls,
+        Err(message) => return message,
+    };
+
+    let key = &arguments[0].content;
+    let write_len = calls
+        .iter()
+        .filter(|call| !matches!(call.op, BitfieldOp::Get))
+        .map(|call| ((call.offset + call.bits as u64 - 1) / 8 + 1) as usize)
+        .max();
+
+    let mut message = format!("*{}\r\n", calls.len()).into_bytes();
+    let Some(write_len) = write_len else {
+        let empty = vec![];
+        let value = match string_for_read(storage, key) {
+            Ok(value) => value.unwrap_or(&empty),
+            Err(message) => return message,
+        };
+        for call in &calls {
+            let field = read_bits(value, call.offset, call.bits);
+            let field = if call.signed {
+                sign_extend(field, call.bits)
+            } else {
+                field as i64
+            };
+            message.extend(to_integer(field));
+        }
+        return message;
+    };
+
+    let value = match string_for_write(storage, key, write_len) {
+        Ok(value) => value,
+        Err(message) => return message,
+    };
+    for call in &calls {
+        let field = read_bits(value, call.offset, call.bits);
+        let current = if call.signed {
+            sign_extend(field, call.bits)
+        } else {
+            field as i64
+        };
+
+        let (updated, reply) = match call.op {
+            BitfieldOp::Get => {
+                message.extend(to_integer(current));
+                continue;
+            }
+            BitfieldOp::Set(new_value) => (apply_overflow(new_value, 0, call), current),
+            BitfieldOp::Incrby(increment) => {
+                let updated = apply_overflow(current, increment, call);
+                (updated, updated.unwrap_or(0))
+            }
+        };
+
+        match updated {
+            Some(updated) => {
+                write_bits(value, call.offset, call.bits, updated as u64);
+                message.extend(to_integer(reply));
+            }
+            None => message.extend(string_to_simple_resp("-1", '$').into_bytes()),
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, ShardedKeyspace};
+
+    fn run(storage: &mut Keyspace, parts: &[&str]) -> Vec<u8> {
+        handle_bitmap_command(&request(parts), storage)
+    }
+
+    #[test]
+    fn setbit_grows_the_string_and_counts() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(run(&mut storage, &["SETBIT", "k", "7", "1"]), b":0\r\n");
+        assert_eq!(run(&mut storage, &["SETBIT", "k", "7", "1"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["SETBIT", "k", "17", "1"]), b":0\r\n");
+        let Some(RedisValue::String(value)) = storage.get(&"k".to_string()) else {
+            panic!("not a string");
+        };
+        assert_eq!(value, &vec![0x01, 0x00, 0x40]);
+        assert_eq!(run(&mut storage, &["BITCOUNT", "k"]), b":2\r\n");
+        assert_eq!(
+            run(&mut storage, &["BITCOUNT", "k", "8", "23", "BIT"]),
+            b":1\r\n"
+        );
+        assert_eq!(run(&mut storage, &["BITPOS", "k", "1", "1"]), b":17\r\n");
+        assert_eq!(run(&mut storage, &["GETBIT", "k", "100"]), b":0\r\n");
+    }
+
+    #[test]
+    fn bitfield_wraps_by_default() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(
+            run(
+                &mut storage,
+                &["BITFIELD", "k", "SET", "u8", "0", "255", "INCRBY", "u8", "0", "10"]
+            ),
+            b"*2\r\n:0\r\n:9\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["BITFIELD", "k", "SET", "i8", "8", "127", "INCRBY", "i8", "8", "1"]
+            ),
+            b"*2\r\n:0\r\n:-128\r\n"
+        );
+        // The example from the BITFIELD documentation.
+        assert_eq!(
+            run(
+                &mut storage,
+                &["BITFIELD", "doc", "INCRBY", "i5", "100", "1", "GET", "u4", "0"]
+            ),
+            b"*2\r\n:1\r\n:0\r\n"
+        );
+    }
+
+    #[test]
+    fn bitfield_saturates() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(
+            run(
+                &mut storage,
+                &[
+                    "BITFIELD", "k", "OVERFLOW", "SAT", "INCRBY", "u8", "0", "300", "INCRBY", "i8",
+                    "8", "-300"
+                ]
+            ),
+            b"*2\r\n:255\r\n:-128\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["BITFIELD", "k", "OVERFLOW", "SAT", "SET", "i4", "16", "100"]
+            ),
+            b"*1\r\n:0\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["BITFIELD_RO", "k", "GET", "i4", "16"]),
+            b"*1\r\n:7\r\n"
+        );
+    }
+
+    #[test]
+    fn bitfield_fail_leaves_the_field_alone() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        run(&mut storage, &["BITFIELD", "k", "SET", "u8", "0", "250"]);
+        assert_eq!(
+            run(
+                &mut storage,
+                &[
+                    "BITFIELD", "k", "OVERFLOW", "FAIL", "INCRBY", "u8", "0", "10", "INCRBY", "u8",
+                    "0", "5"
+                ]
+            ),
+            b"*2\r\n$-1\r\n:255\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["BITFIELD", "k", "GET", "u8", "0"]),
+            b"*1\r\n:255\r\n"
+        );
+    }
+
+    #[test]
+    fn bitfield_ro_refuses_writes() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        let reply = run(&mut storage, &["BITFIELD_RO", "k", "SET", "u8", "0", "1"]);
+        assert!(reply.starts_with(b"-ERR"));
+    }
+
+    #[test]
+    fn bitfield_rejects_offsets_past_the_end() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        let out_of_range = b"-ERR bit offset is not an integer or out of range\r\n";
+        for offset in ["18446744073709551615", "4294967289", "#536870912"] {
+            assert_eq!(
+                run(&mut storage, &["BITFIELD", "k", "GET", "u8", offset]),
+                out_of_range
+            );
+        }
+        assert_eq!(
+            run(&mut storage, &["BITFIELD", "k", "GET", "u8", "4294967288"]),
+            b"*1\r\n:0\r\n"
+        );
+    }
+}
@@ -0,0 +1,604 @@
+use crate::aof::Fsync;
+use crate::database::DEFAULT_DATABASES;
+use crate::glob;
+use crate::replication::DEFAULT_REPL_BACKLOG_SIZE;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use crate::resp_parser::{string_to_simple_resp, to_bulk_bytes, to_error, wrong_arguments, RespRequest};
+
+pub const DEFAULT_MAX_CLIENTS: usize = 10000;
+
+/// Server parameters that can be changed at runtime with CONFIG SET, or at
+/// startup with `--<name> <value>`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    /// Number of logical databases SELECT can pick from.
+    pub databases: usize,
+    /// Connections beyond this many are refused.
+    pub maxclients: usize,
+    /// Bytes of the replication stream kept for replicas to resume from.
+    pub repl_backlog_size: usize,
+    /// Refuse writes from clients other than the master while a replica.
+    pub replica_read_only: bool,
+    /// Keep answering queries while the link to the master is down.
+    pub replica_serve_stale_data: bool,
+    /// Directory the RDB file is written to and loaded from.
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    /// `save <seconds> <changes>` rules: snapshot after `changes` writes once
+    /// `seconds` passed since the last save.
+    pub save: Vec<(u64, u64)>,
+    /// Refuse writes while the last background save failed.
+    pub stop_writes_on_bgsave_error: bool,
+    /// Log every write to the append-only file.
+    pub appendonly: bool,
+    pub appendfsync: Fsync,
+    /// Directory under `dir` holding the AOF files, which are named after
+    /// `appendfilename`.
+    pub appenddirname: String,
+    pub appendfilename: String,
+    /// Load an AOF whose last command was cut short instead of refusing to start.
+    pub aof_load_truncated: bool,
+    /// Output buffer limits of ordinary clients, replicas and subscribers.
+    /// Only subscribers are disconnected when they fall behind.
+    pub client_output_buffer_limit: OutputBufferLimits,
+}
+
+/// How much output may queue up for a client before it is disconnected: at once
+/// past `hard` bytes, or after staying past `soft` bytes for more than
+/// `soft_seconds`. A limit of 0 is disabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Whether a client with `pending` bytes queued must be disconnected.
+    /// `over_soft_since` tracks when it went over the soft limit.
+    pub fn exceeded(&self, pending: u64, over_soft_since: &mut Option<Instant>) -> bool {
+        if self.hard > 0 && pending >= self.hard {
+            return true;
+        }
+        if self.soft == 0 || pending < self.soft {
+            *over_soft_since = None;
+            return false;
+        }
+        let since = over_soft_since.get_or_insert_with(Instant::now);
+        since.elapsed() > Duration::from_secs(self.soft_seconds)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputBufferLimits {
+    pub normal: OutputBufferLimit,
+    pub replica: OutputBufferLimit,
+    pub pubsub: OutputBufferLimit,
+}
+
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        Self {
+            normal: OutputBufferLimit {
+                hard: 0,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 6379,
+            databases: DEFAULT_DATABASES,
+            maxclients: DEFAULT_MAX_CLIENTS,
+            repl_backlog_size: DEFAULT_REPL_BACKLOG_SIZE,
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            dir: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+            dbfilename: "dump.rdb".to_string(),
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            stop_writes_on_bgsave_error: true,
+            appendonly: false,
+            appendfsync: Fsync::Everysec,
+            appenddirname: "appendonlydir".to_string(),
+            appendfilename: "appendonly.aof".to_string(),
+            aof_load_truncated: true,
+            client_output_buffer_limit: OutputBufferLimits::default(),
+        }
+    }
+}
+
+/// Parameter names, with the older names Redis still accepts for them.
+const PARAMETERS: &[(&str, &[&str])] = &[
+    ("port", &[]),
+    ("databases", &[]),
+    ("maxclients", &[]),
+    ("repl-backlog-size", &[]),
+    ("replica-read-only", &["slave-read-only"]),
+    ("replica-serve-stale-data", &["slave-serve-stale-data"]),
+    ("dir", &[]),
+    ("dbfilename", &[]),
+    ("save", &[]),
+    ("stop-writes-on-bgsave-error", &[]),
+    ("appendonly", &[]),
+    ("appendfsync", &[]),
+    ("appenddirname", &[]),
+    ("appendfilename", &[]),
+    ("aof-load-truncated", &[]),
+    ("client-output-buffer-limit", &[]),
+];
+
+/// Parameters that can only be given at startup.
+const IMMUTABLE: &[&str] = &[
+    "port",
+    "databases",
+    "maxclients",
+    "repl-backlog-size",
+    "appenddirname",
+    "appendfilename",
+];
+
+impl Config {
+    /// Applies every `--<name> <value>` pair of the command line that names a
+    /// parameter. Other options are left to the caller.
+    pub fn from_arguments(arguments: &[String]) -> Self {
+        let mut config = Self::default();
+        for pair in arguments.windows(2) {
+            let Some(name) = pair[0].strip_prefix("--") else {
+                continue;
+            };
+            if canonical_name(name).is_some() {
+                if let Err(message) = config.set(name, &pair[1]) {
+                    println!("[ERROR] : Invalid --{}: {}", name, message);
+                }
+            }
+        }
+        config
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match canonical_name(name)? {
+            "port" => return Some(self.port.to_string()),
+            "databases" => return Some(self.databases.to_string()),
+            "maxclients" => return Some(self.maxclients.to_string()),
+            "repl-backlog-size" => return Some(self.repl_backlog_size.to_string()),
+            "replica-read-only" => yes_no(self.replica_read_only),
+            "replica-serve-stale-data" => yes_no(self.replica_serve_stale_data),
+            "dir" => return Some(self.dir.display().to_string()),
+            "dbfilename" => return Some(self.dbfilename.clone()),
+            "save" => {
+                let rules: Vec<String> = self
+                    .save
+                    .iter()
+                    .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                    .collect();
+                return Some(rules.join(" "));
+            }
+            "stop-writes-on-bgsave-error" => yes_no(self.stop_writes_on_bgsave_error),
+            "appendonly" => yes_no(self.appendonly),
+            "appendfsync" => self.appendfsync.name(),
+            "appenddirname" => return Some(self.appenddirname.clone()),
+            "appendfilename" => return Some(self.appendfilename.clone()),
+            "aof-load-truncated" => yes_no(self.aof_load_truncated),
+            "client-output-buffer-limit" => {
+                let limits = &self.client_output_buffer_limit;
+                let classes: Vec<String> = [
+                    ("normal", limits.normal),
+                    ("slave", limits.replica),
+                    ("pubsub", limits.pubsub),
+                ]
+                .iter()
+                .map(|(class, limit)| {
+                    format!("{} {} {} {}", class, limit.hard, limit.soft, limit.soft_seconds)
+                })
+                .collect();
+                return Some(classes.join(" "));
+            }
+            _ => return None,
+        };
+        Some(value.to_string())
+    }
+
+    /// Path of the RDB file.
+    pub fn rdb_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    /// Directory of the append-only files.
+    pub fn aof_dir(&self) -> PathBuf {
+        self.dir.join(&self.appenddirname)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match canonical_name(name) {
+            Some("port") => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| "argument couldn't be parsed into an integer".to_string())?
+            }
+            Some("databases") => self.databases = parse_positive(value)?,
+            Some("maxclients") => self.maxclients = parse_positive(value)?,
+            Some("repl-backlog-size") => {
+                self.repl_backlog_size = parse_memory(value)
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|size| *size > 0)
+                    .ok_or_else(|| "argument must be a memory value".to_string())?
+            }
+            Some("replica-read-only") => self.replica_read_only = parse_bool(value)?,
+            Some("replica-serve-stale-data") => self.replica_serve_stale_data = parse_bool(value)?,
+            Some("dir") => {
+                self.dir = Path::new(value)
+                    .canonicalize()
+                    .ok()
+                    .filter(|dir| dir.is_dir())
+                    .ok_or_else(|| "No such file or directory".to_string())?;
+            }
+            Some("dbfilename") => {
+                if value.is_empty() || Path::new(value).file_name() != Some(OsStr::new(value)) {
+                    return Err("dbfilename can't be a path, just a filename".to_string());
+                }
+                self.dbfilename = value.to_string();
+            }
+            Some("save") => self.save = parse_save_rules(value)?,
+            Some("stop-writes-on-bgsave-error") => {
+                self.stop_writes_on_bgsave_error = parse_bool(value)?
+            }
+            Some("appendonly") => self.appendonly = parse_bool(value)?,
+            Some("appendfsync") => {
+                self.appendfsync = Fsync::parse(value).ok_or_else(|| "argument(s) must be one of the following: always, everysec, no".to_string())?
+            }
+            Some(name @ ("appenddirname" | "appendfilename")) => {
+                if value.is_empty() || Path::new(value).file_name() != Some(OsStr::new(value)) {
+                    return Err(format!("{} can't be a path, just a filename", name));
+                }
+                if name == "appenddirname" {
+                    self.appenddirname = value.to_string();
+                } else {
+                    self.appendfilename = value.to_string();
+                }
+            }
+            Some("aof-load-truncated") => self.aof_load_truncated = parse_bool(value)?,
+            Some("client-output-buffer-limit") => {
+                parse_output_buffer_limits(value, &mut self.client_output_buffer_limit)?
+            }
+            _ => return Err(format!("Unknown option '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+fn canonical_name(name: &str) -> Option<&'static str> {
+    let name = name.to_ascii_lowercase();
+    PARAMETERS
+        .iter()
+        .find(|(canonical, aliases)| *canonical == name || aliases.contains(&name.as_str()))
+        .map(|(canonical, _)| *canonical)
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn parse_positive(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(number) if number > 0 => Ok(number),
+        Ok(_) => Err("argument must be greater than 0".to_string()),
+        Err(_) => Err("argument couldn't be parsed into an integer".to_string()),
+    }
+}
+
+/// Parses `<seconds> <changes>` pairs; an empty value disables snapshotting.
+fn parse_save_rules(value: &str) -> Result<Vec<(u64, u64)>, String> {
+    let numbers = value
+        .split_whitespace()
+        .map(|number| number.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| "Invalid save parameters".to_string())?;
+    if numbers.len() % 2 != 0 {
+        return Err("Invalid save parameters".to_string());
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Parses `<class> <hard> <soft> <soft seconds>` groups, replacing the limits
+/// of the classes they name. Sizes may carry a k, kb, m, mb, g or gb unit.
+fn parse_output_buffer_limits(value: &str, limits: &mut OutputBufferLimits) -> Result<(), String> {
+    let invalid = || "Invalid client output buffer limit".to_string();
+    let words: Vec<&str> = value.split_whitespace().collect();
+    if words.is_empty() || !words.chunks_exact(4).remainder().is_empty() {
+        return Err("Wrong number of arguments in buffer limit configuration.".to_string());
+    }
+    let mut updated = *limits;
+    for group in words.chunks(4) {
+        let limit = match group[0].to_ascii_lowercase().as_str() {
+            "normal" => &mut updated.normal,
+            "replica" | "slave" => &mut updated.replica,
+            "pubsub" => &mut updated.pubsub,
+            _ => return Err("Invalid client class specified in buffer limit configuration.".to_string()),
+        };
+        *limit = OutputBufferLimit {
+            hard: parse_memory(group[1]).ok_or_else(invalid)?,
+            soft: parse_memory(group[2]).ok_or_else(invalid)?,
+            soft_seconds: group[3].parse().map_err(|_| invalid())?,
+        };
+    }
+    *limits = updated;
+    Ok(())
+}
+
+/// Parses a size in bytes like `64mb`: k, m and g are powers of 1000, kb, mb
+/// and gb powers of 1024.
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &value[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+pub fn handle_config_command(request: &RespRequest, config: &mut Config) -> Vec<u8> {
+    let arguments = &request.arguments;
+    let Some(subcommand) = arguments.first() else {
+        return wrong_arguments("config");
+    };
+    match subcommand.content.to_ascii_uppercase().as_str() {
+        "GET" if arguments.len() >= 2 => {
+            let mut pairs: Vec<(&str, String)> = vec![];
+            for pattern in &arguments[1..] {
+                let pattern = pattern.content.to_ascii_lowercase();
+                for (canonical, aliases) in PARAMETERS {
+                    // An old name is only reported when asked for instead of the current one.
+                    let name = std::iter::once(canonical)
+                        .chain(aliases.iter())
+                        .find(|name| glob::matches(pattern.as_bytes(), name.as_bytes()));
+                    if let Some(name) = name {
+                        if !pairs.iter().any(|(added, _)| added == name) {
+                            pairs.push((name, config.get(name).unwrap_or_default()));
+                        }
+                    }
+                }
+            }
+            let mut message = format!("*{}\r\n", pairs.len() * 2).into_bytes();
+            for (name, value) in pairs {
+                message.extend(to_bulk_bytes(name.as_bytes()));
+                message.extend(to_bulk_bytes(value.as_bytes()));
+            }
+            message
+        }
+        "SET" if arguments.len() >= 3 && arguments.len() % 2 == 1 => {
+            // Parameters are validated on a copy so a failing pair changes nothing.
+            let mut updated = config.clone();
+            for pair in arguments[1..].chunks(2) {
+                let (name, value) = (&pair[0].content, &pair[1].content);
+                let Some(canonical) = canonical_name(name) else {
+                    return to_error(&format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name
+                    ));
+                };
+                if IMMUTABLE.contains(&canonical) {
+                    return to_error(&format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                        name
+                    ));
+                }
+                if let Err(message) = updated.set(name, value) {
+                    return to_error(&format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, message
+                    ));
+                }
+            }
+            *config = updated;
+            string_to_simple_resp("OK", '+').into_bytes()
+        }
+        "GET" | "SET" => wrong_arguments(&format!(
+            "config|{}",
+            subcommand.content.to_ascii_lowercase()
+        )),
+        _ => to_error(&format!(
+            "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+            subcommand.content
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+
+    fn run(config: &mut Config, parts: &[&str]) -> Vec<u8> {
+        handle_config_command(&request(parts), config)
+    }
+
+    fn arguments(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|part| part.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_startup_parameters_from_the_command_line() {
+        let config = Config::from_arguments(&arguments(&[
+            "redis-starter-rust",
+            "--port",
+            "6380",
+            "--replicaof",
+            "localhost 6379",
+            "--databases",
+            "4",
+            "--maxclients",
+            "0",
+            "--repl-backlog-size",
+            "2mb",
+            "--slave-read-only",
+            "no",
+        ]));
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.databases, 4);
+        // An invalid value is reported and the default kept.
+        assert_eq!(config.maxclients, DEFAULT_MAX_CLIENTS);
+        assert_eq!(config.repl_backlog_size, 2 * 1024 * 1024);
+        assert!(!config.replica_read_only);
+    }
+
+    #[test]
+    fn get_matches_patterns_and_old_names() {
+        let mut config = Config::default();
+        assert_eq!(
+            run(&mut config, &["CONFIG", "GET", "slave-read-only"]),
+            b"*2\r\n$15\r\nslave-read-only\r\n$3\r\nyes\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut config,
+                &["CONFIG", "GET", "replica-*", "REPLICA-READ-ONLY"]
+            ),
+            b"*4\r\n$17\r\nreplica-read-only\r\n$3\r\nyes\r\n\
+              $24\r\nreplica-serve-stale-data\r\n$3\r\nyes\r\n"
+        );
+        assert_eq!(
+            run(&mut config, &["CONFIG", "GET", "save"]),
+            b"*2\r\n$4\r\nsave\r\n$23\r\n3600 1 300 100 60 10000\r\n"
+        );
+        assert_eq!(run(&mut config, &["CONFIG", "GET", "nosuch"]), b"*0\r\n");
+    }
+
+    #[test]
+    fn set_applies_every_pair_or_none() {
+        let mut config = Config::default();
+        assert_eq!(
+            run(
+                &mut config,
+                &["CONFIG", "SET", "save", "", "appendfsync", "always"]
+            ),
+            b"+OK\r\n"
+        );
+        assert!(config.save.is_empty());
+        assert_eq!(config.appendfsync, Fsync::Always);
+
+        assert_eq!(
+            run(&mut config, &["CONFIG", "SET", "appendonly", "yes", "save", "60"]),
+            b"-ERR CONFIG SET failed (possibly related to argument 'save') - Invalid save parameters\r\n"
+        );
+        assert!(!config.appendonly);
+        assert_eq!(
+            run(&mut config, &["CONFIG", "SET", "dbfilename", "../dump.rdb"]),
+            b"-ERR CONFIG SET failed (possibly related to argument 'dbfilename') - dbfilename can't be a path, just a filename\r\n"
+        );
+    }
+
+    #[test]
+    fn startup_parameters_are_immutable() {
+        let mut config = Config::default();
+        for name in ["port", "databases", "maxclients", "repl-backlog-size"] {
+            let reply = run(&mut config, &["CONFIG", "SET", name, "10"]);
+            assert!(
+                reply.ends_with(b"can't set immutable config\r\n"),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(config.port, 6379);
+        assert_eq!(
+            run(&mut config, &["CONFIG", "SET", "nosuch", "1"]),
+            b"-ERR Unknown option or number of arguments for CONFIG SET - 'nosuch'\r\n"
+        );
+    }
+
+    #[test]
+    fn parses_output_buffer_limits_and_memory_units() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1KB"), Some(1024));
+        assert_eq!(parse_memory("3gb"), Some(3 * 1024 * 1024 * 1024));
+        assert_eq!(parse_memory("1tb"), None);
+        assert_eq!(parse_memory("mb"), None);
+
+        let mut config = Config::default();
+        config
+            .set(
+                "client-output-buffer-limit",
+                "pubsub 1mb 512k 10 slave 0 0 0",
+            )
+            .unwrap();
+        let limits = config.client_output_buffer_limit;
+        assert_eq!(
+            limits.pubsub,
+            OutputBufferLimit {
+                hard: 1024 * 1024,
+                soft: 512_000,
+                soft_seconds: 10,
+            }
+        );
+        assert_eq!(limits.replica.hard, 0);
+        assert!(config
+            .set("client-output-buffer-limit", "pubsub 1mb 512k")
+            .is_err());
+        assert!(config
+            .set("client-output-buffer-limit", "master 1 1 1")
+            .is_err());
+        assert_eq!(config.client_output_buffer_limit, limits);
+    }
+
+    #[test]
+    fn soft_limit_needs_to_last() {
+        let limit = OutputBufferLimit {
+            hard: 100,
+            soft: 10,
+            soft_seconds: 3600,
+        };
+        let mut over_soft_since = None;
+        assert!(!limit.exceeded(5, &mut over_soft_since));
+        assert!(!limit.exceeded(50, &mut over_soft_since));
+        assert!(over_soft_since.is_some());
+        assert!(!limit.exceeded(5, &mut over_soft_since));
+        assert!(over_soft_since.is_none());
+        assert!(limit.exceeded(100, &mut over_soft_since));
+
+        let limit = OutputBufferLimit {
+            soft_seconds: 0,
+            ..limit
+        };
+        limit.exceeded(50, &mut over_soft_since);
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(limit.exceeded(50, &mut over_soft_since));
+    }
+}
@@ -0,0 +1,415 @@
+use crate::resp_parser::{
+    string_to_simple_resp, to_error, to_integer, wrong_arguments, Command, Content, RespRequest,
+};
+use crate::rdb::Entry;
+use crate::storage::{Access, Keyspace, RedisValue, Shard, ShardedKeyspace};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// The numbered logical databases of the server. Each database is sharded and
+/// locks its own shards, so the collection itself is shared without a lock.
+/// Commands spanning databases lock them in ascending index order.
+///
+/// Writes call `propagate` while their locks are still held, so the replication
+/// stream orders them the same way they were applied.
+#[derive(Debug)]
+pub struct Databases {
+    keyspaces: Vec<ShardedKeyspace>,
+    /// Held shared while a command runs and exclusively while a transaction
+    /// does, so no other command observes or interleaves with its writes.
+    transaction: RwLock<()>,
+}
+
+impl Databases {
+    pub fn new(count: usize) -> Self {
+        Self {
+            keyspaces: (0..count).map(|_| ShardedKeyspace::new()).collect(),
+            transaction: RwLock::new(()),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.keyspaces.len()
+    }
+
+    pub fn get(&self, index: usize) -> &ShardedKeyspace {
+        &self.keyspaces[index]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ShardedKeyspace> {
+        self.keyspaces.iter()
+    }
+
+    /// Keeps transactions from running until the guard drops. Must not be
+    /// taken again by the same thread while held.
+    pub fn lock_command(&self) -> RwLockReadGuard<'_, ()> {
+        self.transaction.read().unwrap()
+    }
+
+    /// Waits for running commands to finish and keeps new ones from starting
+    /// until the guard drops.
+    pub fn lock_transaction(&self) -> RwLockWriteGuard<'_, ()> {
+        self.transaction.write().unwrap()
+    }
+
+    /// Copies every live key of every database. Writes are blocked while the copy
+    /// is taken, and `on_locked` runs before they resume, so a replica can start
+    /// receiving exactly the writes that follow the snapshot.
+    pub fn snapshot(&self, on_locked: impl FnOnce()) -> Vec<(usize, Vec<Entry>)> {
+        let locked: Vec<Keyspace> = self
+            .keyspaces
+            .iter()
+            .map(|keyspace| keyspace.lock_all(Access::Read))
+            .collect();
+        on_locked();
+        locked
+            .iter()
+            .enumerate()
+            .map(|(index, keyspace)| (index, keyspace.snapshot()))
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect()
+    }
+
+    /// Replaces the whole dataset with a loaded snapshot.
+    pub fn load(&self, databases: Vec<(usize, Vec<Entry>)>) -> Result<(), String> {
+        if let Some((index, _)) = databases.iter().find(|(index, _)| *index >= self.count()) {
+            return Err(format!(
+                "snapshot uses DB {} but only {} databases are configured",
+                index,
+                self.count()
+            ));
+        }
+        let indexes: Vec<usize> = (0..self.count()).collect();
+        let mut locked = self.lock_databases(&indexes);
+        for keyspace in locked.iter_mut() {
+            keyspace.take_all();
+        }
+        for (index, entries) in databases {
+            for entry in entries {
+                locked[index].restore(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks every shard of the databases at `indexes`, which must be ascending.
+    pub fn lock_databases(&self, indexes: &[usize]) -> Vec<Keyspace<'_>> {
+        indexes
+            .iter()
+            .map(|&index| self.keyspaces[index].lock_all(Access::Write))
+            .collect()
+    }
+}
+
+pub fn handle_database_command(
+    request: &RespRequest,
+    databases: &Databases,
+    selected_db: &mut usize,
+    propagate: &mut dyn FnMut(),
+) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Select => select(databases, arguments, selected_db),
+        Command::Move => move_key(databases, arguments, *selected_db, propagate),
+        Command::Swapdb => swapdb(databases, arguments, propagate),
+        Command::Flushdb => flush(
+            databases,
+            arguments,
+            "flushdb",
+            Some(*selected_db),
+            propagate,
+        ),
+        Command::Flushall => flush(databases, arguments, "flushall", None, propagate),
+        Command::Dbsize => dbsize(databases, arguments, *selected_db),
+        _ => to_error("ERR unknown database command"),
+    }
+}
+
+fn ok() -> Vec<u8> {
+    string_to_simple_resp("OK", '+').into_bytes()
+}
+
+/// Parses a database index, checking it against the configured number of databases.
+fn parse_index(databases: &Databases, argument: &Content) -> Result<usize, Vec<u8>> {
+    let index = argument
+        .content
+        .parse::<i64>()
+        .map_err(|_| to_error("ERR value is not an integer or out of range"))?;
+    if index < 0 || index as usize >= databases.count() {
+        return Err(to_error("ERR DB index is out of range"));
+    }
+    Ok(index as usize)
+}
+
+fn select(databases: &Databases, arguments: &[Content], selected_db: &mut usize) -> Vec<u8> {
+    if arguments.len() != 1 {
+        return wrong_arguments("select");
+    }
+    match parse_index(databases, &arguments[0]) {
+        Ok(index) => {
+            *selected_db = index;
+            ok()
+        }
+        Err(message) => message,
+    }
+}
+
+fn move_key(
+    databases: &Databases,
+    arguments: &[Content],
+    selected_db: usize,
+    propagate: &mut dyn FnMut(),
+) -> Vec<u8> {
+    if arguments.len() != 2 {
+        return wrong_arguments("move");
+    }
+    let target = match parse_index(databases, &arguments[1]) {
+        Ok(target) => target,
+        Err(message) => return message,
+    };
+    if target == selected_db {
+        return to_error("ERR source and destination objects are the same");
+    }
+
+    let key = &arguments[0].content;
+    let (mut source, mut destination) = lock_pair(databases, key, selected_db, target);
+    if !source.contains_key(key) || destination.contains_key(key) {
+        return to_integer(0);
+    }
+    let Some((value, expiry)) = source.remove_with_expiry(key) else {
+        return to_integer(0);
+    };
+    let volatile = matches!(&value, RedisValue::Hash(hash) if hash.has_volatile_fields());
+    destination.update(key.clone(), value, expiry);
+    if volatile {
+        destination.track_volatile(key.clone());
+    }
+    propagate();
+    to_integer(1)
+}
+
+/// Locks `key` in two databases, taking the lower-numbered database first.
+fn lock_pair<'a>(
+    databases: &'a Databases,
+    key: &String,
+    source: usize,
+    destination: usize,
+) -> (Keyspace<'a>, Keyspace<'a>) {
+    if source < destination {
+        let source = databases.get(source).lock(&[key], Access::Write);
+        (
+            source,
+            databases.get(destination).lock(&[key], Access::Write),
+        )
+    } else {
+        let destination = databases.get(destination).lock(&[key], Access::Write);
+        (
+            databases.get(source).lock(&[key], Access::Write),
+            destination,
+        )
+    }
+}
+
+fn swapdb(databases: &Databases, arguments: &[Content], propagate: &mut dyn FnMut()) -> Vec<u8> {
+    if arguments.len() != 2 {
+        return wrong_arguments("swapdb");
+    }
+    let (Ok(first), Ok(second)) = (
+        arguments[0].content.parse::<i64>(),
+        arguments[1].content.parse::<i64>(),
+    ) else {
+        return match arguments[0].content.parse::<i64>() {
+            Ok(_) => to_error("ERR invalid second DB index"),
+            Err(_) => to_error("ERR invalid first DB index"),
+        };
+    };
+    let count = databases.count() as i64;
+    if first < 0 || first >= count || second < 0 || second >= count {
+        return to_error("ERR DB index is out of range");
+    }
+    let (first, second) = (first as usize, second as usize);
+    if first != second {
+        let mut locked = databases.lock_databases(&[first.min(second), first.max(second)]);
+        let (low, high) = locked.split_at_mut(1);
+        low[0].swap_with(&mut high[0]);
+    }
+    propagate();
+    ok()
+}
+
+/// Empties the selected database, or every database when `selected_db` is None.
+/// With ASYNC the old contents are released on a background thread.
+fn flush(
+    databases: &Databases,
+    arguments: &[Content],
+    command: &str,
+    selected_db: Option<usize>,
+    propagate: &mut dyn FnMut(),
+) -> Vec<u8> {
+    let asynchronous = match arguments {
+        [] => false,
+        [mode] if mode.content.eq_ignore_ascii_case("ASYNC") => true,
+        [mode] if mode.content.eq_ignore_ascii_case("SYNC") => false,
+        [_] => return to_error("ERR syntax error"),
+        _ => return wrong_arguments(command),
+    };
+
+    let indexes: Vec<usize> = match selected_db {
+        Some(index) => vec![index],
+        None => (0..databases.count()).collect(),
+    };
+    let mut locked = databases.lock_databases(&indexes);
+    let flushed: Vec<Vec<Shard>> = locked.iter_mut().map(Keyspace::take_all).collect();
+    propagate();
+    drop(locked);
+    if asynchronous {
+        thread::spawn(move || drop(flushed));
+    }
+    ok()
+}
+
+fn dbsize(databases: &Databases, arguments: &[Content], selected_db: usize) -> Vec<u8> {
+    if !arguments.is_empty() {
+        return wrong_arguments("dbsize");
+    }
+    to_integer(
+        databases
+            .get(selected_db)
+            .lock_all(Access::Read)
+            .key_count() as i64,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use chrono::Utc;
+
+    /// Runs a command on database `selected_db`, returning the reply and how
+    /// many times it asked to be propagated.
+    fn run(databases: &Databases, selected_db: &mut usize, parts: &[&str]) -> (Vec<u8>, usize) {
+        let mut propagated = 0;
+        let reply = handle_database_command(&request(parts), databases, selected_db, &mut || {
+            propagated += 1
+        });
+        (reply, propagated)
+    }
+
+    fn set(databases: &Databases, db: usize, key: &str, value: &str) {
+        databases.get(db).lock_all(Access::Write).update(
+            key.to_string(),
+            RedisValue::String(value.as_bytes().to_vec()),
+            i64::MAX,
+        );
+    }
+
+    fn value(databases: &Databases, db: usize, key: &str) -> Option<Vec<u8>> {
+        match databases
+            .get(db)
+            .lock_all(Access::Read)
+            .get(&key.to_string())
+        {
+            Some(RedisValue::String(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn select_checks_the_index() {
+        let databases = Databases::new(4);
+        let mut selected = 0;
+        assert_eq!(
+            run(&databases, &mut selected, &["SELECT", "3"]).0,
+            b"+OK\r\n"
+        );
+        assert_eq!(selected, 3);
+        assert_eq!(
+            run(&databases, &mut selected, &["SELECT", "4"]).0,
+            b"-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            run(&databases, &mut selected, &["SELECT", "one"]).0,
+            b"-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(selected, 3);
+    }
+
+    #[test]
+    fn move_keeps_the_deadline_and_never_overwrites() {
+        let databases = Databases::new(2);
+        let mut selected = 0;
+        let deadline = Utc::now().timestamp_millis() + 60_000;
+        set(&databases, 0, "k", "v");
+        databases
+            .get(0)
+            .lock_all(Access::Write)
+            .set_deadline(&"k".to_string(), Some(deadline));
+
+        assert_eq!(
+            run(&databases, &mut selected, &["MOVE", "k", "1"]),
+            (b":1\r\n".to_vec(), 1)
+        );
+        assert_eq!(value(&databases, 0, "k"), None);
+        assert_eq!(value(&databases, 1, "k"), Some(b"v".to_vec()));
+        let moved = databases
+            .get(1)
+            .lock_all(Access::Read)
+            .deadline(&"k".to_string());
+        assert!(moved.is_some_and(|moved| (moved - deadline).abs() <= 1));
+
+        set(&databases, 0, "k", "other");
+        assert_eq!(
+            run(&databases, &mut selected, &["MOVE", "k", "1"]),
+            (b":0\r\n".to_vec(), 0)
+        );
+        assert_eq!(
+            run(&databases, &mut selected, &["MOVE", "missing", "1"]).0,
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(&databases, &mut selected, &["MOVE", "k", "0"]).0,
+            b"-ERR source and destination objects are the same\r\n"
+        );
+    }
+
+    #[test]
+    fn swapdb_and_flush() {
+        let databases = Databases::new(3);
+        let mut selected = 0;
+        set(&databases, 0, "a", "zero");
+        set(&databases, 2, "b", "two");
+        set(&databases, 2, "c", "two");
+
+        assert_eq!(
+            run(&databases, &mut selected, &["SWAPDB", "0", "2"]),
+            (b"+OK\r\n".to_vec(), 1)
+        );
+        assert_eq!(value(&databases, 0, "b"), Some(b"two".to_vec()));
+        assert_eq!(value(&databases, 2, "a"), Some(b"zero".to_vec()));
+        assert_eq!(run(&databases, &mut selected, &["DBSIZE"]).0, b":2\r\n");
+        assert_eq!(
+            run(&databases, &mut selected, &["SWAPDB", "0", "x"]).0,
+            b"-ERR invalid second DB index\r\n"
+        );
+        assert_eq!(
+            run(&databases, &mut selected, &["SWAPDB", "0", "3"]).0,
+            b"-ERR DB index is out of range\r\n"
+        );
+
+        assert_eq!(
+            run(&databases, &mut selected, &["FLUSHDB", "ASYNC"]),
+            (b"+OK\r\n".to_vec(), 1)
+        );
+        assert_eq!(run(&databases, &mut selected, &["DBSIZE"]).0, b":0\r\n");
+        assert_eq!(value(&databases, 2, "a"), Some(b"zero".to_vec()));
+        assert_eq!(
+            run(&databases, &mut selected, &["FLUSHALL", "LATER"]).0,
+            b"-ERR syntax error\r\n"
+        );
+        assert_eq!(run(&databases, &mut selected, &["FLUSHALL"]).0, b"+OK\r\n");
+        assert_eq!(value(&databases, 2, "a"), None);
+    }
+}
@@ -0,0 +1,570 @@
+//! DUMP, RESTORE and MIGRATE: moving single keys between instances as DUMP
+//! payloads, the RDB encoding of a value followed by the RDB version and a CRC64.
+
+use std::time::Duration;
+
+use crate::rdb::{self, Entry, RdbError};
+use crate::resp_parser::{
+    migrate_keys_option, string_to_simple_resp, to_bulk_bytes, to_command_array, to_error,
+    wrong_arguments, Command, Content, RespRequest,
+};
+use crate::storage::Keyspace;
+use chrono::Utc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+
+const DEFAULT_MIGRATE_TIMEOUT_MS: u64 = 1000;
+
+pub fn handle_dump_command(request: &RespRequest, storage: &mut Keyspace) -> Vec<u8> {
+    match request.command {
+        Command::Dump => dump(storage, &request.arguments),
+        Command::Restore => restore(storage, &request.arguments),
+        _ => to_error("ERR unknown dump command"),
+    }
+}
+
+fn dump(storage: &Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 1 {
+        return wrong_arguments("dump");
+    }
+    match storage.get(&arguments[0].content) {
+        Some(value) => to_bulk_bytes(&rdb::dump_payload(value)),
+        None => string_to_simple_resp("-1", '$').into_bytes(),
+    }
+}
+
+/// RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency].
+/// IDLETIME and FREQ are validated but otherwise ignored, as keys carry no
+/// eviction metadata here.
+fn restore(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 3 {
+        return wrong_arguments("restore");
+    }
+    let mut replace = false;
+    let mut absolute = false;
+    let mut idle_time = None;
+    let mut frequency = None;
+    let mut index = 3;
+    while index < arguments.len() {
+        let option = arguments[index].content.to_ascii_uppercase();
+        let value = arguments.get(index + 1).map(|value| value.content.parse::<i64>());
+        match option.as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absolute = true,
+            "IDLETIME" if frequency.is_none() && value.is_some() => {
+                let Some(Ok(seconds)) = value else {
+                    return to_error("ERR value is not an integer or out of range");
+                };
+                if seconds < 0 {
+                    return to_error("ERR Invalid IDLETIME value, must be >= 0");
+                }
+                idle_time = Some(seconds);
+                index += 1;
+            }
+            "FREQ" if idle_time.is_none() && value.is_some() => {
+                let Some(Ok(counter)) = value else {
+                    return to_error("ERR value is not an integer or out of range");
+                };
+                if !(0..=255).contains(&counter) {
+                    return to_error("ERR Invalid FREQ value, must be >= 0 and <= 255");
+                }
+                frequency = Some(counter);
+                index += 1;
+            }
+            _ => return to_error("ERR syntax error"),
+        }
+        index += 1;
+    }
+
+    let Ok(ttl) = arguments[1].content.parse::<i64>() else {
+        return to_error("ERR value is not an integer or out of range");
+    };
+    if ttl < 0 {
+        return to_error("ERR Invalid TTL value, must be >= 0");
+    }
+    let key = &arguments[0].content;
+    if !replace && storage.contains_key(key) {
+        return to_error("BUSYKEY Target key name already exists.");
+    }
+    let value = match rdb::restore_payload(&arguments[2].bytes) {
+        Ok(value) => value,
+        Err(RdbError::ChecksumMismatch | RdbError::UnsupportedVersion(_)) => {
+            return to_error("ERR DUMP payload version or checksum are wrong")
+        }
+        Err(_) => return to_error("ERR Bad data format"),
+    };
+
+    let now = Utc::now().timestamp_millis();
+    let deadline = match ttl {
+        0 => None,
+        _ if absolute => Some(ttl),
+        _ => Some(now.saturating_add(ttl)),
+    };
+    storage.remove(key);
+    // A key restored with a deadline already in the past is simply gone.
+    if deadline.is_some_and(|deadline| deadline <= now) {
+        return string_to_simple_resp("OK", '+').into_bytes();
+    }
+    storage.restore(Entry {
+        key: key.clone(),
+        value,
+        deadline,
+    });
+    string_to_simple_resp("OK", '+').into_bytes()
+}
+
+/// A MIGRATE whose keys are serialized and ready to be sent to the target.
+pub struct Migration {
+    host: String,
+    port: u16,
+    timeout: Duration,
+    copy: bool,
+    /// The keys to move, in the order of their RESTORE commands.
+    keys: Vec<String>,
+    commands: Vec<Vec<Vec<u8>>>,
+    /// How many AUTH and SELECT commands precede the RESTOREs.
+    setup: usize,
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [AUTH password] [AUTH2 username password] [KEYS key [key ...]].
+///
+/// Serializes the keys to move, with the shards holding them locked. Returns
+/// the reply instead when there is nothing to transfer.
+pub fn prepare_migration(request: &RespRequest, storage: &Keyspace) -> Result<Migration, Vec<u8>> {
+    let arguments = &request.arguments;
+    if arguments.len() < 5 {
+        return Err(wrong_arguments("migrate"));
+    }
+    let (Ok(port), Ok(db), Ok(timeout)) = (
+        arguments[1].content.parse::<u16>(),
+        arguments[3].content.parse::<i64>(),
+        arguments[4].content.parse::<i64>(),
+    ) else {
+        return Err(to_error("ERR value is not an integer or out of range"));
+    };
+    let timeout = match timeout {
+        1.. => Duration::from_millis(timeout as u64),
+        _ => Duration::from_millis(DEFAULT_MIGRATE_TIMEOUT_MS),
+    };
+
+    let mut copy = false;
+    let mut replace = false;
+    let mut auth: Option<Vec<Vec<u8>>> = None;
+    let keys_option = migrate_keys_option(arguments);
+    let options_end = keys_option.unwrap_or(arguments.len());
+    let mut index = 5;
+    while index < options_end {
+        match arguments[index].content.to_ascii_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "AUTH" if index + 1 < options_end => {
+                auth = Some(vec![arguments[index + 1].bytes.clone()]);
+                index += 1;
+            }
+            "AUTH2" if index + 2 < options_end => {
+                auth = Some(vec![
+                    arguments[index + 1].bytes.clone(),
+                    arguments[index + 2].bytes.clone(),
+                ]);
+                index += 2;
+            }
+            _ => return Err(to_error("ERR syntax error")),
+        }
+        index += 1;
+    }
+    let requested = match keys_option {
+        Some(_) if !arguments[2].content.is_empty() => return Err(to_error(
+            "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string",
+        )),
+        Some(keys) => &arguments[keys + 1..],
+        None => &arguments[2..3],
+    };
+
+    let now = Utc::now().timestamp_millis();
+    let mut keys = vec![];
+    let mut restores = vec![];
+    for key in requested {
+        let Some(value) = storage.get(&key.content) else {
+            continue;
+        };
+        // The target takes a relative TTL, at least 1ms for keys about to expire.
+        let ttl = storage
+            .deadline(&key.content)
+            .map(|deadline| (deadline - now).max(1))
+            .unwrap_or(0);
+        let mut restore = vec![
+            b"RESTORE".to_vec(),
+            key.bytes.clone(),
+            ttl.to_string().into_bytes(),
+            rdb::dump_payload(value),
+        ];
+        if replace {
+            restore.push(b"REPLACE".to_vec());
+        }
+        keys.push(key.content.clone());
+        restores.push(restore);
+    }
+    if keys.is_empty() {
+        return Err(string_to_simple_resp("NOKEY", '+').into_bytes());
+    }
+
+    let mut commands = vec![];
+    if let Some(credentials) = auth {
+        let mut command = vec![b"AUTH".to_vec()];
+        command.extend(credentials);
+        commands.push(command);
+    }
+    commands.push(vec![b"SELECT".to_vec(), db.to_string().into_bytes()]);
+    let setup = commands.len();
+    commands.extend(restores);
+
+    Ok(Migration {
+        host: arguments[0].content.clone(),
+        port,
+        timeout,
+        copy,
+        keys,
+        commands,
+        setup,
+    })
+}
+
+impl Migration {
+    /// Pipelines the commands to the target and collects its one-line
+    /// replies, or the IOERR to answer with when it cannot be reached within
+    /// the timeout.
+    pub async fn transfer(&self) -> Result<Vec<String>, Vec<u8>> {
+        let connect = time::timeout(
+            self.timeout,
+            TcpStream::connect((self.host.as_str(), self.port)),
+        );
+        let Ok(Ok(mut stream)) = connect.await else {
+            return Err(to_error("IOERR error or timeout connecting to the client"));
+        };
+
+        let request: Vec<u8> = self
+            .commands
+            .iter()
+            .flat_map(|command| to_command_array(command))
+            .collect();
+        let Ok(Ok(())) = time::timeout(self.timeout, stream.write_all(&request)).await else {
+            return Err(to_error(
+                "IOERR error or timeout writing to target instance",
+            ));
+        };
+
+        let mut reader = BufReader::new(stream);
+        let mut replies = vec![];
+        for _ in &self.commands {
+            let mut line = String::new();
+            match time::timeout(self.timeout, reader.read_line(&mut line)).await {
+                Ok(Ok(read)) if read > 0 => replies.push(line.trim_end().to_string()),
+                _ => {
+                    return Err(to_error(
+                        "IOERR error or timeout reading to target instance",
+                    ))
+                }
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Reads the target's replies into the reply to MIGRATE and the keys that
+    /// should now leave this instance: those the target restored, unless COPY
+    /// was given.
+    pub fn outcome(&self, replies: &[String]) -> (Vec<u8>, Vec<&String>) {
+        if let Some(error) = replies[..self.setup]
+            .iter()
+            .find_map(|reply| reply.strip_prefix('-'))
+        {
+            let message = format!("ERR Target instance replied with error: {}", error);
+            return (to_error(&message), vec![]);
+        }
+
+        // Keys the target refused stay here; the last refusal is reported.
+        let mut restored = vec![];
+        let mut target_error = None;
+        for (key, reply) in self.keys.iter().zip(&replies[self.setup..]) {
+            match reply.strip_prefix('-') {
+                Some(error) => target_error = Some(error.to_string()),
+                None if !self.copy => restored.push(key),
+                None => {}
+            }
+        }
+        let reply = match target_error {
+            Some(error) => to_error(&format!(
+                "ERR Target instance replied with error: {}",
+                error
+            )),
+            None => string_to_simple_resp("OK", '+').into_bytes(),
+        };
+        (reply, restored)
+    }
+
+    /// Deletes the restored keys that still hold the value that was sent, as
+    /// the transfer ran without their shards locked. Returns the deleted keys.
+    pub fn remove_restored(&self, restored: &[&String], storage: &mut Keyspace) -> Vec<String> {
+        let mut removed = vec![];
+        for (key, restore) in self.keys.iter().zip(&self.commands[self.setup..]) {
+            if !restored.contains(&key) {
+                continue;
+            }
+            let unchanged = storage
+                .get(key)
+                .is_some_and(|value| rdb::dump_payload(value) == restore[3]);
+            if unchanged {
+                storage.remove(key);
+                removed.push(key.clone());
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Hash;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, RedisValue, ShardedKeyspace};
+
+    /// `DUMP mykey` of the integer 10, as printed by Redis 7.2.
+    const REDIS_DUMP: &[u8] = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+
+    /// 2100-01-01 in unix milliseconds.
+    const FAR_FUTURE_MS: i64 = 4_102_444_800_000;
+
+    fn run(storage: &mut Keyspace, parts: &[&[u8]]) -> Vec<u8> {
+        handle_dump_command(&request(parts), storage)
+    }
+
+    fn dumped(reply: &[u8]) -> Vec<u8> {
+        let start = reply.iter().position(|&byte| byte == b'\n').unwrap() + 1;
+        reply[start..reply.len() - 2].to_vec()
+    }
+
+    fn insert(storage: &mut Keyspace, key: &str, value: RedisValue) {
+        storage.restore(Entry {
+            key: key.to_string(),
+            value,
+            deadline: None,
+        });
+    }
+
+    fn volatile_hash() -> RedisValue {
+        let mut hash = Hash::new();
+        hash.insert(b"kept".to_vec(), b"1".to_vec());
+        hash.insert(b"volatile".to_vec(), b"2".to_vec());
+        hash.set_expires_at(b"volatile", Some(FAR_FUTURE_MS));
+        RedisValue::Hash(hash)
+    }
+
+    #[test]
+    fn restores_a_redis_payload() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"n", b"0", REDIS_DUMP]),
+            b"+OK\r\n"
+        );
+        assert!(
+            matches!(storage.get(&"n".to_string()), Some(RedisValue::String(value)) if value == b"10")
+        );
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"n", b"0", REDIS_DUMP]),
+            b"-BUSYKEY Target key name already exists.\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &[b"RESTORE", b"n", b"0", REDIS_DUMP, b"REPLACE"]
+            ),
+            b"+OK\r\n"
+        );
+    }
+
+    #[test]
+    fn dump_round_trips_through_restore() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        insert(&mut storage, "s", RedisValue::String(b"hello".to_vec()));
+        insert(&mut storage, "h", volatile_hash());
+
+        let string = dumped(&run(&mut storage, &[b"DUMP", b"s"]));
+        assert_eq!(&string[string.len() - 10..string.len() - 8], &[11, 0]);
+        let hash = dumped(&run(&mut storage, &[b"DUMP", b"h"]));
+        assert_eq!(&hash[hash.len() - 10..hash.len() - 8], &[12, 0]);
+
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"s2", b"0", &string]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"h2", b"0", &hash]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &[b"DUMP", b"s2"]),
+            run(&mut storage, &[b"DUMP", b"s"])
+        );
+        let Some(RedisValue::Hash(restored)) = storage.get(&"h2".to_string()) else {
+            panic!("not a hash");
+        };
+        assert_eq!(restored.get(b"kept"), Some(&b"1".to_vec()));
+        assert_eq!(restored.expires_at(b"kept"), None);
+        assert_eq!(restored.expires_at(b"volatile"), Some(FAR_FUTURE_MS));
+        assert_eq!(run(&mut storage, &[b"DUMP", b"missing"]), b"$-1\r\n");
+    }
+
+    #[test]
+    fn restore_rejects_bad_payloads() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        let rejected = b"-ERR DUMP payload version or checksum are wrong\r\n";
+
+        let mut corrupted = REDIS_DUMP.to_vec();
+        corrupted[2] ^= 0x01;
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"k", b"0", &corrupted]),
+            rejected
+        );
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"k", b"0", b"short"]),
+            rejected
+        );
+
+        // A newer RDB version than this server loads, with a valid checksum.
+        let mut newer = REDIS_DUMP[..REDIS_DUMP.len() - 10].to_vec();
+        newer.extend_from_slice(&[13, 0]);
+        let checksum = rdb::crc64(0, &newer);
+        newer.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"k", b"0", &newer]),
+            rejected
+        );
+        assert!(storage.get(&"k".to_string()).is_none());
+    }
+
+    #[test]
+    fn restore_refuses_impossible_lzf_lengths() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        // A one byte LZF string declaring 2^40 bytes once decompressed.
+        let mut payload = vec![0x00, 0xC3, 0x01, 0x81];
+        payload.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        payload.extend_from_slice(&[0x00, b'x', 11, 0]);
+        let checksum = rdb::crc64(0, &payload);
+        payload.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            run(&mut storage, &[b"RESTORE", b"k", b"0", &payload]),
+            b"-ERR Bad data format\r\n"
+        );
+        assert!(storage.get(&"k".to_string()).is_none());
+    }
+
+    #[test]
+    fn migration_keeps_refused_and_changed_keys() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(
+            prepare_migration(
+                &request(&["MIGRATE", "127.0.0.1", "6380", "", "0", "100", "KEYS", "a"]),
+                &storage
+            )
+            .err(),
+            Some(b"+NOKEY\r\n".to_vec())
+        );
+
+        for key in ["a", "b", "c"] {
+            insert(
+                &mut storage,
+                key,
+                RedisValue::String(key.as_bytes().to_vec()),
+            );
+        }
+        let migration = prepare_migration(
+            &request(&[
+                "MIGRATE",
+                "127.0.0.1",
+                "6380",
+                "",
+                "3",
+                "100",
+                "AUTH",
+                "secret",
+                "KEYS",
+                "a",
+                "missing",
+                "b",
+                "c",
+            ]),
+            &storage,
+        )
+        .unwrap_or_else(|_| panic!("nothing to migrate"));
+        assert_eq!(migration.keys, vec!["a", "b", "c"]);
+        assert_eq!(migration.setup, 2);
+        assert_eq!(
+            migration.commands[1],
+            vec![b"SELECT".to_vec(), b"3".to_vec()]
+        );
+
+        let replies: Vec<String> = [
+            "+OK",
+            "+OK",
+            "+OK",
+            "-BUSYKEY Target key name already exists.",
+            "+OK",
+        ]
+        .iter()
+        .map(|reply| reply.to_string())
+        .collect();
+        let (reply, restored) = migration.outcome(&replies);
+        assert_eq!(
+            reply,
+            b"-ERR Target instance replied with error: BUSYKEY Target key name already exists.\r\n"
+        );
+        assert_eq!(restored, vec!["a", "c"]);
+
+        // "c" was overwritten while the payloads were in flight.
+        insert(&mut storage, "c", RedisValue::String(b"new".to_vec()));
+        assert_eq!(
+            migration.remove_restored(&restored, &mut storage),
+            vec!["a"]
+        );
+        assert!(storage.get(&"a".to_string()).is_none());
+        assert!(storage.get(&"b".to_string()).is_some() && storage.get(&"c".to_string()).is_some());
+
+        let mut failed_auth = replies;
+        failed_auth[0] = "-WRONGPASS invalid username-password pair".to_string();
+        let (reply, restored) = migration.outcome(&failed_auth);
+        assert!(reply.starts_with(b"-ERR Target instance replied with error: WRONGPASS"));
+        assert!(restored.is_empty());
+    }
+
+    #[test]
+    fn migration_with_copy_removes_nothing() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        insert(&mut storage, "a", RedisValue::String(b"a".to_vec()));
+        let migration = prepare_migration(
+            &request(&[
+                "MIGRATE",
+                "127.0.0.1",
+                "6380",
+                "a",
+                "0",
+                "100",
+                "COPY",
+                "REPLACE",
+            ]),
+            &storage,
+        )
+        .unwrap_or_else(|_| panic!("nothing to migrate"));
+        assert_eq!(migration.commands[1].last(), Some(&b"REPLACE".to_vec()));
+        let (reply, restored) = migration.outcome(&["+OK".to_string(), "+OK".to_string()]);
+        assert_eq!(reply, b"+OK\r\n");
+        assert!(restored.is_empty());
+    }
+}
@@ -0,0 +1,945 @@
+use crate::resp_parser::{
+    string_to_simple_resp, to_bulk_bytes, to_bulk_string, to_error, to_integer, wrong_arguments,
+    Command, Content, RespRequest,
+};
+use crate::sorted_set::{add_members, AddOptions, SortedSet};
+use crate::storage::{Keyspace, RedisValue};
+
+// Members are stored in a sorted set whose score is a 52 bit geohash: 26 bits
+// of longitude interleaved with 26 bits of latitude, exactly as Redis does, so
+// that range queries on the score walk neighbouring cells.
+
+const GEO_STEP_MAX: u32 = 26;
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const GEO_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct GeoHashBits {
+    bits: u64,
+    step: u32,
+}
+
+impl GeoHashBits {
+    fn is_zero(&self) -> bool {
+        self.bits == 0 && self.step == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GeoHashRange {
+    min: f64,
+    max: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GeoHashArea {
+    longitude: GeoHashRange,
+    latitude: GeoHashRange,
+}
+
+const LONG_RANGE: GeoHashRange = GeoHashRange {
+    min: GEO_LONG_MIN,
+    max: GEO_LONG_MAX,
+};
+const LAT_RANGE: GeoHashRange = GeoHashRange {
+    min: GEO_LAT_MIN,
+    max: GEO_LAT_MAX,
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GeoShape {
+    longitude: f64,
+    latitude: f64,
+    shape: Shape,
+    /// Meters per unit of the user supplied distances.
+    conversion: f64,
+}
+
+#[derive(Debug, Clone)]
+struct GeoPoint {
+    member: Vec<u8>,
+    score: f64,
+    distance: f64,
+    longitude: f64,
+    latitude: f64,
+}
+
+pub fn handle_geo_command(request: &RespRequest, storage: &mut Keyspace) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Geoadd => geoadd(storage, arguments),
+        Command::Geodist => geodist(storage, arguments),
+        Command::Geopos => geopos(storage, arguments),
+        Command::Geohash => geohash(storage, arguments),
+        Command::Geosearch => geosearch(storage, arguments, false),
+        Command::Geosearchstore => geosearch(storage, arguments, true),
+        _ => to_error("ERR unknown geo command"),
+    }
+}
+
+fn interleave64(latitude: u32, longitude: u32) -> u64 {
+    const B: [u64; 5] = [
+        0x5555555555555555,
+        0x3333333333333333,
+        0x0F0F0F0F0F0F0F0F,
+        0x00FF00FF00FF00FF,
+        0x0000FFFF0000FFFF,
+    ];
+    const S: [u32; 5] = [1, 2, 4, 8, 16];
+
+    let mut x = latitude as u64;
+    let mut y = longitude as u64;
+    for index in (0..5).rev() {
+        x = (x | (x << S[index])) & B[index];
+        y = (y | (y << S[index])) & B[index];
+    }
+    x | (y << 1)
+}
+
+/// Splits an interleaved hash into latitude (low 32 bits) and longitude (high 32 bits).
+fn deinterleave64(interleaved: u64) -> u64 {
+    const B: [u64; 6] = [
+        0x5555555555555555,
+        0x3333333333333333,
+        0x0F0F0F0F0F0F0F0F,
+        0x00FF00FF00FF00FF,
+        0x0000FFFF0000FFFF,
+        0x00000000FFFFFFFF,
+    ];
+    const S: [u32; 6] = [0, 1, 2, 4, 8, 16];
+
+    let mut x = interleaved;
+    let mut y = interleaved >> 1;
+    for index in 0..6 {
+        x = (x | (x >> S[index])) & B[index];
+        y = (y | (y >> S[index])) & B[index];
+    }
+    x | (y << 32)
+}
+
+fn geohash_encode(
+    long_range: GeoHashRange,
+    lat_range: GeoHashRange,
+    longitude: f64,
+    latitude: f64,
+    step: u32,
+) -> Option<GeoHashBits> {
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+        || !(lat_range.min..=lat_range.max).contains(&latitude)
+        || !(long_range.min..=long_range.max).contains(&longitude)
+    {
+        return None;
+    }
+
+    let cells = (1u64 << step) as f64;
+    let lat_offset = (latitude - lat_range.min) / (lat_range.max - lat_range.min) * cells;
+    let long_offset = (longitude - long_range.min) / (long_range.max - long_range.min) * cells;
+    Some(GeoHashBits {
+        bits: interleave64(lat_offset as u32, long_offset as u32),
+        step,
+    })
+}
+
+fn geohash_decode(
+    long_range: GeoHashRange,
+    lat_range: GeoHashRange,
+    hash: GeoHashBits,
+) -> GeoHashArea {
+    let separated = deinterleave64(hash.bits);
+    let lat_scale = lat_range.max - lat_range.min;
+    let long_scale = long_range.max - long_range.min;
+    let ilato = (separated & 0xFFFFFFFF) as f64;
+    let ilono = (separated >> 32) as f64;
+    let cells = (1u64 << hash.step) as f64;
+
+    GeoHashArea {
+        latitude: GeoHashRange {
+            min: lat_range.min + (ilato / cells) * lat_scale,
+            max: lat_range.min + ((ilato + 1.0) / cells) * lat_scale,
+        },
+        longitude: GeoHashRange {
+            min: long_range.min + (ilono / cells) * long_scale,
+            max: long_range.min + ((ilono + 1.0) / cells) * long_scale,
+        },
+    }
+}
+
+/// Decodes a stored score into the centre of its cell as `(longitude, latitude)`.
+fn decode_score(score: f64) -> (f64, f64) {
+    let area = geohash_decode(
+        LONG_RANGE,
+        LAT_RANGE,
+        GeoHashBits {
+            bits: score as u64,
+            step: GEO_STEP_MAX,
+        },
+    );
+    let longitude =
+        ((area.longitude.min + area.longitude.max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let latitude = ((area.latitude.min + area.latitude.max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (longitude, latitude)
+}
+
+fn encode_score(longitude: f64, latitude: f64) -> Option<f64> {
+    geohash_encode(LONG_RANGE, LAT_RANGE, longitude, latitude, GEO_STEP_MAX)
+        .map(|hash| hash.bits as f64)
+}
+
+fn move_x(hash: &mut GeoHashBits, direction: i8) {
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0x5555555555555555u64 >> (64 - hash.step * 2);
+    let x = if direction > 0 {
+        x.wrapping_add(zz + 1)
+    } else {
+        (x | zz).wrapping_sub(zz + 1)
+    };
+    hash.bits = (x & (0xaaaaaaaaaaaaaaaa >> (64 - hash.step * 2))) | y;
+}
+
+fn move_y(hash: &mut GeoHashBits, direction: i8) {
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step * 2);
+    let y = if direction > 0 {
+        y.wrapping_add(zz + 1)
+    } else {
+        (y | zz).wrapping_sub(zz + 1)
+    };
+    hash.bits = x | (y & (0x5555555555555555 >> (64 - hash.step * 2)));
+}
+
+fn neighbour(hash: GeoHashBits, x: i8, y: i8) -> GeoHashBits {
+    let mut neighbour = hash;
+    if x != 0 {
+        move_x(&mut neighbour, x);
+    }
+    if y != 0 {
+        move_y(&mut neighbour, y);
+    }
+    neighbour
+}
+
+const D_R: f64 = std::f64::consts::PI / 180.0;
+
+fn deg_rad(degrees: f64) -> f64 {
+    degrees * D_R
+}
+
+fn rad_deg(radians: f64) -> f64 {
+    radians / D_R
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2) - deg_rad(lat1)).abs()
+}
+
+/// Haversine distance in meters.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let v = ((deg_rad(lon2) - deg_rad(lon1)) / 2.0).sin();
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let lat1r = deg_rad(lat1);
+    let lat2r = deg_rad(lat2);
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+fn estimate_steps_by_radius(range_meters: f64, latitude: f64) -> u32 {
+    if range_meters == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut range_meters = range_meters;
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+
+    // Cells get narrower towards the poles.
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u32
+}
+
+impl GeoShape {
+    fn half_extents(&self) -> (f64, f64) {
+        match self.shape {
+            Shape::Radius(radius) => (radius * self.conversion, radius * self.conversion),
+            Shape::Box { width, height } => (
+                width / 2.0 * self.conversion,
+                height / 2.0 * self.conversion,
+            ),
+        }
+    }
+
+    /// Returns `[min_lon, min_lat, max_lon, max_lat]` around the search centre.
+    fn bounding_box(&self) -> [f64; 4] {
+        let (width, height) = self.half_extents();
+        let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+        let long_delta_top =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.latitude + lat_delta).cos());
+        let long_delta_bottom =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.latitude - lat_delta).cos());
+        let long_delta = if self.latitude < 0.0 {
+            long_delta_bottom
+        } else {
+            long_delta_top
+        };
+        [
+            self.longitude - long_delta,
+            self.latitude - lat_delta,
+            self.longitude + long_delta,
+            self.latitude + lat_delta,
+        ]
+    }
+
+    /// Computes the centre cell and its eight neighbours covering the shape,
+    /// with cells that cannot contain results zeroed out.
+    fn search_areas(&self) -> [GeoHashBits; 9] {
+        let [min_lon, min_lat, max_lon, max_lat] = self.bounding_box();
+        let radius_meters = match self.shape {
+            Shape::Radius(radius) => radius,
+            Shape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        } * self.conversion;
+
+        let mut steps = estimate_steps_by_radius(radius_meters, self.latitude);
+        let mut hash = geohash_encode(LONG_RANGE, LAT_RANGE, self.longitude, self.latitude, steps)
+            .unwrap_or_default();
+
+        // Near the edges of a cell the estimated step may be too coarse for the
+        // neighbours to cover the whole shape.
+        let north = geohash_decode(LONG_RANGE, LAT_RANGE, neighbour(hash, 0, 1));
+        let south = geohash_decode(LONG_RANGE, LAT_RANGE, neighbour(hash, 0, -1));
+        let east = geohash_decode(LONG_RANGE, LAT_RANGE, neighbour(hash, 1, 0));
+        let west = geohash_decode(LONG_RANGE, LAT_RANGE, neighbour(hash, -1, 0));
+        let decrease_step = north.latitude.max < max_lat
+            || south.latitude.min > min_lat
+            || east.longitude.max < max_lon
+            || west.longitude.min > min_lon;
+        if steps > 1 && decrease_step {
+            steps -= 1;
+            hash = geohash_encode(LONG_RANGE, LAT_RANGE, self.longitude, self.latitude, steps)
+                .unwrap_or_default();
+        }
+        let area = geohash_decode(LONG_RANGE, LAT_RANGE, hash);
+
+        // Same order as Redis: centre, N, S, E, W, NE, NW, SE, SW.
+        let mut areas = [
+            hash,
+            neighbour(hash, 0, 1),
+            neighbour(hash, 0, -1),
+            neighbour(hash, 1, 0),
+            neighbour(hash, -1, 0),
+            neighbour(hash, 1, 1),
+            neighbour(hash, -1, 1),
+            neighbour(hash, 1, -1),
+            neighbour(hash, -1, -1),
+        ];
+        if steps >= 2 {
+            let zero = GeoHashBits::default();
+            if area.latitude.min < min_lat {
+                areas[2] = zero;
+                areas[8] = zero;
+                areas[7] = zero;
+            }
+            if area.latitude.max > max_lat {
+                areas[1] = zero;
+                areas[5] = zero;
+                areas[6] = zero;
+            }
+            if area.longitude.min < min_lon {
+                areas[4] = zero;
+                areas[8] = zero;
+                areas[6] = zero;
+            }
+            if area.longitude.max > max_lon {
+                areas[3] = zero;
+                areas[7] = zero;
+                areas[5] = zero;
+            }
+        }
+        areas
+    }
+
+    /// Returns the distance to the point stored with `score` if it lies inside the shape.
+    fn contains(&self, score: f64) -> Option<(f64, f64, f64)> {
+        let (longitude, latitude) = decode_score(score);
+        match self.shape {
+            Shape::Radius(radius) => {
+                let distance = distance(self.longitude, self.latitude, longitude, latitude);
+                (distance <= radius * self.conversion).then_some((distance, longitude, latitude))
+            }
+            Shape::Box { width, height } => {
+                if lat_distance(latitude, self.latitude) > height * self.conversion / 2.0 {
+                    return None;
+                }
+                let lon_distance = distance(longitude, latitude, self.longitude, latitude);
+                if lon_distance > width * self.conversion / 2.0 {
+                    return None;
+                }
+                let distance = distance(self.longitude, self.latitude, longitude, latitude);
+                Some((distance, longitude, latitude))
+            }
+        }
+    }
+
+    /// Collects the members inside the shape, stopping after `limit` matches when non-zero.
+    fn members_within(&self, set: &SortedSet, limit: usize) -> Vec<GeoPoint> {
+        let areas = self.search_areas();
+        let mut points = vec![];
+        let mut last_processed = 0;
+
+        for (index, area) in areas.into_iter().enumerate() {
+            if area.is_zero() {
+                continue;
+            }
+            // Huge radii can make adjacent neighbours identical.
+            if last_processed != 0 && area == areas[last_processed] {
+                continue;
+            }
+            if limit != 0 && points.len() >= limit {
+                break;
+            }
+
+            let shift = 52 - area.step * 2;
+            let min = (area.bits << shift) as f64;
+            let max = ((area.bits + 1) << shift) as f64;
+            for (member, score) in set.range_by_score(min, max) {
+                if let Some((distance, longitude, latitude)) = self.contains(score) {
+                    points.push(GeoPoint {
+                        member: member.to_vec(),
+                        score,
+                        distance,
+                        longitude,
+                        latitude,
+                    });
+                }
+                if limit != 0 && points.len() >= limit {
+                    break;
+                }
+            }
+            last_processed = index;
+        }
+        points
+    }
+}
+
+fn parse_float(argument: &Content, message: &str) -> Result<f64, Vec<u8>> {
+    match argument.content.parse::<f64>() {
+        Ok(value) if !value.is_nan() => Ok(value),
+        _ => Err(to_error(message)),
+    }
+}
+
+fn parse_long_lat(longitude: &Content, latitude: &Content) -> Result<(f64, f64), Vec<u8>> {
+    let longitude = parse_float(longitude, "ERR value is not a valid float")?;
+    let latitude = parse_float(latitude, "ERR value is not a valid float")?;
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+    {
+        return Err(to_error(&format!(
+            "ERR invalid longitude,latitude pair {:.6},{:.6}",
+            longitude, latitude
+        )));
+    }
+    Ok((longitude, latitude))
+}
+
+fn parse_unit(argument: &Content) -> Result<f64, Vec<u8>> {
+    match argument.content.to_ascii_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(to_error(
+            "ERR unsupported unit provided. please use M, KM, FT, MI",
+        )),
+    }
+}
+
+/// Formats coordinates like Redis' `addReplyHumanLongDouble`.
+fn format_coordinate(value: f64) -> String {
+    let formatted = format!("{:.17}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    if trimmed == "-0" {
+        "0".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+fn format_distance(distance: f64) -> Vec<u8> {
+    to_bulk_string(format!("{:.4}", distance)).into_bytes()
+}
+
+fn null_bulk() -> Vec<u8> {
+    string_to_simple_resp("-1", '$').into_bytes()
+}
+
+fn geoadd(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 4 {
+        return wrong_arguments("geoadd");
+    }
+    let mut options = AddOptions::default();
+    let mut index = 1;
+    while let Some(argument) = arguments.get(index) {
+        match argument.content.to_ascii_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "CH" => options.ch = true,
+            _ => break,
+        }
+        index += 1;
+    }
+    let triples = arguments[index..].chunks_exact(3);
+    if triples.len() == 0 || !triples.remainder().is_empty() || (options.nx && options.xx) {
+        return to_error("ERR syntax error");
+    }
+
+    let mut members = vec![];
+    for triple in triples {
+        let (longitude, latitude) = match parse_long_lat(&triple[0], &triple[1]) {
+            Ok(coordinates) => coordinates,
+            Err(message) => return message,
+        };
+        let score = encode_score(longitude, latitude).unwrap_or(0.0);
+        members.push((score, triple[2].bytes.clone()));
+    }
+
+    match add_members(storage, &arguments[0].content, members, options) {
+        Ok(count) => to_integer(count as i64),
+        Err(message) => message,
+    }
+}
+
+fn geodist(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 3 {
+        return wrong_arguments("geodist");
+    }
+    if arguments.len() > 4 {
+        return to_error("ERR syntax error");
+    }
+    let conversion = match arguments.get(3).map(parse_unit).transpose() {
+        Ok(conversion) => conversion.unwrap_or(1.0),
+        Err(message) => return message,
+    };
+
+    let set = match storage.get_sorted_set(&arguments[0].content) {
+        Ok(Some(set)) => set,
+        Ok(None) => return null_bulk(),
+        Err(error) => return to_error(&error.to_string()),
+    };
+    let (Some(first), Some(second)) = (
+        set.score(&arguments[1].bytes),
+        set.score(&arguments[2].bytes),
+    ) else {
+        return null_bulk();
+    };
+
+    let (lon1, lat1) = decode_score(first);
+    let (lon2, lat2) = decode_score(second);
+    format_distance(distance(lon1, lat1, lon2, lat2) / conversion)
+}
+
+fn geopos(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("geopos");
+    }
+    let set = match storage.get_sorted_set(&arguments[0].content) {
+        Ok(set) => set,
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    let members = &arguments[1..];
+    let mut message = format!("*{}\r\n", members.len()).into_bytes();
+    for member in members {
+        match set.and_then(|set| set.score(&member.bytes)) {
+            Some(score) => {
+                let (longitude, latitude) = decode_score(score);
+                message.extend(b"*2\r\n");
+                message.extend(to_bulk_string(format_coordinate(longitude)).into_bytes());
+                message.extend(to_bulk_string(format_coordinate(latitude)).into_bytes());
+            }
+            None => message.extend(b"*-1\r\n"),
+        }
+    }
+    message
+}
+
+fn geohash(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("geohash");
+    }
+    let set = match storage.get_sorted_set(&arguments[0].content) {
+        Ok(set) => set,
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    // The standard geohash alphabet assumes latitudes in [-90, 90] rather than
+    // the Mercator limits used for the scores, so re-encode before printing.
+    let standard_lat_range = GeoHashRange {
+        min: -90.0,
+        max: 90.0,
+    };
+    let members = &arguments[1..];
+    let mut message = format!("*{}\r\n", members.len()).into_bytes();
+    for member in members {
+        let Some(score) = set.and_then(|set| set.score(&member.bytes)) else {
+            message.extend(null_bulk());
+            continue;
+        };
+        let (longitude, latitude) = decode_score(score);
+        let hash = geohash_encode(
+            LONG_RANGE,
+            standard_lat_range,
+            longitude,
+            latitude,
+            GEO_STEP_MAX,
+        )
+        .unwrap_or_default();
+
+        let encoded: Vec<u8> = (0..11)
+            .map(|index| {
+                // 52 bits only fill ten characters; the eleventh is always zero.
+                let alphabet_index = if index == 10 {
+                    0
+                } else {
+                    (hash.bits >> (52 - (index + 1) * 5)) & 0x1f
+                };
+                GEO_ALPHABET[alphabet_index as usize]
+            })
+            .collect();
+        message.extend(to_bulk_bytes(&encoded));
+    }
+    message
+}
+
+fn geosearch(storage: &mut Keyspace, arguments: &[Content], store: bool) -> Vec<u8> {
+    let command_name = if store { "geosearchstore" } else { "geosearch" };
+    let base_args = if store { 2 } else { 1 };
+    if arguments.len() < base_args + 4 {
+        return wrong_arguments(command_name);
+    }
+    let source = &arguments[base_args - 1].content;
+
+    let set = match storage.get_sorted_set(source) {
+        Ok(set) => set,
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    let mut with_dist = false;
+    let mut with_hash = false;
+    let mut with_coord = false;
+    let mut store_dist = false;
+    let mut any = false;
+    let mut ascending: Option<bool> = None;
+    let mut count: usize = 0;
+    let mut center: Option<(f64, f64)> = None;
+    let mut from_member = false;
+    let mut from_lonlat = false;
+    let mut shape: Option<(Shape, f64)> = None;
+
+    let options = &arguments[base_args..];
+    let mut index = 0;
+    while index < options.len() {
+        let remaining = options.len() - index - 1;
+        match options[index].content.to_ascii_uppercase().as_str() {
+            "WITHDIST" => with_dist = true,
+            "WITHHASH" => with_hash = true,
+            "WITHCOORD" => with_coord = true,
+            "STOREDIST" if store => store_dist = true,
+            "ANY" => any = true,
+            "ASC" => ascending = Some(true),
+            "DESC" => ascending = Some(false),
+            "COUNT" if remaining >= 1 => {
+                count = match options[index + 1].content.parse::<i64>() {
+                    Ok(value) if value > 0 => value as usize,
+                    Ok(_) => return to_error("ERR COUNT must be > 0"),
+                    Err(_) => return to_error("ERR value is not an integer or out of range"),
+                };
+                index += 1;
+            }
+            "FROMMEMBER" if remaining >= 1 && !from_lonlat => {
+                if let Some(set) = set {
+                    let Some(score) = set.score(&options[index + 1].bytes) else {
+                        return to_error("ERR could not decode requested zset member");
+                    };
+                    center = Some(decode_score(score));
+                } else {
+                    center = Some((0.0, 0.0));
+                }
+                from_member = true;
+                index += 1;
+            }
+            "FROMLONLAT" if remaining >= 2 && !from_member => {
+                center = match parse_long_lat(&options[index + 1], &options[index + 2]) {
+                    Ok(coordinates) => Some(coordinates),
+                    Err(message) => return message,
+                };
+                from_lonlat = true;
+                index += 2;
+            }
+            "BYRADIUS" if remaining >= 2 && !matches!(shape, Some((Shape::Box { .. }, _))) => {
+                let radius = match parse_float(&options[index + 1], "ERR need numeric radius") {
+                    Ok(radius) if radius < 0.0 => return to_error("ERR radius cannot be negative"),
+                    Ok(radius) => radius,
+                    Err(message) => return message,
+                };
+                let conversion = match parse_unit(&options[index + 2]) {
+                    Ok(conversion) => conversion,
+                    Err(message) => return message,
+                };
+                shape = Some((Shape::Radius(radius), conversion));
+                index += 2;
+            }
+            "BYBOX" if remaining >= 3 && !matches!(shape, Some((Shape::Radius(_), _))) => {
+                let width = match parse_float(&options[index + 1], "ERR need numeric width") {
+                    Ok(width) => width,
+                    Err(message) => return message,
+                };
+                let height = match parse_float(&options[index + 2], "ERR need numeric height") {
+                    Ok(height) => height,
+                    Err(message) => return message,
+                };
+                if width < 0.0 || height < 0.0 {
+                    return to_error("ERR height or width cannot be negative");
+                }
+                let conversion = match parse_unit(&options[index + 3]) {
+                    Ok(conversion) => conversion,
+                    Err(message) => return message,
+                };
+                shape = Some((Shape::Box { width, height }, conversion));
+                index += 3;
+            }
+            _ => return to_error("ERR syntax error"),
+        }
+        index += 1;
+    }
+
+    if store && (with_dist || with_hash || with_coord) {
+        return to_error(
+            "ERR GEOSEARCHSTORE is not compatible with WITHDIST, WITHHASH and WITHCOORD options",
+        );
+    }
+    let Some((longitude, latitude)) = center else {
+        return to_error(&format!(
+            "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+            command_name
+        ));
+    };
+    let Some((shape, conversion)) = shape else {
+        return to_error(&format!(
+            "ERR exactly one of BYRADIUS and BYBOX can be specified for {}",
+            command_name
+        ));
+    };
+    if any && count == 0 {
+        return to_error("ERR the ANY argument requires COUNT argument");
+    }
+
+    let Some(set) = set else {
+        if store {
+            storage.remove(&arguments[0].content);
+            return to_integer(0);
+        }
+        return b"*0\r\n".to_vec();
+    };
+
+    let search = GeoShape {
+        longitude,
+        latitude,
+        shape,
+        conversion,
+    };
+    let mut points = search.members_within(set, if any { count } else { 0 });
+
+    // COUNT without ANY has to return the closest matches.
+    let ascending = if count != 0 && !any {
+        Some(ascending.unwrap_or(true))
+    } else {
+        ascending
+    };
+    if let Some(ascending) = ascending {
+        points.sort_by(|a, b| {
+            let ordering = a.distance.total_cmp(&b.distance);
+            if ascending {
+                ordering
+            } else {
+                ordering.reverse()
+            }
+        });
+    }
+    if count != 0 {
+        points.truncate(count);
+    }
+
+    if store {
+        let destination = arguments[0].content.clone();
+        if points.is_empty() {
+            storage.remove(&destination);
+            return to_integer(0);
+        }
+        let mut result = SortedSet::new();
+        for point in &points {
+            let score = if store_dist {
+                point.distance / conversion
+            } else {
+                point.score
+            };
+            result.insert(point.member.clone(), score);
+        }
+        storage.update(destination, RedisValue::SortedSet(result), i64::MAX);
+        return to_integer(points.len() as i64);
+    }
+
+    let option_count = with_dist as usize + with_hash as usize + with_coord as usize;
+    let mut message = format!("*{}\r\n", points.len()).into_bytes();
+    for point in points {
+        if option_count > 0 {
+            message.extend(format!("*{}\r\n", option_count + 1).into_bytes());
+        }
+        message.extend(to_bulk_bytes(&point.member));
+        if with_dist {
+            message.extend(format_distance(point.distance / conversion));
+        }
+        if with_hash {
+            message.extend(to_integer(point.score as i64));
+        }
+        if with_coord {
+            message.extend(b"*2\r\n");
+            message.extend(to_bulk_string(format_coordinate(point.longitude)).into_bytes());
+            message.extend(to_bulk_string(format_coordinate(point.latitude)).into_bytes());
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, ShardedKeyspace};
+
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    fn run(storage: &mut Keyspace, parts: &[&str]) -> Vec<u8> {
+        handle_geo_command(&request(parts), storage)
+    }
+
+    fn sicily(storage: &mut Keyspace) {
+        let reply = run(
+            storage,
+            &[
+                "GEOADD",
+                "Sicily",
+                "13.361389",
+                "38.115556",
+                "Palermo",
+                "15.087269",
+                "37.502669",
+                "Catania",
+            ],
+        );
+        assert_eq!(reply, b":2\r\n");
+    }
+
+    #[test]
+    fn encodes_scores_as_redis_does() {
+        assert_eq!(encode_score(PALERMO.0, PALERMO.1), Some(3479099956230698.0));
+        assert_eq!(encode_score(CATANIA.0, CATANIA.1), Some(3479447370796909.0));
+        assert_eq!(encode_score(0.0, 85.1), None);
+        assert_eq!(encode_score(180.1, 0.0), None);
+    }
+
+    #[test]
+    fn decodes_scores_to_the_cell_centre() {
+        let (longitude, latitude) = decode_score(3479099956230698.0);
+        assert!((longitude - PALERMO.0).abs() < 1e-5);
+        assert!((latitude - PALERMO.1).abs() < 1e-5);
+        assert_eq!(format_coordinate(longitude), "13.36138933897018433");
+        assert_eq!(format_coordinate(latitude), "38.11555639549629859");
+    }
+
+    #[test]
+    fn encode_decode_round_trips_within_a_cell() {
+        for (longitude, latitude) in [
+            (0.0, 0.0),
+            (-179.9, -85.0),
+            (179.9, 85.0),
+            (-0.1275, 51.5072),
+        ] {
+            let score = encode_score(longitude, latitude).unwrap();
+            let (decoded_longitude, decoded_latitude) = decode_score(score);
+            assert!(
+                (decoded_longitude - longitude).abs() < 1e-5,
+                "{}",
+                longitude
+            );
+            assert!((decoded_latitude - latitude).abs() < 1e-5, "{}", latitude);
+        }
+    }
+
+    #[test]
+    fn answers_the_documentation_examples() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        sicily(&mut storage);
+        assert_eq!(
+            run(&mut storage, &["GEODIST", "Sicily", "Palermo", "Catania"]),
+            b"$11\r\n166274.1516\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["GEODIST", "Sicily", "Palermo", "Catania", "km"]
+            ),
+            b"$8\r\n166.2742\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["GEODIST", "Sicily", "Palermo", "Catania", "mi"]
+            ),
+            b"$8\r\n103.3182\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["GEOHASH", "Sicily", "Palermo", "Catania"]),
+            b"*2\r\n$11\r\nsqc8b49rny0\r\n$11\r\nsqdtr74hyu0\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["GEOPOS", "Sicily", "Palermo", "NonExisting"]
+            ),
+            b"*2\r\n*2\r\n$20\r\n13.36138933897018433\r\n$20\r\n38.11555639549629859\r\n*-1\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["GEOSEARCH", "Sicily", "FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "ASC", "WITHDIST"]
+            ),
+            b"*2\r\n*2\r\n$7\r\nCatania\r\n$7\r\n56.4413\r\n*2\r\n$7\r\nPalermo\r\n$8\r\n190.4424\r\n"
+        );
+    }
+}
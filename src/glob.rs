@@ -0,0 +1,119 @@
+/// Matches `text` against a Redis glob-style `pattern`: `*` matches any run of
+/// bytes, `?` any single byte, `[abc]`, `[^abc]` and `[a-z]` byte classes, and
+/// `\` escapes the next byte.
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            // Consecutive stars behave like one.
+            let rest = trim_stars(rest);
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=text.len()).any(|start| matches(rest, &text[start..]))
+        }
+        Some((b'?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some((&byte, text_rest)) = text.split_first() else {
+                return false;
+            };
+            let (matched, rest) = match_class(rest, byte);
+            matched && matches(rest, text_rest)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && matches(&rest[1..], &text[1..])
+        }
+        Some((&literal, rest)) => text.first() == Some(&literal) && matches(rest, &text[1..]),
+    }
+}
+
+fn trim_stars(mut pattern: &[u8]) -> &[u8] {
+    while let Some((b'*', rest)) = pattern.split_first() {
+        pattern = rest;
+    }
+    pattern
+}
+
+/// Matches `byte` against the class starting after `[`, returning the result
+/// and the pattern after the closing `]`.
+fn match_class(pattern: &[u8], byte: u8) -> (bool, &[u8]) {
+    let (negated, mut pattern) = match pattern.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        match pattern {
+            // An unterminated class ends with the pattern.
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == byte;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= (low..=high).contains(&byte);
+                pattern = rest;
+            }
+            [single, rest @ ..] => {
+                matched |= *single == byte;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negated, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, text: &str) -> bool {
+        matches(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn stars_and_question_marks() {
+        assert!(check("*", ""));
+        assert!(check("news.*", "news.sport"));
+        assert!(check("n**s", "news"));
+        assert!(check("*.*.*", "a.b.c"));
+        assert!(!check("*.*.*", "a.b"));
+        assert!(check("h?llo", "hello"));
+        assert!(!check("h?llo", "hllo"));
+        assert!(!check("news", "news.sport"));
+    }
+
+    #[test]
+    fn byte_classes() {
+        assert!(check("h[ae]llo", "hallo"));
+        assert!(!check("h[ae]llo", "hillo"));
+        assert!(check("h[^e]llo", "hallo"));
+        assert!(!check("h[^e]llo", "hello"));
+        assert!(check("key:[0-9]", "key:7"));
+        // Reversed ranges work like Redis, from the lower byte to the higher.
+        assert!(check("key:[9-0]", "key:7"));
+        assert!(!check("key:[0-9]", "key:x"));
+        assert!(check("[a-]", "-"));
+        assert!(check("[\\]]", "]"));
+        // An unterminated class ends with the pattern.
+        assert!(check("a[bc", "ab"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(check("a\\*b", "a*b"));
+        assert!(!check("a\\*b", "axb"));
+        assert!(check("what\\?", "what?"));
+        // A trailing backslash is a literal.
+        assert!(check("a\\", "a\\"));
+    }
+}
@@ -0,0 +1,868 @@
+use crate::resp_parser::{
+    string_to_simple_resp, to_bulk_bytes, to_error, to_integer, wrong_arguments, Command, Content,
+    RespRequest,
+};
+use crate::storage::{Keyspace, RedisValue};
+use chrono::Utc;
+use std::collections::{BTreeSet, HashMap};
+
+/// Field deadlines are limited to 48 bits of milliseconds, as in Redis.
+const MAX_FIELD_EXPIRE_MS: i64 = (1 << 48) - 1;
+
+#[derive(Debug, Clone)]
+struct HashField {
+    value: Vec<u8>,
+    /// Absolute deadline in unix milliseconds.
+    expires_at: Option<i64>,
+}
+
+/// A hash whose fields may carry their own absolute expiry deadline.
+#[derive(Debug, Clone, Default)]
+pub struct Hash {
+    fields: HashMap<Vec<u8>, HashField>,
+    expiries: BTreeSet<(i64, Vec<u8>)>,
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Vec<u8>> {
+        self.fields.get(field).map(|field| &field.value)
+    }
+
+    pub fn contains(&self, field: &[u8]) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    /// Sets `field`, dropping any deadline it had. Returns true if the field is new.
+    pub fn insert(&mut self, field: Vec<u8>, value: Vec<u8>) -> bool {
+        let previous = self.fields.insert(
+            field.clone(),
+            HashField {
+                value,
+                expires_at: None,
+            },
+        );
+        match previous {
+            Some(HashField {
+                expires_at: Some(deadline),
+                ..
+            }) => {
+                self.expiries.remove(&(deadline, field));
+                false
+            }
+            Some(_) => false,
+            None => true,
+        }
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        match self.fields.remove(field) {
+            Some(removed) => {
+                if let Some(deadline) = removed.expires_at {
+                    self.expiries.remove(&(deadline, field.to_vec()));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn expires_at(&self, field: &[u8]) -> Option<i64> {
+        self.fields.get(field).and_then(|field| field.expires_at)
+    }
+
+    /// Sets or clears the deadline of an existing field.
+    pub fn set_expires_at(&mut self, field: &[u8], deadline: Option<i64>) {
+        let Some(entry) = self.fields.get_mut(field) else {
+            return;
+        };
+        if let Some(previous) = entry.expires_at {
+            self.expiries.remove(&(previous, field.to_vec()));
+        }
+        entry.expires_at = deadline;
+        if let Some(deadline) = deadline {
+            self.expiries.insert((deadline, field.to_vec()));
+        }
+    }
+
+    pub fn has_volatile_fields(&self) -> bool {
+        !self.expiries.is_empty()
+    }
+
+    /// Deletes every field whose deadline is not after `now`, returning them.
+    pub fn expire_fields(&mut self, now: i64) -> Vec<Vec<u8>> {
+        let mut removed = vec![];
+        while let Some((deadline, field)) = self.expiries.first().cloned() {
+            if deadline > now {
+                break;
+            }
+            self.expiries.pop_first();
+            self.fields.remove(&field);
+            removed.push(field);
+        }
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.fields
+            .iter()
+            .map(|(field, entry)| (field, &entry.value))
+    }
+
+    /// Like `get`, but treats fields whose deadline is not after `now` as gone.
+    pub fn get_at(&self, field: &[u8], now: i64) -> Option<&Vec<u8>> {
+        self.fields
+            .get(field)
+            .filter(|field| field.is_live(now))
+            .map(|field| &field.value)
+    }
+
+    pub fn contains_at(&self, field: &[u8], now: i64) -> bool {
+        self.get_at(field, now).is_some()
+    }
+
+    /// Number of fields still live at `now`.
+    pub fn len_at(&self, now: i64) -> usize {
+        let expired = self.expiries.range(..(now + 1, vec![])).count();
+        self.fields.len() - expired
+    }
+
+    pub fn iter_at(&self, now: i64) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.fields
+            .iter()
+            .filter(move |(_, entry)| entry.is_live(now))
+            .map(|(field, entry)| (field, &entry.value))
+    }
+}
+
+impl HashField {
+    fn is_live(&self, now: i64) -> bool {
+        match self.expires_at {
+            Some(deadline) => deadline > now,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Always,
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+/// What HGETEX/HSETEX should do with the deadline of the fields they touch.
+#[derive(Debug, Clone, Copy)]
+enum ExpiryUpdate {
+    Keep,
+    Persist,
+    At(i64),
+}
+
+pub fn handle_hash_command(request: &RespRequest, storage: &mut Keyspace) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Hset => hset(storage, arguments),
+        Command::Hget => hget(storage, arguments),
+        Command::Hmget => hmget(storage, arguments),
+        Command::Hdel => hdel(storage, arguments),
+        Command::Hgetall => hgetall(storage, arguments),
+        Command::Hlen => hlen(storage, arguments),
+        Command::Hexists => hexists(storage, arguments),
+        Command::Hexpire => hexpire(storage, arguments, "hexpire", 1000, false),
+        Command::Hpexpire => hexpire(storage, arguments, "hpexpire", 1, false),
+        Command::Hexpireat => hexpire(storage, arguments, "hexpireat", 1000, true),
+        Command::Hpexpireat => hexpire(storage, arguments, "hpexpireat", 1, true),
+        Command::Httl => httl(storage, arguments, "httl", |deadline, now| {
+            (deadline - now + 999) / 1000
+        }),
+        Command::Hpttl => httl(storage, arguments, "hpttl", |deadline, now| deadline - now),
+        Command::Hexpiretime => httl(storage, arguments, "hexpiretime", |deadline, _| {
+            deadline / 1000
+        }),
+        Command::Hpexpiretime => httl(storage, arguments, "hpexpiretime", |deadline, _| deadline),
+        Command::Hpersist => hpersist(storage, arguments),
+        Command::Hgetex => hgetex(storage, arguments),
+        Command::Hsetex => hsetex(storage, arguments),
+        _ => to_error("ERR unknown hash command"),
+    }
+}
+
+fn now_ms() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+fn null_bulk() -> Vec<u8> {
+    string_to_simple_resp("-1", '$').into_bytes()
+}
+
+fn hash_for_read<'a>(storage: &'a Keyspace, key: &String) -> Result<Option<&'a Hash>, Vec<u8>> {
+    storage
+        .get_hash(key)
+        .map_err(|error| to_error(&error.to_string()))
+}
+
+fn hash_for_write<'a>(storage: &'a mut Keyspace, key: &String) -> Result<&'a mut Hash, Vec<u8>> {
+    match storage.get_hash(key) {
+        Ok(Some(_)) => {}
+        Ok(None) => storage.update(key.clone(), RedisValue::Hash(Hash::new()), i64::MAX),
+        Err(error) => return Err(to_error(&error.to_string())),
+    }
+    Ok(storage.get_hash_mut(key).unwrap().unwrap())
+}
+
+fn remove_if_empty(storage: &mut Keyspace, key: &String) {
+    if let Ok(Some(hash)) = storage.get_hash(key) {
+        if hash.is_empty() {
+            storage.remove(key);
+        }
+    }
+}
+
+/// Parses `FIELDS numfields field ...` starting at `index`, where each field
+/// spans `width` arguments.
+fn parse_fields(arguments: &[Content], index: usize, width: usize) -> Result<&[Content], Vec<u8>> {
+    match arguments.get(index) {
+        Some(keyword) if keyword.content.eq_ignore_ascii_case("FIELDS") => {}
+        _ => {
+            return Err(to_error(
+                "ERR Mandatory argument FIELDS is missing or not at the right position",
+            ))
+        }
+    }
+    let count = match arguments
+        .get(index + 1)
+        .map(|count| count.content.parse::<i64>())
+    {
+        Some(Ok(count)) if count > 0 => count as usize,
+        _ => return Err(to_error("ERR Number of fields must be a positive integer")),
+    };
+    let fields = &arguments[index + 2..];
+    if fields.len() != count * width {
+        return Err(to_error(
+            "ERR The `numfields` parameter must match the number of arguments",
+        ));
+    }
+    Ok(fields)
+}
+
+/// Converts a relative or absolute expiry argument to an absolute deadline in milliseconds.
+fn parse_deadline(
+    argument: &Content,
+    command: &str,
+    unit_ms: i64,
+    absolute: bool,
+) -> Result<i64, Vec<u8>> {
+    let invalid = || to_error(&format!("ERR invalid expire time in '{}' command", command));
+    let value = argument
+        .content
+        .parse::<i64>()
+        .map_err(|_| to_error("ERR value is not an integer or out of range"))?;
+    if value < 0 {
+        return Err(invalid());
+    }
+    let deadline = value
+        .checked_mul(unit_ms)
+        .and_then(|value| {
+            if absolute {
+                Some(value)
+            } else {
+                value.checked_add(now_ms())
+            }
+        })
+        .ok_or_else(invalid)?;
+    if deadline > MAX_FIELD_EXPIRE_MS {
+        return Err(invalid());
+    }
+    Ok(deadline)
+}
+
+fn hset(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 3 || arguments[1..].len() % 2 == 1 {
+        return wrong_arguments("hset");
+    }
+    let hash = match hash_for_write(storage, &arguments[0].content) {
+        Ok(hash) => hash,
+        Err(message) => return message,
+    };
+    let added = arguments[1..]
+        .chunks(2)
+        .filter(|pair| hash.insert(pair[0].bytes.clone(), pair[1].bytes.clone()))
+        .count();
+    to_integer(added as i64)
+}
+
+fn hget(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 2 {
+        return wrong_arguments("hget");
+    }
+    match hash_for_read(storage, &arguments[0].content) {
+        Ok(hash) => match hash.and_then(|hash| hash.get_at(&arguments[1].bytes, now_ms())) {
+            Some(value) => to_bulk_bytes(value),
+            None => null_bulk(),
+        },
+        Err(message) => message,
+    }
+}
+
+fn hmget(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 2 {
+        return wrong_arguments("hmget");
+    }
+    let hash = match hash_for_read(storage, &arguments[0].content) {
+        Ok(hash) => hash,
+        Err(message) => return message,
+    };
+    let now = now_ms();
+    let mut message = format!("*{}\r\n", arguments.len() - 1).into_bytes();
+    for field in &arguments[1..] {
+        match hash.and_then(|hash| hash.get_at(&field.bytes, now)) {
+            Some(value) => message.extend(to_bulk_bytes(value)),
+            None => message.extend(null_bulk()),
+        }
+    }
+    message
+}
+
+fn hdel(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 2 {
+        return wrong_arguments("hdel");
+    }
+    let key = &arguments[0].content;
+    let hash = match storage.get_hash_mut(key) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return to_integer(0),
+        Err(error) => return to_error(&error.to_string()),
+    };
+    let removed = arguments[1..]
+        .iter()
+        .filter(|field| hash.remove(&field.bytes))
+        .count();
+    remove_if_empty(storage, key);
+    to_integer(removed as i64)
+}
+
+fn hgetall(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 1 {
+        return wrong_arguments("hgetall");
+    }
+    let hash = match hash_for_read(storage, &arguments[0].content) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return b"*0\r\n".to_vec(),
+        Err(message) => return message,
+    };
+    let now = now_ms();
+    let mut message = format!("*{}\r\n", hash.len_at(now) * 2).into_bytes();
+    for (field, value) in hash.iter_at(now) {
+        message.extend(to_bulk_bytes(field));
+        message.extend(to_bulk_bytes(value));
+    }
+    message
+}
+
+fn hlen(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 1 {
+        return wrong_arguments("hlen");
+    }
+    match hash_for_read(storage, &arguments[0].content) {
+        Ok(hash) => to_integer(hash.map_or(0, |hash| hash.len_at(now_ms())) as i64),
+        Err(message) => message,
+    }
+}
+
+fn hexists(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 2 {
+        return wrong_arguments("hexists");
+    }
+    match hash_for_read(storage, &arguments[0].content) {
+        Ok(hash) => to_integer(
+            hash.is_some_and(|hash| hash.contains_at(&arguments[1].bytes, now_ms())) as i64,
+        ),
+        Err(message) => message,
+    }
+}
+
+fn hexpire(
+    storage: &mut Keyspace,
+    arguments: &[Content],
+    command: &str,
+    unit_ms: i64,
+    absolute: bool,
+) -> Vec<u8> {
+    if arguments.len() < 5 {
+        return wrong_arguments(command);
+    }
+    let deadline = match parse_deadline(&arguments[1], command, unit_ms, absolute) {
+        Ok(deadline) => deadline,
+        Err(message) => return message,
+    };
+    let (condition, fields_index) = match arguments[2].content.to_ascii_uppercase().as_str() {
+        "NX" => (Condition::Nx, 3),
+        "XX" => (Condition::Xx, 3),
+        "GT" => (Condition::Gt, 3),
+        "LT" => (Condition::Lt, 3),
+        _ => (Condition::Always, 2),
+    };
+    let fields = match parse_fields(arguments, fields_index, 1) {
+        Ok(fields) => fields,
+        Err(message) => return message,
+    };
+
+    let key = &arguments[0].content;
+    let hash = match storage.get_hash_mut(key) {
+        Ok(Some(hash)) => Some(hash),
+        Ok(None) => None,
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    let mut message = format!("*{}\r\n", fields.len()).into_bytes();
+    let Some(hash) = hash else {
+        for _ in fields {
+            message.extend(to_integer(-2));
+        }
+        return message;
+    };
+
+    let now = now_ms();
+    for field in fields {
+        if !hash.contains(&field.bytes) {
+            message.extend(to_integer(-2));
+            continue;
+        }
+        let current = hash.expires_at(&field.bytes);
+        let allowed = match condition {
+            Condition::Always => true,
+            Condition::Nx => current.is_none(),
+            Condition::Xx => current.is_some(),
+            Condition::Gt => current.is_some_and(|current| deadline > current),
+            Condition::Lt => match current {
+                Some(current) => deadline < current,
+                None => true,
+            },
+        };
+        if !allowed {
+            message.extend(to_integer(0));
+        } else if deadline <= now {
+            hash.remove(&field.bytes);
+            message.extend(to_integer(2));
+        } else {
+            hash.set_expires_at(&field.bytes, Some(deadline));
+            message.extend(to_integer(1));
+        }
+    }
+
+    if hash.has_volatile_fields() {
+        storage.track_volatile(key.clone());
+    }
+    remove_if_empty(storage, key);
+    message
+}
+
+fn httl(
+    storage: &mut Keyspace,
+    arguments: &[Content],
+    command: &str,
+    reply: fn(i64, i64) -> i64,
+) -> Vec<u8> {
+    if arguments.len() < 4 {
+        return wrong_arguments(command);
+    }
+    let fields = match parse_fields(arguments, 1, 1) {
+        Ok(fields) => fields,
+        Err(message) => return message,
+    };
+    let hash = match hash_for_read(storage, &arguments[0].content) {
+        Ok(hash) => hash,
+        Err(message) => return message,
+    };
+
+    let now = now_ms();
+    let mut message = format!("*{}\r\n", fields.len()).into_bytes();
+    for field in fields {
+        let value = match hash {
+            Some(hash) if hash.contains_at(&field.bytes, now) => match hash.expires_at(&field.bytes) {
+                Some(deadline) => reply(deadline, now),
+                None => -1,
+            },
+            _ => -2,
+        };
+        message.extend(to_integer(value));
+    }
+    message
+}
+
+fn hpersist(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 4 {
+        return wrong_arguments("hpersist");
+    }
+    let fields = match parse_fields(arguments, 1, 1) {
+        Ok(fields) => fields,
+        Err(message) => return message,
+    };
+    let hash = match storage.get_hash_mut(&arguments[0].content) {
+        Ok(hash) => hash,
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    let mut message = format!("*{}\r\n", fields.len()).into_bytes();
+    let Some(hash) = hash else {
+        for _ in fields {
+            message.extend(to_integer(-2));
+        }
+        return message;
+    };
+    for field in fields {
+        let value = if !hash.contains(&field.bytes) {
+            -2
+        } else if hash.expires_at(&field.bytes).is_none() {
+            -1
+        } else {
+            hash.set_expires_at(&field.bytes, None);
+            1
+        };
+        message.extend(to_integer(value));
+    }
+    message
+}
+
+/// Parses one `EX|PX|EXAT|PXAT <time>` / `PERSIST` / `KEEPTTL` option at `index`,
+/// returning the update and how many arguments it used.
+fn parse_expiry_option(
+    arguments: &[Content],
+    index: usize,
+    command: &str,
+    allow_persist: bool,
+    allow_keepttl: bool,
+) -> Result<Option<(ExpiryUpdate, usize)>, Vec<u8>> {
+    let option = arguments[index].content.to_ascii_uppercase();
+    let (unit_ms, absolute) = match option.as_str() {
+        "EX" => (1000, false),
+        "PX" => (1, false),
+        "EXAT" => (1000, true),
+        "PXAT" => (1, true),
+        "PERSIST" if allow_persist => return Ok(Some((ExpiryUpdate::Persist, 1))),
+        "KEEPTTL" if allow_keepttl => return Ok(Some((ExpiryUpdate::Keep, 1))),
+        _ => return Ok(None),
+    };
+    let Some(argument) = arguments.get(index + 1) else {
+        return Err(to_error("ERR syntax error"));
+    };
+    let deadline = parse_deadline(argument, command, unit_ms, absolute)?;
+    Ok(Some((ExpiryUpdate::At(deadline), 2)))
+}
+
+fn apply_expiry(hash: &mut Hash, field: &[u8], update: ExpiryUpdate, now: i64) {
+    match update {
+        ExpiryUpdate::Keep => {}
+        ExpiryUpdate::Persist => hash.set_expires_at(field, None),
+        ExpiryUpdate::At(deadline) if deadline <= now => {
+            hash.remove(field);
+        }
+        ExpiryUpdate::At(deadline) => hash.set_expires_at(field, Some(deadline)),
+    }
+}
+
+fn hgetex(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 4 {
+        return wrong_arguments("hgetex");
+    }
+    let mut update = ExpiryUpdate::Keep;
+    let mut index = 1;
+    match parse_expiry_option(arguments, index, "hgetex", true, false) {
+        Ok(Some((parsed, used))) => {
+            update = parsed;
+            index += used;
+        }
+        Ok(None) => {}
+        Err(message) => return message,
+    }
+    let fields = match parse_fields(arguments, index, 1) {
+        Ok(fields) => fields,
+        Err(message) => return message,
+    };
+
+    let key = &arguments[0].content;
+    let hash = match storage.get_hash_mut(key) {
+        Ok(hash) => hash,
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    let mut message = format!("*{}\r\n", fields.len()).into_bytes();
+    let Some(hash) = hash else {
+        for _ in fields {
+            message.extend(null_bulk());
+        }
+        return message;
+    };
+    let now = now_ms();
+    for field in fields {
+        match hash.get(&field.bytes) {
+            Some(value) => {
+                message.extend(to_bulk_bytes(value));
+                apply_expiry(hash, &field.bytes, update, now);
+            }
+            None => message.extend(null_bulk()),
+        }
+    }
+
+    if hash.has_volatile_fields() {
+        storage.track_volatile(key.clone());
+    }
+    remove_if_empty(storage, key);
+    message
+}
+
+fn hsetex(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 5 {
+        return wrong_arguments("hsetex");
+    }
+    let mut update = ExpiryUpdate::Persist;
+    let mut expiry_given = false;
+    let mut only_new = false;
+    let mut only_existing = false;
+    let mut index = 1;
+
+    while index < arguments.len() && !arguments[index].content.eq_ignore_ascii_case("FIELDS") {
+        let option = arguments[index].content.to_ascii_uppercase();
+        if option == "FNX" || option == "FXX" {
+            if only_new || only_existing {
+                return to_error("ERR Only one of FXX or FNX arguments can be specified");
+            }
+            only_new = option == "FNX";
+            only_existing = option == "FXX";
+            index += 1;
+            continue;
+        }
+        match parse_expiry_option(arguments, index, "hsetex", false, true) {
+            Ok(Some(_)) if expiry_given => {
+                return to_error(
+                    "ERR Only one of EX, PX, EXAT, PXAT or KEEPTTL arguments can be specified",
+                )
+            }
+            Ok(Some((parsed, used))) => {
+                update = parsed;
+                expiry_given = true;
+                index += used;
+            }
+            Ok(None) => return to_error("ERR unknown argument"),
+            Err(message) => return message,
+        }
+    }
+    let pairs = match parse_fields(arguments, index, 2) {
+        Ok(pairs) => pairs,
+        Err(message) => return message,
+    };
+
+    let key = &arguments[0].content;
+    let existing = match hash_for_read(storage, key) {
+        Ok(hash) => hash.map_or(0, |hash| {
+            pairs
+                .chunks(2)
+                .filter(|pair| hash.contains(&pair[0].bytes))
+                .count()
+        }),
+        Err(message) => return message,
+    };
+    let field_count = pairs.len() / 2;
+    if (only_new && existing > 0) || (only_existing && existing < field_count) {
+        return to_integer(0);
+    }
+
+    let hash = match hash_for_write(storage, key) {
+        Ok(hash) => hash,
+        Err(message) => return message,
+    };
+    let now = now_ms();
+    for pair in pairs.chunks(2) {
+        let previous = hash.expires_at(&pair[0].bytes);
+        hash.insert(pair[0].bytes.clone(), pair[1].bytes.clone());
+        match update {
+            ExpiryUpdate::Keep => hash.set_expires_at(&pair[0].bytes, previous),
+            update => apply_expiry(hash, &pair[0].bytes, update, now),
+        }
+    }
+
+    if hash.has_volatile_fields() {
+        storage.track_volatile(key.clone());
+    }
+    remove_if_empty(storage, key);
+    to_integer(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, ShardedKeyspace};
+
+    fn run(storage: &mut Keyspace, parts: &[&str]) -> Vec<u8> {
+        handle_hash_command(&request(parts), storage)
+    }
+
+    #[test]
+    fn expire_fields_removes_due_fields_in_deadline_order() {
+        let mut hash = Hash::new();
+        for field in ["a", "b", "c"] {
+            hash.insert(field.as_bytes().to_vec(), b"v".to_vec());
+        }
+        hash.set_expires_at(b"b", Some(200));
+        hash.set_expires_at(b"a", Some(100));
+        hash.set_expires_at(b"c", Some(300));
+        hash.set_expires_at(b"c", None);
+
+        assert_eq!(hash.len_at(150), 2);
+        assert!(!hash.contains_at(b"a", 100) && hash.contains_at(b"a", 99));
+        assert_eq!(hash.expire_fields(99), Vec::<Vec<u8>>::new());
+        assert_eq!(hash.expire_fields(250), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(hash.len(), 1);
+        assert!(!hash.has_volatile_fields());
+    }
+
+    #[test]
+    fn insert_clears_the_deadline() {
+        let mut hash = Hash::new();
+        assert!(hash.insert(b"f".to_vec(), b"1".to_vec()));
+        hash.set_expires_at(b"f", Some(100));
+        assert!(!hash.insert(b"f".to_vec(), b"2".to_vec()));
+        assert_eq!(hash.expires_at(b"f"), None);
+        assert!(hash.expire_fields(i64::MAX).is_empty());
+    }
+
+    #[test]
+    fn hexpire_conditions() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        run(&mut storage, &["HSET", "h", "a", "1", "b", "2"]);
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HEXPIRE", "h", "100", "FIELDS", "3", "a", "b", "missing"]
+            ),
+            b"*3\r\n:1\r\n:1\r\n:-2\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HEXPIRE", "h", "50", "GT", "FIELDS", "1", "a"]
+            ),
+            b"*1\r\n:0\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HEXPIRE", "h", "50", "LT", "FIELDS", "1", "a"]
+            ),
+            b"*1\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HEXPIRE", "h", "500", "NX", "FIELDS", "1", "a"]
+            ),
+            b"*1\r\n:0\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["HTTL", "h", "FIELDS", "1", "a"]),
+            b"*1\r\n:50\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["HPERSIST", "h", "FIELDS", "2", "a", "a"]),
+            b"*2\r\n:1\r\n:-1\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["HTTL", "h", "FIELDS", "1", "a"]),
+            b"*1\r\n:-1\r\n"
+        );
+        assert!(
+            run(&mut storage, &["HEXPIRE", "h", "10", "FIELDS", "2", "a"]).starts_with(b"-ERR")
+        );
+    }
+
+    #[test]
+    fn past_deadlines_delete_fields_and_the_emptied_hash() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        run(&mut storage, &["HSET", "h", "a", "1", "b", "2"]);
+        assert_eq!(
+            run(&mut storage, &["HPEXPIREAT", "h", "1", "FIELDS", "1", "a"]),
+            b"*1\r\n:2\r\n"
+        );
+        assert_eq!(run(&mut storage, &["HLEN", "h"]), b":1\r\n");
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HGETEX", "h", "PXAT", "1", "FIELDS", "1", "b"]
+            ),
+            b"*1\r\n$1\r\n2\r\n"
+        );
+        assert!(!storage.contains_key(&"h".to_string()));
+    }
+
+    #[test]
+    fn reads_skip_expired_fields_left_in_place() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        run(&mut storage, &["HSET", "h", "a", "1", "b", "2"]);
+        let Ok(Some(hash)) = storage.get_hash_mut(&"h".to_string()) else {
+            panic!("not a hash");
+        };
+        hash.set_expires_at(b"a", Some(1));
+
+        assert_eq!(run(&mut storage, &["HGET", "h", "a"]), b"$-1\r\n");
+        assert_eq!(run(&mut storage, &["HLEN", "h"]), b":1\r\n");
+        assert_eq!(
+            run(&mut storage, &["HGETALL", "h"]),
+            b"*2\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(run(&mut storage, &["HEXISTS", "h", "a"]), b":0\r\n");
+        assert_eq!(
+            storage.expire_fields_of(&"h".to_string(), now_ms()),
+            vec![b"a".to_vec()]
+        );
+    }
+
+    #[test]
+    fn hsetex_fnx_and_fxx() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HSETEX", "h", "FNX", "EX", "100", "FIELDS", "2", "a", "1", "b", "2"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HSETEX", "h", "FNX", "FIELDS", "1", "a", "3"]
+            ),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run(
+                &mut storage,
+                &["HSETEX", "h", "FXX", "KEEPTTL", "FIELDS", "1", "a", "3"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(run(&mut storage, &["HGET", "h", "a"]), b"$1\r\n3\r\n");
+        assert_eq!(
+            run(&mut storage, &["HTTL", "h", "FIELDS", "1", "a"]),
+            b"*1\r\n:100\r\n"
+        );
+    }
+}
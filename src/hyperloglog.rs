@@ -0,0 +1,564 @@
+use crate::resp_parser::{
+    string_to_simple_resp, to_error, to_integer, wrong_arguments, Command, Content, RespRequest,
+};
+use crate::storage::{Keyspace, RedisValue};
+
+// The layout below follows Redis' hyperloglog.c exactly, so that values can be
+// exchanged with real Redis instances through GET/SET, replication and RDB.
+//
+// +------+---+-----+----------+
+// | HYLL | E | N/U | Cardin.  |  (16 byte header)
+// +------+---+-----+----------+
+//
+// E is the encoding (dense or sparse), N/U is unused and the cardinality is a
+// little endian u64 whose most significant bit flags the cache as stale.
+
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_P_MASK: u64 = HLL_REGISTERS as u64 - 1;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const HLL_DENSE: u8 = 0;
+const HLL_SPARSE: u8 = 1;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+const HLL_SPARSE_XZERO_BIT: u8 = 0x40;
+const HLL_SPARSE_VAL_BIT: u8 = 0x80;
+const HLL_SPARSE_VAL_MAX_VALUE: u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN: usize = 4;
+const HLL_SPARSE_ZERO_MAX_LEN: usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+/// Sparse values growing past this size are promoted to the dense encoding
+/// (`hll-sparse-max-bytes` in redis.conf).
+const HLL_SPARSE_MAX_BYTES: usize = 3000;
+
+const INVALID_HLL: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+const CORRUPTED_HLL: &str = "INVALIDOBJ Corrupted HLL object detected";
+
+pub fn handle_hyperloglog_command(request: &RespRequest, storage: &mut Keyspace) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Pfadd => pfadd(storage, arguments),
+        Command::Pfcount => pfcount(storage, arguments),
+        Command::Pfmerge => pfmerge(storage, arguments),
+        _ => to_error("ERR unknown hyperloglog command"),
+    }
+}
+
+/// MurmurHash2, 64 bit version, as used by Redis (little endian loads).
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut hash = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        hash ^= k;
+        hash = hash.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (index, byte) in tail.iter().enumerate() {
+            hash ^= (*byte as u64) << (8 * index);
+        }
+        hash = hash.wrapping_mul(M);
+    }
+
+    hash ^= hash >> R;
+    hash = hash.wrapping_mul(M);
+    hash ^= hash >> R;
+    hash
+}
+
+/// Returns the register index for `element` and the length of the run of
+/// zeros (plus one) that follows the index bits in its hash.
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, 0xadc8_3b19);
+    let index = (hash & HLL_P_MASK) as usize;
+    let pattern = (hash >> HLL_P) | (1 << HLL_Q);
+    (index, pattern.trailing_zeros() as u8 + 1)
+}
+
+/// A decoded HyperLogLog: the encoding it was stored with and one byte per register.
+struct Hll {
+    encoding: u8,
+    registers: Vec<u8>,
+}
+
+/// Checks the header of a stored value the same way Redis' `isHLLObjectOrReply` does.
+fn validate(value: &[u8]) -> Result<(), Vec<u8>> {
+    if value.len() < HLL_HDR_SIZE || &value[..4] != b"HYLL" {
+        return Err(to_error(INVALID_HLL));
+    }
+    match value[4] {
+        HLL_SPARSE => Ok(()),
+        HLL_DENSE if value.len() == HLL_DENSE_SIZE => Ok(()),
+        _ => Err(to_error(INVALID_HLL)),
+    }
+}
+
+fn decode(value: &[u8]) -> Result<Hll, Vec<u8>> {
+    validate(value)?;
+    let payload = &value[HLL_HDR_SIZE..];
+    let mut registers = vec![0u8; HLL_REGISTERS];
+
+    if value[4] == HLL_DENSE {
+        for (index, register) in registers.iter_mut().enumerate() {
+            *register = dense_get(payload, index);
+        }
+        return Ok(Hll {
+            encoding: HLL_DENSE,
+            registers,
+        });
+    }
+
+    let mut index = 0;
+    let mut position = 0;
+    while position < payload.len() {
+        let opcode = payload[position];
+        if opcode & HLL_SPARSE_VAL_BIT != 0 {
+            let register = ((opcode >> 2) & 0x1f) + 1;
+            let run = (opcode & 0x3) as usize + 1;
+            if index + run > HLL_REGISTERS {
+                return Err(to_error(CORRUPTED_HLL));
+            }
+            registers[index..index + run].fill(register);
+            index += run;
+            position += 1;
+        } else if opcode & 0xc0 == HLL_SPARSE_XZERO_BIT {
+            let Some(low) = payload.get(position + 1) else {
+                return Err(to_error(CORRUPTED_HLL));
+            };
+            index += ((((opcode & 0x3f) as usize) << 8) | *low as usize) + 1;
+            position += 2;
+        } else {
+            index += (opcode & 0x3f) as usize + 1;
+            position += 1;
+        }
+    }
+    if index != HLL_REGISTERS {
+        return Err(to_error(CORRUPTED_HLL));
+    }
+
+    Ok(Hll {
+        encoding: HLL_SPARSE,
+        registers,
+    })
+}
+
+fn dense_get(payload: &[u8], index: usize) -> u8 {
+    let byte = index * HLL_BITS / 8;
+    let first_bit = (index * HLL_BITS) & 7;
+    let b0 = payload[byte] as u16;
+    let b1 = payload.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> first_bit) | (b1 << (8 - first_bit))) & HLL_REGISTER_MAX as u16) as u8
+}
+
+fn dense_set(payload: &mut [u8], index: usize, register: u8) {
+    let byte = index * HLL_BITS / 8;
+    let first_bit = (index * HLL_BITS) & 7;
+    let register = register as u16;
+    let max = HLL_REGISTER_MAX as u16;
+
+    payload[byte] &= !((max << first_bit) as u8);
+    payload[byte] |= (register << first_bit) as u8;
+    if let Some(next) = payload.get_mut(byte + 1) {
+        *next &= !((max >> (8 - first_bit)) as u8);
+        *next |= (register >> (8 - first_bit)) as u8;
+    }
+}
+
+fn encode_sparse(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = vec![];
+    let mut index = 0;
+    while index < registers.len() {
+        let register = registers[index];
+        if register > HLL_SPARSE_VAL_MAX_VALUE {
+            return None;
+        }
+        let mut run = registers[index..]
+            .iter()
+            .take_while(|other| **other == register)
+            .count();
+        index += run;
+
+        while run > 0 {
+            if register != 0 {
+                let len = run.min(HLL_SPARSE_VAL_MAX_LEN);
+                payload.push(HLL_SPARSE_VAL_BIT | ((register - 1) << 2) | (len as u8 - 1));
+                run -= len;
+            } else if run > HLL_SPARSE_ZERO_MAX_LEN {
+                let len = run.min(HLL_SPARSE_XZERO_MAX_LEN) - 1;
+                payload.push(HLL_SPARSE_XZERO_BIT | (len >> 8) as u8);
+                payload.push((len & 0xff) as u8);
+                run -= len + 1;
+            } else {
+                payload.push(run as u8 - 1);
+                run = 0;
+            }
+        }
+    }
+    Some(payload)
+}
+
+/// Serialises `hll` with an invalidated cardinality cache. Sparse values are
+/// promoted to dense once they no longer fit the sparse representation.
+fn encode(hll: &Hll) -> Vec<u8> {
+    let mut value = b"HYLL".to_vec();
+
+    let sparse = if hll.encoding == HLL_SPARSE {
+        encode_sparse(&hll.registers)
+            .filter(|payload| HLL_HDR_SIZE + payload.len() <= HLL_SPARSE_MAX_BYTES)
+    } else {
+        None
+    };
+
+    let payload = match sparse {
+        Some(payload) => {
+            value.push(HLL_SPARSE);
+            payload
+        }
+        None => {
+            value.push(HLL_DENSE);
+            let mut payload = vec![0u8; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+            for (index, register) in hll.registers.iter().enumerate() {
+                dense_set(&mut payload, index, *register);
+            }
+            payload
+        }
+    };
+    value.extend_from_slice(&[0, 0, 0]);
+    value.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1 << 7]);
+    value.extend(payload);
+    value
+}
+
+fn empty_hll() -> Hll {
+    Hll {
+        encoding: HLL_SPARSE,
+        registers: vec![0; HLL_REGISTERS],
+    }
+}
+
+fn cached_cardinality(value: &[u8]) -> Option<u64> {
+    if value[15] & (1 << 7) != 0 {
+        return None;
+    }
+    Some(u64::from_le_bytes(value[8..16].try_into().unwrap()))
+}
+
+fn hll_sigma(x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut x = x;
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+fn hll_tau(x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut x = x;
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+/// Estimates the cardinality with Otmar Ertl's improved estimator, as Redis does.
+fn count(registers: &[u8]) -> u64 {
+    let m = HLL_REGISTERS as f64;
+    let mut histogram = [0u32; 64];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+
+    let q = HLL_Q as usize;
+    let mut z = m * hll_tau((m - histogram[q + 1] as f64) / m);
+    for j in (1..=q).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * hll_sigma(histogram[0] as f64 / m);
+    (HLL_ALPHA_INF * m * m / z).round() as u64
+}
+
+fn pfadd(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("pfadd");
+    }
+    let key = &arguments[0].content;
+
+    let (mut hll, mut updated) = match storage.get_string(key) {
+        Ok(Some(value)) => match decode(value) {
+            Ok(hll) => (hll, false),
+            Err(message) => return message,
+        },
+        Ok(None) => (empty_hll(), true),
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    for element in &arguments[1..] {
+        let (index, run) = pattern_len(&element.bytes);
+        if hll.registers[index] < run {
+            hll.registers[index] = run;
+            updated = true;
+        }
+    }
+
+    if updated {
+        write_hll(storage, key, &hll);
+    }
+    to_integer(updated as i64)
+}
+
+/// Stores `hll` at `key`, keeping the expiry of an existing value.
+fn write_hll(storage: &mut Keyspace, key: &String, hll: &Hll) {
+    let encoded = encode(hll);
+    match storage.get_string_mut(key) {
+        Ok(Some(value)) => *value = encoded,
+        _ => storage.update(key.clone(), RedisValue::String(encoded), i64::MAX),
+    }
+}
+
+fn pfcount(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("pfcount");
+    }
+
+    if arguments.len() > 1 {
+        let mut registers = vec![0u8; HLL_REGISTERS];
+        for key in arguments {
+            let value = match storage.get_string(&key.content) {
+                Ok(Some(value)) => value,
+                Ok(None) => continue,
+                Err(error) => return to_error(&error.to_string()),
+            };
+            match decode(value) {
+                Ok(hll) => merge_into(&mut registers, &hll.registers),
+                Err(message) => return message,
+            }
+        }
+        return to_integer(count(&registers) as i64);
+    }
+
+    let value = match storage.get_string_mut(&arguments[0].content) {
+        Ok(Some(value)) => value,
+        Ok(None) => return to_integer(0),
+        Err(error) => return to_error(&error.to_string()),
+    };
+    if let Err(message) = validate(value) {
+        return message;
+    }
+    if let Some(cardinality) = cached_cardinality(value) {
+        return to_integer(cardinality as i64);
+    }
+    let cardinality = match decode(value) {
+        Ok(hll) => count(&hll.registers),
+        Err(message) => return message,
+    };
+    value[8..16].copy_from_slice(&cardinality.to_le_bytes());
+    to_integer(cardinality as i64)
+}
+
+fn merge_into(registers: &mut [u8], other: &[u8]) {
+    for (register, other) in registers.iter_mut().zip(other) {
+        *register = (*register).max(*other);
+    }
+}
+
+fn pfmerge(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("pfmerge");
+    }
+
+    // The destination takes part in the union, and the result is dense as soon
+    // as any of the inputs is.
+    let mut merged = empty_hll();
+    for key in arguments {
+        let value = match storage.get_string(&key.content) {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(error) => return to_error(&error.to_string()),
+        };
+        match decode(value) {
+            Ok(hll) => {
+                merge_into(&mut merged.registers, &hll.registers);
+                if hll.encoding == HLL_DENSE {
+                    merged.encoding = HLL_DENSE;
+                }
+            }
+            Err(message) => return message,
+        }
+    }
+
+    write_hll(storage, &arguments[0].content, &merged);
+    string_to_simple_resp("OK", '+').into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, ShardedKeyspace};
+
+    fn run(storage: &mut Keyspace, parts: &[&str]) -> Vec<u8> {
+        handle_hyperloglog_command(&request(parts), storage)
+    }
+
+    fn add_elements(storage: &mut Keyspace, key: &str, elements: std::ops::Range<usize>) {
+        let elements: Vec<String> = elements.map(|index| format!("element:{}", index)).collect();
+        let mut parts = vec!["PFADD", key];
+        parts.extend(elements.iter().map(String::as_str));
+        run(storage, &parts);
+    }
+
+    fn count_of(reply: &[u8]) -> i64 {
+        std::str::from_utf8(&reply[1..reply.len() - 2])
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn stored(storage: &Keyspace, key: &str) -> Vec<u8> {
+        match storage.get(&key.to_string()) {
+            Some(RedisValue::String(value)) => value.clone(),
+            _ => panic!("not a string"),
+        }
+    }
+
+    #[test]
+    fn counts_the_documentation_examples() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(
+            run(
+                &mut storage,
+                &["PFADD", "hll", "a", "b", "c", "d", "e", "f", "g"]
+            ),
+            b":1\r\n"
+        );
+        assert_eq!(run(&mut storage, &["PFCOUNT", "hll"]), b":7\r\n");
+        assert_eq!(run(&mut storage, &["PFADD", "hll", "a", "b"]), b":0\r\n");
+
+        run(&mut storage, &["PFADD", "hll1", "foo", "bar", "zap", "a"]);
+        run(&mut storage, &["PFADD", "hll2", "a", "b", "c", "foo"]);
+        assert_eq!(
+            run(&mut storage, &["PFMERGE", "hll3", "hll1", "hll2"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(run(&mut storage, &["PFCOUNT", "hll3"]), b":6\r\n");
+        assert_eq!(run(&mut storage, &["PFCOUNT", "hll1", "hll2"]), b":6\r\n");
+    }
+
+    #[test]
+    fn empty_hll_is_a_single_sparse_xzero_run() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(run(&mut storage, &["PFADD", "hll"]), b":1\r\n");
+        assert_eq!(
+            stored(&storage, "hll"),
+            b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x7f\xff"
+        );
+        assert_eq!(run(&mut storage, &["PFCOUNT", "hll"]), b":0\r\n");
+    }
+
+    #[test]
+    fn sparse_turns_dense_and_keeps_the_estimate() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        add_elements(&mut storage, "hll", 0..100);
+        assert_eq!(stored(&storage, "hll")[4], HLL_SPARSE);
+        let sparse_count = count_of(&run(&mut storage, &["PFCOUNT", "hll"]));
+        assert!((95..=105).contains(&sparse_count), "{}", sparse_count);
+
+        let mut hll = decode(&stored(&storage, "hll")).unwrap();
+        hll.encoding = HLL_DENSE;
+        let dense = encode(&hll);
+        assert_eq!(dense.len(), HLL_DENSE_SIZE);
+        assert_eq!(decode(&dense).unwrap().registers, hll.registers);
+
+        add_elements(&mut storage, "hll", 100..20000);
+        let value = stored(&storage, "hll");
+        assert_eq!(value[4], HLL_DENSE);
+        assert_eq!(value.len(), HLL_DENSE_SIZE);
+        let dense_count = count_of(&run(&mut storage, &["PFCOUNT", "hll"]));
+        assert!(
+            (dense_count - 20000).abs() < 20000 * 2 / 100,
+            "{}",
+            dense_count
+        );
+    }
+
+    #[test]
+    fn registers_past_the_sparse_maximum_need_dense() {
+        let mut registers = vec![0; HLL_REGISTERS];
+        registers[5] = HLL_SPARSE_VAL_MAX_VALUE;
+        assert!(encode_sparse(&registers).is_some());
+        registers[5] = HLL_SPARSE_VAL_MAX_VALUE + 1;
+        assert!(encode_sparse(&registers).is_none());
+        let value = encode(&Hll {
+            encoding: HLL_SPARSE,
+            registers: registers.clone(),
+        });
+        assert_eq!(value[4], HLL_DENSE);
+        assert_eq!(decode(&value).unwrap().registers, registers);
+    }
+
+    #[test]
+    fn dense_registers_round_trip() {
+        let mut payload = vec![0; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+        for index in 0..HLL_REGISTERS {
+            dense_set(&mut payload, index, (index % 64) as u8);
+        }
+        for index in 0..HLL_REGISTERS {
+            assert_eq!(dense_get(&payload, index), (index % 64) as u8);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_sparse_values() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        // A single zero run covers 64 registers instead of all 16384.
+        let corrupted = b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x3f".to_vec();
+        storage.update("hll".to_string(), RedisValue::String(corrupted), i64::MAX);
+        let reply = run(&mut storage, &["PFCOUNT", "hll"]);
+        assert!(reply.starts_with(b"-INVALIDOBJ"));
+
+        storage.update(
+            "plain".to_string(),
+            RedisValue::String(b"hello".to_vec()),
+            i64::MAX,
+        );
+        let reply = run(&mut storage, &["PFADD", "plain", "a"]);
+        assert!(reply.starts_with(b"-WRONGTYPE"));
+    }
+}
@@ -0,0 +1,591 @@
+use crate::config::Config;
+use crate::database::Databases;
+use crate::persistence::Persistence;
+use crate::replication::{new_replid, RedisReplicationState};
+use crate::resp_parser::{to_bulk_bytes, RespRequest};
+use crate::storage::Access;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    fs,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Sections in the order INFO prints them, with their headers and whether INFO
+/// without arguments includes them.
+const SECTIONS: &[(&str, &str, bool)] = &[
+    ("server", "Server", true),
+    ("clients", "Clients", true),
+    ("memory", "Memory", true),
+    ("persistence", "Persistence", true),
+    ("stats", "Stats", true),
+    ("replication", "Replication", true),
+    ("cpu", "CPU", true),
+    ("commandstats", "Commandstats", false),
+    ("errorstats", "Errorstats", true),
+    ("keyspace", "Keyspace", true),
+];
+
+/// Counters are spread over this many independently locked maps, picked by
+/// client id, so recording a command does not serialize all connections.
+const STAT_SHARDS: usize = 16;
+/// Number of command rate samples, taken every 100ms, averaged for
+/// instantaneous_ops_per_sec.
+const OPS_SAMPLES: usize = 16;
+
+/// The system allocator, counting the bytes in use for INFO memory.
+pub struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = System.alloc(layout);
+        if !pointer.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        System.dealloc(pointer, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_pointer = System.realloc(pointer, layout, new_size);
+        if !new_pointer.is_null() {
+            if new_size >= layout.size() {
+                let grown = new_size - layout.size();
+                let allocated = ALLOCATED.fetch_add(grown, Ordering::Relaxed) + grown;
+                PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+            } else {
+                ALLOCATED.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        new_pointer
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct CommandStats {
+    calls: u64,
+    usec: u64,
+    rejected_calls: u64,
+    failed_calls: u64,
+}
+
+#[derive(Debug, Default)]
+struct StatShard {
+    commands: HashMap<String, CommandStats>,
+    /// Error replies by their prefix, such as `ERR` or `WRONGTYPE`.
+    errors: HashMap<String, u64>,
+}
+
+#[derive(Debug)]
+struct OpsSampler {
+    last_sample: Instant,
+    last_commands: u64,
+    samples: [u64; OPS_SAMPLES],
+    next: usize,
+}
+
+/// Server-wide counters reported by INFO.
+#[derive(Debug)]
+pub struct Stats {
+    started_at: Instant,
+    run_id: String,
+    pub tcp_port: u16,
+    pub max_clients: usize,
+    pub connected_clients: AtomicUsize,
+    /// Clients waiting in WAIT.
+    pub blocked_clients: AtomicUsize,
+    pub total_connections_received: AtomicU64,
+    pub rejected_connections: AtomicU64,
+    pub total_commands_processed: AtomicU64,
+    pub total_net_input_bytes: AtomicU64,
+    pub total_net_output_bytes: AtomicU64,
+    pub total_error_replies: AtomicU64,
+    pub sync_full: AtomicU64,
+    pub sync_partial_ok: AtomicU64,
+    pub sync_partial_err: AtomicU64,
+    ops: Mutex<OpsSampler>,
+    shards: Vec<Mutex<StatShard>>,
+}
+
+impl Stats {
+    pub fn new(tcp_port: u16, max_clients: usize) -> Self {
+        Self {
+            started_at: Instant::now(),
+            run_id: new_replid(),
+            tcp_port,
+            max_clients,
+            connected_clients: AtomicUsize::new(0),
+            blocked_clients: AtomicUsize::new(0),
+            total_connections_received: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            total_commands_processed: AtomicU64::new(0),
+            total_net_input_bytes: AtomicU64::new(0),
+            total_net_output_bytes: AtomicU64::new(0),
+            total_error_replies: AtomicU64::new(0),
+            sync_full: AtomicU64::new(0),
+            sync_partial_ok: AtomicU64::new(0),
+            sync_partial_err: AtomicU64::new(0),
+            ops: Mutex::new(OpsSampler {
+                last_sample: Instant::now(),
+                last_commands: 0,
+                samples: [0; OPS_SAMPLES],
+                next: 0,
+            }),
+            shards: (0..STAT_SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    /// Records an executed command. `name` is None for unknown commands, which
+    /// only count towards the error statistics. A rejected command was refused
+    /// before it ran.
+    pub fn record_command(
+        &self,
+        client_id: u64,
+        name: Option<&str>,
+        elapsed: Duration,
+        reply: &[u8],
+        rejected: bool,
+    ) {
+        self.total_commands_processed.fetch_add(1, Ordering::Relaxed);
+        let error = reply.strip_prefix(b"-").map(|message| {
+            let prefix = message
+                .split(|byte| *byte == b' ' || *byte == b'\r')
+                .next()
+                .unwrap_or_default();
+            String::from_utf8_lossy(prefix).into_owned()
+        });
+        if error.is_some() {
+            self.total_error_replies.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut shard = self.shards[client_id as usize % STAT_SHARDS].lock().unwrap();
+        if let Some(name) = name {
+            let command = shard.commands.entry(name.to_ascii_lowercase()).or_default();
+            if rejected {
+                command.rejected_calls += 1;
+            } else {
+                command.calls += 1;
+                command.usec += elapsed.as_micros() as u64;
+                if error.is_some() {
+                    command.failed_calls += 1;
+                }
+            }
+        }
+        if let Some(error) = error {
+            *shard.errors.entry(error).or_default() += 1;
+        }
+    }
+
+    /// Takes a sample of the command rate; called periodically.
+    pub fn sample_ops(&self) {
+        let mut ops = self.ops.lock().unwrap();
+        let commands = self.total_commands_processed.load(Ordering::Relaxed);
+        let elapsed = ops.last_sample.elapsed().as_millis().max(1) as u64;
+        let index = ops.next;
+        ops.samples[index] = (commands - ops.last_commands) * 1000 / elapsed;
+        ops.next = (index + 1) % OPS_SAMPLES;
+        ops.last_sample = Instant::now();
+        ops.last_commands = commands;
+    }
+
+    fn instantaneous_ops(&self) -> u64 {
+        let ops = self.ops.lock().unwrap();
+        ops.samples.iter().sum::<u64>() / OPS_SAMPLES as u64
+    }
+
+    fn merged(&self) -> (HashMap<String, CommandStats>, HashMap<String, u64>) {
+        let mut commands: HashMap<String, CommandStats> = HashMap::new();
+        let mut errors: HashMap<String, u64> = HashMap::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            for (name, stats) in &shard.commands {
+                let merged = commands.entry(name.clone()).or_default();
+                merged.calls += stats.calls;
+                merged.usec += stats.usec;
+                merged.rejected_calls += stats.rejected_calls;
+                merged.failed_calls += stats.failed_calls;
+            }
+            for (error, count) in &shard.errors {
+                *errors.entry(error.clone()).or_default() += count;
+            }
+        }
+        (commands, errors)
+    }
+}
+
+/// INFO [section ...]: without arguments prints the default sections, `all`
+/// or `everything` every section, and `default` can be combined with others.
+pub fn handle_info_command(
+    request: &RespRequest,
+    storage: &Databases,
+    state: &Mutex<RedisReplicationState>,
+    config: &Config,
+    stats: &Stats,
+    persistence: &Persistence,
+) -> Vec<u8> {
+    let requested: Vec<String> = request
+        .arguments
+        .iter()
+        .map(|argument| argument.content.to_ascii_lowercase())
+        .collect();
+    let included = |section: &str, default: bool| {
+        (requested.is_empty() && default)
+            || requested.iter().any(|name| {
+                name == section
+                    || name == "all"
+                    || name == "everything"
+                    || (name == "default" && default)
+            })
+    };
+
+    let mut sections: Vec<String> = vec![];
+    for (section, title, default) in SECTIONS {
+        if !included(section, *default) {
+            continue;
+        }
+        let lines = match *section {
+            "server" => server_section(stats),
+            "clients" => clients_section(stats),
+            "memory" => memory_section(),
+            "persistence" => persistence_section(persistence),
+            "stats" => stats_section(stats),
+            "replication" => state.lock().unwrap().info(config.replica_read_only),
+            "cpu" => cpu_section(),
+            "commandstats" => commandstats_section(stats),
+            "errorstats" => errorstats_section(stats),
+            "keyspace" => keyspace_section(storage),
+            _ => vec![],
+        };
+        let mut text = format!("# {}\r\n", title);
+        for line in lines {
+            text += &line;
+            text += "\r\n";
+        }
+        sections.push(text);
+    }
+    to_bulk_bytes(sections.join("\r\n").as_bytes())
+}
+
+fn server_section(stats: &Stats) -> Vec<String> {
+    let uptime = stats.started_at.elapsed().as_secs();
+    vec![
+        "redis_version:7.2.0".to_string(),
+        "redis_mode:standalone".to_string(),
+        format!("os:{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        format!("arch_bits:{}", usize::BITS),
+        "multiplexing_api:tokio".to_string(),
+        format!("process_id:{}", std::process::id()),
+        format!("run_id:{}", stats.run_id),
+        format!("tcp_port:{}", stats.tcp_port),
+        format!(
+            "server_time_usec:{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_micros())
+                .unwrap_or_default()
+        ),
+        format!("uptime_in_seconds:{}", uptime),
+        format!("uptime_in_days:{}", uptime / 86400),
+        "hz:10".to_string(),
+        format!(
+            "executable:{}",
+            std::env::current_exe()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        ),
+    ]
+}
+
+fn clients_section(stats: &Stats) -> Vec<String> {
+    vec![
+        format!(
+            "connected_clients:{}",
+            stats.connected_clients.load(Ordering::Relaxed)
+        ),
+        format!("maxclients:{}", stats.max_clients),
+        format!(
+            "blocked_clients:{}",
+            stats.blocked_clients.load(Ordering::Relaxed)
+        ),
+    ]
+}
+
+fn human_bytes(bytes: usize) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.2}{}", value, units[unit])
+    }
+}
+
+/// Resident set size from /proc, where available.
+fn resident_memory() -> usize {
+    fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<usize>().ok())
+        .map(|pages| pages * 4096)
+        .unwrap_or(0)
+}
+
+fn memory_section() -> Vec<String> {
+    let used = ALLOCATED.load(Ordering::Relaxed);
+    let peak = PEAK_ALLOCATED.load(Ordering::Relaxed);
+    let rss = resident_memory();
+    vec![
+        format!("used_memory:{}", used),
+        format!("used_memory_human:{}", human_bytes(used)),
+        format!("used_memory_rss:{}", rss),
+        format!("used_memory_rss_human:{}", human_bytes(rss)),
+        format!("used_memory_peak:{}", peak),
+        format!("used_memory_peak_human:{}", human_bytes(peak)),
+        "maxmemory:0".to_string(),
+        "maxmemory_human:0B".to_string(),
+        "maxmemory_policy:noeviction".to_string(),
+        format!(
+            "mem_fragmentation_ratio:{:.2}",
+            rss as f64 / used.max(1) as f64
+        ),
+        "mem_allocator:libc".to_string(),
+    ]
+}
+
+fn persistence_section(persistence: &Persistence) -> Vec<String> {
+    let mut lines = vec!["loading:0".to_string(), "async_loading:0".to_string()];
+    lines.extend(persistence.info());
+    lines
+}
+
+fn stats_section(stats: &Stats) -> Vec<String> {
+    let counter = |name: &str, value: &AtomicU64| format!("{}:{}", name, value.load(Ordering::Relaxed));
+    vec![
+        counter(
+            "total_connections_received",
+            &stats.total_connections_received,
+        ),
+        counter("total_commands_processed", &stats.total_commands_processed),
+        format!("instantaneous_ops_per_sec:{}", stats.instantaneous_ops()),
+        counter("total_net_input_bytes", &stats.total_net_input_bytes),
+        counter("total_net_output_bytes", &stats.total_net_output_bytes),
+        counter("rejected_connections", &stats.rejected_connections),
+        counter("sync_full", &stats.sync_full),
+        counter("sync_partial_ok", &stats.sync_partial_ok),
+        counter("sync_partial_err", &stats.sync_partial_err),
+        counter("total_error_replies", &stats.total_error_replies),
+    ]
+}
+
+/// CPU time of the process from /proc, in seconds.
+fn cpu_section() -> Vec<String> {
+    // Fields 14 and 15 of /proc/self/stat, after the parenthesised command name,
+    // are user and system time in clock ticks of 1/100 s.
+    let times: Vec<f64> = fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| {
+            let fields = stat.rsplit_once(')')?.1;
+            Some(
+                fields
+                    .split_whitespace()
+                    .skip(11)
+                    .take(2)
+                    .filter_map(|ticks| ticks.parse::<f64>().ok())
+                    .map(|ticks| ticks / 100.0)
+                    .collect(),
+            )
+        })
+        .unwrap_or_default();
+    let (user, system) = match times[..] {
+        [user, system] => (user, system),
+        _ => (0.0, 0.0),
+    };
+    vec![
+        format!("used_cpu_sys:{:.6}", system),
+        format!("used_cpu_user:{:.6}", user),
+        "used_cpu_sys_children:0.000000".to_string(),
+        "used_cpu_user_children:0.000000".to_string(),
+    ]
+}
+
+fn commandstats_section(stats: &Stats) -> Vec<String> {
+    let (commands, _) = stats.merged();
+    let mut commands: Vec<(String, CommandStats)> = commands.into_iter().collect();
+    commands.sort_by(|a, b| a.0.cmp(&b.0));
+    commands
+        .into_iter()
+        .map(|(name, command)| {
+            format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                name,
+                command.calls,
+                command.usec,
+                command.usec as f64 / command.calls.max(1) as f64,
+                command.rejected_calls,
+                command.failed_calls
+            )
+        })
+        .collect()
+}
+
+fn errorstats_section(stats: &Stats) -> Vec<String> {
+    let (_, errors) = stats.merged();
+    let mut errors: Vec<(String, u64)> = errors.into_iter().collect();
+    errors.sort();
+    errors
+        .into_iter()
+        .map(|(error, count)| format!("errorstat_{}:count={}", error, count))
+        .collect()
+}
+
+fn keyspace_section(storage: &Databases) -> Vec<String> {
+    storage
+        .iter()
+        .enumerate()
+        .filter_map(|(index, keyspace)| {
+            let (keys, expires, ttl_sum) = keyspace.lock_all(Access::Read).expiry_stats();
+            (keys > 0).then(|| {
+                let avg_ttl = if expires > 0 { ttl_sum / expires as i64 } else { 0 };
+                format!(
+                    "db{}:keys={},expires={},avg_ttl={}",
+                    index, keys, expires, avg_ttl
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use crate::storage::RedisValue;
+
+    fn info(storage: &Databases, stats: &Stats, parts: &[&str]) -> String {
+        let reply = handle_info_command(
+            &request(parts),
+            storage,
+            &Mutex::new(RedisReplicationState::new()),
+            &Config::default(),
+            stats,
+            &Persistence::new(),
+        );
+        String::from_utf8(reply).unwrap()
+    }
+
+    /// The titles of the sections of an INFO reply, in order.
+    fn titles(reply: &str) -> Vec<&str> {
+        reply
+            .lines()
+            .filter_map(|line| line.strip_prefix("# "))
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_requested_sections() {
+        let (storage, stats) = (Databases::new(1), Stats::new(6380, 10));
+        let default = [
+            "Server",
+            "Clients",
+            "Memory",
+            "Persistence",
+            "Stats",
+            "Replication",
+            "CPU",
+            "Errorstats",
+            "Keyspace",
+        ];
+        assert_eq!(titles(&info(&storage, &stats, &["INFO"])), default);
+        assert_eq!(
+            titles(&info(&storage, &stats, &["INFO", "default"])),
+            default
+        );
+        assert_eq!(
+            titles(&info(&storage, &stats, &["INFO", "all"])).len(),
+            SECTIONS.len()
+        );
+        assert_eq!(
+            titles(&info(&storage, &stats, &["INFO", "KEYSPACE", "server"])),
+            ["Server", "Keyspace"]
+        );
+        assert!(info(&storage, &stats, &["INFO", "server"]).contains("tcp_port:6380\r\n"));
+        assert_eq!(info(&storage, &stats, &["INFO", "nosuch"]), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn counts_commands_and_errors() {
+        let stats = Stats::new(6379, 10);
+        let elapsed = Duration::from_micros(10);
+        stats.record_command(1, Some("GET"), elapsed, b"$1\r\nv\r\n", false);
+        stats.record_command(2, Some("get"), elapsed, b"-WRONGTYPE Operation\r\n", false);
+        stats.record_command(
+            3,
+            Some("SET"),
+            elapsed,
+            b"-READONLY You can't write\r\n",
+            true,
+        );
+        stats.record_command(4, None, elapsed, b"-ERR unknown command\r\n", false);
+
+        assert_eq!(stats.total_commands_processed.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.total_error_replies.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            commandstats_section(&stats),
+            [
+                "cmdstat_get:calls=2,usec=20,usec_per_call=10.00,rejected_calls=0,failed_calls=1",
+                "cmdstat_set:calls=0,usec=0,usec_per_call=0.00,rejected_calls=1,failed_calls=0",
+            ]
+        );
+        assert_eq!(
+            errorstats_section(&stats),
+            [
+                "errorstat_ERR:count=1",
+                "errorstat_READONLY:count=1",
+                "errorstat_WRONGTYPE:count=1",
+            ]
+        );
+    }
+
+    #[test]
+    fn keyspace_lists_databases_with_keys() {
+        let storage = Databases::new(3);
+        let mut keyspace = storage.get(1).lock_all(Access::Write);
+        for key in ["a", "b"] {
+            keyspace.update(key.to_string(), RedisValue::String(b"v".to_vec()), i64::MAX);
+        }
+        let deadline = chrono::Utc::now().timestamp_millis() + 10_000;
+        keyspace.set_deadline(&"a".to_string(), Some(deadline));
+        drop(keyspace);
+
+        let lines = keyspace_section(&storage);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("db1:keys=2,expires=1,avg_ttl="));
+        let avg_ttl: i64 = lines[0].rsplit('=').next().unwrap().parse().unwrap();
+        assert!((9_000..=10_000).contains(&avg_ttl));
+    }
+
+    #[test]
+    fn formats_memory_sizes() {
+        assert_eq!(human_bytes(0), "0B");
+        assert_eq!(human_bytes(1023), "1023B");
+        assert_eq!(human_bytes(1024), "1.00K");
+        assert_eq!(human_bytes(1536 * 1024), "1.50M");
+        assert_eq!(human_bytes(3 << 30), "3.00G");
+    }
+}
@@ -0,0 +1,224 @@
+use crate::resp_parser::{to_error, to_integer, wrong_arguments, Command, Content, RespRequest};
+use crate::storage::Keyspace;
+use chrono::Utc;
+
+pub fn handle_keys_command(request: &RespRequest, storage: &mut Keyspace) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Del => del(storage, arguments),
+        Command::Exists => exists(storage, arguments),
+        Command::Expire => expire(storage, arguments, "expire", 1000, false),
+        Command::Pexpire => expire(storage, arguments, "pexpire", 1, false),
+        Command::Expireat => expire(storage, arguments, "expireat", 1000, true),
+        Command::Pexpireat => expire(storage, arguments, "pexpireat", 1, true),
+        Command::Ttl => ttl(storage, arguments, "ttl", |remaining| {
+            (remaining + 500) / 1000
+        }),
+        Command::Pttl => ttl(storage, arguments, "pttl", |remaining| remaining),
+        Command::Persist => persist(storage, arguments),
+        _ => to_error("ERR unknown keyspace command"),
+    }
+}
+
+fn del(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("del");
+    }
+    let removed = arguments
+        .iter()
+        .filter(|key| storage.remove(&key.content).is_some())
+        .count();
+    to_integer(removed as i64)
+}
+
+fn exists(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.is_empty() {
+        return wrong_arguments("exists");
+    }
+    let found = arguments
+        .iter()
+        .filter(|key| storage.contains_key(&key.content))
+        .count();
+    to_integer(found as i64)
+}
+
+fn expire(
+    storage: &mut Keyspace,
+    arguments: &[Content],
+    command: &str,
+    unit_ms: i64,
+    absolute: bool,
+) -> Vec<u8> {
+    if arguments.len() < 2 || arguments.len() > 3 {
+        return wrong_arguments(command);
+    }
+    let Ok(value) = arguments[1].content.parse::<i64>() else {
+        return to_error("ERR value is not an integer or out of range");
+    };
+    let now = Utc::now().timestamp_millis();
+    let Some(deadline) = value.checked_mul(unit_ms).and_then(|value| {
+        if absolute {
+            Some(value)
+        } else {
+            value.checked_add(now)
+        }
+    }) else {
+        return to_error(&format!("ERR invalid expire time in '{}' command", command));
+    };
+
+    let key = &arguments[0].content;
+    if !storage.contains_key(key) {
+        return to_integer(0);
+    }
+    let current = storage.deadline(key);
+    let allowed = match arguments
+        .get(2)
+        .map(|option| option.content.to_ascii_uppercase())
+    {
+        None => true,
+        Some(option) => match option.as_str() {
+            "NX" => current.is_none(),
+            "XX" => current.is_some(),
+            "GT" => current.is_some_and(|current| deadline > current),
+            // A key without a deadline has an infinite TTL, so any deadline is lower.
+            "LT" => match current {
+                Some(current) => deadline < current,
+                None => true,
+            },
+            _ => return to_error(&format!("ERR Unsupported option {}", arguments[2].content)),
+        },
+    };
+    if !allowed {
+        return to_integer(0);
+    }
+
+    if deadline <= now {
+        storage.remove(key);
+    } else {
+        storage.set_deadline(key, Some(deadline));
+    }
+    to_integer(1)
+}
+
+fn ttl(
+    storage: &mut Keyspace,
+    arguments: &[Content],
+    command: &str,
+    reply: fn(i64) -> i64,
+) -> Vec<u8> {
+    if arguments.len() != 1 {
+        return wrong_arguments(command);
+    }
+    let key = &arguments[0].content;
+    if !storage.contains_key(key) {
+        return to_integer(-2);
+    }
+    match storage.deadline(key) {
+        Some(deadline) => {
+            let remaining = (deadline - Utc::now().timestamp_millis()).max(0);
+            to_integer(reply(remaining))
+        }
+        None => to_integer(-1),
+    }
+}
+
+fn persist(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 1 {
+        return wrong_arguments("persist");
+    }
+    let key = &arguments[0].content;
+    if storage.deadline(key).is_none() {
+        return to_integer(0);
+    }
+    storage.set_deadline(key, None);
+    to_integer(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, RedisValue, ShardedKeyspace};
+
+    fn run(storage: &mut Keyspace, parts: &[&str]) -> Vec<u8> {
+        handle_keys_command(&request(parts), storage)
+    }
+
+    fn set(storage: &mut Keyspace, key: &str) {
+        storage.update(key.to_string(), RedisValue::String(b"v".to_vec()), i64::MAX);
+    }
+
+    #[test]
+    fn del_and_exists_count_keys() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        set(&mut storage, "a");
+        set(&mut storage, "b");
+        assert_eq!(
+            run(&mut storage, &["EXISTS", "a", "a", "missing"]),
+            b":2\r\n"
+        );
+        assert_eq!(run(&mut storage, &["DEL", "a", "missing", "a"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["EXISTS", "a", "b"]), b":1\r\n");
+    }
+
+    #[test]
+    fn expire_ttl_and_persist() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        set(&mut storage, "k");
+        assert_eq!(run(&mut storage, &["TTL", "k"]), b":-1\r\n");
+        assert_eq!(run(&mut storage, &["TTL", "missing"]), b":-2\r\n");
+        assert_eq!(run(&mut storage, &["EXPIRE", "missing", "10"]), b":0\r\n");
+
+        assert_eq!(run(&mut storage, &["EXPIRE", "k", "100"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["TTL", "k"]), b":100\r\n");
+        let pttl = run(&mut storage, &["PTTL", "k"]);
+        let pttl: i64 = std::str::from_utf8(&pttl[1..pttl.len() - 2])
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((99_000..=100_000).contains(&pttl));
+
+        assert_eq!(run(&mut storage, &["PERSIST", "k"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["PERSIST", "k"]), b":0\r\n");
+        assert_eq!(run(&mut storage, &["TTL", "k"]), b":-1\r\n");
+    }
+
+    #[test]
+    fn expire_options_compare_with_the_current_deadline() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        set(&mut storage, "k");
+        assert_eq!(run(&mut storage, &["EXPIRE", "k", "100", "XX"]), b":0\r\n");
+        assert_eq!(run(&mut storage, &["EXPIRE", "k", "100", "GT"]), b":0\r\n");
+        assert_eq!(run(&mut storage, &["EXPIRE", "k", "100", "LT"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["EXPIRE", "k", "50", "NX"]), b":0\r\n");
+        assert_eq!(run(&mut storage, &["EXPIRE", "k", "200", "LT"]), b":0\r\n");
+        assert_eq!(run(&mut storage, &["EXPIRE", "k", "200", "GT"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["TTL", "k"]), b":200\r\n");
+        assert_eq!(
+            run(&mut storage, &["EXPIRE", "k", "200", "SOON"]),
+            b"-ERR Unsupported option SOON\r\n"
+        );
+    }
+
+    #[test]
+    fn deadlines_in_the_past_delete_the_key() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        set(&mut storage, "a");
+        set(&mut storage, "b");
+        assert_eq!(run(&mut storage, &["PEXPIRE", "a", "-1"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["EXPIREAT", "b", "1"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["EXISTS", "a", "b"]), b":0\r\n");
+        assert_eq!(
+            run(&mut storage, &["EXPIRE", "a", "9223372036854775807"]),
+            b"-ERR invalid expire time in 'expire' command\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["EXPIRE", "a", "soon"]),
+            b"-ERR value is not an integer or out of range\r\n"
+        );
+    }
+}
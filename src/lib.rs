@@ -0,0 +1,21 @@
+pub mod aof;
+pub mod bitmap;
+pub mod config;
+pub mod database;
+pub mod dump;
+pub mod geo;
+pub mod glob;
+pub mod hash;
+pub mod hyperloglog;
+pub mod info;
+pub mod keys;
+pub mod listpack;
+pub mod persistence;
+pub mod pubsub;
+pub mod rdb;
+pub mod replication;
+pub mod resp_parser;
+pub mod sorted_set;
+pub mod storage;
+pub mod stream;
+pub mod watch;
@@ -0,0 +1,407 @@
+//! The compact serialized containers Redis embeds in RDB files as plain strings:
+//! listpacks (Redis 7), ziplists and zipmaps (older versions) and intsets.
+//! Integers stored natively are returned in their decimal string form.
+
+use crate::rdb::RdbError;
+
+const LISTPACK_HEADER_SIZE: usize = 6;
+const LISTPACK_END: u8 = 0xFF;
+const ZIPLIST_HEADER_SIZE: usize = 10;
+const ZIPLIST_END: u8 = 0xFF;
+const ZIPLIST_BIG_PREVLEN: u8 = 0xFE;
+const ZIPMAP_BIGLEN: u8 = 254;
+const ZIPMAP_END: u8 = 0xFF;
+
+/// The elements of a listpack, in order.
+pub fn listpack_entries(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let corrupt = || RdbError::Corrupt("listpack");
+    if data.len() < LISTPACK_HEADER_SIZE + 1 {
+        return Err(corrupt());
+    }
+    let total = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+    if total != data.len() {
+        return Err(corrupt());
+    }
+    let mut cursor = Cursor::new(data, LISTPACK_HEADER_SIZE, "listpack");
+    let mut entries = Vec::new();
+    loop {
+        let start = cursor.position;
+        let first = cursor.byte()?;
+        let entry = match first {
+            LISTPACK_END => break,
+            0x00..=0x7F => (first as i64).to_string().into_bytes(),
+            0x80..=0xBF => cursor.take((first & 0x3F) as usize)?.to_vec(),
+            0xC0..=0xDF => {
+                let value = ((first as i64 & 0x1F) << 8) | cursor.byte()? as i64;
+                sign_extend(value, 13).to_string().into_bytes()
+            }
+            0xE0..=0xEF => {
+                let length = ((first as usize & 0x0F) << 8) | cursor.byte()? as usize;
+                cursor.take(length)?.to_vec()
+            }
+            0xF0 => {
+                let length = cursor.u32()? as usize;
+                cursor.take(length)?.to_vec()
+            }
+            0xF1 => cursor.integer(2)?.to_string().into_bytes(),
+            0xF2 => cursor.integer(3)?.to_string().into_bytes(),
+            0xF3 => cursor.integer(4)?.to_string().into_bytes(),
+            0xF4 => cursor.integer(8)?.to_string().into_bytes(),
+            _ => return Err(corrupt()),
+        };
+        let backlen = backlen(cursor.position - start);
+        if cursor.take(backlen.len())? != backlen.as_slice() {
+            return Err(corrupt());
+        }
+        entries.push(entry);
+    }
+    if cursor.position != data.len() {
+        return Err(corrupt());
+    }
+    Ok(entries)
+}
+
+/// Serializes `entries` as a listpack, storing canonical integers natively
+/// like Redis does.
+pub fn encode_listpack(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0; LISTPACK_HEADER_SIZE];
+    for entry in entries {
+        let start = out.len();
+        match canonical_integer(entry) {
+            Some(value @ 0..=127) => out.push(value as u8),
+            Some(value @ -4096..=4095) => {
+                let value = value as u16 & 0x1FFF;
+                out.push(0xC0 | (value >> 8) as u8);
+                out.push(value as u8);
+            }
+            Some(value) => {
+                let (tag, width) = if i16::try_from(value).is_ok() {
+                    (0xF1, 2)
+                } else if (-(1 << 23)..(1 << 23)).contains(&value) {
+                    (0xF2, 3)
+                } else if i32::try_from(value).is_ok() {
+                    (0xF3, 4)
+                } else {
+                    (0xF4, 8)
+                };
+                out.push(tag);
+                out.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+            None if entry.len() < 64 => {
+                out.push(0x80 | entry.len() as u8);
+                out.extend_from_slice(entry);
+            }
+            None if entry.len() < 4096 => {
+                out.push(0xE0 | (entry.len() >> 8) as u8);
+                out.push(entry.len() as u8);
+                out.extend_from_slice(entry);
+            }
+            None => {
+                out.push(0xF0);
+                out.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+                out.extend_from_slice(entry);
+            }
+        }
+        let backlen = backlen(out.len() - start);
+        out.extend_from_slice(&backlen);
+    }
+    out.push(LISTPACK_END);
+    let total = out.len() as u32;
+    out[..4].copy_from_slice(&total.to_le_bytes());
+    // Counts past u16::MAX are stored as unknown, to be found by walking the entries.
+    let count = u16::try_from(entries.len()).unwrap_or(u16::MAX);
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+/// The elements of a ziplist, the listpack predecessor used until Redis 7.
+pub fn ziplist_entries(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let corrupt = || RdbError::Corrupt("ziplist");
+    if data.len() < ZIPLIST_HEADER_SIZE + 1 {
+        return Err(corrupt());
+    }
+    let total = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+    if total != data.len() {
+        return Err(corrupt());
+    }
+    let mut cursor = Cursor::new(data, ZIPLIST_HEADER_SIZE, "ziplist");
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = cursor.byte()?;
+        if prevlen == ZIPLIST_END {
+            break;
+        }
+        if prevlen == ZIPLIST_BIG_PREVLEN {
+            cursor.take(4)?;
+        }
+        let encoding = cursor.byte()?;
+        let entry = match encoding >> 6 {
+            0 => cursor.take((encoding & 0x3F) as usize)?.to_vec(),
+            1 => {
+                let length = ((encoding as usize & 0x3F) << 8) | cursor.byte()? as usize;
+                cursor.take(length)?.to_vec()
+            }
+            2 => {
+                let length = u32::from_be_bytes(cursor.take(4)?.try_into().unwrap());
+                cursor.take(length as usize)?.to_vec()
+            }
+            _ => {
+                let value = match encoding {
+                    0xC0 => cursor.integer(2)?,
+                    0xD0 => cursor.integer(4)?,
+                    0xE0 => cursor.integer(8)?,
+                    0xF0 => cursor.integer(3)?,
+                    0xFE => cursor.integer(1)?,
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => return Err(corrupt()),
+                };
+                value.to_string().into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+    if cursor.position != data.len() {
+        return Err(corrupt());
+    }
+    Ok(entries)
+}
+
+/// The members of an intset, a sorted array of fixed width integers.
+pub fn intset_entries(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let corrupt = || RdbError::Corrupt("intset");
+    let mut cursor = Cursor::new(data, 0, "intset");
+    let width = cursor.u32()? as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(corrupt());
+    }
+    let length = cursor.u32()? as usize;
+    if data.len() != 8 + width * length {
+        return Err(corrupt());
+    }
+    (0..length)
+        .map(|_| Ok(cursor.integer(width)?.to_string().into_bytes()))
+        .collect()
+}
+
+/// The fields and values of a zipmap, the hash encoding of Redis 2, as one
+/// alternating sequence.
+pub fn zipmap_entries(data: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let corrupt = || RdbError::Corrupt("zipmap");
+    // The first byte is a pair count, only meaningful below 254.
+    let mut cursor = Cursor::new(data, 1, "zipmap");
+    let mut entries = Vec::new();
+    loop {
+        let length = match cursor.byte()? {
+            ZIPMAP_END => break,
+            ZIPMAP_BIGLEN => cursor.u32()? as usize,
+            length => length as usize,
+        };
+        let field = cursor.take(length)?.to_vec();
+        let length = match cursor.byte()? {
+            ZIPMAP_BIGLEN => cursor.u32()? as usize,
+            ZIPMAP_END => return Err(corrupt()),
+            length => length as usize,
+        };
+        // Values are followed by padding left over from in-place updates.
+        let free = cursor.byte()? as usize;
+        let value = cursor.take(length)?.to_vec();
+        cursor.take(free)?;
+        entries.push(field);
+        entries.push(value);
+    }
+    if cursor.position != data.len() {
+        return Err(corrupt());
+    }
+    Ok(entries)
+}
+
+/// The length of a listpack entry as stored after it, so the listpack can
+/// also be walked backwards: 7 bits per byte, most significant first.
+/// Every byte but the first has its high bit set.
+fn backlen(length: usize) -> Vec<u8> {
+    let mut bytes = vec![(length & 0x7F) as u8];
+    let mut rest = length >> 7;
+    while rest > 0 {
+        bytes[0] |= 0x80;
+        bytes.insert(0, (rest & 0x7F) as u8);
+        rest >>= 7;
+    }
+    bytes
+}
+
+fn sign_extend(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+/// The integer `value` spells, if it reads back exactly the same.
+fn canonical_integer(value: &[u8]) -> Option<i64> {
+    if value.is_empty() || value.len() > 20 {
+        return None;
+    }
+    let integer = std::str::from_utf8(value).ok()?.parse::<i64>().ok()?;
+    (integer.to_string().as_bytes() == value).then_some(integer)
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+    container: &'static str,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8], position: usize, container: &'static str) -> Self {
+        Self {
+            data,
+            position,
+            container,
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], RdbError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(RdbError::Corrupt(self.container))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, RdbError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A little endian signed integer `width` bytes wide.
+    fn integer(&mut self, width: usize) -> Result<i64, RdbError> {
+        let mut bytes = [0; 8];
+        bytes[..width].copy_from_slice(self.take(width)?);
+        Ok(sign_extend(i64::from_le_bytes(bytes), width as u32 * 8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(values: &[&str]) -> Vec<Vec<u8>> {
+        values
+            .iter()
+            .map(|value| value.as_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn listpack_round_trips_every_encoding() {
+        let long = "x".repeat(5000);
+        let values = entries(&[
+            "0",
+            "127",
+            "128",
+            "-1",
+            "-4096",
+            "4095",
+            "4096",
+            "-32768",
+            "32767",
+            "32768",
+            "8388607",
+            "8388608",
+            "-2147483648",
+            "2147483648",
+            "9223372036854775807",
+            "-9223372036854775808",
+            "01",
+            "-0",
+            "+5",
+            "",
+            "short",
+            &"y".repeat(63),
+            &"y".repeat(64),
+            &"z".repeat(4095),
+            &"z".repeat(4096),
+            &long,
+        ]);
+        let encoded = encode_listpack(&values);
+        assert_eq!(listpack_entries(&encoded).unwrap(), values);
+        assert_eq!(
+            u32::from_le_bytes(encoded[..4].try_into().unwrap()) as usize,
+            encoded.len()
+        );
+        assert_eq!(
+            u16::from_le_bytes([encoded[4], encoded[5]]),
+            values.len() as u16
+        );
+    }
+
+    #[test]
+    fn listpack_stores_integers_natively() {
+        // As Redis writes ["hello", "7", "-1"]: a 6-bit string, a 7-bit uint
+        // and a 13-bit int, each followed by its length.
+        let redis = b"\x13\x00\x00\x00\x03\x00\x85hello\x06\x07\x01\xdf\xff\x02\xff";
+        assert_eq!(encode_listpack(&entries(&["hello", "7", "-1"])), redis);
+        assert_eq!(
+            listpack_entries(redis).unwrap(),
+            entries(&["hello", "7", "-1"])
+        );
+    }
+
+    #[test]
+    fn listpack_rejects_corruption() {
+        let mut encoded = encode_listpack(&entries(&["hello", "world"]));
+        assert!(listpack_entries(&encoded[..encoded.len() - 1]).is_err());
+        // A wrong backlen after the first entry.
+        encoded[12] ^= 0x01;
+        assert!(listpack_entries(&encoded).is_err());
+        assert!(listpack_entries(b"\x07\x00\x00\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn backlen_uses_seven_bits_per_byte() {
+        assert_eq!(backlen(5), vec![5]);
+        assert_eq!(backlen(127), vec![127]);
+        assert_eq!(backlen(128), vec![1, 0x80]);
+        assert_eq!(backlen(5000), vec![39, 0x80 | 8]);
+    }
+
+    #[test]
+    fn reads_ziplists() {
+        // ["ab", 12, -2, 300]: a string, a 4-bit immediate, a 1-byte and a
+        // 2-byte integer.
+        let mut data = vec![0; ZIPLIST_HEADER_SIZE];
+        data.extend_from_slice(&[0x00, 0x02, b'a', b'b']);
+        data.extend_from_slice(&[0x04, 0xFD]);
+        data.extend_from_slice(&[0x02, 0xFE, 0xFE]);
+        data.extend_from_slice(&[0x03, 0xC0, 0x2C, 0x01]);
+        data.push(ZIPLIST_END);
+        let total = data.len() as u32;
+        data[..4].copy_from_slice(&total.to_le_bytes());
+        assert_eq!(
+            ziplist_entries(&data).unwrap(),
+            entries(&["ab", "12", "-2", "300"])
+        );
+        data[0] += 1;
+        assert!(ziplist_entries(&data).is_err());
+    }
+
+    #[test]
+    fn reads_intsets_and_zipmaps() {
+        let intset = b"\x04\x00\x00\x00\x02\x00\x00\x00\xff\xff\xff\xff\x00\x00\x01\x00";
+        assert_eq!(intset_entries(intset).unwrap(), entries(&["-1", "65536"]));
+        assert!(intset_entries(&intset[..15]).is_err());
+        assert!(intset_entries(b"\x03\x00\x00\x00\x00\x00\x00\x00").is_err());
+
+        // {"f": "v", "name": "redis"}, the second value with a byte of padding.
+        let zipmap = b"\x02\x01f\x01\x00v\x04name\x05\x01redis!\xff";
+        assert_eq!(
+            zipmap_entries(zipmap).unwrap(),
+            entries(&["f", "v", "name", "redis"])
+        );
+        assert!(zipmap_entries(&zipmap[..zipmap.len() - 1]).is_err());
+    }
+}
@@ -1,107 +1,482 @@
-mod resp_parser;
-mod storage;
-use crate::resp_parser::{Command, ContentType, RespRequest};
-use crate::storage::TimeKeyValueStorage;
+use redis_starter_rust::{
+    aof, bitmap, config, database, dump, geo, hash, hyperloglog, info, keys, persistence, pubsub,
+    rdb, resp_parser, sorted_set, watch,
+};
+use redis_starter_rust::resp_parser::{Command, ContentType, RespRequest};
+use redis_starter_rust::config::Config;
+use redis_starter_rust::database::Databases;
+use redis_starter_rust::info::{CountingAllocator, Stats};
+use redis_starter_rust::persistence::Persistence;
+use redis_starter_rust::pubsub::{PubSub, Subscriptions};
+use redis_starter_rust::replication::{
+    connect_to_master, replicated_commands, LinkState, MasterLink, RedisReplicationState,
+    Resync, Role, REPL_ACK_PERIOD, REPL_PING_PERIOD, REPL_TIMEOUT,
+};
+use redis_starter_rust::rdb::Snapshot;
+use redis_starter_rust::storage::{Access, Keyspace, RedisValue};
+use redis_starter_rust::watch::WatchedKey;
 
 use std::vec;
 use std::{
     cmp::Ordering,
     env::args,
-    fmt, i64,
-    io::{Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
-    thread,
+    signal::{self, unix::SignalKind},
+    sync::{broadcast, mpsc, Semaphore},
+    time,
 };
-use base64::prelude::*;
-use resp_parser::{string_to_simple_resp, to_bulk_string};
+use resp_parser::{
+    string_to_simple_resp, to_bulk_bytes, to_bulk_string, to_command_array, to_error, to_integer,
+    wrong_arguments,
+};
+
 
+/// Bounds of the delay between attempts to reach an unavailable master.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(250);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
 
-const EMPTY_RDB_BASE64 :&str= 
-"UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
+/// Source of unique connection ids.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
-#[derive(Debug, Clone)]
-enum Role {
-    Master,
-    Slave,
+/// Per-connection state.
+struct Client {
+    id: u64,
+    /// Address the connection comes from, and the port it announced with
+    /// REPLCONF listening-port if it is a replica.
+    peer_ip: String,
+    listening_port: u16,
+    selected_db: usize,
+    /// Whether this is the replica's link to its master, which may always write.
+    master_link: bool,
+    /// Set once the connection has turned into a replica with PSYNC.
+    replication_feed: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+    /// Snapshot to send once the FULLRESYNC reply has been written.
+    pending_snapshot: Option<Snapshot>,
+    /// Replication offset right after this client's latest write, which WAIT
+    /// expects the replicas to acknowledge.
+    write_offset: usize,
+    /// Commands queued since MULTI, until EXEC or DISCARD.
+    transaction: Option<Transaction>,
+    /// Keys whose modification makes the next EXEC fail.
+    watched: Vec<WatchedKey>,
+    /// Channels and patterns the connection receives messages from, and the
+    /// protocol they are sent in.
+    subscriptions: Subscriptions,
 }
 
-impl fmt::Display for Role {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Role::Master => write!(f, "master"),
-            Role::Slave => write!(f, "slave"),
-        }
+#[derive(Default)]
+struct Transaction {
+    commands: Vec<RespRequest>,
+    /// Set when a command was rejected while queueing, so EXEC must abort.
+    failed: bool,
+}
+
+/// Forwards an executed write to the AOF and the replicas. Called while the
+/// command's shard locks are still held, so both see writes to a key in the
+/// order they ran.
+fn replicate(
+    state: &Mutex<RedisReplicationState>,
+    persistence: &Persistence,
+    db: usize,
+    request: &RespRequest,
+    reply: &[u8],
+    keyspace: Option<&Keyspace>,
+) {
+    propagate(state, persistence, db, replicated_commands(request, reply, keyspace));
+}
+
+fn propagate(
+    state: &Mutex<RedisReplicationState>,
+    persistence: &Persistence,
+    db: usize,
+    commands: Vec<Vec<Vec<u8>>>,
+) {
+    if !commands.is_empty() {
+        persistence.append(db, &commands);
+        state.lock().unwrap().feed_replicas(db, commands);
+    }
+}
+
+/// Lazily drops the expired fields of the hash a write addresses, so the
+/// command never sees them. Only masters delete fields; the removals reach the
+/// AOF and the replicas as HDEL. Reads skip expired fields without removing
+/// them, so they neither need the write lock nor modify watched keys.
+fn expire_hash_fields(
+    state: &Mutex<RedisReplicationState>,
+    persistence: &Persistence,
+    db: usize,
+    request: &RespRequest,
+    keyspace: &mut Keyspace,
+) {
+    let Some(key) = request.arguments.first() else {
+        return;
+    };
+    if !request.is_write() || matches!(state.lock().unwrap().role, Role::Slave) {
+        return;
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    let fields = keyspace.expire_fields_of(&key.content, now);
+    if !fields.is_empty() {
+        propagate(state, persistence, db, vec![hdel_command(key.content.clone(), fields)]);
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-struct RedisReplicationState {
-    role: Role,
-    connected_slaves: usize,
-    master_replid: String,
-    master_repl_offset: usize,
-    second_repl_offset: i32,
-    repl_acklog_active: usize,
-    repl_backlog_size: i32,
-    repl_backlog_first_byte_offset: usize,
-    repl_backlog_histlen: i32,
+/// Builds the HDEL that removes `fields` from the hash at `key`.
+fn hdel_command(key: String, fields: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut command = vec![b"HDEL".to_vec(), key.into_bytes()];
+    command.extend(fields);
+    command
+}
+
+/// Locks the shards of the selected database that hold the request's keys.
+fn lock_keyspace<'a>(
+    storage: &'a Databases,
+    selected_db: usize,
+    request: &RespRequest,
+) -> Keyspace<'a> {
+    let access = if request.is_read_only() {
+        Access::Read
+    } else {
+        Access::Write
+    };
+    storage.get(selected_db).lock(&request.keys(), access)
 }
 
-impl RedisReplicationState {
-    fn new() -> Self {
-        Self {
-            role: Role::Master,
-            connected_slaves: 0,
-            master_replid: String::from("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb"),
-            master_repl_offset: 0,
-            second_repl_offset: 0,
-            repl_acklog_active: 0,
-            repl_backlog_size: 0,
-            repl_backlog_first_byte_offset: 0,
-            repl_backlog_histlen: 0,
-        }
-    }
-    fn to_string_vec(self) -> Vec<String> {
-        let mut string_vec = vec![];
-
-        string_vec.push("#Replication".to_string());
-        string_vec.push(format!("role:{:#?}", self.role));
-        string_vec.push(format!("connected_slaves:{}", self.connected_slaves));
-        string_vec.push(format!("master_replid:{}", self.master_replid));
-        string_vec.push(format!("second_repl_offset:{}", self.second_repl_offset));
-        string_vec.push(format!("repl_backlog_size:{}", self.repl_backlog_size));
-        string_vec.push(format!(
-            "repl_backlog_first_byte_offset:{}",
-            self.repl_backlog_first_byte_offset,
+/// REPLICAOF host port starts following a new master; REPLICAOF NO ONE turns
+/// a replica back into a master that keeps its dataset.
+fn replicaof(
+    request: &RespRequest,
+    storage: &Arc<Databases>,
+    state: &Arc<Mutex<RedisReplicationState>>,
+    config: &Arc<RwLock<Config>>,
+    stats: &Arc<Stats>,
+    persistence: &Arc<Persistence>,
+    pubsub: &Arc<PubSub>,
+) -> Vec<u8> {
+    if request.arguments.len() != 2 {
+        return wrong_arguments("replicaof");
+    }
+    let (host, port) = (&request.arguments[0].content, &request.arguments[1].content);
+    let mut state_locked = state.lock().unwrap();
+    if host.eq_ignore_ascii_case("NO") && port.eq_ignore_ascii_case("ONE") {
+        if matches!(state_locked.role, Role::Slave) {
+            state_locked.promote();
+            println!("[INFO] : MASTER MODE enabled");
+        }
+        return string_to_simple_resp("OK", '+').into_bytes();
+    }
+    if port.parse::<u16>().is_err() {
+        return to_error("ERR Invalid master port");
+    }
+    let address = (host.clone(), port.clone());
+    if state_locked.master_address.as_ref() == Some(&address) {
+        return string_to_simple_resp("OK Already connected to specified master", '+').into_bytes();
+    }
+    println!("[INFO] : Connecting to master {}:{}", host, port);
+    let task = tokio::spawn(serve_master_link(
+        address.clone(),
+        Arc::clone(storage),
+        Arc::clone(state),
+        Arc::clone(config),
+        Arc::clone(stats),
+        Arc::clone(persistence),
+        Arc::clone(pubsub),
+        true,
+    ));
+    state_locked.follow_master(address, task);
+    string_to_simple_resp("OK", '+').into_bytes()
+}
+
+/// Answers PSYNC with FULLRESYNC and a snapshot of the dataset. The replica is
+/// registered while writes are blocked, so writes made during the transfer wait
+/// in its feed and follow the snapshot.
+fn full_resync(
+    storage: &Databases,
+    state: &Mutex<RedisReplicationState>,
+    client: &mut Client,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    reply: &mut Vec<u8>,
+) {
+    let mut aux = persistence::aux_fields();
+    let databases = storage.snapshot(|| {
+        let mut state_locked = state.lock().unwrap();
+        reply.extend_from_slice(
+            format!(
+                "+FULLRESYNC {} {}\r\n",
+                state_locked.master_replid, state_locked.master_repl_offset
+            )
+            .as_bytes(),
+        );
+        state_locked.register_replica(
+            client.id,
+            (client.peer_ip.clone(), client.listening_port),
+            sender,
+            true,
+        );
+        // A replica passes on its master's stream, which continues in its current DB.
+        if let Some(db) = state_locked.stream_db {
+            aux.push(("repl-stream-db".to_string(), db.to_string().into_bytes()));
+        }
+        aux.push((
+            "repl-id".to_string(),
+            state_locked.master_replid.clone().into_bytes(),
         ));
-        string_vec.push(format!(
-            "repl_backlog_histlen:{}",
-            self.repl_backlog_histlen
+        aux.push((
+            "repl-offset".to_string(),
+            state_locked.master_repl_offset.to_string().into_bytes(),
         ));
+    });
+    aux.push(("aof-base".to_string(), b"0".to_vec()));
+    client.pending_snapshot = Some(Snapshot {
+        databases,
+        aux,
+        functions: Vec::new(),
+    });
+}
 
-        string_vec
+/// Refuses commands a replica must not run for ordinary clients: writes when
+/// `replica-read-only` is set, and queries on stale data while the link to the
+/// master is down and `replica-serve-stale-data` is off.
+fn replica_refusal(
+    request: &RespRequest,
+    state: &Mutex<RedisReplicationState>,
+    config: &RwLock<Config>,
+    client: &Client,
+) -> Option<Vec<u8>> {
+    if client.master_link {
+        return None;
+    }
+    let (replica, link_up) = {
+        let state_locked = state.lock().unwrap();
+        (
+            matches!(state_locked.role, Role::Slave),
+            state_locked.link_state == LinkState::Connected,
+        )
+    };
+    if !replica {
+        return None;
+    }
+    let config = config.read().unwrap();
+    if !link_up && !config.replica_serve_stale_data && !request.is_allowed_when_stale() {
+        return Some(to_error(
+            "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.",
+        ));
     }
+    if config.replica_read_only && request.is_write() {
+        return Some(to_error("READONLY You can't write against a read only replica."));
+    }
+    None
 }
 
 fn handle_request(
     request: RespRequest,
-    mut stream: TcpStream,
-    storage: Arc<Mutex<TimeKeyValueStorage<String, String>>>,
+    storage: Arc<Databases>,
     state: Arc<Mutex<RedisReplicationState>>,
-) {
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+    persistence: Arc<Persistence>,
+    client: &mut Client,
+) -> Vec<u8> {
+    if client.subscriptions.is_active()
+        && !client.subscriptions.resp3()
+        && !request.is_allowed_when_subscribed()
+        && !matches!(request.command, Command::None)
+    {
+        return to_error(&format!(
+            "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            request.name.to_ascii_lowercase()
+        ));
+    }
+    // Queued commands are checked by `queue_command`, which also fails the
+    // transaction, and unknown ones get their own error.
+    let queued = client.transaction.is_some() && request.is_queued_in_transaction();
+    if !queued && !matches!(request.command, Command::None) && !request.has_valid_arity() {
+        return wrong_arguments(&request.name.to_ascii_lowercase());
+    }
+    if matches!(request.command, Command::Quit) {
+        return string_to_simple_resp("OK", '+').into_bytes();
+    }
+    if matches!(request.command, Command::Reset) {
+        client.transaction = None;
+        watch::unwatch_all(&storage, &mut client.watched);
+        client.subscriptions.unsubscribe_all();
+        client.subscriptions.set_resp3(false);
+        client.selected_db = 0;
+        return string_to_simple_resp("RESET", '+').into_bytes();
+    }
+    if let Some(transaction) = client.transaction.as_mut() {
+        if request.is_queued_in_transaction() {
+            return queue_command(request, transaction);
+        }
+    }
+    if matches!(request.command, Command::Multi) {
+        if client.transaction.is_some() {
+            return to_error("ERR MULTI calls can not be nested");
+        }
+        client.transaction = Some(Transaction::default());
+        return string_to_simple_resp("OK", '+').into_bytes();
+    }
+    if matches!(request.command, Command::Discard) {
+        if client.transaction.take().is_none() {
+            return to_error("ERR DISCARD without MULTI");
+        }
+        watch::unwatch_all(&storage, &mut client.watched);
+        return string_to_simple_resp("OK", '+').into_bytes();
+    }
+    if matches!(request.command, Command::Exec) {
+        return exec(storage, state, config, stats, persistence, client);
+    }
+    let _command = storage.lock_command();
+    execute_command(request, storage.clone(), state, config, stats, persistence, client)
+}
+
+/// Queues a command sent after MULTI. Commands that could never run abort the
+/// whole transaction.
+fn queue_command(request: RespRequest, transaction: &mut Transaction) -> Vec<u8> {
+    if matches!(request.command, Command::None) {
+        transaction.failed = true;
+        return to_error(&format!(
+            "ERR Unknown command '{}'",
+            request.arguments.first().unwrap().content
+        ));
+    }
+    if !request.has_valid_arity() {
+        transaction.failed = true;
+        return wrong_arguments(&request.name.to_ascii_lowercase());
+    }
+    transaction.commands.push(request);
+    string_to_simple_resp("QUEUED", '+').into_bytes()
+}
+
+/// Runs the queued commands with every other command held off, replying with
+/// an array of their replies, or a null array if a watched key was modified.
+/// Writes reach the AOF and replicas wrapped in MULTI and EXEC so they are
+/// applied all at once there too.
+fn exec(
+    storage: Arc<Databases>,
+    state: Arc<Mutex<RedisReplicationState>>,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+    persistence: Arc<Persistence>,
+    client: &mut Client,
+) -> Vec<u8> {
+    let Some(transaction) = client.transaction.take() else {
+        return to_error("ERR EXEC without MULTI");
+    };
+    if transaction.failed {
+        watch::unwatch_all(&storage, &mut client.watched);
+        return to_error("EXECABORT Transaction discarded because of previous errors.");
+    }
+    let _transaction = storage.lock_transaction();
+    let modified = watch::any_modified(&storage, &client.watched);
+    watch::unwatch_all(&storage, &mut client.watched);
+    if modified {
+        return b"*-1\r\n".to_vec();
+    }
+    let writes = transaction.commands.iter().any(RespRequest::is_write);
+    if writes {
+        propagate(&state, &persistence, client.selected_db, vec![vec![b"MULTI".to_vec()]]);
+    }
+    let mut reply = format!("*{}\r\n", transaction.commands.len()).into_bytes();
+    for request in transaction.commands {
+        reply.extend(execute_command(
+            request,
+            storage.clone(),
+            state.clone(),
+            config.clone(),
+            stats.clone(),
+            persistence.clone(),
+            client,
+        ));
+    }
+    if writes {
+        propagate(&state, &persistence, client.selected_db, vec![vec![b"EXEC".to_vec()]]);
+        client.write_offset = state.lock().unwrap().master_repl_offset;
+    }
+    reply
+}
+
+/// HELLO [protover]: switches the connection to RESP2 or RESP3, and describes
+/// the server.
+fn hello(request: &RespRequest, state: &Mutex<RedisReplicationState>, client: &Client) -> Vec<u8> {
+    let resp3 = match request.arguments.first().map(|version| version.content.parse::<i64>()) {
+        None => client.subscriptions.resp3(),
+        Some(Ok(2)) => false,
+        Some(Ok(3)) => true,
+        Some(Ok(_)) => return to_error("NOPROTO unsupported protocol version"),
+        Some(Err(_)) => return to_error("ERR Protocol version is not an integer or out of range"),
+    };
+    if let Some(option) = request.arguments.get(1) {
+        return to_error(&format!("ERR Syntax error in HELLO option '{}'", option.content));
+    }
+    client.subscriptions.set_resp3(resp3);
+
+    let role = match state.lock().unwrap().role {
+        Role::Slave => "replica",
+        _ => "master",
+    };
+    let fields = [
+        ("server", to_bulk_bytes(b"redis")),
+        ("version", to_bulk_bytes(b"7.2.0")),
+        ("proto", to_integer(if resp3 { 3 } else { 2 })),
+        ("id", to_integer(client.id as i64)),
+        ("mode", to_bulk_bytes(b"standalone")),
+        ("role", to_bulk_bytes(role.as_bytes())),
+        ("modules", b"*0\r\n".to_vec()),
+    ];
+    // RESP3 has maps; RESP2 flattens them into arrays.
+    let mut reply = if resp3 {
+        format!("%{}\r\n", fields.len()).into_bytes()
+    } else {
+        format!("*{}\r\n", fields.len() * 2).into_bytes()
+    };
+    for (name, value) in fields {
+        reply.extend(to_bulk_bytes(name.as_bytes()));
+        reply.extend(value);
+    }
+    reply
+}
+
+/// Runs a single command. The caller holds the transaction lock.
+fn execute_command(
+    request: RespRequest,
+    storage: Arc<Databases>,
+    state: Arc<Mutex<RedisReplicationState>>,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+    persistence: Arc<Persistence>,
+    client: &mut Client,
+) -> Vec<u8> {
+    let mut reply: Vec<u8> = vec![];
     let pong = "+PONG\r\n";
+    let is_write = request.is_write();
 
     if matches!(request.command, Command::None) {
         let error = format!(
             "-ERR Unknown command '{}'\r\n",
             request.arguments.first().unwrap().content
         );
-        stream.write_all(error.as_bytes()).unwrap();
+        reply.extend_from_slice(error.as_bytes());
     } else if matches!(request.command, Command::Ping) {
-        stream.write_all(pong.as_bytes()).unwrap();
+        if client.subscriptions.is_active() && !client.subscriptions.resp3() {
+            // Subscribed RESP2 connections only expect arrays.
+            let message = request.arguments.first().map(|message| message.bytes.clone());
+            reply.extend(to_command_array(&[b"pong".to_vec(), message.unwrap_or_default()]));
+        } else {
+            reply.extend_from_slice(pong.as_bytes());
+        }
     } else if matches!(request.command, Command::Echo) {
         let count = request.arguments.len();
         let mut message: String;
@@ -128,10 +503,10 @@ fn handle_request(
             }
         }
 
-        stream.write_all(message.as_bytes()).unwrap();
+        reply.extend_from_slice(message.as_bytes());
     } else if matches!(request.command, Command::Set) {
-        // Block thread only when the parsed command requires storage access
-        let mut storage_hash = storage.lock().unwrap();
+        // Block only the shard that holds the key
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
         let count = request.arguments.len();
 
         let message: String = if count >= 2 {
@@ -142,236 +517,1304 @@ fn handle_request(
                 _ => expiry_ms_string.parse::<i64>().unwrap(),
             };
 
-            storage_hash.insert(
-                request.arguments.get(0).unwrap().content.clone(),
-                request.arguments.get(1).unwrap().content.clone(),
+            storage_hash.update(
+                request.arguments.first().unwrap().content.clone(),
+                RedisValue::String(request.arguments.get(1).unwrap().bytes.clone()),
                 expiry_ms,
             );
             string_to_simple_resp("OK", '+')
         } else {
             string_to_simple_resp("ERR wrong number of arguments for 'get' command", '-')
         };
-        stream.write_all(message.as_bytes()).unwrap();
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            message.as_bytes(),
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(message.as_bytes());
     } else if matches!(request.command, Command::Get) {
-        let storage_hash = storage.lock().unwrap();
-        let key = request.arguments.get(0).unwrap().content.clone();
-        let value = storage_hash.get(&key);
+        let storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let key = request.arguments.first().unwrap().content.clone();
+        let value = storage_hash.get_string(&key);
 
         let message = match value {
-            Some(val) => to_bulk_string(val.to_string()),
+            Ok(Some(val)) => to_bulk_bytes(val),
 
-            None => string_to_simple_resp("-1", '$'),
+            Ok(None) => string_to_simple_resp("-1", '$').into_bytes(),
+            Err(error) => to_error(&error.to_string()),
         };
-        stream.write_all(message.as_bytes()).unwrap();
+        reply.extend_from_slice(&message);
     } else if matches!(request.command, Command::Info) {
-        let mut state_locked = state.lock().unwrap();
-        state_locked.connected_slaves += 1;
-
-        let mut message = String::new();
-
-        if let Some(argument) = request.arguments.get(0) {
-            if argument.content == "replication" {
-                // let string_vec = state_locked.clone().to_string_vec();
-                // message = format!("*{}\r\n", string_vec.len());
-                // for stri in string_vec {
-                //     message += to_bulk_string(stri).as_str();
-                // }
-                let mut content = format!("role:{}\n", state_locked.role);
-                content += format!("master_replid:{}\n", state_locked.master_replid).as_str();
-                content +=
-                    format!("master_repl_offset:{}\n", state_locked.master_repl_offset).as_str();
-                message = to_bulk_string(content);
-            } else {
-                message = String::from("$-1\r\n");
+        let config = config.read().unwrap().clone();
+        reply.extend(info::handle_info_command(
+            &request,
+            &storage,
+            &state,
+            &config,
+            &stats,
+            &persistence,
+        ));
+    } else if matches!(request.command, Command::Replconf) {
+        let subcommand = request
+            .arguments
+            .first()
+            .map(|argument| argument.content.to_ascii_uppercase());
+        match subcommand.as_deref() {
+            Some("GETACK") => {
+                let offset = state.lock().unwrap().master_repl_offset;
+                reply.extend(to_command_array(&[
+                    b"REPLCONF".to_vec(),
+                    b"ACK".to_vec(),
+                    offset.to_string().into_bytes(),
+                ]));
+            }
+            Some("LISTENING-PORT") => {
+                match request
+                    .arguments
+                    .get(1)
+                    .and_then(|port| port.content.parse::<u16>().ok())
+                {
+                    Some(port) => {
+                        client.listening_port = port;
+                        reply.extend_from_slice("+OK\r\n".as_bytes());
+                    }
+                    None => reply.extend(to_error("ERR value is not an integer or out of range")),
+                }
+            }
+            // Acknowledgements arrive on the replication link and are never answered.
+            Some("ACK") => {
+                if let Some(offset) = request
+                    .arguments
+                    .get(1)
+                    .and_then(|offset| offset.content.parse::<usize>().ok())
+                {
+                    state.lock().unwrap().record_ack(client.id, offset);
+                }
             }
+            _ => reply.extend_from_slice("+OK\r\n".as_bytes()),
         }
-        stream.write_all(message.as_bytes()).unwrap();
-    } else if matches!(request.command, Command::Replconf) {
-        stream.write_all("+OK\r\n".as_bytes()).unwrap();
+    } else if matches!(request.command, Command::Config) {
+        let (old, new) = {
+            let mut config = config.write().unwrap();
+            let old = config.clone();
+            reply.extend(config::handle_config_command(&request, &mut config));
+            (old, config.clone())
+        };
+        persistence.reconfigure(&storage, &old, &new);
+    } else if matches!(
+        request.command,
+        Command::Save | Command::Bgsave | Command::Lastsave | Command::Bgrewriteaof
+    ) {
+        let config = config.read().unwrap().clone();
+        reply.extend(persistence::handle_save_command(
+            &request,
+            &storage,
+            &config,
+            &persistence,
+        ));
+    } else if matches!(request.command, Command::Replicaof) {
+        reply.extend(replicaof(
+            &request,
+            &storage,
+            &state,
+            &config,
+            &stats,
+            &persistence,
+            client.subscriptions.pubsub(),
+        ));
     } else if matches!(request.command, Command::Psync) {
-        let mut message = "-Err Invalid format".to_string();
-
-        if request.arguments.get(0).unwrap().content == "?" {
-            let state_locked = state.lock().unwrap();
-            message = format!(
-                "+FULLRESYNC {} {}\r\n",
-                state_locked.master_replid, state_locked.master_repl_offset
+        let replid = &request.arguments[0].content;
+        let offset = request.arguments[1].content.parse::<i64>().unwrap_or(-1);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let state_locked = state.lock().unwrap();
+        match state_locked.partial_resync(replid, offset) {
+            Some(backlog) => {
+                let message = format!("+CONTINUE {}\r\n", state_locked.master_replid);
+                reply.extend_from_slice(message.as_bytes());
+                reply.extend(backlog);
+                let mut state_locked = state_locked;
+                state_locked.register_replica(
+                    client.id,
+                    (client.peer_ip.clone(), client.listening_port),
+                    sender,
+                    false,
+                );
+                stats.sync_partial_ok.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+            None => {
+                drop(state_locked);
+                // "?" asks for a full resynchronization outright.
+                if replid != "?" {
+                    stats.sync_partial_err.fetch_add(1, AtomicOrdering::Relaxed);
+                }
+                stats.sync_full.fetch_add(1, AtomicOrdering::Relaxed);
+                full_resync(&storage, &state, client, sender, &mut reply);
+            }
+        }
+        client.replication_feed = Some(receiver);
+    } else if matches!(
+        request.command,
+        Command::Setbit
+            | Command::Getbit
+            | Command::Bitcount
+            | Command::Bitpos
+            | Command::Bitop
+            | Command::Bitfield
+            | Command::BitfieldRo
+    ) {
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = bitmap::handle_bitmap_command(&request, &mut storage_hash);
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            &message,
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(
+        request.command,
+        Command::Pfadd | Command::Pfcount | Command::Pfmerge
+    ) {
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = hyperloglog::handle_hyperloglog_command(&request, &mut storage_hash);
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            &message,
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(
+        request.command,
+        Command::Zadd | Command::Zscore | Command::Zrem | Command::Zcard | Command::Zrange
+    ) {
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = sorted_set::handle_sorted_set_command(&request, &mut storage_hash);
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            &message,
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(
+        request.command,
+        Command::Geoadd
+            | Command::Geodist
+            | Command::Geopos
+            | Command::Geohash
+            | Command::Geosearch
+            | Command::Geosearchstore
+    ) {
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = geo::handle_geo_command(&request, &mut storage_hash);
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            &message,
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(
+        request.command,
+        Command::Hset
+            | Command::Hget
+            | Command::Hmget
+            | Command::Hdel
+            | Command::Hgetall
+            | Command::Hlen
+            | Command::Hexists
+            | Command::Hexpire
+            | Command::Hpexpire
+            | Command::Hexpireat
+            | Command::Hpexpireat
+            | Command::Httl
+            | Command::Hpttl
+            | Command::Hexpiretime
+            | Command::Hpexpiretime
+            | Command::Hpersist
+            | Command::Hgetex
+            | Command::Hsetex
+    ) {
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        expire_hash_fields(&state, &persistence, client.selected_db, &request, &mut storage_hash);
+        let message = hash::handle_hash_command(&request, &mut storage_hash);
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            &message,
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(
+        request.command,
+        Command::Select
+            | Command::Move
+            | Command::Swapdb
+            | Command::Flushdb
+            | Command::Flushall
+            | Command::Dbsize
+    ) {
+        let db = client.selected_db;
+        let mut propagate = || replicate(&state, &persistence, db, &request, b"", None);
+        let message = database::handle_database_command(
+            &request,
+            &storage,
+            &mut client.selected_db,
+            &mut propagate,
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(
+        request.command,
+        Command::Del
+            | Command::Exists
+            | Command::Expire
+            | Command::Pexpire
+            | Command::Expireat
+            | Command::Pexpireat
+            | Command::Ttl
+            | Command::Pttl
+            | Command::Persist
+    ) {
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = keys::handle_keys_command(&request, &mut storage_hash);
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            &message,
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(request.command, Command::Dump | Command::Restore) {
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = dump::handle_dump_command(&request, &mut storage_hash);
+        replicate(
+            &state,
+            &persistence,
+            client.selected_db,
+            &request,
+            &message,
+            Some(&storage_hash),
+        );
+        reply.extend_from_slice(&message);
+    } else if matches!(request.command, Command::Migrate) {
+        // Only reached from EXEC, which already holds every other command off
+        // for the whole transaction, so the transfer blocks under its locks.
+        // It gets a runtime of its own, as tasks of the shared one may be
+        // waiting for the transaction to end.
+        let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = match dump::prepare_migration(&request, &storage_hash) {
+            Ok(migration) => {
+                let transferred = tokio::task::block_in_place(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .map_err(|e| to_error(&format!("IOERR {}", e)))?
+                        .block_on(migration.transfer())
+                });
+                match transferred {
+                    Ok(replies) => {
+                        let (message, restored) = migration.outcome(&replies);
+                        let moved = migration.remove_restored(&restored, &mut storage_hash);
+                        propagate_migrated(&state, &persistence, client.selected_db, moved);
+                        message
+                    }
+                    Err(message) => message,
+                }
+            }
+            Err(message) => message,
+        };
+        reply.extend_from_slice(&message);
+    } else if matches!(request.command, Command::Watch) {
+        let message = if let Some(transaction) = client.transaction.as_mut() {
+            transaction.failed = true;
+            to_error("ERR WATCH inside MULTI is not allowed")
+        } else {
+            let mut storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+            watch::watch(
+                &request,
+                &mut storage_hash,
+                client.selected_db,
+                &mut client.watched,
             )
+        };
+        reply.extend_from_slice(&message);
+    } else if matches!(request.command, Command::Unwatch) {
+        watch::unwatch_all(&storage, &mut client.watched);
+        reply.extend_from_slice("+OK\r\n".as_bytes());
+    } else if matches!(
+        request.command,
+        Command::Subscribe
+            | Command::Unsubscribe
+            | Command::Psubscribe
+            | Command::Punsubscribe
+            | Command::Ssubscribe
+            | Command::Sunsubscribe
+            | Command::Publish
+            | Command::Spublish
+            | Command::Pubsub
+    ) {
+        let limit = config.read().unwrap().client_output_buffer_limit.pubsub;
+        let message = pubsub::handle_pubsub_command(&request, &mut client.subscriptions, &limit);
+        // Replicas deliver messages to their own subscribers too.
+        if matches!(request.command, Command::Publish | Command::Spublish)
+            && !message.starts_with(b"-")
+        {
+            let mut publish = vec![request.name.as_bytes().to_vec()];
+            publish.extend(request.arguments.iter().map(|argument| argument.bytes.clone()));
+            state
+                .lock()
+                .unwrap()
+                .feed_replicas(client.selected_db, vec![publish]);
         }
-        let _ = stream.write(message.as_bytes()).unwrap();
-        let content= BASE64_STANDARD.decode(EMPTY_RDB_BASE64).unwrap();
-        let _ = stream.write(format!("${}\r\n", content.len()).as_bytes()).unwrap();
-        stream.write_all(&content).unwrap();
-
+        reply.extend_from_slice(&message);
+    } else if matches!(request.command, Command::Hello) {
+        reply.extend(hello(&request, &state, client));
+    } else if matches!(request.command, Command::Wait) {
+        // Only reached from EXEC, where WAIT cannot block: it reports the
+        // replicas that already acknowledged the client's writes.
+        let acked = state.lock().unwrap().acked_replicas(client.write_offset);
+        reply.extend(to_integer(acked as i64));
+    } else if matches!(request.command, Command::Type) {
+        let storage_hash = lock_keyspace(&storage, client.selected_db, &request);
+        let message = match request.arguments.first() {
+            Some(key) => {
+                let type_name = storage_hash
+                    .get(&key.content)
+                    .map(RedisValue::type_name)
+                    .unwrap_or("none");
+                string_to_simple_resp(type_name, '+')
+            }
+            None => string_to_simple_resp("ERR wrong number of arguments for 'type' command", '-'),
+        };
+        reply.extend_from_slice(message.as_bytes());
     }
+    if is_write && !reply.starts_with(b"-") {
+        persistence.dirty.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+    reply
 }
 
-fn handle_client(
+#[allow(clippy::too_many_arguments)]
+async fn handle_client(
     mut stream: TcpStream,
-    storage: Arc<Mutex<TimeKeyValueStorage<String, String>>>,
+    storage: Arc<Databases>,
     state: Arc<Mutex<RedisReplicationState>>,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+    persistence: Arc<Persistence>,
+    pubsub: Arc<PubSub>,
+    mut shutdown: broadcast::Receiver<()>,
 ) {
+    let mut pending = BytesMut::with_capacity(4096);
+    let id = NEXT_CLIENT_ID.fetch_add(1, AtomicOrdering::Relaxed);
+    let mut client = Client {
+        id,
+        peer_ip: stream
+            .peer_addr()
+            .map(|address| address.ip().to_string())
+            .unwrap_or_default(),
+        listening_port: 0,
+        selected_db: 0,
+        master_link: false,
+        replication_feed: None,
+        pending_snapshot: None,
+        write_offset: 0,
+        transaction: None,
+        watched: Vec::new(),
+        subscriptions: Subscriptions::new(pubsub, id),
+    };
     loop {
-        let mut buffer = [0; 256];
-        let bytes_read = match stream.read(&mut buffer) {
+        let read = tokio::select! {
+            read = stream.read_buf(&mut pending) => read,
+            message = client.subscriptions.next_message() => {
+                // No more messages come once the subscriber fell too far behind.
+                let Some(message) = message else {
+                    break;
+                };
+                let written = tokio::select! {
+                    written = stream.write_all(&message) => written,
+                    _ = client.subscriptions.closed() => break,
+                };
+                if let Err(e) = written {
+                    println!("[ERROR] : {}", e);
+                    break;
+                }
+                stats
+                    .total_net_output_bytes
+                    .fetch_add(message.len() as u64, AtomicOrdering::Relaxed);
+                continue;
+            }
+            data = next_feed(&mut client.replication_feed) => {
+                // The feed closes when the master drops its replicas.
+                let Some(data) = data else {
+                    break;
+                };
+                if let Err(e) = stream.write_all(&data).await {
+                    println!("[ERROR] : {}", e);
+                    break;
+                }
+                stats
+                    .total_net_output_bytes
+                    .fetch_add(data.len() as u64, AtomicOrdering::Relaxed);
+                continue;
+            }
+            _ = shutdown.recv() => break,
+        };
+        match read {
             Ok(0) => {
                 // Connection closed by client
                 println!("[INFO] : Connection closed by client");
-                return;
+                break;
+            }
+            Ok(read) => {
+                stats
+                    .total_net_input_bytes
+                    .fetch_add(read as u64, AtomicOrdering::Relaxed);
             }
-            Ok(n) => n,
             Err(e) => {
                 println!("[ERROR] : {}", e);
-                return;
+                break;
             }
-        };
+        }
 
-        let shortened_buffer = &buffer[..bytes_read];
-        let stream_buf = String::from_utf8_lossy(shortened_buffer).into_owned();
+        // A single read may carry several pipelined requests, or only part of one.
+        // Replies to a whole batch are flushed together.
+        let mut replies: Vec<u8> = vec![];
+        let mut quit = false;
+        while let Some((resp_request, consumed)) = resp_parser::handle_resp_request(&pending) {
+            pending.advance(consumed);
 
-        println!("[INFO] : accepted new request");
-        println!(
-            "=====REQUEST==========\n{}======================",
-            stream_buf
-        );
-
-        let resp_request: RespRequest = resp_parser::handle_resp_request(stream_buf);
+            let started = Instant::now();
+            let name = (!matches!(resp_request.command, Command::None))
+                .then(|| resp_request.name.clone());
+            let refusal = persistence
+                .write_refusal(&resp_request, &config.read().unwrap())
+                .or_else(|| replica_refusal(&resp_request, &state, &config, &client));
+            if let Some(refusal) = refusal {
+                // A command refused while queueing dooms the transaction.
+                if let Some(transaction) = client.transaction.as_mut() {
+                    if resp_request.is_queued_in_transaction() {
+                        transaction.failed = true;
+                    }
+                }
+                stats.record_command(client.id, name.as_deref(), started.elapsed(), &refusal, true);
+                replies.extend(refusal);
+                continue;
+            }
+            let reply = if matches!(resp_request.command, Command::Wait)
+                && client.transaction.is_none()
+            {
+                stats.blocked_clients.fetch_add(1, AtomicOrdering::Relaxed);
+                let reply =
+                    wait_for_replicas(&resp_request, &state, &client, &mut shutdown).await;
+                stats.blocked_clients.fetch_sub(1, AtomicOrdering::Relaxed);
+                // The connection closes without a reply when the server shuts down.
+                let Some(reply) = reply else {
+                    break;
+                };
+                reply
+            } else if matches!(resp_request.command, Command::Migrate)
+                && client.transaction.is_none()
+                && (client.subscriptions.resp3() || !client.subscriptions.is_active())
+            {
+                let reply = migrate(&resp_request, &storage, &state, &persistence, &client).await;
+                client.write_offset = state.lock().unwrap().master_repl_offset;
+                reply
+            } else {
+                let is_write = resp_request.is_write();
+                quit = matches!(resp_request.command, Command::Quit);
+                let reply = handle_request(
+                    resp_request,
+                    storage.clone(),
+                    state.clone(),
+                    config.clone(),
+                    stats.clone(),
+                    persistence.clone(),
+                    &mut client,
+                );
+                if is_write {
+                    client.write_offset = state.lock().unwrap().master_repl_offset;
+                }
+                reply
+            };
+            stats.record_command(client.id, name.as_deref(), started.elapsed(), &reply, false);
+            replies.extend(reply);
+            if quit {
+                break;
+            }
+        }
+        if let Err(e) = stream.write_all(&replies).await {
+            println!("[ERROR] : {}", e);
+            break;
+        }
+        stats
+            .total_net_output_bytes
+            .fetch_add(replies.len() as u64, AtomicOrdering::Relaxed);
+        if quit {
+            break;
+        }
+        if let Some(snapshot) = client.pending_snapshot.take() {
+            if let Err(e) = send_snapshot(&mut stream, snapshot).await {
+                println!("[ERROR] : {}", e);
+                break;
+            }
+        }
+    }
 
-        handle_request(
-            resp_request,
-            stream.try_clone().unwrap(),
-            storage.clone(),
-            state.clone(),
-        );
+    if client.replication_feed.is_some() {
+        state.lock().unwrap().unregister_replica(client.id);
     }
+    watch::unwatch_all(&storage, &mut client.watched);
 }
 
-fn read_parse_stream(mut stream: TcpStream) -> RespRequest {
-    let mut buffer = [0; 256];
-    let bytes_read = match stream.read(&mut buffer) {
-        Ok(0) => {
-            // Connection closed by client
-            println!("[INFO] : Connection closed by client");
-            return RespRequest::new();
+/// Streams an RDB snapshot to a replica as `$<length>\r\n<payload>`, encoding
+/// it off the async workers as it may be large.
+async fn send_snapshot(stream: &mut TcpStream, snapshot: Snapshot) -> std::io::Result<()> {
+    let payload = tokio::task::spawn_blocking(move || rdb::encode(&snapshot))
+        .await
+        .map_err(std::io::Error::other)?;
+    println!("[INFO] : Sending RDB snapshot of {} bytes", payload.len());
+    stream
+        .write_all(format!("${}\r\n", payload.len()).as_bytes())
+        .await?;
+    stream.write_all(&payload).await
+}
+
+/// WAIT numreplicas timeout: blocks until `numreplicas` replicas acknowledged
+/// the client's latest write, or the timeout in milliseconds (0 for none)
+/// elapses, and replies with the number of replicas that did. Returns `None`
+/// when the server shuts down first.
+async fn wait_for_replicas(
+    request: &RespRequest,
+    state: &Mutex<RedisReplicationState>,
+    client: &Client,
+    shutdown: &mut broadcast::Receiver<()>,
+) -> Option<Vec<u8>> {
+    if request.arguments.len() != 2 {
+        return Some(wrong_arguments("wait"));
+    }
+    let (Ok(needed), Ok(timeout)) = (
+        request.arguments[0].content.parse::<i64>(),
+        request.arguments[1].content.parse::<i64>(),
+    ) else {
+        return Some(to_error("ERR value is not an integer or out of range"));
+    };
+    if timeout < 0 {
+        return Some(to_error("ERR timeout is negative"));
+    }
+
+    let mut updates = {
+        let mut state_locked = state.lock().unwrap();
+        if matches!(state_locked.role, Role::Slave) {
+            return Some(to_error("ERR WAIT cannot be used with replica instances."));
         }
-        Ok(n) => n,
-        Err(e) => {
-            println!("[ERROR] : {}", e);
-            return RespRequest::new();
+        let acked = state_locked.acked_replicas(client.write_offset);
+        if acked as i64 >= needed {
+            return Some(to_integer(acked as i64));
         }
+        state_locked.request_acks();
+        state_locked.ack_updates.subscribe()
     };
-    let shortened_buffer = &buffer[..bytes_read];
-    let stream_buf = String::from_utf8_lossy(shortened_buffer).into_owned();
-    let resp_response: RespRequest = resp_parser::handle_resp_request(stream_buf);
-    resp_response
+    let deadline = (timeout > 0)
+        .then(|| time::Instant::now() + Duration::from_millis(timeout as u64));
+    loop {
+        let acked = state.lock().unwrap().acked_replicas(client.write_offset);
+        if acked as i64 >= needed {
+            return Some(to_integer(acked as i64));
+        }
+        let changed = async {
+            match deadline {
+                Some(deadline) => time::timeout_at(deadline, updates.changed())
+                    .await
+                    .unwrap_or(Ok(())),
+                None => updates.changed().await,
+            }
+        };
+        let changed = tokio::select! {
+            changed = changed => changed,
+            _ = shutdown.recv() => return None,
+        };
+        let timed_out = deadline.is_some_and(|deadline| time::Instant::now() >= deadline);
+        if changed.is_err() || timed_out {
+            let acked = state.lock().unwrap().acked_replicas(client.write_offset);
+            return Some(to_integer(acked as i64));
+        }
+    }
 }
 
-fn main() {
-    println!("[INFO] : Logs will appear here!");
+/// MIGRATE outside of a transaction. The keys are serialized under their
+/// shard locks, sent to the target without holding any lock, and only locked
+/// again to delete the ones the target restored.
+async fn migrate(
+    request: &RespRequest,
+    storage: &Databases,
+    state: &Mutex<RedisReplicationState>,
+    persistence: &Persistence,
+    client: &Client,
+) -> Vec<u8> {
+    let migration = {
+        let _command = storage.lock_command();
+        let keyspace = lock_keyspace(storage, client.selected_db, request);
+        match dump::prepare_migration(request, &keyspace) {
+            Ok(migration) => migration,
+            Err(message) => return message,
+        }
+    };
+    let replies = match migration.transfer().await {
+        Ok(replies) => replies,
+        Err(message) => return message,
+    };
+    let (message, restored) = migration.outcome(&replies);
+    if !restored.is_empty() {
+        let _command = storage.lock_command();
+        let mut keyspace = storage.get(client.selected_db).lock(&restored, Access::Write);
+        let moved = migration.remove_restored(&restored, &mut keyspace);
+        if !moved.is_empty() {
+            persistence.dirty.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        propagate_migrated(state, persistence, client.selected_db, moved);
+    }
+    message
+}
 
-    let storage_struct = Arc::new(Mutex::new(TimeKeyValueStorage::<String, String>::new()));
-    let mut replication_state = RedisReplicationState::new();
-    let arguments: Vec<String> = args().collect();
+/// Propagates the keys MIGRATE moved away as deleted, even when the target
+/// refused others.
+fn propagate_migrated(
+    state: &Mutex<RedisReplicationState>,
+    persistence: &Persistence,
+    db: usize,
+    moved: Vec<String>,
+) {
+    if moved.is_empty() {
+        return;
+    }
+    let mut del = vec![b"DEL".to_vec()];
+    del.extend(moved.into_iter().map(String::into_bytes));
+    propagate(state, persistence, db, vec![del]);
+}
 
-    let mut address = String::from("127.0.0.1:");
-    let mut port = String::from("6379");
+/// Waits for the next chunk of the replication stream, or forever if the
+/// connection is not a replica.
+async fn next_feed(feed: &mut Option<mpsc::UnboundedReceiver<Vec<u8>>>) -> Option<Vec<u8>> {
+    match feed {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-    if arguments.len() >= 2 && arguments[1] == "--port" {
-        if let Ok(_port_number) = arguments[2].parse::<u16>() {
-            port = arguments[2].clone();
+/// Keeps the replica attached to its master, reconnecting with exponential
+/// backoff while the master is unreachable. Once synchronized, reconnections ask
+/// to continue from the processed offset; so does the first attempt when
+/// `resume` is set, as when a running server is turned into a replica.
+#[allow(clippy::too_many_arguments)]
+async fn serve_master_link(
+    (host, port): (String, String),
+    storage: Arc<Databases>,
+    state: Arc<Mutex<RedisReplicationState>>,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+    persistence: Arc<Persistence>,
+    pubsub: Arc<PubSub>,
+    mut resume: bool,
+) {
+    let id = NEXT_CLIENT_ID.fetch_add(1, AtomicOrdering::Relaxed);
+    let mut client = Client {
+        id,
+        peer_ip: host.clone(),
+        listening_port: 0,
+        selected_db: 0,
+        master_link: true,
+        replication_feed: None,
+        pending_snapshot: None,
+        write_offset: 0,
+        transaction: None,
+        watched: Vec::new(),
+        subscriptions: Subscriptions::new(pubsub, id),
+    };
+    let mut delay = RECONNECT_MIN_DELAY;
+    loop {
+        let cached = resume.then(|| {
+            let state_locked = state.lock().unwrap();
+            (
+                state_locked.master_replid.clone(),
+                state_locked.master_repl_offset,
+            )
+        });
+        let cached = cached
+            .as_ref()
+            .map(|(replid, offset)| (replid.as_str(), *offset));
+        match connect_to_master(&host, &port, cached, &state).await {
+            Ok(link) => {
+                resume = true;
+                delay = RECONNECT_MIN_DELAY;
+                follow_master(
+                    link,
+                    &storage,
+                    &state,
+                    &config,
+                    &stats,
+                    &persistence,
+                    &mut client,
+                )
+                .await;
+            }
+            Err(e) => println!(
+                "[ERROR] : Could not connect to master {}:{}: {}, retrying in {:?}",
+                host, port, e, delay
+            ),
+        }
+        {
+            let mut state_locked = state.lock().unwrap();
+            state_locked.link_state = LinkState::Connect;
         }
+        time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
     }
-    if let Some(replicaof_index) = arguments.iter().position(|arg| arg == "--replicaof") {
-        if replicaof_index + 2 <= arguments.len() {
-            let mut host_port = arguments[replicaof_index + 1]
-                .split_whitespace()
-                .into_iter();
-            let host = host_port.next().expect("[Error] Could not read host");
-            let port = host_port.next().expect("[Error] Could not read port");
-
-            println!("Master Host: {} {}", host, port);
-            replication_state.role = Role::Slave;
-
-            let mut stream = TcpStream::connect(format!("{}:{}", host, port))
-                .expect("[ERROR] Could not connect to master");
+}
 
-            // --------- HANDSHAKE BEGINS---------------------
-            let ping = format!("*1\r\n{}", to_bulk_string("PING".to_string()));
+/// Applies the master's command stream to the local dataset. Commands from the
+/// master are not answered, and every processed byte advances the replica's
+/// offset, which is acknowledged every `REPL_ACK_PERIOD`.
+async fn follow_master(
+    link: MasterLink,
+    storage: &Arc<Databases>,
+    state: &Arc<Mutex<RedisReplicationState>>,
+    config: &Arc<RwLock<Config>>,
+    stats: &Arc<Stats>,
+    persistence: &Arc<Persistence>,
+    client: &mut Client,
+) {
+    let MasterLink {
+        mut stream,
+        buffer: mut pending,
+        resync,
+    } = link;
+    // The snapshot is loaded before the state lock is taken, as commands lock
+    // the shards first and the state second.
+    if let Resync::Full { rdb, .. } = &resync {
+        println!("[INFO] : Received RDB snapshot of {} bytes", rdb.len());
+        let snapshot = match rdb::decode(rdb) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                println!("[ERROR] : Could not load the master's snapshot: {}", e);
+                return;
+            }
+        };
+        client.selected_db = snapshot
+            .aux_field("repl-stream-db")
+            .and_then(|db| std::str::from_utf8(db).ok()?.parse().ok())
+            .unwrap_or(0);
+        if let Err(e) = storage.load(snapshot.databases) {
+            println!("[ERROR] : Could not load the master's snapshot: {}", e);
+            return;
+        }
+    }
+    {
+        let mut state_locked = state.lock().unwrap();
+        state_locked.create_backlog();
+        match resync {
+            Resync::Full { replid, offset, .. } => {
+                state_locked.master_replid = replid;
+                state_locked.second_replid = "0".repeat(40);
+                state_locked.second_repl_offset = -1;
+                state_locked.master_repl_offset = offset;
+                state_locked.stream_db = Some(client.selected_db);
+                state_locked.reset_backlog();
+                // Sub-replicas hold the replaced dataset and must resynchronize.
+                state_locked.disconnect_replicas();
+            }
+            Resync::Partial { replid } => {
+                println!("[INFO] : Continuing replication stream");
+                if let Some(replid) = replid {
+                    if replid != state_locked.master_replid {
+                        state_locked.switch_replid(replid);
+                    }
+                }
+            }
+        }
+        state_locked.link_state = LinkState::Connected;
+        state_locked.master_last_io = Some(Instant::now());
+    }
 
-            stream
-                .write_all(ping.as_bytes())
-                .expect("[ERROR] Could not ping master");
-            let parsed_response_ping = read_parse_stream(
-                stream
-                    .try_clone()
-                    .expect("[ERROR] Could not read the master"),
+    let mut ack_interval = time::interval(REPL_ACK_PERIOD);
+    loop {
+        while let Some((resp_request, consumed)) = resp_parser::handle_resp_request(&pending) {
+            let raw = pending.split_to(consumed);
+            // GETACK is the only command from the master that gets a reply.
+            let getack = matches!(resp_request.command, Command::Replconf)
+                && resp_request
+                    .arguments
+                    .first()
+                    .is_some_and(|argument| argument.content.eq_ignore_ascii_case("GETACK"));
+            let reply = handle_request(
+                resp_request,
+                storage.clone(),
+                state.clone(),
+                config.clone(),
+                stats.clone(),
+                persistence.clone(),
+                client,
             );
-            println!("{:#?}", parsed_response_ping.arguments);
-            if parsed_response_ping
-                .arguments
-                .get(0)
+            state
+                .lock()
                 .unwrap()
-                .content
-                .to_uppercase()
-                == "PONG"
-            {
-                println!("[INFO] Ping Successful");
-                let message = format!(
-                    "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n{}\r\n",
-                    6380
-                );
-                stream
-                    .write_all(message.as_bytes())
-                    .expect("Could not write REPLCONF to master");
-                let second_response = read_parse_stream(stream.try_clone().unwrap());
-                if second_response.arguments.get(0).unwrap().content == "OK" {
-                    println!("[INFO] REPLCONF 1 Successful");
-
-                    stream
-                        .write_all(
-                            "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n".as_bytes(),
-                        )
-                        .unwrap();
-                    if let Some(third_response) = read_parse_stream(stream.try_clone().unwrap())
-                        .arguments
-                        .get(0)
-                    {
-                        if third_response.content == "OK" {
-                            println!("[INFO] REPLCONF 2 Successful");
-                            stream
-                                .write_all(
-                                    "*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n".as_bytes(),
-                                )
-                                .expect("[ERROR] Handshake (3/3) Failed");
-                            if let Some(_) = read_parse_stream(stream.try_clone().unwrap())
-                                .arguments
-                                .get(0)
-                            {
-                            let rdb_data = read_parse_stream(stream.try_clone().unwrap());                           }
-                            println!("[INFO] Handshake Successfull");
-                        }
-                    }
+                .proxy_master_stream(client.selected_db, &raw);
+            if getack {
+                if let Err(e) = stream.write_all(&reply).await {
+                    println!("[ERROR] : Connection to master lost: {}", e);
+                    return;
                 }
             }
         }
+        let read = tokio::select! {
+            read = stream.read_buf(&mut pending) => read,
+            _ = ack_interval.tick() => {
+                let (offset, last_io) = {
+                    let state_locked = state.lock().unwrap();
+                    (state_locked.master_repl_offset, state_locked.master_last_io)
+                };
+                if last_io.is_some_and(|last_io| last_io.elapsed() > REPL_TIMEOUT) {
+                    println!("[ERROR] : Connection to master lost: timeout");
+                    return;
+                }
+                let ack = to_command_array(&[
+                    b"REPLCONF".to_vec(),
+                    b"ACK".to_vec(),
+                    offset.to_string().into_bytes(),
+                ]);
+                if let Err(e) = stream.write_all(&ack).await {
+                    println!("[ERROR] : Connection to master lost: {}", e);
+                    return;
+                }
+                continue;
+            }
+        };
+        match read {
+            Ok(0) => {
+                println!("[INFO] : Connection to master closed");
+                return;
+            }
+            Ok(_) => state.lock().unwrap().master_last_io = Some(Instant::now()),
+            Err(e) => {
+                println!("[ERROR] : Connection to master lost: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Loads the dataset at startup: from the AOF when it is enabled and exists,
+/// otherwise from the RDB file. Exits if the files are corrupt.
+fn load_dataset(
+    storage: &Arc<Databases>,
+    state: &Arc<Mutex<RedisReplicationState>>,
+    config: &Arc<RwLock<Config>>,
+    stats: &Arc<Stats>,
+    persistence: &Arc<Persistence>,
+    pubsub: &Arc<PubSub>,
+) {
+    let settings = config.read().unwrap().clone();
+    let mut aof_loaded = false;
+    if settings.appendonly {
+        // The AOF replays like a client whose writes are already logged.
+        let id = NEXT_CLIENT_ID.fetch_add(1, AtomicOrdering::Relaxed);
+        let mut client = Client {
+            id,
+            peer_ip: String::new(),
+            listening_port: 0,
+            selected_db: 0,
+            master_link: false,
+            replication_feed: None,
+            pending_snapshot: None,
+            write_offset: 0,
+            transaction: None,
+            watched: Vec::new(),
+            subscriptions: Subscriptions::new(pubsub.clone(), id),
+        };
+        let started = Instant::now();
+        let loaded = aof::load(
+            &settings.aof_dir(),
+            &settings.appendfilename,
+            settings.aof_load_truncated,
+            |snapshot| {
+                persistence::warn_ignored_functions(&snapshot);
+                storage.load(snapshot.databases)
+            },
+            |request| {
+                handle_request(
+                    request,
+                    storage.clone(),
+                    state.clone(),
+                    config.clone(),
+                    stats.clone(),
+                    persistence.clone(),
+                    &mut client,
+                );
+            },
+        );
+        match loaded {
+            Ok(loaded) => aof_loaded = loaded,
+            Err(e) => {
+                println!("[ERROR] : Fatal error loading the AOF: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if aof_loaded {
+            println!(
+                "[INFO] : DB loaded from append only file: {:.3} seconds",
+                started.elapsed().as_secs_f64()
+            );
+        }
+    }
+    if !aof_loaded {
+        let rdb_path = settings.rdb_path();
+        match persistence::load(storage, &rdb_path) {
+            Ok(true) => {}
+            Ok(false) => println!("[INFO] : No RDB file at {}", rdb_path.display()),
+            Err(e) => {
+                println!("[ERROR] : Fatal error loading the DB {}: {}", rdb_path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    persistence.dirty.store(0, AtomicOrdering::Relaxed);
+
+    if settings.appendonly {
+        if let Err(e) = persistence.enable_aof(storage, &settings, !aof_loaded) {
+            println!("[ERROR] : Can't open the append-only file: {}", e);
+            std::process::exit(1);
+        }
     }
-    address += port.as_str();
+}
+
+/// Completes when the process is asked to stop with Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate =
+        signal::unix::signal(SignalKind::terminate()).expect("[ERROR] Could not listen for SIGTERM");
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    println!("[INFO] : Logs will appear here!");
 
+    let mut replication_state = RedisReplicationState::new();
+    let arguments: Vec<String> = args().collect();
+    let startup_config = Config::from_arguments(&arguments);
+    let storage_struct = Arc::new(Databases::new(startup_config.databases));
+    let persistence = Arc::new(Persistence::new());
+    let pubsub = Arc::new(PubSub::new());
+    replication_state.repl_backlog_size = startup_config.repl_backlog_size;
+    replication_state.listening_port = startup_config.port;
+    let address = format!("127.0.0.1:{}", startup_config.port);
+    let config = Arc::new(RwLock::new(startup_config.clone()));
+
+    let mut master_address = None;
+    if let Some(index) = arguments.iter().position(|arg| arg == "--replicaof") {
+        let host_port: Vec<&str> = arguments
+            .get(index + 1)
+            .map(|value| value.split_whitespace().collect())
+            .unwrap_or_default();
+        match host_port[..] {
+            [host, port] if port.parse::<u16>().is_ok() => {
+                println!("[INFO] : Replicating master {}:{}", host, port);
+                replication_state.role = Role::Slave;
+                master_address = Some((host.to_string(), port.to_string()));
+            }
+            _ => println!("[ERROR] : Invalid replicaof, expected \"<host> <port>\""),
+        }
+    }
     let replication_state_arc = Arc::new(Mutex::new(replication_state));
-    let listener = TcpListener::bind(address).unwrap();
-    for stream in listener.incoming() {
+    let stats = Arc::new(Stats::new(startup_config.port, startup_config.maxclients));
+    load_dataset(
+        &storage_struct,
+        &replication_state_arc,
+        &config,
+        &stats,
+        &persistence,
+        &pubsub,
+    );
+
+    if let Some((host, port)) = master_address {
         let storage = Arc::clone(&storage_struct);
         let state = Arc::clone(&replication_state_arc);
-        match stream {
-            Ok(stream) => {
-                // Spawn a new thread to handle the client
-                thread::spawn(move || {
-                    handle_client(stream, storage, state);
+        let task = tokio::spawn(serve_master_link(
+            (host.clone(), port.clone()),
+            storage,
+            Arc::clone(&state),
+            Arc::clone(&config),
+            Arc::clone(&stats),
+            Arc::clone(&persistence),
+            Arc::clone(&pubsub),
+            false,
+        ));
+        let mut state_locked = replication_state_arc.lock().unwrap();
+        state_locked.master_address = Some((host, port));
+        state_locked.master_link_task = Some(task);
+    }
+
+    let ping_state = Arc::clone(&replication_state_arc);
+    tokio::spawn(async move {
+        let mut interval = time::interval(REPL_PING_PERIOD);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            ping_state.lock().unwrap().ping_replicas();
+        }
+    });
+
+    // Actively expire hash fields so that idle hashes do not keep stale data.
+    // Replicas leave this to their master, which propagates the removals as HDEL.
+    let expiry_storage = Arc::clone(&storage_struct);
+    let expiry_state = Arc::clone(&replication_state_arc);
+    let expiry_persistence = Arc::clone(&persistence);
+    let sampled_stats = Arc::clone(&stats);
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            sampled_stats.sample_ops();
+            if matches!(expiry_state.lock().unwrap().role, Role::Slave) {
+                continue;
+            }
+            let _command = expiry_storage.lock_command();
+            let now = chrono::Utc::now().timestamp_millis();
+            for (db, keyspace) in expiry_storage.iter().enumerate() {
+                keyspace.for_each_shard(|shard| {
+                    let commands = shard
+                        .expire_hash_fields(now)
+                        .into_iter()
+                        .map(|(key, fields)| hdel_command(key, fields))
+                        .collect();
+                    propagate(&expiry_state, &expiry_persistence, db, commands);
                 });
             }
+        }
+    });
+
+    // Snapshot the dataset whenever one of the save rules is met.
+    let cron_storage = Arc::clone(&storage_struct);
+    let cron_config = Arc::clone(&config);
+    let cron_persistence = Arc::clone(&persistence);
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            let config = cron_config.read().unwrap().clone();
+            // Snapshots never capture half of a transaction.
+            let _command = cron_storage.lock_command();
+            cron_persistence.check_save_rules(&cron_storage, &config);
+        }
+    });
+
+    // Flush the AOF once a second under `appendfsync everysec`.
+    let fsync_persistence = Arc::clone(&persistence);
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            if let Some(file) = fsync_persistence.aof_pending_sync() {
+                let synced = tokio::task::spawn_blocking(move || file.sync_data()).await;
+                if let Ok(Err(e)) = synced {
+                    println!("[ERROR] : Could not fsync the AOF file: {}", e);
+                }
+            }
+        }
+    });
+
+    let listener = TcpListener::bind(address).await.unwrap();
+    let client_limit = Arc::new(Semaphore::new(startup_config.maxclients));
+
+    // Connections subscribe to `notify_shutdown` and hold a clone of
+    // `shutdown_complete` until they finish, so dropping both here and waiting on
+    // the receiver lets in-flight requests complete before the process exits.
+    let (notify_shutdown, _) = broadcast::channel::<()>(1);
+    let (shutdown_complete, mut shutdown_completed) = mpsc::channel::<()>(1);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => {
+                println!("[INFO] : Shutting down");
+                break;
+            }
+        };
+        let mut stream = match accepted {
+            Ok((stream, _)) => {
+                // Small pipelined replies must not wait on delayed ACKs.
+                let _ = stream.set_nodelay(true);
+                stream
+            }
             Err(e) => {
                 println!("[ERROR] : {}", e);
+                continue;
+            }
+        };
+
+        stats
+            .total_connections_received
+            .fetch_add(1, AtomicOrdering::Relaxed);
+        let permit = match Arc::clone(&client_limit).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                stats.rejected_connections.fetch_add(1, AtomicOrdering::Relaxed);
+                tokio::spawn(async move {
+                    let _ = stream
+                        .write_all(b"-ERR max number of clients reached\r\n")
+                        .await;
+                });
+                continue;
+            }
+        };
+        let storage = Arc::clone(&storage_struct);
+        let state = Arc::clone(&replication_state_arc);
+        let config = Arc::clone(&config);
+        let stats = Arc::clone(&stats);
+        let persistence = Arc::clone(&persistence);
+        let pubsub = Arc::clone(&pubsub);
+        let shutdown = notify_shutdown.subscribe();
+        let completed = shutdown_complete.clone();
+        stats.connected_clients.fetch_add(1, AtomicOrdering::Relaxed);
+        tokio::spawn(async move {
+            handle_client(
+                stream,
+                storage,
+                state,
+                config,
+                Arc::clone(&stats),
+                persistence,
+                pubsub,
+                shutdown,
+            )
+            .await;
+            stats.connected_clients.fetch_sub(1, AtomicOrdering::Relaxed);
+            drop(permit);
+            drop(completed);
+        });
+    }
+
+    drop(notify_shutdown);
+    drop(shutdown_complete);
+    let _ = shutdown_completed.recv().await;
+
+    persistence.sync_aof();
+    let config = config.read().unwrap().clone();
+    if !config.save.is_empty() {
+        println!("[INFO] : Saving the final RDB snapshot before exiting");
+        // Wait out a background save still writing the file, then save over it.
+        loop {
+            match persistence.save(&storage_struct, &config.rdb_path()) {
+                Ok(true) => break,
+                Ok(false) => time::sleep(Duration::from_millis(10)).await,
+                Err(e) => {
+                    println!("[ERROR] : Error trying to save the DB: {}", e);
+                    break;
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::task::JoinHandle;
+
+    /// A connection served by `handle_client`, and what stops it.
+    async fn connect() -> (TcpStream, Arc<Stats>, broadcast::Sender<()>, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let stats = Arc::new(Stats::new(0, 1));
+        let (notify_shutdown, shutdown) = broadcast::channel::<()>(1);
+        let client = tokio::spawn(handle_client(
+            stream,
+            Arc::new(Databases::new(1)),
+            Arc::new(Mutex::new(RedisReplicationState::new())),
+            Arc::new(RwLock::new(Config::default())),
+            Arc::clone(&stats),
+            Arc::new(Persistence::new()),
+            Arc::new(PubSub::new()),
+            shutdown,
+        ));
+        (peer, stats, notify_shutdown, client)
+    }
+
+    async fn send(peer: &mut TcpStream, parts: &[&str]) -> Vec<u8> {
+        let parts: Vec<Vec<u8>> = parts.iter().map(|part| part.as_bytes().to_vec()).collect();
+        peer.write_all(&to_command_array(&parts)).await.unwrap();
+        let mut reply = vec![0; 512];
+        let read = peer.read(&mut reply).await.unwrap();
+        reply.truncate(read);
+        reply
+    }
+
+    #[tokio::test]
+    async fn shutdown_interrupts_a_blocked_wait() {
+        let (mut peer, stats, notify_shutdown, client) = connect().await;
+
+        // Without replicas, WAIT 1 0 blocks until the connection is told to stop.
+        peer.write_all(b"*3\r\n$4\r\nWAIT\r\n$1\r\n1\r\n$1\r\n0\r\n")
+            .await
+            .unwrap();
+        while stats.blocked_clients.load(AtomicOrdering::Relaxed) == 0 {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(notify_shutdown);
+        time::timeout(Duration::from_secs(5), client)
+            .await
+            .expect("the connection outlived the shutdown")
+            .unwrap();
+        let mut reply = Vec::new();
+        peer.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn short_commands_are_refused_before_running() {
+        let (mut peer, _stats, _notify_shutdown, _client) = connect().await;
+        assert_eq!(
+            send(&mut peer, &["GET"]).await,
+            b"-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            send(&mut peer, &["SET", "k"]).await,
+            b"-ERR wrong number of arguments for 'set' command\r\n"
+        );
+        assert_eq!(
+            send(&mut peer, &["SET"]).await,
+            b"-ERR wrong number of arguments for 'set' command\r\n"
+        );
+        assert_eq!(
+            send(&mut peer, &["NOPE"]).await,
+            b"-ERR Unknown command 'NOPE'\r\n"
+        );
+
+        // The shards were never locked by the refused commands.
+        assert_eq!(send(&mut peer, &["SET", "k", "v"]).await, b"+OK\r\n");
+        assert_eq!(send(&mut peer, &["GET", "k"]).await, b"$1\r\nv\r\n");
+    }
+}
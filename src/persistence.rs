@@ -0,0 +1,699 @@
+use crate::aof::{AppendOnlyFile, RewriteTarget};
+use crate::config::Config;
+use crate::database::Databases;
+use crate::rdb::{self, Snapshot};
+use crate::resp_parser::{
+    string_to_simple_resp, to_error, to_integer, wrong_arguments, Command, RespRequest,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Numbers the temporary files snapshots are written to, so that two saves
+/// never write the same file.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Seconds to wait after a failed background save before a save rule may
+/// trigger another one.
+const BGSAVE_RETRY_DELAY: i64 = 5;
+
+/// State of saving the dataset to the RDB file.
+#[derive(Debug)]
+pub struct Persistence {
+    /// Writes since the last successful save.
+    pub dirty: AtomicU64,
+    status: Mutex<SaveStatus>,
+    aof: Mutex<AofStatus>,
+}
+
+#[derive(Debug)]
+struct SaveStatus {
+    /// Unix time of the last successful save, or of startup.
+    last_save: i64,
+    /// When the running background save started.
+    bgsave_started: Option<Instant>,
+    /// Whether a SAVE is writing the file, which background saves wait out.
+    save_running: bool,
+    /// `dirty` when the running background save took its snapshot.
+    dirty_before_bgsave: u64,
+    last_bgsave_ok: bool,
+    /// Unix time the last background save was attempted.
+    last_bgsave_try: i64,
+    last_bgsave_duration: Option<Duration>,
+    saves: u64,
+}
+
+#[derive(Debug)]
+struct AofStatus {
+    /// The open append-only file while `appendonly` is on.
+    file: Option<AppendOnlyFile>,
+    rewrite_started: Option<Instant>,
+    last_rewrite_ok: bool,
+    last_rewrite_duration: Option<Duration>,
+    rewrites: u64,
+    /// Why the last append failed, until one succeeds again.
+    last_write_error: Option<String>,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Self {
+            dirty: AtomicU64::new(0),
+            status: Mutex::new(SaveStatus {
+                last_save: chrono::Utc::now().timestamp(),
+                bgsave_started: None,
+                save_running: false,
+                dirty_before_bgsave: 0,
+                last_bgsave_ok: true,
+                last_bgsave_try: 0,
+                last_bgsave_duration: None,
+                saves: 0,
+            }),
+            aof: Mutex::new(AofStatus {
+                file: None,
+                rewrite_started: None,
+                last_rewrite_ok: true,
+                last_rewrite_duration: None,
+                rewrites: 0,
+                last_write_error: None,
+            }),
+        }
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.status.lock().unwrap().bgsave_started.is_some()
+    }
+
+    /// Saves the dataset, blocking the caller until the file is written.
+    /// Returns false if another save is running.
+    pub fn save(&self, storage: &Databases, path: &Path) -> io::Result<bool> {
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.bgsave_started.is_some() || status.save_running {
+                return Ok(false);
+            }
+            status.save_running = true;
+        }
+        let mut dirty = 0;
+        let snapshot = Snapshot {
+            databases: storage.snapshot(|| dirty = self.dirty.load(Ordering::Relaxed)),
+            aux: aux_fields(),
+            functions: Vec::new(),
+        };
+        let result = write_snapshot(path, &snapshot);
+        let mut status = self.status.lock().unwrap();
+        status.save_running = false;
+        result?;
+        self.clear_dirty(dirty);
+        status.last_save = chrono::Utc::now().timestamp();
+        status.last_bgsave_ok = true;
+        status.saves += 1;
+        println!("[INFO] : DB saved on disk");
+        Ok(true)
+    }
+
+    /// Takes the `saved` writes that made it into a snapshot off `dirty`. It
+    /// may have been reset meanwhile, so it never goes below zero.
+    fn clear_dirty(&self, saved: u64) {
+        let _ = self
+            .dirty
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |dirty| {
+                Some(dirty.saturating_sub(saved))
+            });
+    }
+
+    /// Copies the dataset and writes it on a blocking task. Returns false if
+    /// another save is already running.
+    pub fn background_save(self: &Arc<Self>, storage: &Databases, path: PathBuf) -> bool {
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.bgsave_started.is_some() || status.save_running {
+                return false;
+            }
+            status.bgsave_started = Some(Instant::now());
+            status.last_bgsave_try = chrono::Utc::now().timestamp();
+        }
+        let mut dirty = 0;
+        let snapshot = Snapshot {
+            databases: storage.snapshot(|| dirty = self.dirty.load(Ordering::Relaxed)),
+            aux: aux_fields(),
+            functions: Vec::new(),
+        };
+        self.status.lock().unwrap().dirty_before_bgsave = dirty;
+        let persistence = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let result = write_snapshot(&path, &snapshot);
+            let mut status = persistence.status.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    // Writes made while saving are not in the file and stay dirty.
+                    persistence.clear_dirty(status.dirty_before_bgsave);
+                    status.last_save = chrono::Utc::now().timestamp();
+                    status.saves += 1;
+                    println!("[INFO] : Background saving terminated with success");
+                }
+                Err(ref e) => println!("[ERROR] : Background saving error: {}", e),
+            }
+            status.last_bgsave_ok = result.is_ok();
+            status.last_bgsave_duration = status.bgsave_started.take().map(|started| started.elapsed());
+        });
+        true
+    }
+
+    /// Starts a background save when one of the `save <seconds> <changes>` rules
+    /// is met: at least `changes` writes and `seconds` since the last save.
+    /// Called periodically.
+    pub fn check_save_rules(self: &Arc<Self>, storage: &Databases, config: &Config) {
+        let now = chrono::Utc::now().timestamp();
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let rule = {
+            let status = self.status.lock().unwrap();
+            if status.bgsave_started.is_some() || status.save_running {
+                return;
+            }
+            // After a failure, retry only once in a while.
+            let may_retry =
+                status.last_bgsave_ok || now - status.last_bgsave_try > BGSAVE_RETRY_DELAY;
+            config.save.iter().find(|(seconds, changes)| {
+                dirty >= *changes && now - status.last_save > *seconds as i64 && may_retry
+            })
+        };
+        if let Some((seconds, changes)) = rule {
+            println!(
+                "[INFO] : {} changes in {} seconds. Saving...",
+                changes, seconds
+            );
+            self.background_save(storage, config.rdb_path());
+        }
+    }
+
+    /// Appends `commands`, executed against database `db`, to the AOF if it is on.
+    /// Called under the command's shard locks, so writes to a key are logged in
+    /// the order they ran.
+    pub fn append(&self, db: usize, commands: &[Vec<Vec<u8>>]) {
+        let mut aof = self.aof.lock().unwrap();
+        let Some(file) = aof.file.as_mut() else {
+            return;
+        };
+        match file.append(db, commands) {
+            Ok(()) => aof.last_write_error = None,
+            Err(e) => {
+                println!("[ERROR] : Error writing to the AOF file: {}", e);
+                aof.last_write_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Opens the append-only file. Unless `rewrite` is false, because the AOF
+    /// was just loaded, a rewrite stores the current dataset as its base.
+    pub fn enable_aof(
+        self: &Arc<Self>,
+        storage: &Databases,
+        config: &Config,
+        rewrite: bool,
+    ) -> Result<(), String> {
+        let (file, _) =
+            AppendOnlyFile::open(config.aof_dir(), &config.appendfilename, config.appendfsync)?;
+        self.aof.lock().unwrap().file = Some(file);
+        if rewrite {
+            self.rewrite_aof(storage, config);
+        }
+        Ok(())
+    }
+
+    pub fn disable_aof(&self) {
+        let mut aof = self.aof.lock().unwrap();
+        if let Some(file) = aof.file.take() {
+            if let Err(e) = file.sync() {
+                println!("[ERROR] : Could not fsync the AOF file: {}", e);
+            }
+        }
+        aof.last_write_error = None;
+    }
+
+    /// Applies changes of the AOF parameters made with CONFIG SET.
+    pub fn reconfigure(self: &Arc<Self>, storage: &Databases, old: &Config, new: &Config) {
+        if new.appendonly && !old.appendonly {
+            if let Err(e) = self.enable_aof(storage, new, true) {
+                println!("[ERROR] : Could not open the AOF: {}", e);
+            }
+        } else if !new.appendonly && old.appendonly {
+            self.disable_aof();
+        }
+        if let Some(file) = self.aof.lock().unwrap().file.as_mut() {
+            file.fsync = new.appendfsync;
+        }
+    }
+
+    /// A handle on the AOF to fsync, if `everysec` writes are waiting for it.
+    pub fn aof_pending_sync(&self) -> Option<File> {
+        let mut aof = self.aof.lock().unwrap();
+        match aof.file.as_mut()?.take_unsynced()? {
+            Ok(file) => Some(file),
+            Err(e) => {
+                println!("[ERROR] : Could not fsync the AOF file: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Flushes the AOF to disk, before the server exits.
+    pub fn sync_aof(&self) {
+        if let Some(file) = self.aof.lock().unwrap().file.as_ref() {
+            if let Err(e) = file.sync() {
+                println!("[ERROR] : Could not fsync the AOF file: {}", e);
+            }
+        }
+    }
+
+    /// Replaces the AOF files with a base holding the current dataset, written
+    /// on a blocking task. Returns false if a rewrite is already running.
+    pub fn rewrite_aof(self: &Arc<Self>, storage: &Databases, config: &Config) -> bool {
+        {
+            let mut aof = self.aof.lock().unwrap();
+            if aof.rewrite_started.is_some() {
+                return false;
+            }
+            aof.rewrite_started = Some(Instant::now());
+        }
+        // Writes after the snapshot go to a new incremental file, the rest to the base.
+        let mut target = Err(String::new());
+        let databases = storage.snapshot(|| {
+            target = match self.aof.lock().unwrap().file.as_mut() {
+                Some(file) => file.start_rewrite().map_err(|e| e.to_string()),
+                None => RewriteTarget::offline(config.aof_dir(), &config.appendfilename),
+            };
+        });
+        let target = match target {
+            Ok(target) => target,
+            Err(e) => {
+                self.finish_aof_rewrite(Err(e));
+                return true;
+            }
+        };
+        let mut aux = aux_fields();
+        aux.push(("aof-base".to_string(), b"1".to_vec()));
+        let persistence = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let name = target.write_base(&Snapshot {
+                databases,
+                aux,
+                functions: Vec::new(),
+            });
+            let result = name.map_err(|e| e.to_string()).and_then(|name| {
+                let mut aof = persistence.aof.lock().unwrap();
+                match aof.file.as_mut() {
+                    Some(file) => file.finish_rewrite(&name, target.seq),
+                    None => target.finish_offline(&name),
+                }
+                .map_err(|e| e.to_string())
+            });
+            persistence.finish_aof_rewrite(result);
+        });
+        true
+    }
+
+    fn finish_aof_rewrite(&self, result: Result<(), String>) {
+        let mut aof = self.aof.lock().unwrap();
+        match &result {
+            Ok(()) => {
+                aof.rewrites += 1;
+                println!("[INFO] : Background AOF rewrite finished successfully");
+            }
+            Err(e) => {
+                if let Some(file) = aof.file.as_mut() {
+                    file.abort_rewrite();
+                }
+                println!("[ERROR] : Background AOF rewrite failed: {}", e);
+            }
+        }
+        aof.last_rewrite_ok = result.is_ok();
+        aof.last_rewrite_duration = aof.rewrite_started.take().map(|started| started.elapsed());
+    }
+
+    /// Refuses writes with MISCONF while data cannot be persisted: when the last
+    /// background save failed and `stop-writes-on-bgsave-error` is set, or when
+    /// appending to the AOF fails.
+    pub fn write_refusal(&self, request: &RespRequest, config: &Config) -> Option<Vec<u8>> {
+        if !request.is_write() {
+            return None;
+        }
+        if config.stop_writes_on_bgsave_error
+            && !config.save.is_empty()
+            && !self.status.lock().unwrap().last_bgsave_ok
+        {
+            return Some(to_error(
+                "MISCONF Redis is configured to save RDB snapshots, but it's currently unable to \
+                 persist to disk. Commands that may modify the data set are disabled, because this \
+                 instance is configured to report errors during writes if RDB snapshotting fails \
+                 (stop-writes-on-bgsave-error option). Please check the Redis logs for details \
+                 about the RDB error.",
+            ));
+        }
+        let aof = self.aof.lock().unwrap();
+        let error = aof.last_write_error.as_ref()?;
+        Some(to_error(&format!(
+            "MISCONF Errors writing to the AOF file: {}",
+            error
+        )))
+    }
+
+    /// The RDB and AOF lines of INFO persistence.
+    pub fn info(&self) -> Vec<String> {
+        let status = self.status.lock().unwrap();
+        let aof = self.aof.lock().unwrap();
+        let seconds = |duration: Option<Duration>| {
+            duration
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(-1)
+        };
+        vec![
+            format!(
+                "rdb_changes_since_last_save:{}",
+                self.dirty.load(Ordering::Relaxed)
+            ),
+            format!(
+                "rdb_bgsave_in_progress:{}",
+                status.bgsave_started.is_some() as u8
+            ),
+            format!("rdb_last_save_time:{}", status.last_save),
+            format!(
+                "rdb_last_bgsave_status:{}",
+                if status.last_bgsave_ok { "ok" } else { "err" }
+            ),
+            format!(
+                "rdb_last_bgsave_time_sec:{}",
+                seconds(status.last_bgsave_duration)
+            ),
+            format!(
+                "rdb_current_bgsave_time_sec:{}",
+                seconds(status.bgsave_started.map(|started| started.elapsed()))
+            ),
+            format!("rdb_saves:{}", status.saves),
+            format!("aof_enabled:{}", aof.file.is_some() as u8),
+            format!(
+                "aof_rewrite_in_progress:{}",
+                aof.rewrite_started.is_some() as u8
+            ),
+            "aof_rewrite_scheduled:0".to_string(),
+            format!(
+                "aof_last_rewrite_time_sec:{}",
+                seconds(aof.last_rewrite_duration)
+            ),
+            format!(
+                "aof_current_rewrite_time_sec:{}",
+                seconds(aof.rewrite_started.map(|started| started.elapsed()))
+            ),
+            format!(
+                "aof_last_bgrewrite_status:{}",
+                if aof.last_rewrite_ok { "ok" } else { "err" }
+            ),
+            format!("aof_rewrites:{}", aof.rewrites),
+            format!(
+                "aof_last_write_status:{}",
+                if aof.last_write_error.is_none() { "ok" } else { "err" }
+            ),
+        ]
+    }
+}
+
+/// AUX fields describing the server that wrote a snapshot.
+pub fn aux_fields() -> Vec<(String, Vec<u8>)> {
+    vec![
+        ("redis-ver".to_string(), b"7.2.0".to_vec()),
+        (
+            "redis-bits".to_string(),
+            usize::BITS.to_string().into_bytes(),
+        ),
+        (
+            "ctime".to_string(),
+            chrono::Utc::now().timestamp().to_string().into_bytes(),
+        ),
+    ]
+}
+
+/// Writes the snapshot to a temporary file in the same directory and renames
+/// it over `path`, so a crash never leaves a truncated RDB file behind.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let payload = rdb::encode(snapshot);
+    let temp = path.with_file_name(format!(
+        "temp-{}-{}.rdb",
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(&payload)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Loads the RDB file at `path` into the empty dataset. Returns false if there
+/// is no file.
+pub fn load(storage: &Databases, path: &Path) -> Result<bool, String> {
+    let started = Instant::now();
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.to_string()),
+    };
+    let snapshot = rdb::decode(&data).map_err(|e| e.to_string())?;
+    warn_ignored_functions(&snapshot);
+    storage.load(snapshot.databases)?;
+    println!(
+        "[INFO] : DB loaded from disk: {:.3} seconds",
+        started.elapsed().as_secs_f64()
+    );
+    Ok(true)
+}
+
+/// Function libraries need scripting, which this server doesn't have.
+pub fn warn_ignored_functions(snapshot: &Snapshot) {
+    if !snapshot.functions.is_empty() {
+        println!(
+            "[INFO] : Ignoring {} function libraries, scripting is not supported",
+            snapshot.functions.len()
+        );
+    }
+}
+
+/// SAVE, BGSAVE, LASTSAVE and BGREWRITEAOF.
+pub fn handle_save_command(
+    request: &RespRequest,
+    storage: &Databases,
+    config: &Config,
+    persistence: &Arc<Persistence>,
+) -> Vec<u8> {
+    match request.command {
+        Command::Save => {
+            if !request.arguments.is_empty() {
+                return wrong_arguments("save");
+            }
+            match persistence.save(storage, &config.rdb_path()) {
+                Ok(true) => string_to_simple_resp("OK", '+').into_bytes(),
+                Ok(false) => to_error("ERR Background save already in progress"),
+                Err(e) => {
+                    println!("[ERROR] : Failed saving the DB: {}", e);
+                    to_error("ERR")
+                }
+            }
+        }
+        Command::Bgsave => {
+            if !request.arguments.is_empty() {
+                return to_error("ERR syntax error");
+            }
+            if persistence.background_save(storage, config.rdb_path()) {
+                println!("[INFO] : Background saving started");
+                string_to_simple_resp("Background saving started", '+').into_bytes()
+            } else {
+                to_error("ERR Background save already in progress")
+            }
+        }
+        Command::Lastsave => {
+            if !request.arguments.is_empty() {
+                return wrong_arguments("lastsave");
+            }
+            to_integer(persistence.status.lock().unwrap().last_save)
+        }
+        Command::Bgrewriteaof => {
+            if !request.arguments.is_empty() {
+                return wrong_arguments("bgrewriteaof");
+            }
+            if persistence.rewrite_aof(storage, config) {
+                println!("[INFO] : Background append only file rewriting started");
+                string_to_simple_resp("Background append only file rewriting started", '+')
+                    .into_bytes()
+            } else {
+                to_error("ERR Background append only file rewriting already in progress")
+            }
+        }
+        _ => to_error("ERR unknown persistence command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::Entry;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, RedisValue};
+
+    /// An empty directory of its own for each test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("persistence-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config_in(dir: &Path) -> Config {
+        let mut config = Config::default();
+        config.set("dir", dir.to_str().unwrap()).unwrap();
+        config
+    }
+
+    fn run(
+        storage: &Databases,
+        config: &Config,
+        persistence: &Arc<Persistence>,
+        parts: &[&str],
+    ) -> Vec<u8> {
+        handle_save_command(&request(parts), storage, config, persistence)
+    }
+
+    #[test]
+    fn save_writes_what_load_reads_back() {
+        let dir = scratch("save");
+        let config = config_in(&dir);
+        let persistence = Arc::new(Persistence::new());
+        let storage = Databases::new(4);
+        let deadline = chrono::Utc::now().timestamp_millis() + 60_000;
+        storage.get(2).lock_all(Access::Write).restore(Entry {
+            key: "k".to_string(),
+            value: RedisValue::String(b"v".to_vec()),
+            deadline: Some(deadline),
+        });
+        persistence.dirty.store(3, Ordering::Relaxed);
+
+        assert_eq!(run(&storage, &config, &persistence, &["SAVE"]), b"+OK\r\n");
+        assert_eq!(persistence.dirty.load(Ordering::Relaxed), 0);
+        assert!(persistence.info().contains(&"rdb_saves:1".to_string()));
+        let snapshot = rdb::decode(&fs::read(config.rdb_path()).unwrap()).unwrap();
+        assert_eq!(snapshot.aux_field("redis-ver"), Some(&b"7.2.0"[..]));
+
+        let loaded = Databases::new(4);
+        assert_eq!(load(&loaded, &config.rdb_path()), Ok(true));
+        let keyspace = loaded.get(2).lock_all(Access::Read);
+        assert!(
+            matches!(keyspace.get(&"k".to_string()), Some(RedisValue::String(value)) if value == b"v")
+        );
+        assert_eq!(keyspace.deadline(&"k".to_string()), Some(deadline));
+        assert_eq!(
+            load(&loaded, &config.rdb_path().with_file_name("missing.rdb")),
+            Ok(false)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saves_do_not_overlap() {
+        let dir = scratch("overlap");
+        let mut config = config_in(&dir);
+        config.set("save", "1 1").unwrap();
+        let persistence = Arc::new(Persistence::new());
+        let storage = Databases::new(1);
+        persistence.status.lock().unwrap().last_save = 0;
+        persistence.dirty.store(5, Ordering::Relaxed);
+
+        // While a SAVE writes the file, no other save starts.
+        persistence.status.lock().unwrap().save_running = true;
+        let refused = b"-ERR Background save already in progress\r\n";
+        assert_eq!(run(&storage, &config, &persistence, &["SAVE"]), refused);
+        assert_eq!(run(&storage, &config, &persistence, &["BGSAVE"]), refused);
+        persistence.check_save_rules(&storage, &config);
+        assert!(!persistence.bgsave_in_progress());
+        assert!(!config.rdb_path().exists());
+        persistence.status.lock().unwrap().save_running = false;
+
+        // A reset in the meantime leaves fewer writes than the save took off.
+        persistence.clear_dirty(8);
+        assert_eq!(persistence.dirty.load(Ordering::Relaxed), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_save_refuses_writes() {
+        let config = Config::default();
+        let persistence = Persistence::new();
+        let write = request(&["SET", "k", "v"]);
+        let read = request(&["GET", "k"]);
+        assert_eq!(persistence.write_refusal(&write, &config), None);
+
+        persistence.status.lock().unwrap().last_bgsave_ok = false;
+        let refusal = persistence.write_refusal(&write, &config).unwrap();
+        assert!(refusal.starts_with(b"-MISCONF Redis is configured to save RDB snapshots"));
+        assert_eq!(persistence.write_refusal(&read, &config), None);
+
+        // Without save rules, or with the check disabled, writes go on.
+        let mut relaxed = config.clone();
+        relaxed.set("stop-writes-on-bgsave-error", "no").unwrap();
+        assert_eq!(persistence.write_refusal(&write, &relaxed), None);
+        relaxed = config.clone();
+        relaxed.set("save", "").unwrap();
+        assert_eq!(persistence.write_refusal(&write, &relaxed), None);
+    }
+
+    #[tokio::test]
+    async fn background_save_reports_its_outcome() {
+        let dir = scratch("bgsave");
+        let config = config_in(&dir);
+        let persistence = Arc::new(Persistence::new());
+        let storage = Databases::new(1);
+        assert_eq!(
+            run(&storage, &config, &persistence, &["BGSAVE"]),
+            b"+Background saving started\r\n"
+        );
+        while persistence.bgsave_in_progress() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(config.rdb_path().exists());
+        assert!(persistence
+            .info()
+            .contains(&"rdb_last_bgsave_status:ok".to_string()));
+
+        // A directory removed after startup makes the next save fail.
+        fs::remove_dir_all(&dir).unwrap();
+        persistence.background_save(&storage, config.rdb_path());
+        while persistence.bgsave_in_progress() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(persistence
+            .info()
+            .contains(&"rdb_last_bgsave_status:err".to_string()));
+        assert!(persistence
+            .write_refusal(&request(&["DEL", "k"]), &config)
+            .is_some());
+        assert_eq!(
+            run(&storage, &config, &persistence, &["SAVE", "now"]),
+            b"-ERR wrong number of arguments for 'save' command\r\n"
+        );
+    }
+}
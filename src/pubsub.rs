@@ -0,0 +1,569 @@
+//! Publish/subscribe: connections subscribe to channels, or to glob-style
+//! patterns of channel names, and receive every message published to them.
+//! Channels are not keys, so they are shared by all databases. Shard channels
+//! are a separate namespace whose names hash like keys, so in a cluster their
+//! messages stay on the node owning the slot.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::Instant;
+
+use crate::config::OutputBufferLimit;
+use crate::glob;
+use crate::resp_parser::{to_bulk_bytes, to_error, to_integer, wrong_arguments, Command, RespRequest};
+use crate::storage::{ShardedKeyspace, SHARD_COUNT};
+use tokio::sync::{mpsc, Notify};
+
+/// The subscribers of every channel and pattern.
+pub struct PubSub {
+    registry: Mutex<Registry>,
+    /// Shard channels, split over the same shards as keys so that publishing
+    /// to one only locks its own.
+    shard_channels: Vec<Mutex<SubscriberMap>>,
+}
+
+/// Subscribers by channel or pattern, then by client id.
+type SubscriberMap = HashMap<Vec<u8>, HashMap<u64, Arc<Subscriber>>>;
+
+#[derive(Default)]
+struct Registry {
+    channels: SubscriberMap,
+    patterns: SubscriberMap,
+}
+
+/// A connection as publishers see it: where its messages are queued until it
+/// writes them out.
+struct Subscriber {
+    client_id: u64,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    resp3: AtomicBool,
+    /// Bytes of the messages queued and not yet written to the connection.
+    pending: AtomicUsize,
+    /// When `pending` went over the soft output buffer limit, while it stays over.
+    over_soft_since: Mutex<Option<Instant>>,
+    /// Set once the output buffer limit was exceeded; the connection then closes.
+    closed: AtomicBool,
+    on_close: Notify,
+}
+
+impl Subscriber {
+    async fn closed(&self) {
+        while !self.closed.load(Ordering::Relaxed) {
+            self.on_close.notified().await;
+        }
+    }
+
+    /// Queues a message made of `parts`, unless that takes the connection over
+    /// `limit`, which gets it closed instead. Returns whether it was queued.
+    fn deliver(&self, parts: &[&[u8]], limit: &OutputBufferLimit) -> bool {
+        if self.closed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let message = push(self.resp3.load(Ordering::Relaxed), parts);
+        let pending = self.pending.fetch_add(message.len(), Ordering::Relaxed) + message.len();
+        if limit.exceeded(pending as u64, &mut self.over_soft_since.lock().unwrap()) {
+            self.closed.store(true, Ordering::Relaxed);
+            println!(
+                "[INFO] : Client id={} scheduled to be closed ASAP for overcoming of output buffer limits.",
+                self.client_id
+            );
+            self.on_close.notify_one();
+            return false;
+        }
+        self.sender.send(message).is_ok()
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self {
+            registry: Mutex::default(),
+            shard_channels: (0..SHARD_COUNT).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn shard(&self, channel: &[u8]) -> &Mutex<SubscriberMap> {
+        let channel = String::from_utf8_lossy(channel).into_owned();
+        &self.shard_channels[ShardedKeyspace::shard_index(&channel)]
+    }
+
+    /// Runs `f` on the subscribers of the kind of subscription named `name`.
+    fn with_subscribers<T>(&self, kind: Kind, name: &[u8], f: impl FnOnce(&mut SubscriberMap) -> T) -> T {
+        match kind {
+            Kind::Channel => f(&mut self.registry.lock().unwrap().channels),
+            Kind::Pattern => f(&mut self.registry.lock().unwrap().patterns),
+            Kind::ShardChannel => f(&mut self.shard(name).lock().unwrap()),
+        }
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of the patterns
+    /// matching it, and returns how many received it. A connection subscribed
+    /// both ways receives it once for each.
+    pub fn publish(&self, channel: &[u8], message: &[u8], limit: &OutputBufferLimit) -> usize {
+        let registry = self.registry.lock().unwrap();
+        let mut receivers = 0;
+        if let Some(subscribers) = registry.channels.get(channel) {
+            for subscriber in subscribers.values() {
+                if subscriber.deliver(&[b"message", channel, message], limit) {
+                    receivers += 1;
+                }
+            }
+        }
+        for (pattern, subscribers) in &registry.patterns {
+            if !glob::matches(pattern, channel) {
+                continue;
+            }
+            for subscriber in subscribers.values() {
+                if subscriber.deliver(&[b"pmessage", pattern, channel, message], limit) {
+                    receivers += 1;
+                }
+            }
+        }
+        receivers
+    }
+
+    /// Sends `message` to the subscribers of the shard channel `channel`, and
+    /// returns how many received it.
+    pub fn spublish(&self, channel: &[u8], message: &[u8], limit: &OutputBufferLimit) -> usize {
+        let shard = self.shard(channel).lock().unwrap();
+        shard.get(channel).map_or(0, |subscribers| {
+            subscribers
+                .values()
+                .filter(|subscriber| subscriber.deliver(&[b"smessage", channel, message], limit))
+                .count()
+        })
+    }
+
+    /// Channels, or shard channels, with at least one subscriber, optionally
+    /// only those matching a pattern.
+    fn channels(&self, kind: Kind, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        let matching = |channel: &&Vec<u8>| match pattern {
+            Some(pattern) => glob::matches(pattern, channel),
+            None => true,
+        };
+        match kind {
+            Kind::ShardChannel => self
+                .shard_channels
+                .iter()
+                .flat_map(|shard| {
+                    let shard = shard.lock().unwrap();
+                    shard.keys().filter(matching).cloned().collect::<Vec<_>>()
+                })
+                .collect(),
+            _ => {
+                let registry = self.registry.lock().unwrap();
+                registry.channels.keys().filter(matching).cloned().collect()
+            }
+        }
+    }
+
+    fn subscriber_count(&self, kind: Kind, channel: &[u8]) -> usize {
+        self.with_subscribers(kind, channel, |subscribers| {
+            subscribers.get(channel).map_or(0, HashMap::len)
+        })
+    }
+
+    fn pattern_count(&self) -> usize {
+        self.registry.lock().unwrap().patterns.len()
+    }
+}
+
+/// Whether a subscription is to a channel, a pattern or a shard channel.
+#[derive(Clone, Copy)]
+enum Kind {
+    Channel,
+    Pattern,
+    ShardChannel,
+}
+
+/// The subscriptions of one connection, which it leaves when dropped.
+pub struct Subscriptions {
+    pubsub: Arc<PubSub>,
+    subscriber: Arc<Subscriber>,
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    shard_channels: HashSet<Vec<u8>>,
+}
+
+impl Subscriptions {
+    pub fn new(pubsub: Arc<PubSub>, client_id: u64) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            pubsub,
+            subscriber: Arc::new(Subscriber {
+                client_id,
+                sender,
+                resp3: AtomicBool::new(false),
+                pending: AtomicUsize::new(0),
+                over_soft_since: Mutex::new(None),
+                closed: AtomicBool::new(false),
+                on_close: Notify::new(),
+            }),
+            receiver,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+        }
+    }
+
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        &self.pubsub
+    }
+
+    /// Whether the connection speaks RESP3, where messages are push frames.
+    pub fn resp3(&self) -> bool {
+        self.subscriber.resp3.load(Ordering::Relaxed)
+    }
+
+    pub fn set_resp3(&self, resp3: bool) {
+        self.subscriber.resp3.store(resp3, Ordering::Relaxed);
+    }
+
+    /// Whether the connection is subscribed to anything, which restricts the
+    /// commands a RESP2 connection may send.
+    pub fn is_active(&self) -> bool {
+        self.count(Kind::Channel) > 0 || self.count(Kind::ShardChannel) > 0
+    }
+
+    /// The number of subscriptions reported along with those of `kind`: shard
+    /// channels are counted apart from channels and patterns.
+    fn count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::ShardChannel => self.shard_channels.len(),
+            _ => self.channels.len() + self.patterns.len(),
+        }
+    }
+
+    /// Waits for the next message to write to the connection, or returns
+    /// `None` once it must be closed for exceeding its output buffer limit.
+    pub async fn next_message(&mut self) -> Option<Vec<u8>> {
+        let subscriber = &self.subscriber;
+        tokio::select! {
+            // The sender lives as long as `self`, so the channel never closes.
+            message = self.receiver.recv() => {
+                let message = message?;
+                subscriber.pending.fetch_sub(message.len(), Ordering::Relaxed);
+                Some(message)
+            }
+            _ = subscriber.closed() => None,
+        }
+    }
+
+    /// Completes once the connection must be closed for exceeding its output
+    /// buffer limit, which may happen while it is stuck writing to a slow client.
+    pub async fn closed(&self) {
+        self.subscriber.closed().await
+    }
+
+    fn subscribed(&mut self, kind: Kind) -> &mut HashSet<Vec<u8>> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+
+    fn subscribe(&mut self, kind: Kind, name: &[u8]) {
+        if self.subscribed(kind).insert(name.to_vec()) {
+            let subscriber = &self.subscriber;
+            self.pubsub.with_subscribers(kind, name, |map| {
+                map.entry(name.to_vec())
+                    .or_default()
+                    .insert(subscriber.client_id, subscriber.clone());
+            });
+        }
+    }
+
+    fn unsubscribe(&mut self, kind: Kind, name: &[u8]) {
+        if !self.subscribed(kind).remove(name) {
+            return;
+        }
+        let client_id = self.subscriber.client_id;
+        self.pubsub.with_subscribers(kind, name, |map| {
+            if let Some(subscribers) = map.get_mut(name) {
+                subscribers.remove(&client_id);
+                if subscribers.is_empty() {
+                    map.remove(name);
+                }
+            }
+        });
+    }
+
+    /// Leaves every channel, pattern and shard channel, as RESET does.
+    pub fn unsubscribe_all(&mut self) {
+        for kind in [Kind::Channel, Kind::Pattern, Kind::ShardChannel] {
+            let names: Vec<Vec<u8>> = self.subscribed(kind).iter().cloned().collect();
+            for name in names {
+                self.unsubscribe(kind, &name);
+            }
+        }
+    }
+
+    /// The reply confirming a (un)subscription: its type, the channel or
+    /// pattern, and the number of subscriptions left.
+    fn confirmation(&self, kind: Kind, action: &str, name: Option<&[u8]>) -> Vec<u8> {
+        let resp3 = self.resp3();
+        let mut reply = format!("{}3\r\n", if resp3 { '>' } else { '*' }).into_bytes();
+        reply.extend(to_bulk_bytes(action.as_bytes()));
+        match name {
+            Some(name) => reply.extend(to_bulk_bytes(name)),
+            None if resp3 => reply.extend_from_slice(b"_\r\n"),
+            None => reply.extend_from_slice(b"$-1\r\n"),
+        }
+        reply.extend(to_integer(self.count(kind) as i64));
+        reply
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        self.unsubscribe_all();
+    }
+}
+
+/// Encodes a message as an array, or in RESP3 as a push frame.
+fn push(resp3: bool, parts: &[&[u8]]) -> Vec<u8> {
+    let mut message = format!("{}{}\r\n", if resp3 { '>' } else { '*' }, parts.len()).into_bytes();
+    for part in parts {
+        message.extend(to_bulk_bytes(part));
+    }
+    message
+}
+
+/// SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE, PUNSUBSCRIBE, SSUBSCRIBE, SUNSUBSCRIBE,
+/// PUBLISH, SPUBLISH and PUBSUB. Subscribers are held to the `pubsub` output
+/// buffer `limit`.
+pub fn handle_pubsub_command(
+    request: &RespRequest,
+    subscriptions: &mut Subscriptions,
+    limit: &OutputBufferLimit,
+) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Subscribe | Command::Psubscribe | Command::Ssubscribe => {
+            let (kind, name) = match request.command {
+                Command::Subscribe => (Kind::Channel, "subscribe"),
+                Command::Psubscribe => (Kind::Pattern, "psubscribe"),
+                _ => (Kind::ShardChannel, "ssubscribe"),
+            };
+            if arguments.is_empty() {
+                return wrong_arguments(name);
+            }
+            let mut reply = vec![];
+            for argument in arguments {
+                subscriptions.subscribe(kind, &argument.bytes);
+                reply.extend(subscriptions.confirmation(kind, name, Some(&argument.bytes)));
+            }
+            reply
+        }
+        Command::Unsubscribe | Command::Punsubscribe | Command::Sunsubscribe => {
+            let (kind, name) = match request.command {
+                Command::Unsubscribe => (Kind::Channel, "unsubscribe"),
+                Command::Punsubscribe => (Kind::Pattern, "punsubscribe"),
+                _ => (Kind::ShardChannel, "sunsubscribe"),
+            };
+            // Without arguments, every subscription of the kind is left.
+            let targets: Vec<Vec<u8>> = if arguments.is_empty() {
+                subscriptions.subscribed(kind).iter().cloned().collect()
+            } else {
+                arguments.iter().map(|argument| argument.bytes.clone()).collect()
+            };
+            if targets.is_empty() {
+                return subscriptions.confirmation(kind, name, None);
+            }
+            let mut reply = vec![];
+            for target in targets {
+                subscriptions.unsubscribe(kind, &target);
+                reply.extend(subscriptions.confirmation(kind, name, Some(&target)));
+            }
+            reply
+        }
+        Command::Publish | Command::Spublish => {
+            if arguments.len() != 2 {
+                return wrong_arguments(&request.name.to_ascii_lowercase());
+            }
+            let (channel, message) = (&arguments[0].bytes, &arguments[1].bytes);
+            let receivers = match request.command {
+                Command::Publish => subscriptions.pubsub.publish(channel, message, limit),
+                _ => subscriptions.pubsub.spublish(channel, message, limit),
+            };
+            to_integer(receivers as i64)
+        }
+        Command::Pubsub => pubsub_introspection(request, &subscriptions.pubsub),
+        _ => to_error("ERR unknown pubsub command"),
+    }
+}
+
+/// PUBSUB CHANNELS|SHARDCHANNELS [pattern], PUBSUB NUMSUB|SHARDNUMSUB
+/// [channel ...] and PUBSUB NUMPAT.
+fn pubsub_introspection(request: &RespRequest, pubsub: &PubSub) -> Vec<u8> {
+    let Some(subcommand) = request.arguments.first() else {
+        return wrong_arguments("pubsub");
+    };
+    let arguments = &request.arguments[1..];
+    let subcommand_name = subcommand.content.to_ascii_uppercase();
+    let kind = match subcommand_name.as_str() {
+        "SHARDCHANNELS" | "SHARDNUMSUB" => Kind::ShardChannel,
+        _ => Kind::Channel,
+    };
+    match subcommand_name.as_str() {
+        "CHANNELS" | "SHARDCHANNELS" if arguments.len() <= 1 => {
+            let pattern = arguments.first().map(|pattern| pattern.bytes.as_slice());
+            let channels = pubsub.channels(kind, pattern);
+            let mut reply = format!("*{}\r\n", channels.len()).into_bytes();
+            for channel in channels {
+                reply.extend(to_bulk_bytes(&channel));
+            }
+            reply
+        }
+        "NUMSUB" | "SHARDNUMSUB" => {
+            let mut reply = format!("*{}\r\n", arguments.len() * 2).into_bytes();
+            for channel in arguments {
+                reply.extend(to_bulk_bytes(&channel.bytes));
+                reply.extend(to_integer(pubsub.subscriber_count(kind, &channel.bytes) as i64));
+            }
+            reply
+        }
+        "NUMPAT" if arguments.is_empty() => to_integer(pubsub.pattern_count() as i64),
+        "CHANNELS" | "SHARDCHANNELS" | "NUMPAT" => wrong_arguments(&format!(
+            "pubsub|{}",
+            subcommand.content.to_ascii_lowercase()
+        )),
+        _ => to_error(&format!(
+            "ERR unknown subcommand '{}'. Try PUBSUB HELP.",
+            subcommand.content
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutputBufferLimits;
+    use crate::resp_parser::request;
+
+    fn run(subscriptions: &mut Subscriptions, parts: &[&str]) -> Vec<u8> {
+        let limit = OutputBufferLimits::default().pubsub;
+        handle_pubsub_command(&request(parts), subscriptions, &limit)
+    }
+
+    fn received(subscriptions: &mut Subscriptions) -> Vec<u8> {
+        subscriptions.receiver.try_recv().unwrap_or_default()
+    }
+
+    #[test]
+    fn delivers_to_channel_and_pattern_subscribers() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut channel = Subscriptions::new(pubsub.clone(), 1);
+        let mut pattern = Subscriptions::new(pubsub.clone(), 2);
+        let mut publisher = Subscriptions::new(pubsub.clone(), 3);
+
+        assert_eq!(
+            run(&mut channel, &["SUBSCRIBE", "news", "sport"]),
+            b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n\
+              *3\r\n$9\r\nsubscribe\r\n$5\r\nsport\r\n:2\r\n"
+        );
+        run(&mut pattern, &["PSUBSCRIBE", "n*"]);
+        pattern.set_resp3(true);
+        assert!(channel.is_active() && pattern.is_active());
+
+        assert_eq!(run(&mut publisher, &["PUBLISH", "news", "hi"]), b":2\r\n");
+        assert_eq!(
+            received(&mut channel),
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            received(&mut pattern),
+            b">4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            run(&mut publisher, &["PUBLISH", "weather", "rain"]),
+            b":0\r\n"
+        );
+        assert_eq!(run(&mut publisher, &["SPUBLISH", "news", "hi"]), b":0\r\n");
+    }
+
+    #[test]
+    fn shard_channels_are_a_separate_namespace() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut subscriber = Subscriptions::new(pubsub.clone(), 1);
+        assert_eq!(
+            run(&mut subscriber, &["SSUBSCRIBE", "orders"]),
+            b"*3\r\n$10\r\nssubscribe\r\n$6\r\norders\r\n:1\r\n"
+        );
+        assert_eq!(run(&mut subscriber, &["PUBLISH", "orders", "x"]), b":0\r\n");
+        assert_eq!(
+            run(&mut subscriber, &["SPUBLISH", "orders", "x"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            received(&mut subscriber),
+            b"*3\r\n$8\r\nsmessage\r\n$6\r\norders\r\n$1\r\nx\r\n"
+        );
+        assert_eq!(
+            run(&mut subscriber, &["PUBSUB", "SHARDCHANNELS"]),
+            b"*1\r\n$6\r\norders\r\n"
+        );
+        assert_eq!(run(&mut subscriber, &["PUBSUB", "CHANNELS"]), b"*0\r\n");
+    }
+
+    #[test]
+    fn unsubscribing_and_dropping_leave_channels() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut first = Subscriptions::new(pubsub.clone(), 1);
+        let mut second = Subscriptions::new(pubsub.clone(), 2);
+        run(&mut first, &["SUBSCRIBE", "a", "b"]);
+        run(&mut first, &["PSUBSCRIBE", "a*"]);
+        run(&mut second, &["SUBSCRIBE", "a"]);
+        assert_eq!(
+            run(&mut second, &["PUBSUB", "NUMSUB", "a", "b", "c"]),
+            b"*6\r\n$1\r\na\r\n:2\r\n$1\r\nb\r\n:1\r\n$1\r\nc\r\n:0\r\n"
+        );
+        assert_eq!(run(&mut second, &["PUBSUB", "NUMPAT"]), b":1\r\n");
+
+        assert_eq!(
+            run(&mut first, &["UNSUBSCRIBE", "b"]),
+            b"*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:2\r\n"
+        );
+        run(&mut first, &["UNSUBSCRIBE"]);
+        assert_eq!(
+            run(&mut first, &["UNSUBSCRIBE"]),
+            b"*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:1\r\n"
+        );
+        drop(first);
+        assert_eq!(run(&mut second, &["PUBSUB", "NUMPAT"]), b":0\r\n");
+        assert_eq!(
+            run(&mut second, &["PUBSUB", "CHANNELS", "*"]),
+            b"*1\r\n$1\r\na\r\n"
+        );
+    }
+
+    #[test]
+    fn closes_subscribers_over_the_hard_limit() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut subscriber = Subscriptions::new(pubsub.clone(), 1);
+        run(&mut subscriber, &["SUBSCRIBE", "news"]);
+        let limit = OutputBufferLimit {
+            hard: 64,
+            soft: 0,
+            soft_seconds: 0,
+        };
+        assert_eq!(pubsub.publish(b"news", b"short", &limit), 1);
+        assert_eq!(pubsub.publish(b"news", &[b'x'; 64], &limit), 0);
+        assert!(subscriber.subscriber.closed.load(Ordering::Relaxed));
+        // Nothing more is queued once the connection is being closed.
+        assert_eq!(pubsub.publish(b"news", b"short", &limit), 0);
+        assert!(!received(&mut subscriber).is_empty());
+        assert!(received(&mut subscriber).is_empty());
+    }
+}
@@ -0,0 +1,1453 @@
+use crate::hash::Hash;
+use crate::listpack::{
+    encode_listpack, intset_entries, listpack_entries, ziplist_entries, zipmap_entries,
+};
+use crate::sorted_set::SortedSet;
+use crate::storage::RedisValue;
+use crate::stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use thiserror::Error;
+
+pub const RDB_VERSION: u32 = 11;
+/// Redis 7.4 files are version 12, which only adds the hash field expiry types.
+/// Files are only stamped with it when they hold such a hash.
+const RDB_VERSION_HASH_METADATA: u32 = 12;
+const MAX_LOADABLE_VERSION: u32 = RDB_VERSION_HASH_METADATA;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+/// Sorted set with scores stored as strings, written before Redis 4.
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_PRE_GA: u8 = 6;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+const TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+/// Hash whose fields carry their own deadlines, as written by Redis 7.4.
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+/// Quicklist nodes holding a single large element, or a listpack of them.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// Typed values of module data, which can be skipped without the module.
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+const MODULE_NAME_CHARSET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Stream entry flags and the most entries written to one listpack node.
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+
+/// Strings longer than this are stored LZF compressed when that saves space.
+const COMPRESSION_THRESHOLD: usize = 20;
+/// Limits of LZF: literal runs, back reference distance and length.
+const LZF_MAX_LITERAL: usize = 32;
+const LZF_MAX_OFFSET: usize = 1 << 13;
+const LZF_MAX_REFERENCE: usize = (1 << 8) + (1 << 3);
+/// The most one compressed byte can expand to, in a 3 byte back reference.
+const LZF_MAX_EXPANSION: usize = LZF_MAX_REFERENCE / 3;
+const LZF_MAX_HASH_BITS: u32 = 14;
+
+#[derive(Debug, Error)]
+pub enum RdbError {
+    #[error("not an RDB file")]
+    InvalidHeader,
+    #[error("unsupported RDB version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unexpected end of RDB file")]
+    Truncated,
+    #[error("unsupported RDB value type {0}")]
+    UnknownType(u8),
+    #[error("invalid string encoding {0}")]
+    UnknownEncoding(u8),
+    #[error("corrupt LZF compressed string")]
+    CorruptLzf,
+    #[error("RDB checksum mismatch")]
+    ChecksumMismatch,
+    #[error("corrupt {0} in RDB file")]
+    Corrupt(&'static str),
+    #[error("RDB file contains {0}, which is not supported")]
+    Unsupported(String),
+}
+
+/// A key with its value and absolute deadline in unix milliseconds.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: String,
+    pub value: RedisValue,
+    pub deadline: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// Keys of each database that holds any, by database index.
+    pub databases: Vec<(usize, Vec<Entry>)>,
+    pub aux: Vec<(String, Vec<u8>)>,
+    /// Source code of the function libraries the file carries.
+    pub functions: Vec<Vec<u8>>,
+}
+
+impl Snapshot {
+    pub fn aux_field(&self, name: &str) -> Option<&[u8]> {
+        self.aux
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let version = snapshot
+        .databases
+        .iter()
+        .flat_map(|(_, entries)| entries)
+        .fold(RDB_VERSION, |version, entry| {
+            version.max(value_version(&entry.value))
+        });
+    let mut out = format!("REDIS{:04}", version).into_bytes();
+    for (field, value) in &snapshot.aux {
+        out.push(OPCODE_AUX);
+        write_string(&mut out, field.as_bytes());
+        write_string(&mut out, value);
+    }
+    for library in &snapshot.functions {
+        out.push(OPCODE_FUNCTION2);
+        write_string(&mut out, library);
+    }
+    for (index, entries) in &snapshot.databases {
+        if entries.is_empty() {
+            continue;
+        }
+        out.push(OPCODE_SELECTDB);
+        write_length(&mut out, *index as u64);
+        out.push(OPCODE_RESIZEDB);
+        write_length(&mut out, entries.len() as u64);
+        let expiring = entries.iter().filter(|entry| entry.deadline.is_some()).count();
+        write_length(&mut out, expiring as u64);
+        for entry in entries {
+            write_entry(&mut out, entry);
+        }
+    }
+    out.push(OPCODE_EOF);
+    let checksum = crc64(0, &out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+fn write_entry(out: &mut Vec<u8>, entry: &Entry) {
+    if let Some(deadline) = entry.deadline {
+        out.push(OPCODE_EXPIRETIME_MS);
+        out.extend_from_slice(&deadline.to_le_bytes());
+    }
+    out.push(value_type(&entry.value));
+    write_string(out, entry.key.as_bytes());
+    write_value(out, &entry.value);
+}
+
+/// The type byte `write_value` lays the value out as.
+fn value_type(value: &RedisValue) -> u8 {
+    match value {
+        RedisValue::String(_) => TYPE_STRING,
+        RedisValue::List(_) => TYPE_LIST,
+        RedisValue::Set(_) => TYPE_SET,
+        RedisValue::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+        RedisValue::SortedSet(_) => TYPE_ZSET_2,
+        RedisValue::Hash(hash) if hash.has_volatile_fields() => TYPE_HASH_METADATA,
+        RedisValue::Hash(_) => TYPE_HASH,
+    }
+}
+
+/// The oldest RDB version that has the type `value_type` picks for the value.
+fn value_version(value: &RedisValue) -> u32 {
+    match value_type(value) {
+        TYPE_HASH_METADATA => RDB_VERSION_HASH_METADATA,
+        _ => RDB_VERSION,
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &RedisValue) {
+    match value {
+        RedisValue::String(value) => write_string(out, value),
+        RedisValue::List(list) => {
+            write_length(out, list.len() as u64);
+            for element in list {
+                write_string(out, element);
+            }
+        }
+        RedisValue::Set(set) => {
+            write_length(out, set.len() as u64);
+            for member in set {
+                write_string(out, member);
+            }
+        }
+        RedisValue::Stream(stream) => write_stream(out, stream),
+        RedisValue::SortedSet(set) => {
+            write_length(out, set.len() as u64);
+            for (member, score) in set.iter() {
+                write_string(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        RedisValue::Hash(hash) => {
+            let min_deadline = hash
+                .iter()
+                .filter_map(|(field, _)| hash.expires_at(field))
+                .min();
+            match min_deadline {
+                None => {
+                    write_length(out, hash.len() as u64);
+                    for (field, value) in hash.iter() {
+                        write_string(out, field);
+                        write_string(out, value);
+                    }
+                }
+                Some(min_deadline) => {
+                    out.extend_from_slice(&min_deadline.to_le_bytes());
+                    write_length(out, hash.len() as u64);
+                    for (field, value) in hash.iter() {
+                        // Deadlines are stored relative to the earliest one, 0 meaning none.
+                        let ttl = hash
+                            .expires_at(field)
+                            .map(|deadline| (deadline - min_deadline) as u64 + 1)
+                            .unwrap_or(0);
+                        write_length(out, ttl);
+                        write_string(out, field);
+                        write_string(out, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Serializes a single value as DUMP does: its type byte and RDB encoding,
+/// then the RDB version of that encoding as two little endian bytes and a
+/// CRC64 of the rest.
+pub fn dump_payload(value: &RedisValue) -> Vec<u8> {
+    let mut out = vec![value_type(value)];
+    write_value(&mut out, value);
+    out.extend_from_slice(&(value_version(value) as u16).to_le_bytes());
+    let checksum = crc64(0, &out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Reads back a value serialized by DUMP, from this server or a Redis release
+/// whose RDB version it can load.
+pub fn restore_payload(payload: &[u8]) -> Result<RedisValue, RdbError> {
+    // Too short to even hold the footer, which Redis reports as a bad checksum.
+    let Some(body_length) = payload.len().checked_sub(10) else {
+        return Err(RdbError::ChecksumMismatch);
+    };
+    let (body, footer) = payload.split_at(body_length);
+    let version = u16::from_le_bytes([footer[0], footer[1]]) as u32;
+    if version > MAX_LOADABLE_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+    let checksum = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if checksum != crc64(0, &payload[..body_length + 2]) {
+        return Err(RdbError::ChecksumMismatch);
+    }
+    let mut reader = Reader {
+        data: body,
+        position: 0,
+    };
+    let value_type = reader.byte()?;
+    let value = reader.value(value_type)?;
+    if reader.position != body.len() || is_empty_collection(&value) {
+        return Err(RdbError::Corrupt("DUMP payload"));
+    }
+    Ok(value)
+}
+
+/// Writes a stream as Redis does: listpack nodes of entries keyed by their
+/// first id, then its metadata and consumer groups.
+fn write_stream(out: &mut Vec<u8>, stream: &Stream) {
+    let entries: Vec<_> = stream.entries.iter().collect();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+    write_length(out, nodes.len() as u64);
+    for node in nodes {
+        let (master_id, master_fields) = node[0];
+        let master_fields: Vec<&Vec<u8>> = master_fields.iter().map(|(field, _)| field).collect();
+        // The master entry: live and deleted counts, then the shared field names.
+        let mut items = vec![
+            node.len().to_string().into_bytes(),
+            b"0".to_vec(),
+            master_fields.len().to_string().into_bytes(),
+        ];
+        items.extend(master_fields.iter().map(|field| field.to_vec()));
+        items.push(b"0".to_vec());
+        for (id, pairs) in node {
+            let same_fields = pairs.len() == master_fields.len()
+                && pairs
+                    .iter()
+                    .zip(&master_fields)
+                    .all(|((field, _), master)| field == *master);
+            let flags = if same_fields {
+                STREAM_ITEM_FLAG_SAMEFIELDS
+            } else {
+                0
+            };
+            let start = items.len();
+            items.push(flags.to_string().into_bytes());
+            items.push((id.ms - master_id.ms).to_string().into_bytes());
+            items.push((id.seq.wrapping_sub(master_id.seq) as i64).to_string().into_bytes());
+            if same_fields {
+                items.extend(pairs.iter().map(|(_, value)| value.clone()));
+            } else {
+                items.push(pairs.len().to_string().into_bytes());
+                for (field, value) in pairs.iter() {
+                    items.push(field.clone());
+                    items.push(value.clone());
+                }
+            }
+            // Each entry ends with its item count so the node can be walked backwards.
+            let count = items.len() - start;
+            items.push(count.to_string().into_bytes());
+        }
+        write_string(out, &master_id.to_bytes());
+        write_string(out, &encode_listpack(&items));
+    }
+    write_length(out, stream.len() as u64);
+    for id in [stream.last_id, stream.first_id, stream.max_deleted_id] {
+        write_length(out, id.ms);
+        write_length(out, id.seq);
+    }
+    write_length(out, stream.entries_added);
+    write_length(out, stream.groups.len() as u64);
+    for group in &stream.groups {
+        write_string(out, &group.name);
+        write_length(out, group.last_id.ms);
+        write_length(out, group.last_id.seq);
+        write_length(out, group.entries_read as u64);
+        write_length(out, group.pending.len() as u64);
+        for (id, pending) in &group.pending {
+            out.extend_from_slice(&id.to_bytes());
+            out.extend_from_slice(&pending.delivery_time.to_le_bytes());
+            write_length(out, pending.delivery_count);
+        }
+        write_length(out, group.consumers.len() as u64);
+        for consumer in &group.consumers {
+            write_string(out, &consumer.name);
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            out.extend_from_slice(&consumer.active_time.to_le_bytes());
+            let owned: Vec<&StreamId> = group
+                .pending
+                .iter()
+                .filter(|(_, pending)| pending.consumer == consumer.name)
+                .map(|(id, _)| id)
+                .collect();
+            write_length(out, owned.len() as u64);
+            for id in owned {
+                out.extend_from_slice(&id.to_bytes());
+            }
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, length: u64) {
+    if length < 1 << 6 {
+        out.push(length as u8);
+    } else if length < 1 << 14 {
+        out.push(0x40 | (length >> 8) as u8);
+        out.push(length as u8);
+    } else if length <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(length as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&length.to_be_bytes());
+    }
+}
+
+/// Writes a string, using the compact integer encodings for canonical integers
+/// and LZF compression for long strings.
+fn write_string(out: &mut Vec<u8>, value: &[u8]) {
+    if let Some(integer) = canonical_integer(value) {
+        if let Ok(integer) = i8::try_from(integer) {
+            out.push(0xC0 | ENCODING_INT8);
+            out.extend_from_slice(&integer.to_le_bytes());
+            return;
+        }
+        if let Ok(integer) = i16::try_from(integer) {
+            out.push(0xC0 | ENCODING_INT16);
+            out.extend_from_slice(&integer.to_le_bytes());
+            return;
+        }
+        if let Ok(integer) = i32::try_from(integer) {
+            out.push(0xC0 | ENCODING_INT32);
+            out.extend_from_slice(&integer.to_le_bytes());
+            return;
+        }
+    }
+    if value.len() > COMPRESSION_THRESHOLD {
+        // Like Redis, only keep the compressed form if it saves at least 4 bytes.
+        if let Some(compressed) = lzf_compress(value, value.len() - 4) {
+            out.push(0xC0 | ENCODING_LZF);
+            write_length(out, compressed.len() as u64);
+            write_length(out, value.len() as u64);
+            out.extend_from_slice(&compressed);
+            return;
+        }
+    }
+    write_length(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// The integer `value` spells, if it reads back exactly the same.
+fn canonical_integer(value: &[u8]) -> Option<i64> {
+    if value.is_empty() || value.len() > 11 {
+        return None;
+    }
+    let integer = std::str::from_utf8(value).ok()?.parse::<i64>().ok()?;
+    (integer.to_string().as_bytes() == value).then_some(integer)
+}
+
+pub fn decode(data: &[u8]) -> Result<Snapshot, RdbError> {
+    let mut databases: HashMap<usize, Vec<Entry>> = HashMap::new();
+    let mut snapshot = decode_with(data, |db, entry, _| {
+        databases.entry(db).or_default().push(entry)
+    })?;
+    let mut databases: Vec<(usize, Vec<Entry>)> = databases.into_iter().collect();
+    databases.sort_by_key(|(index, _)| *index);
+    snapshot.databases = databases;
+    Ok(snapshot)
+}
+
+/// Decodes an RDB file, handing each key to `visit` along with its database
+/// index and the encoding it was stored in instead of collecting them.
+pub fn decode_with(
+    data: &[u8],
+    mut visit: impl FnMut(usize, Entry, &'static str),
+) -> Result<Snapshot, RdbError> {
+    let mut reader = Reader { data, position: 0 };
+    if reader.take(5)? != b"REDIS" {
+        return Err(RdbError::InvalidHeader);
+    }
+    let version = std::str::from_utf8(reader.take(4)?)
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or(RdbError::InvalidHeader)?;
+    if version > MAX_LOADABLE_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let mut snapshot = Snapshot::default();
+    let mut db = 0;
+    let mut deadline = None;
+    loop {
+        let opcode = reader.byte()?;
+        match opcode {
+            OPCODE_AUX => {
+                let field = String::from_utf8_lossy(&reader.string()?).into_owned();
+                let value = reader.string()?;
+                snapshot.aux.push((field, value));
+            }
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                deadline = Some(i64::from_le_bytes(reader.array()?));
+            }
+            OPCODE_EXPIRETIME => {
+                deadline = Some(i32::from_le_bytes(reader.array()?) as i64 * 1000);
+            }
+            OPCODE_SELECTDB => db = reader.length()? as usize,
+            OPCODE_EOF => break,
+            OPCODE_SLOT_INFO => {
+                // Cluster slot, slot size and expiring keys in the slot.
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            }
+            OPCODE_FUNCTION2 => snapshot.functions.push(reader.string()?),
+            OPCODE_FUNCTION_PRE_GA => {
+                return Err(RdbError::Unsupported(
+                    "functions in the Redis 7.0 release candidate format".to_string(),
+                ))
+            }
+            OPCODE_MODULE_AUX => {
+                // Module metadata: the module id, when it was saved, then its values.
+                reader.length()?;
+                if reader.length()? != MODULE_OPCODE_UINT {
+                    return Err(RdbError::Corrupt("module aux data"));
+                }
+                reader.length()?;
+                reader.skip_module_values()?;
+            }
+            // Eviction hints for the next key: LRU idle time and LFU frequency.
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            value_type => {
+                let key = String::from_utf8_lossy(&reader.string()?).into_owned();
+                let value = reader.value(value_type)?;
+                let deadline = deadline.take();
+                // Redis skips keys whose collections were left empty, so does this.
+                if is_empty_collection(&value) {
+                    continue;
+                }
+                let entry = Entry {
+                    key,
+                    value,
+                    deadline,
+                };
+                visit(db, entry, encoding_name(value_type));
+            }
+        }
+    }
+
+    // Version 5 and later end with a CRC64 of everything before it; zero means disabled.
+    if version >= 5 {
+        let end = reader.position;
+        let checksum = u64::from_le_bytes(reader.array()?);
+        if checksum != 0 && checksum != crc64(0, &data[..end]) {
+            return Err(RdbError::ChecksumMismatch);
+        }
+    }
+    Ok(snapshot)
+}
+
+/// How a value of the given type is laid out in the file, named after the
+/// Redis object encoding it came from.
+fn encoding_name(value_type: u8) -> &'static str {
+    match value_type {
+        TYPE_STRING => "string",
+        TYPE_LIST => "linkedlist",
+        TYPE_SET | TYPE_HASH | TYPE_HASH_METADATA => "hashtable",
+        TYPE_ZSET | TYPE_ZSET_2 => "skiplist",
+        TYPE_HASH_ZIPMAP => "zipmap",
+        TYPE_LIST_ZIPLIST | TYPE_ZSET_ZIPLIST | TYPE_HASH_ZIPLIST => "ziplist",
+        TYPE_SET_INTSET => "intset",
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => "quicklist",
+        TYPE_HASH_LISTPACK | TYPE_ZSET_LISTPACK | TYPE_SET_LISTPACK | TYPE_HASH_LISTPACK_EX => {
+            "listpack"
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => "stream",
+        _ => "unknown",
+    }
+}
+
+fn is_empty_collection(value: &RedisValue) -> bool {
+    match value {
+        RedisValue::String(_) | RedisValue::Stream(_) => false,
+        RedisValue::List(list) => list.is_empty(),
+        RedisValue::Set(set) => set.is_empty(),
+        RedisValue::SortedSet(set) => set.is_empty(),
+        RedisValue::Hash(hash) => hash.is_empty(),
+    }
+}
+
+/// The 9 character name encoded in the upper 54 bits of a module type id.
+fn module_type_name(id: u64) -> String {
+    (0..9)
+        .map(|index| {
+            let shift = 10 + 6 * (8 - index);
+            MODULE_NAME_CHARSET[((id >> shift) & 63) as usize] as char
+        })
+        .collect()
+}
+
+/// Pairs up the flat field, value sequence of a zipmap, ziplist or listpack hash.
+fn hash_from_pairs(items: Vec<Vec<u8>>) -> Result<Hash, RdbError> {
+    let pairs = items.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(RdbError::Corrupt("hash"));
+    }
+    let mut hash = Hash::new();
+    for pair in pairs {
+        hash.insert(pair[0].clone(), pair[1].clone());
+    }
+    Ok(hash)
+}
+
+/// Pairs up the flat member, score sequence of a ziplist or listpack sorted set.
+fn sorted_set_from_pairs(items: Vec<Vec<u8>>) -> Result<SortedSet, RdbError> {
+    let pairs = items.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(RdbError::Corrupt("sorted set"));
+    }
+    let mut set = SortedSet::new();
+    for pair in pairs {
+        set.insert(pair[0].clone(), parse_score(&pair[1])?);
+    }
+    Ok(set)
+}
+
+fn parse_score(score: &[u8]) -> Result<f64, RdbError> {
+    std::str::from_utf8(score)
+        .ok()
+        .and_then(|score| score.parse::<f64>().ok())
+        .ok_or(RdbError::Corrupt("sorted set score"))
+}
+
+/// Reads the entries of one stream listpack node whose master id is `master_id`,
+/// skipping the ones flagged deleted.
+fn read_stream_node(
+    master_id: StreamId,
+    items: Vec<Vec<u8>>,
+    stream: &mut Stream,
+) -> Result<(), RdbError> {
+    let mut items = items.into_iter();
+    // The master entry: live and deleted counts, then the shared field names.
+    let live = next_integer(&mut items)?;
+    let deleted = next_integer(&mut items)?;
+    let master_fields = (0..next_integer(&mut items)?)
+        .map(|_| next_item(&mut items))
+        .collect::<Result<Vec<_>, _>>()?;
+    if next_integer(&mut items)? != 0 {
+        return Err(RdbError::Corrupt("stream"));
+    }
+    for _ in 0..live + deleted {
+        let flags = next_integer(&mut items)?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(next_integer(&mut items)? as u64),
+            seq: master_id.seq.wrapping_add(next_integer(&mut items)? as u64),
+        };
+        let mut pairs = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                pairs.push((field.clone(), next_item(&mut items)?));
+            }
+        } else {
+            for _ in 0..next_integer(&mut items)? {
+                let field = next_item(&mut items)?;
+                pairs.push((field, next_item(&mut items)?));
+            }
+        }
+        // The entry's item count, only needed to iterate backwards.
+        next_integer(&mut items)?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            stream.entries.insert(id, pairs);
+        }
+    }
+    if items.next().is_some() {
+        return Err(RdbError::Corrupt("stream"));
+    }
+    Ok(())
+}
+
+fn next_item(items: &mut impl Iterator<Item = Vec<u8>>) -> Result<Vec<u8>, RdbError> {
+    items.next().ok_or(RdbError::Corrupt("stream"))
+}
+
+fn next_integer(items: &mut impl Iterator<Item = Vec<u8>>) -> Result<i64, RdbError> {
+    std::str::from_utf8(&next_item(items)?)
+        .ok()
+        .and_then(|item| item.parse::<i64>().ok())
+        .ok_or(RdbError::Corrupt("stream"))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], RdbError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(RdbError::Truncated)?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    /// Reads a length, or the special string encoding that takes its place.
+    fn length_or_encoding(&mut self) -> Result<(u64, bool), RdbError> {
+        let first = self.byte()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3F) as u64, false)),
+            1 => Ok(((((first & 0x3F) as u64) << 8) | self.byte()? as u64, false)),
+            2 => match first {
+                0x80 => Ok((u32::from_be_bytes(self.array()?) as u64, false)),
+                0x81 => Ok((u64::from_be_bytes(self.array()?), false)),
+                _ => Err(RdbError::UnknownEncoding(first)),
+            },
+            _ => Ok(((first & 0x3F) as u64, true)),
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, RdbError> {
+        match self.length_or_encoding()? {
+            (length, false) => Ok(length),
+            (encoding, true) => Err(RdbError::UnknownEncoding(encoding as u8)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, RdbError> {
+        let (length, encoded) = self.length_or_encoding()?;
+        if !encoded {
+            return Ok(self.take(length as usize)?.to_vec());
+        }
+        match length as u8 {
+            ENCODING_INT8 => Ok((self.byte()? as i8).to_string().into_bytes()),
+            ENCODING_INT16 => Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes()),
+            ENCODING_INT32 => Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes()),
+            ENCODING_LZF => {
+                let compressed_length = self.length()? as usize;
+                let length = self.length()? as usize;
+                lzf_decompress(self.take(compressed_length)?, length)
+            }
+            encoding => Err(RdbError::UnknownEncoding(encoding)),
+        }
+    }
+
+    fn value(&mut self, value_type: u8) -> Result<RedisValue, RdbError> {
+        match value_type {
+            TYPE_STRING => Ok(RedisValue::String(self.string()?)),
+            TYPE_ZSET_2 => {
+                let mut set = SortedSet::new();
+                for _ in 0..self.length()? {
+                    let member = self.string()?;
+                    set.insert(member, f64::from_le_bytes(self.array()?));
+                }
+                Ok(RedisValue::SortedSet(set))
+            }
+            TYPE_HASH => {
+                let mut hash = Hash::new();
+                for _ in 0..self.length()? {
+                    let field = self.string()?;
+                    hash.insert(field, self.string()?);
+                }
+                Ok(RedisValue::Hash(hash))
+            }
+            TYPE_HASH_METADATA => {
+                let min_deadline = i64::from_le_bytes(self.array()?);
+                let mut hash = Hash::new();
+                for _ in 0..self.length()? {
+                    let ttl = self.length()?;
+                    let field = self.string()?;
+                    let value = self.string()?;
+                    let deadline = (ttl != 0).then(|| min_deadline + ttl as i64 - 1);
+                    hash.insert(field.clone(), value);
+                    hash.set_expires_at(&field, deadline);
+                }
+                Ok(RedisValue::Hash(hash))
+            }
+            TYPE_LIST => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    list.push_back(self.string()?);
+                }
+                Ok(RedisValue::List(list))
+            }
+            TYPE_LIST_ZIPLIST => Ok(RedisValue::List(ziplist_entries(&self.string()?)?.into())),
+            TYPE_LIST_QUICKLIST => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    list.extend(ziplist_entries(&self.string()?)?);
+                }
+                Ok(RedisValue::List(list))
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    let container = self.length()?;
+                    let node = self.string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => list.push_back(node),
+                        QUICKLIST_NODE_PACKED => list.extend(listpack_entries(&node)?),
+                        _ => return Err(RdbError::Corrupt("quicklist")),
+                    }
+                }
+                Ok(RedisValue::List(list))
+            }
+            TYPE_SET => {
+                let mut set = HashSet::new();
+                for _ in 0..self.length()? {
+                    set.insert(self.string()?);
+                }
+                Ok(RedisValue::Set(set))
+            }
+            TYPE_SET_INTSET => Ok(RedisValue::Set(
+                intset_entries(&self.string()?)?.into_iter().collect(),
+            )),
+            TYPE_SET_LISTPACK => Ok(RedisValue::Set(
+                listpack_entries(&self.string()?)?.into_iter().collect(),
+            )),
+            TYPE_ZSET => {
+                let mut set = SortedSet::new();
+                for _ in 0..self.length()? {
+                    let member = self.string()?;
+                    set.insert(member, self.string_score()?);
+                }
+                Ok(RedisValue::SortedSet(set))
+            }
+            TYPE_ZSET_ZIPLIST => Ok(RedisValue::SortedSet(sorted_set_from_pairs(
+                ziplist_entries(&self.string()?)?,
+            )?)),
+            TYPE_ZSET_LISTPACK => Ok(RedisValue::SortedSet(sorted_set_from_pairs(
+                listpack_entries(&self.string()?)?,
+            )?)),
+            TYPE_HASH_ZIPMAP => Ok(RedisValue::Hash(hash_from_pairs(zipmap_entries(
+                &self.string()?,
+            )?)?)),
+            TYPE_HASH_ZIPLIST => Ok(RedisValue::Hash(hash_from_pairs(ziplist_entries(
+                &self.string()?,
+            )?)?)),
+            TYPE_HASH_LISTPACK => Ok(RedisValue::Hash(hash_from_pairs(listpack_entries(
+                &self.string()?,
+            )?)?)),
+            TYPE_HASH_LISTPACK_EX => {
+                // The earliest deadline, then field, value, deadline triples (0 for none).
+                self.array::<8>()?;
+                let items = listpack_entries(&self.string()?)?;
+                let triples = items.chunks_exact(3);
+                if !triples.remainder().is_empty() {
+                    return Err(RdbError::Corrupt("hash"));
+                }
+                let mut hash = Hash::new();
+                for triple in triples {
+                    let deadline = std::str::from_utf8(&triple[2])
+                        .ok()
+                        .and_then(|deadline| deadline.parse::<i64>().ok())
+                        .ok_or(RdbError::Corrupt("hash"))?;
+                    hash.insert(triple[0].clone(), triple[1].clone());
+                    hash.set_expires_at(&triple[0], (deadline != 0).then_some(deadline));
+                }
+                Ok(RedisValue::Hash(hash))
+            }
+            TYPE_STREAM_LISTPACKS => Ok(RedisValue::Stream(self.stream(1)?)),
+            TYPE_STREAM_LISTPACKS_2 => Ok(RedisValue::Stream(self.stream(2)?)),
+            TYPE_STREAM_LISTPACKS_3 => Ok(RedisValue::Stream(self.stream(3)?)),
+            TYPE_MODULE_PRE_GA | TYPE_MODULE_2 => {
+                let name = module_type_name(self.length()?);
+                Err(RdbError::Unsupported(format!("a value of module type '{name}'")))
+            }
+            TYPE_HASH_METADATA_PRE_GA | TYPE_HASH_LISTPACK_EX_PRE_GA => Err(RdbError::Unsupported(
+                "hash field expiry in the Redis 7.4 release candidate format".to_string(),
+            )),
+            value_type => Err(RdbError::UnknownType(value_type)),
+        }
+    }
+
+    /// A sorted set score of the oldest format, as a length prefixed string
+    /// with special lengths for NaN and the infinities.
+    fn string_score(&mut self) -> Result<f64, RdbError> {
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            length => parse_score(self.take(length as usize)?),
+        }
+    }
+
+    fn stream_id(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId {
+            ms: self.length()?,
+            seq: self.length()?,
+        })
+    }
+
+    fn raw_stream_id(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId::from_bytes(&self.array()?))
+    }
+
+    fn milliseconds(&mut self) -> Result<i64, RdbError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    /// Reads a stream in the layout of the given stream type version: 2 added
+    /// the first, max deleted and entries added metadata and group read
+    /// counters, 3 the consumers' active time.
+    fn stream(&mut self, version: u8) -> Result<Stream, RdbError> {
+        let mut stream = Stream::default();
+        for _ in 0..self.length()? {
+            let key = self.string()?;
+            let master_id = StreamId::from_bytes(
+                key.as_slice()
+                    .try_into()
+                    .map_err(|_| RdbError::Corrupt("stream"))?,
+            );
+            let items = listpack_entries(&self.string()?)?;
+            if items.is_empty() {
+                return Err(RdbError::Corrupt("stream"));
+            }
+            read_stream_node(master_id, items, &mut stream)?;
+        }
+        let length = self.length()?;
+        stream.last_id = self.stream_id()?;
+        if version >= 2 {
+            stream.first_id = self.stream_id()?;
+            stream.max_deleted_id = self.stream_id()?;
+            stream.entries_added = self.length()?;
+        } else {
+            stream.first_id = stream.entries.keys().next().copied().unwrap_or_default();
+            stream.entries_added = length;
+        }
+        if length != stream.len() as u64 {
+            return Err(RdbError::Corrupt("stream"));
+        }
+
+        for _ in 0..self.length()? {
+            let name = self.string()?;
+            let last_id = self.stream_id()?;
+            let entries_read = if version >= 2 {
+                self.length()? as i64
+            } else {
+                -1
+            };
+            let mut pending = BTreeMap::new();
+            for _ in 0..self.length()? {
+                let id = self.raw_stream_id()?;
+                let delivery_time = self.milliseconds()?;
+                let delivery_count = self.length()?;
+                pending.insert(
+                    id,
+                    PendingEntry {
+                        delivery_time,
+                        delivery_count,
+                        consumer: Vec::new(),
+                    },
+                );
+            }
+            let mut consumers = Vec::new();
+            for _ in 0..self.length()? {
+                let name = self.string()?;
+                let seen_time = self.milliseconds()?;
+                // Older files don't track activity; the last interaction is the best guess.
+                let active_time = if version >= 3 {
+                    self.milliseconds()?
+                } else {
+                    seen_time
+                };
+                // A consumer's pending entries must all be in its group's list.
+                for _ in 0..self.length()? {
+                    let id = self.raw_stream_id()?;
+                    let entry = pending
+                        .get_mut(&id)
+                        .ok_or(RdbError::Corrupt("stream consumer group"))?;
+                    entry.consumer = name.clone();
+                }
+                consumers.push(Consumer {
+                    name,
+                    seen_time,
+                    active_time,
+                });
+            }
+            if pending.values().any(|entry| entry.consumer.is_empty()) {
+                return Err(RdbError::Corrupt("stream consumer group"));
+            }
+            stream.groups.push(ConsumerGroup {
+                name,
+                last_id,
+                entries_read,
+                pending,
+                consumers,
+            });
+        }
+        Ok(stream)
+    }
+
+    /// Skips module values up to their end marker.
+    fn skip_module_values(&mut self) -> Result<(), RdbError> {
+        loop {
+            match self.length()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.length()?;
+                }
+                MODULE_OPCODE_FLOAT => {
+                    self.take(4)?;
+                }
+                MODULE_OPCODE_DOUBLE => {
+                    self.take(8)?;
+                }
+                MODULE_OPCODE_STRING => {
+                    self.string()?;
+                }
+                _ => return Err(RdbError::Corrupt("module data")),
+            }
+        }
+    }
+}
+
+/// Compresses `input` in the LZF format, or returns None if the result would
+/// exceed `max_length` bytes.
+fn lzf_compress(input: &[u8], max_length: usize) -> Option<Vec<u8>> {
+    let hash_bits = (input.len().next_power_of_two().trailing_zeros()).clamp(8, LZF_MAX_HASH_BITS);
+    // Last position at which each 3-byte sequence started, plus one.
+    let mut table = vec![0usize; 1 << hash_bits];
+    let mut output = Vec::with_capacity(max_length);
+    // Every literal run is preceded by its length, patched in once it ends.
+    let mut literal_start = 0;
+    let mut literal = 0;
+    output.push(0);
+
+    let mut position = 0;
+    while position < input.len() {
+        let candidate = (position + 2 < input.len()).then(|| {
+            let sequence = u32::from_le_bytes([
+                input[position],
+                input[position + 1],
+                input[position + 2],
+                0,
+            ]);
+            let slot = (sequence.wrapping_mul(2654435761) >> (32 - hash_bits)) as usize;
+            let previous = table[slot];
+            table[slot] = position + 1;
+            previous.checked_sub(1)
+        });
+        let reference = candidate.flatten().filter(|&reference| {
+            position - reference <= LZF_MAX_OFFSET
+                && input[reference..reference + 3] == input[position..position + 3]
+        });
+
+        if let Some(reference) = reference {
+            let max_run = (input.len() - position).min(LZF_MAX_REFERENCE);
+            let mut run = 3;
+            while run < max_run && input[reference + run] == input[position + run] {
+                run += 1;
+            }
+            if literal == 0 {
+                output.pop();
+            } else {
+                output[literal_start] = (literal - 1) as u8;
+            }
+            let offset = position - reference - 1;
+            let encoded_run = run - 2;
+            if encoded_run < 7 {
+                output.push(((encoded_run << 5) | (offset >> 8)) as u8);
+            } else {
+                output.push(((7 << 5) | (offset >> 8)) as u8);
+                output.push((encoded_run - 7) as u8);
+            }
+            output.push(offset as u8);
+            literal_start = output.len();
+            literal = 0;
+            output.push(0);
+            position += run;
+        } else {
+            output.push(input[position]);
+            literal += 1;
+            position += 1;
+            if literal == LZF_MAX_LITERAL {
+                output[literal_start] = (literal - 1) as u8;
+                literal_start = output.len();
+                literal = 0;
+                output.push(0);
+            }
+        }
+        if output.len() > max_length {
+            return None;
+        }
+    }
+    if literal == 0 {
+        output.pop();
+    } else {
+        output[literal_start] = (literal - 1) as u8;
+    }
+    (output.len() <= max_length).then_some(output)
+}
+
+fn lzf_decompress(input: &[u8], length: usize) -> Result<Vec<u8>, RdbError> {
+    // The declared length is only trusted as far as the input could produce it.
+    if input.len().checked_mul(LZF_MAX_EXPANSION).is_some_and(|max| length > max) {
+        return Err(RdbError::CorruptLzf);
+    }
+    let mut output = Vec::with_capacity(length);
+    let mut position = 0;
+    while position < input.len() {
+        if output.len() > length {
+            return Err(RdbError::CorruptLzf);
+        }
+        let control = input[position] as usize;
+        position += 1;
+        if control < 32 {
+            // Literal run of control + 1 bytes.
+            let end = position + control + 1;
+            let literal = input.get(position..end).ok_or(RdbError::CorruptLzf)?;
+            output.extend_from_slice(literal);
+            position = end;
+        } else {
+            // Back reference: length in the top 3 bits, extended by a byte when all set.
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *input.get(position).ok_or(RdbError::CorruptLzf)? as usize;
+                position += 1;
+            }
+            let low = *input.get(position).ok_or(RdbError::CorruptLzf)? as usize;
+            position += 1;
+            let distance = ((control & 0x1F) << 8) + low + 1;
+            let start = output
+                .len()
+                .checked_sub(distance)
+                .ok_or(RdbError::CorruptLzf)?;
+            // The reference may overlap the bytes it produces, so copy one at a time.
+            for index in start..start + run + 2 {
+                output.push(output[index]);
+            }
+        }
+    }
+    if output.len() != length {
+        return Err(RdbError::CorruptLzf);
+    }
+    Ok(output)
+}
+
+/// CRC-64 with the Jones polynomial, as used by Redis for RDB checksums.
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        crc = CRC64_TABLE[((crc ^ *byte as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+const fn crc64_table() -> [u64; 256] {
+    // Reflected form of 0xad93d23594c935a9.
+    const POLYNOMIAL: u64 = 0x95AC_9329_AC4B_C9B5;
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// 2100-01-01, the far deadline the fixture generator uses.
+    const FAR_FUTURE_MS: i64 = 4_102_444_800_000;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/rdb")
+            .join(name);
+        std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    }
+
+    /// Database, key, type and deadline of every key in a fixture, sorted.
+    fn keys(name: &str) -> Vec<(usize, String, &'static str, Option<i64>)> {
+        let snapshot = decode(&fixture(name)).unwrap();
+        let mut keys: Vec<_> = snapshot
+            .databases
+            .iter()
+            .flat_map(|(db, entries)| {
+                entries.iter().map(|entry| {
+                    (
+                        *db,
+                        entry.key.clone(),
+                        entry.value.type_name(),
+                        entry.deadline,
+                    )
+                })
+            })
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn entry(name: &str, key: &str) -> Entry {
+        decode(&fixture(name))
+            .unwrap()
+            .databases
+            .into_iter()
+            .flat_map(|(_, entries)| entries)
+            .find(|entry| entry.key == key)
+            .unwrap()
+    }
+
+    #[test]
+    fn loads_empty_redis_7_2_dump() {
+        let snapshot = decode(&fixture("redis-7.2.0-empty.rdb")).unwrap();
+        assert!(snapshot.databases.is_empty());
+        assert_eq!(snapshot.aux_field("redis-ver"), Some(&b"7.2.0"[..]));
+    }
+
+    #[test]
+    fn loads_redis_2_4_zipmap() {
+        assert_eq!(
+            keys("redis-2.4-zipmap.rdb"),
+            vec![
+                (0, "hash:zipmap".to_string(), "hash", None),
+                (
+                    0,
+                    "string:expires-s".to_string(),
+                    "string",
+                    Some((i32::MAX as i64) * 1000)
+                ),
+            ]
+        );
+        let RedisValue::Hash(hash) = entry("redis-2.4-zipmap.rdb", "hash:zipmap").value else {
+            panic!("not a hash");
+        };
+        assert_eq!(hash.len(), 3);
+    }
+
+    #[test]
+    fn loads_redis_2_6_ziplist_and_intset() {
+        let keys = keys("redis-2.6-ziplist-intset.rdb");
+        assert_eq!(keys.len(), 12);
+        assert_eq!(keys.iter().filter(|(db, ..)| *db == 1).count(), 3);
+        assert!(keys.contains(&(
+            0,
+            "string:expires-ms".to_string(),
+            "string",
+            Some(FAR_FUTURE_MS)
+        )));
+        assert!(keys.contains(&(0, "set:intset64".to_string(), "set", None)));
+        assert!(keys.contains(&(1, "zset:plain".to_string(), "zset", None)));
+        assert_eq!(
+            keys.iter()
+                .filter(|(.., deadline)| deadline.is_some())
+                .count(),
+            1
+        );
+
+        let RedisValue::SortedSet(zset) = entry("redis-2.6-ziplist-intset.rdb", "zset:plain").value
+        else {
+            panic!("not a sorted set");
+        };
+        let scores: Vec<f64> = zset.iter().map(|(_, score)| score).collect();
+        assert!(scores.contains(&f64::INFINITY) && scores.contains(&f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn loads_redis_3_2_quicklist() {
+        let keys = keys("redis-3.2-quicklist.rdb");
+        let types: Vec<_> = keys.iter().map(|(_, _, kind, _)| *kind).collect();
+        assert_eq!(types, vec!["hash", "list", "zset"]);
+        let RedisValue::List(list) = entry("redis-3.2-quicklist.rdb", "list:quicklist").value
+        else {
+            panic!("not a list");
+        };
+        assert_eq!(list.len(), 9);
+    }
+
+    #[test]
+    fn loads_redis_5_0_stream() {
+        assert_eq!(
+            keys("redis-5.0-stream.rdb"),
+            vec![
+                (0, "set:lfu".to_string(), "set", None),
+                (0, "stream:v1".to_string(), "stream", None),
+            ]
+        );
+        let RedisValue::Stream(stream) = entry("redis-5.0-stream.rdb", "stream:v1").value else {
+            panic!("not a stream");
+        };
+        assert_eq!(stream.len(), 2);
+    }
+
+    #[test]
+    fn loads_redis_7_0_listpacks_and_functions() {
+        let snapshot = decode(&fixture("redis-7.0-listpack.rdb")).unwrap();
+        assert_eq!(snapshot.functions.len(), 1);
+        let keys = keys("redis-7.0-listpack.rdb");
+        assert_eq!(keys.len(), 5);
+        assert!(keys.contains(&(0, "stream:empty".to_string(), "stream", None)));
+    }
+
+    #[test]
+    fn loads_redis_7_2_set_listpack() {
+        let keys = keys("redis-7.2-set-listpack.rdb");
+        let types: Vec<_> = keys.iter().map(|(_, _, kind, _)| *kind).collect();
+        assert_eq!(types, vec!["set", "set", "stream"]);
+    }
+
+    #[test]
+    fn loads_redis_7_4_hash_field_deadlines() {
+        assert_eq!(keys("redis-7.4-hash-field-expiry.rdb").len(), 2);
+        for key in ["hash:listpack-ex", "hash:metadata"] {
+            let RedisValue::Hash(hash) = entry("redis-7.4-hash-field-expiry.rdb", key).value else {
+                panic!("not a hash");
+            };
+            assert_eq!(hash.len(), 2);
+            let deadlines: Vec<_> = hash
+                .iter()
+                .map(|(field, _)| hash.expires_at(field))
+                .collect();
+            assert!(deadlines.contains(&Some(FAR_FUTURE_MS)) && deadlines.contains(&None));
+        }
+    }
+
+    #[test]
+    fn refuses_module_values() {
+        match decode(&fixture("unsupported-module-value.rdb")) {
+            Err(RdbError::Unsupported(what)) => assert!(what.contains("ReJSON-RL")),
+            other => panic!("expected an unsupported module error, got {:?}", other),
+        }
+    }
+
+    /// One key of every type this server writes, a string with a deadline
+    /// and, when asked, a hash field with its own deadline.
+    fn sample(field_deadline: bool) -> Snapshot {
+        let mut set = HashSet::new();
+        set.insert(b"a".to_vec());
+        set.insert(b"12345".to_vec());
+        let mut zset = SortedSet::new();
+        zset.insert(b"low".to_vec(), -1.5);
+        zset.insert(b"high".to_vec(), f64::INFINITY);
+        let mut hash = Hash::new();
+        hash.insert(b"kept".to_vec(), b"1".to_vec());
+        hash.insert(b"volatile".to_vec(), b"2".to_vec());
+        if field_deadline {
+            hash.set_expires_at(b"volatile", Some(FAR_FUTURE_MS));
+        }
+        let entries = vec![
+            Entry {
+                key: "string".to_string(),
+                value: RedisValue::String(b"value".to_vec()),
+                deadline: Some(FAR_FUTURE_MS),
+            },
+            Entry {
+                key: "list".to_string(),
+                value: RedisValue::List(VecDeque::from(vec![b"x".to_vec(), b"-7".to_vec()])),
+                deadline: None,
+            },
+            Entry {
+                key: "set".to_string(),
+                value: RedisValue::Set(set),
+                deadline: None,
+            },
+            Entry {
+                key: "zset".to_string(),
+                value: RedisValue::SortedSet(zset),
+                deadline: None,
+            },
+            Entry {
+                key: "hash".to_string(),
+                value: RedisValue::Hash(hash),
+                deadline: None,
+            },
+        ];
+        Snapshot {
+            databases: vec![(0, vec![]), (3, entries)],
+            aux: vec![("redis-ver".to_string(), b"7.4.0".to_vec())],
+            functions: vec![],
+        }
+    }
+
+    fn find<'a>(snapshot: &'a Snapshot, key: &str) -> &'a Entry {
+        snapshot
+            .databases
+            .iter()
+            .flat_map(|(_, entries)| entries)
+            .find(|entry| entry.key == key)
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        let decoded = decode(&encode(&sample(true))).unwrap();
+        assert_eq!(decoded.aux_field("redis-ver"), Some(&b"7.4.0"[..]));
+        let indexes: Vec<_> = decoded.databases.iter().map(|(db, _)| *db).collect();
+        assert_eq!(indexes, vec![3]);
+
+        let string = find(&decoded, "string");
+        assert_eq!(string.deadline, Some(FAR_FUTURE_MS));
+        assert!(matches!(&string.value, RedisValue::String(value) if value == b"value"));
+        let RedisValue::List(list) = &find(&decoded, "list").value else {
+            panic!("not a list");
+        };
+        assert_eq!(list, &VecDeque::from(vec![b"x".to_vec(), b"-7".to_vec()]));
+        let RedisValue::Set(set) = &find(&decoded, "set").value else {
+            panic!("not a set");
+        };
+        assert!(set.len() == 2 && set.contains(&b"12345"[..]));
+        let RedisValue::SortedSet(zset) = &find(&decoded, "zset").value else {
+            panic!("not a sorted set");
+        };
+        assert_eq!(zset.score(b"low"), Some(-1.5));
+        assert_eq!(zset.score(b"high"), Some(f64::INFINITY));
+        let RedisValue::Hash(hash) = &find(&decoded, "hash").value else {
+            panic!("not a hash");
+        };
+        assert_eq!(hash.get(b"volatile"), Some(&b"2".to_vec()));
+        assert_eq!(hash.expires_at(b"volatile"), Some(FAR_FUTURE_MS));
+        assert_eq!(hash.expires_at(b"kept"), None);
+    }
+
+    #[test]
+    fn writes_version_12_only_for_field_deadlines() {
+        assert!(encode(&sample(false)).starts_with(b"REDIS0011"));
+        assert!(encode(&sample(true)).starts_with(b"REDIS0012"));
+    }
+
+    #[test]
+    fn rejects_a_corrupted_file() {
+        // Flip a bit inside a string so the file still parses and only the
+        // checksum can catch it.
+        let mut data = encode(&sample(false));
+        let position = data
+            .windows(5)
+            .position(|window| window == b"value")
+            .unwrap();
+        data[position] ^= 0x01;
+        assert!(matches!(decode(&data), Err(RdbError::ChecksumMismatch)));
+    }
+}
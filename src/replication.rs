@@ -0,0 +1,904 @@
+use crate::resp_parser::{self, to_command_array, Command, RespRequest};
+use crate::storage::Keyspace;
+use bytes::{Buf, BytesMut};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::BuildHasher,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+    time,
+};
+
+#[derive(Debug, Clone)]
+pub enum Role {
+    Master,
+    Slave,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Master => write!(f, "master"),
+            Role::Slave => write!(f, "slave"),
+        }
+    }
+}
+
+/// Stages of a replica's link to its master, following the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    /// Not connected, waiting before the next attempt.
+    Connect,
+    Connecting,
+    ReceivePong,
+    ReceivePortReply,
+    ReceiveCapaReply,
+    ReceivePsyncReply,
+    /// Receiving the snapshot of a full resynchronization.
+    Transfer,
+    Connected,
+}
+
+/// A replica attached to this master, fed through its connection task.
+#[derive(Debug, Clone)]
+pub struct ReplicaLink {
+    pub client_id: u64,
+    /// Address of the replica and the port it announced with REPLCONF listening-port.
+    pub ip: String,
+    pub port: u16,
+    pub sender: UnboundedSender<Vec<u8>>,
+    /// Replication offset the replica last acknowledged with REPLCONF ACK.
+    pub ack_offset: usize,
+    pub last_ack: Instant,
+}
+
+pub const DEFAULT_REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+/// How long either side of a replication link may stay silent before the
+/// link is considered lost.
+pub const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+/// Interval at which a master pings its replicas.
+pub const REPL_PING_PERIOD: Duration = Duration::from_secs(10);
+/// Interval at which a replica acknowledges its processed offset unasked.
+pub const REPL_ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Generates a random 40 character replication id.
+pub fn new_replid() -> String {
+    let state = RandomState::new();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let mut replid = String::with_capacity(48);
+    for round in 0..3u8 {
+        replid += &format!("{:016x}", state.hash_one((nanos, round)));
+    }
+    replid.truncate(40);
+    replid
+}
+
+/// Replication offsets follow Redis: `master_repl_offset` counts the bytes of
+/// the stream produced (or, on a replica, processed) so far, and PSYNC asks for
+/// the offset of the next byte, `master_repl_offset + 1`.
+#[derive(Debug)]
+pub struct RedisReplicationState {
+    pub role: Role,
+    pub connected_slaves: usize,
+    pub master_replid: String,
+    /// The previous replication id, still accepted by PSYNC up to
+    /// `second_repl_offset` so replicas of a failed-over master can continue.
+    pub second_replid: String,
+    pub master_repl_offset: usize,
+    pub second_repl_offset: i64,
+    /// Circular buffer with the latest `repl_backlog_histlen` bytes of the
+    /// stream, created when the first replica attaches.
+    pub repl_backlog: Option<Vec<u8>>,
+    pub repl_backlog_size: usize,
+    /// Position in `repl_backlog` the next byte is written at.
+    pub repl_backlog_idx: usize,
+    pub repl_backlog_first_byte_offset: usize,
+    pub repl_backlog_histlen: usize,
+    pub replicas: Vec<ReplicaLink>,
+    /// Database the replication stream last selected, so SELECT is only sent on change.
+    pub stream_db: Option<usize>,
+    /// Signalled on every REPLCONF ACK, to wake clients blocked in WAIT.
+    pub ack_updates: watch::Sender<()>,
+    /// Port this server accepts connections on, announced to a master.
+    pub listening_port: u16,
+    /// Host and port of the master when this server is a replica.
+    pub master_address: Option<(String, String)>,
+    pub link_state: LinkState,
+    /// When data was last received from the master.
+    pub master_last_io: Option<Instant>,
+    /// Task maintaining the link to the master, aborted by REPLICAOF.
+    pub master_link_task: Option<JoinHandle<()>>,
+}
+
+impl Default for RedisReplicationState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RedisReplicationState {
+    pub fn new() -> Self {
+        Self {
+            role: Role::Master,
+            connected_slaves: 0,
+            master_replid: new_replid(),
+            second_replid: "0".repeat(40),
+            master_repl_offset: 0,
+            second_repl_offset: -1,
+            repl_backlog: None,
+            repl_backlog_size: DEFAULT_REPL_BACKLOG_SIZE,
+            repl_backlog_idx: 0,
+            repl_backlog_first_byte_offset: 0,
+            repl_backlog_histlen: 0,
+            replicas: vec![],
+            stream_db: None,
+            ack_updates: watch::channel(()).0,
+            listening_port: 6379,
+            master_address: None,
+            link_state: LinkState::Connect,
+            master_last_io: None,
+            master_link_task: None,
+        }
+    }
+    /// The `replication` section of INFO, one `field:value` per line.
+    pub fn info(&self, read_only: bool) -> Vec<String> {
+        let mut lines = vec![format!("role:{}", self.role)];
+        if let Some((host, port)) = &self.master_address {
+            let connected = self.link_state == LinkState::Connected;
+            let last_io = self
+                .master_last_io
+                .map(|last_io| last_io.elapsed().as_secs() as i64)
+                .unwrap_or(-1);
+            lines.push(format!("master_host:{}", host));
+            lines.push(format!("master_port:{}", port));
+            lines.push(format!(
+                "master_link_status:{}",
+                if connected { "up" } else { "down" }
+            ));
+            lines.push(format!("master_last_io_seconds_ago:{}", last_io));
+            lines.push(format!(
+                "master_sync_in_progress:{}",
+                (self.link_state == LinkState::Transfer) as u8
+            ));
+            lines.push(format!("slave_read_repl_offset:{}", self.master_repl_offset));
+            lines.push(format!("slave_repl_offset:{}", self.master_repl_offset));
+            lines.push("slave_priority:100".to_string());
+            lines.push(format!("slave_read_only:{}", read_only as u8));
+            lines.push("replica_announced:1".to_string());
+        }
+        lines.push(format!("connected_slaves:{}", self.replicas.len()));
+        for (index, replica) in self.replicas.iter().enumerate() {
+            lines.push(format!(
+                "slave{}:ip={},port={},state=online,offset={},lag={}",
+                index,
+                replica.ip,
+                replica.port,
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs()
+            ));
+        }
+        lines.push("master_failover_state:no-failover".to_string());
+        lines.push(format!("master_replid:{}", self.master_replid));
+        lines.push(format!("master_replid2:{}", self.second_replid));
+        lines.push(format!("master_repl_offset:{}", self.master_repl_offset));
+        lines.push(format!("second_repl_offset:{}", self.second_repl_offset));
+        lines.push(format!(
+            "repl_backlog_active:{}",
+            self.repl_backlog.is_some() as u8
+        ));
+        lines.push(format!("repl_backlog_size:{}", self.repl_backlog_size));
+        lines.push(format!(
+            "repl_backlog_first_byte_offset:{}",
+            self.repl_backlog_first_byte_offset
+        ));
+        lines.push(format!("repl_backlog_histlen:{}", self.repl_backlog_histlen));
+        lines
+    }
+
+    /// Starts streaming writes to a replica. After a full resynchronization a
+    /// master's next write re-selects its database, as the replica starts from
+    /// DB 0; a partial one continues the stream where the replica left it.
+    pub fn register_replica(
+        &mut self,
+        client_id: u64,
+        (ip, port): (String, u16),
+        sender: UnboundedSender<Vec<u8>>,
+        full_resync: bool,
+    ) {
+        self.create_backlog();
+        self.replicas.push(ReplicaLink {
+            client_id,
+            ip,
+            port,
+            sender,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+        });
+        self.connected_slaves = self.replicas.len();
+        if full_resync && matches!(self.role, Role::Master) {
+            self.stream_db = None;
+        }
+    }
+
+    pub fn create_backlog(&mut self) {
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(vec![0; self.repl_backlog_size]);
+            self.reset_backlog();
+        }
+    }
+
+    /// Drops the backlog history, which no longer matches a dataset that was
+    /// just replaced by a full resynchronization.
+    pub fn reset_backlog(&mut self) {
+        self.repl_backlog_idx = 0;
+        self.repl_backlog_histlen = 0;
+        self.repl_backlog_first_byte_offset = self.master_repl_offset + 1;
+    }
+
+    /// Appends stream bytes that `master_repl_offset` already accounts for.
+    fn feed_backlog(&mut self, mut data: &[u8]) {
+        let Some(backlog) = self.repl_backlog.as_mut() else {
+            return;
+        };
+        let size = backlog.len();
+        let written = data.len();
+        while !data.is_empty() {
+            let count = (size - self.repl_backlog_idx).min(data.len());
+            backlog[self.repl_backlog_idx..self.repl_backlog_idx + count]
+                .copy_from_slice(&data[..count]);
+            self.repl_backlog_idx = (self.repl_backlog_idx + count) % size;
+            data = &data[count..];
+        }
+        self.repl_backlog_histlen = (self.repl_backlog_histlen + written).min(size);
+        self.repl_backlog_first_byte_offset =
+            self.master_repl_offset + 1 - self.repl_backlog_histlen;
+    }
+
+    /// Returns the backlog from stream offset `offset` onwards, for a replica
+    /// resuming with `PSYNC replid offset`, or None if it needs a full resync.
+    pub fn partial_resync(&self, replid: &str, offset: i64) -> Option<Vec<u8>> {
+        let backlog = self.repl_backlog.as_ref()?;
+        let known = replid == self.master_replid
+            || (replid == self.second_replid && offset <= self.second_repl_offset);
+        let first = self.repl_backlog_first_byte_offset as i64;
+        if !known || offset < first || offset > first + self.repl_backlog_histlen as i64 {
+            return None;
+        }
+
+        let size = backlog.len();
+        let skip = (offset - first) as usize;
+        let start = (self.repl_backlog_idx + size - self.repl_backlog_histlen + skip) % size;
+        let length = self.repl_backlog_histlen - skip;
+        let mut data = Vec::with_capacity(length);
+        data.extend_from_slice(&backlog[start..size.min(start + length)]);
+        data.extend_from_slice(&backlog[..length - data.len()]);
+        Some(data)
+    }
+
+    /// Adopts the replication id of a master that changed it, keeping the
+    /// previous one valid for partial resynchronization up to the current offset.
+    pub fn switch_replid(&mut self, replid: String) {
+        self.second_replid = std::mem::replace(&mut self.master_replid, replid);
+        self.second_repl_offset = self.master_repl_offset as i64 + 1;
+    }
+
+    /// Closes the links of all replicas, so they resynchronize with this
+    /// server's new history.
+    pub fn disconnect_replicas(&mut self) {
+        self.replicas.clear();
+        self.connected_slaves = 0;
+    }
+
+    /// Turns this server into a replica of `address`, replacing the task that
+    /// followed a previous master.
+    pub fn follow_master(&mut self, address: (String, String), task: JoinHandle<()>) {
+        self.stop_master_link();
+        self.role = Role::Slave;
+        self.master_address = Some(address);
+        self.master_link_task = Some(task);
+        self.disconnect_replicas();
+    }
+
+    /// Promotes this replica to a master. The replication id changes, but the
+    /// old one stays valid so replicas can continue with a partial resync.
+    pub fn promote(&mut self) {
+        self.stop_master_link();
+        self.role = Role::Master;
+        self.master_address = None;
+        self.switch_replid(new_replid());
+    }
+
+    fn stop_master_link(&mut self) {
+        if let Some(task) = self.master_link_task.take() {
+            task.abort();
+        }
+        self.link_state = LinkState::Connect;
+        self.master_last_io = None;
+    }
+
+    /// Sends a PING down the stream, so replicas can tell an idle master from
+    /// a lost one.
+    pub fn ping_replicas(&mut self) {
+        if matches!(self.role, Role::Master) && !self.replicas.is_empty() {
+            self.send_to_replicas(to_command_array(&[b"PING".to_vec()]));
+        }
+    }
+
+    pub fn unregister_replica(&mut self, client_id: u64) {
+        self.replicas
+            .retain(|replica| replica.client_id != client_id);
+        self.connected_slaves = self.replicas.len();
+    }
+
+    pub fn record_ack(&mut self, client_id: u64, offset: usize) {
+        if let Some(replica) = self
+            .replicas
+            .iter_mut()
+            .find(|replica| replica.client_id == client_id)
+        {
+            replica.ack_offset = offset;
+            replica.last_ack = Instant::now();
+            let _ = self.ack_updates.send(());
+        }
+    }
+
+    /// Number of replicas that have acknowledged at least `offset`.
+    pub fn acked_replicas(&self, offset: usize) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// Asks every replica to report its offset. The request is part of the
+    /// stream, so it advances the replication offset like any command.
+    pub fn request_acks(&mut self) {
+        let getack = to_command_array(&[
+            b"REPLCONF".to_vec(),
+            b"GETACK".to_vec(),
+            b"*".to_vec(),
+        ]);
+        self.send_to_replicas(getack);
+    }
+
+    /// On a replica, passes bytes of the master's stream on to the backlog and
+    /// any sub-replicas unchanged, once they have been applied. `db` is the
+    /// database the stream has selected after them.
+    pub fn proxy_master_stream(&mut self, db: usize, data: &[u8]) {
+        self.send_to_replicas(data.to_vec());
+        self.stream_db = Some(db);
+    }
+
+    fn send_to_replicas(&mut self, payload: Vec<u8>) {
+        self.master_repl_offset += payload.len();
+        self.feed_backlog(&payload);
+        self.replicas
+            .retain(|replica| replica.sender.send(payload.clone()).is_ok());
+        self.connected_slaves = self.replicas.len();
+    }
+
+    /// Sends `commands`, executed against database `db`, to every replica and
+    /// the backlog, and advances the replication offset by the bytes sent.
+    /// A replica only proxies its master's stream, so it generates none itself.
+    pub fn feed_replicas(&mut self, db: usize, commands: Vec<Vec<Vec<u8>>>) {
+        if matches!(self.role, Role::Slave)
+            || (self.repl_backlog.is_none() && self.replicas.is_empty())
+            || commands.is_empty()
+        {
+            return;
+        }
+        let mut payload = vec![];
+        if self.stream_db != Some(db) {
+            payload.extend(to_command_array(&[
+                b"SELECT".to_vec(),
+                db.to_string().into_bytes(),
+            ]));
+            self.stream_db = Some(db);
+        }
+        for command in &commands {
+            payload.extend(to_command_array(command));
+        }
+        self.send_to_replicas(payload);
+    }
+}
+
+/// Rewrites an executed write into the commands replicas should apply. Relative
+/// expiry times become absolute deadlines so replicas agree regardless of delay.
+/// `keyspace` must still hold the shards the command ran against.
+pub fn replicated_commands(
+    request: &RespRequest,
+    reply: &[u8],
+    keyspace: Option<&Keyspace>,
+) -> Vec<Vec<Vec<u8>>> {
+    if !request.is_write() || reply.starts_with(b"-") {
+        return vec![];
+    }
+    let arguments: Vec<Vec<u8>> = request.arguments.iter().map(|a| a.bytes.clone()).collect();
+    let command = |name: &str, rest: &[Vec<u8>]| {
+        let mut command = vec![name.as_bytes().to_vec()];
+        command.extend_from_slice(rest);
+        command
+    };
+    let deadline_of = |key: &Vec<u8>| {
+        keyspace.and_then(|keyspace| keyspace.deadline(&String::from_utf8_lossy(key).into_owned()))
+    };
+
+    match request.command {
+        // Arguments were normalised to [key, value, relative ms or MAX_VALUE] by the parser.
+        Command::Set => {
+            let mut set = command("SET", &arguments[..2]);
+            if arguments[2] != b"MAX_VALUE" {
+                // A key that has already expired still needs a (past) deadline.
+                let deadline = deadline_of(&arguments[0]).unwrap_or(1);
+                set.push(b"PXAT".to_vec());
+                set.push(deadline.to_string().into_bytes());
+            }
+            vec![set]
+        }
+        Command::Expire | Command::Pexpire | Command::Expireat | Command::Pexpireat => {
+            if reply != b":1\r\n" {
+                return vec![];
+            }
+            match deadline_of(&arguments[0]) {
+                Some(deadline) => vec![command(
+                    "PEXPIREAT",
+                    &[arguments[0].clone(), deadline.to_string().into_bytes()],
+                )],
+                None => vec![command("DEL", &arguments[..1])],
+            }
+        }
+        // The payload is replayed with the deadline the key ended up with, or as
+        // a DEL if it was restored already expired.
+        Command::Restore => {
+            let key = String::from_utf8_lossy(&arguments[0]).into_owned();
+            if !keyspace.is_some_and(|keyspace| keyspace.contains_key(&key)) {
+                return vec![command("DEL", &arguments[..1])];
+            }
+            let deadline = deadline_of(&arguments[0]).unwrap_or(0);
+            vec![command(
+                "RESTORE",
+                &[
+                    arguments[0].clone(),
+                    deadline.to_string().into_bytes(),
+                    arguments[2].clone(),
+                    b"REPLACE".to_vec(),
+                    b"ABSTTL".to_vec(),
+                ],
+            )]
+        }
+        Command::Hexpire | Command::Hpexpire | Command::Hexpireat | Command::Hpexpireat => {
+            let fields_index = arguments
+                .iter()
+                .position(|argument| argument.eq_ignore_ascii_case(b"FIELDS"))
+                .unwrap_or(arguments.len());
+            let fields = arguments.get(fields_index + 2..).unwrap_or_default();
+            hash_expiry_commands(&arguments[0], fields, reply_integers(reply), keyspace)
+        }
+        Command::Hgetex => {
+            let option = arguments[1].to_ascii_uppercase();
+            if !matches!(
+                option.as_slice(),
+                b"EX" | b"PX" | b"EXAT" | b"PXAT" | b"PERSIST"
+            ) {
+                return vec![];
+            }
+            let fields_index = if option == b"PERSIST" { 2 } else { 3 };
+            let fields = arguments.get(fields_index + 2..).unwrap_or_default();
+            // Only fields that existed (non-null replies) were touched.
+            let touched: Vec<Vec<u8>> = fields
+                .iter()
+                .zip(reply_bulk_presence(reply))
+                .filter(|(_, present)| *present)
+                .map(|(field, _)| field.clone())
+                .collect();
+            if touched.is_empty() {
+                return vec![];
+            }
+            if option == b"PERSIST" {
+                let mut persist = command("HPERSIST", &arguments[..1]);
+                persist.push(b"FIELDS".to_vec());
+                persist.push(touched.len().to_string().into_bytes());
+                persist.extend(touched);
+                return vec![persist];
+            }
+            let statuses = touched.iter().map(|_| 1).collect();
+            hash_expiry_commands(&arguments[0], &touched, statuses, keyspace)
+        }
+        Command::Hsetex => {
+            if reply != b":1\r\n" {
+                return vec![];
+            }
+            let fields_index = arguments
+                .iter()
+                .position(|argument| argument.eq_ignore_ascii_case(b"FIELDS"))
+                .unwrap_or(arguments.len());
+            let mut rewritten = command("HSETEX", &arguments[..1]);
+            let mut index = 1;
+            while index < fields_index {
+                let option = arguments[index].to_ascii_uppercase();
+                if matches!(option.as_slice(), b"EX" | b"PX" | b"EXAT" | b"PXAT") {
+                    // Fields already past their deadline were deleted; any past time works.
+                    let deadline = arguments
+                        .get(fields_index + 2)
+                        .and_then(|field| field_deadline(keyspace, &arguments[0], field))
+                        .unwrap_or(0);
+                    rewritten.push(b"PXAT".to_vec());
+                    rewritten.push(deadline.to_string().into_bytes());
+                    index += 2;
+                } else {
+                    rewritten.push(arguments[index].clone());
+                    index += 1;
+                }
+            }
+            rewritten.extend_from_slice(&arguments[fields_index..]);
+            vec![rewritten]
+        }
+        _ => vec![command(&request.name, &arguments)],
+    }
+}
+
+fn field_deadline(keyspace: Option<&Keyspace>, key: &[u8], field: &[u8]) -> Option<i64> {
+    let key = String::from_utf8_lossy(key).into_owned();
+    keyspace?.get_hash(&key).ok()??.expires_at(field)
+}
+
+/// Turns per-field expiry results (1 = deadline set, 2 = deleted) into an
+/// HPEXPIREAT for the updated fields and an HDEL for the deleted ones.
+fn hash_expiry_commands(
+    key: &[u8],
+    fields: &[Vec<u8>],
+    statuses: Vec<i64>,
+    keyspace: Option<&Keyspace>,
+) -> Vec<Vec<Vec<u8>>> {
+    let mut updated = vec![];
+    let mut deleted = vec![];
+    for (field, status) in fields.iter().zip(statuses) {
+        match status {
+            1 => updated.push(field.clone()),
+            2 => deleted.push(field.clone()),
+            _ => {}
+        }
+    }
+
+    let mut commands = vec![];
+    let deadline = updated
+        .first()
+        .and_then(|field| field_deadline(keyspace, key, field));
+    if let Some(deadline) = deadline {
+        let mut expire = vec![
+            b"HPEXPIREAT".to_vec(),
+            key.to_vec(),
+            deadline.to_string().into_bytes(),
+            b"FIELDS".to_vec(),
+            updated.len().to_string().into_bytes(),
+        ];
+        expire.extend(updated);
+        commands.push(expire);
+    }
+    if !deleted.is_empty() {
+        let mut del = vec![b"HDEL".to_vec(), key.to_vec()];
+        del.extend(deleted);
+        commands.push(del);
+    }
+    commands
+}
+
+/// Integers of a flat RESP array reply such as `*2\r\n:1\r\n:-2\r\n`.
+fn reply_integers(reply: &[u8]) -> Vec<i64> {
+    String::from_utf8_lossy(reply)
+        .split("\r\n")
+        .filter_map(|line| line.strip_prefix(':'))
+        .filter_map(|value| value.parse().ok())
+        .collect()
+}
+
+/// For a flat array of bulk strings, whether each element is non-null.
+fn reply_bulk_presence(reply: &[u8]) -> Vec<bool> {
+    let mut presence = vec![];
+    let mut position = reply
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(0, |end| end + 1);
+    while position < reply.len() {
+        let Some(end) = reply[position..].iter().position(|&byte| byte == b'\n') else {
+            break;
+        };
+        let header = String::from_utf8_lossy(&reply[position + 1..position + end - 1]).into_owned();
+        position += end + 1;
+        match header.parse::<i64>() {
+            Ok(length) if length >= 0 => {
+                presence.push(true);
+                position += length as usize + 2;
+            }
+            _ => presence.push(false),
+        }
+    }
+    presence
+}
+
+/// Reads more of the master's data into `buffer`, failing when the master has
+/// been silent for longer than `REPL_TIMEOUT`.
+async fn read_from_master(stream: &mut TcpStream, buffer: &mut BytesMut) -> io::Result<usize> {
+    match time::timeout(REPL_TIMEOUT, stream.read_buf(buffer)).await {
+        Ok(read) => read,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timeout talking to the master",
+        )),
+    }
+}
+
+/// Reads from the master until `buffer` holds a complete reply.
+async fn read_master_reply(
+    stream: &mut TcpStream,
+    buffer: &mut BytesMut,
+) -> io::Result<RespRequest> {
+    loop {
+        if let Some((reply, consumed)) = resp_parser::handle_resp_request(buffer) {
+            buffer.advance(consumed);
+            return Ok(reply);
+        }
+        if read_from_master(stream, buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Reads the `$<length>\r\n<payload>` RDB transfer that follows FULLRESYNC.
+/// Unlike a bulk string, the payload is not terminated by CRLF.
+async fn read_rdb_payload(stream: &mut TcpStream, buffer: &mut BytesMut) -> io::Result<Vec<u8>> {
+    loop {
+        if let Some(end) = buffer.windows(2).position(|window| window == b"\r\n") {
+            let length = std::str::from_utf8(&buffer[..end])
+                .ok()
+                .and_then(|header| header.strip_prefix('$'))
+                .and_then(|length| length.parse::<usize>().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid RDB header"))?;
+            while buffer.len() < end + 2 + length {
+                if read_from_master(stream, buffer).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+            buffer.advance(end + 2);
+            return Ok(buffer.split_to(length).to_vec());
+        }
+        if read_from_master(stream, buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+fn first_reply(reply: &RespRequest) -> String {
+    reply
+        .arguments
+        .first()
+        .map(|argument| argument.content.clone())
+        .unwrap_or_default()
+}
+
+fn handshake_error(step: &str, reply: &RespRequest) -> io::Error {
+    io::Error::other(format!("{} failed: '{}'", step, first_reply(reply)))
+}
+
+/// How the master agreed to synchronize the replica.
+pub enum Resync {
+    /// The dataset is replaced by `rdb`, and the stream starts at `offset`.
+    Full {
+        replid: String,
+        offset: usize,
+        rdb: Vec<u8>,
+    },
+    /// The stream continues from the offset the replica asked for, under
+    /// `replid`, which differs from the cached one after a failover.
+    Partial { replid: Option<String> },
+}
+
+/// An established connection to the master.
+pub struct MasterLink {
+    pub stream: TcpStream,
+    /// Bytes already read past the handshake: the start of the command stream.
+    pub buffer: BytesMut,
+    pub resync: Resync,
+}
+
+/// Performs the replica handshake and returns the link to the master, moving
+/// `link_state` through the handshake stages. With the replication id and
+/// offset of a previous link in `cached`, the replica asks to continue from
+/// where it stopped instead of loading a new snapshot.
+pub async fn connect_to_master(
+    host: &str,
+    port: &str,
+    cached: Option<(&str, usize)>,
+    state: &Mutex<RedisReplicationState>,
+) -> io::Result<MasterLink> {
+    let set_stage = |stage: LinkState| state.lock().unwrap().link_state = stage;
+    let listening_port = state.lock().unwrap().listening_port;
+
+    set_stage(LinkState::Connecting);
+    let address = format!("{}:{}", host, port);
+    let mut stream = match time::timeout(REPL_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(stream) => stream?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timeout connecting to the master",
+            ))
+        }
+    };
+    let _ = stream.set_nodelay(true);
+    let mut buffer = BytesMut::with_capacity(4096);
+
+    set_stage(LinkState::ReceivePong);
+    stream.write_all(&to_command_array(&[b"PING".to_vec()])).await?;
+    let reply = read_master_reply(&mut stream, &mut buffer).await?;
+    if first_reply(&reply).to_uppercase() != "PONG" {
+        return Err(handshake_error("PING", &reply));
+    }
+
+    set_stage(LinkState::ReceivePortReply);
+    let port_message = to_command_array(&[
+        b"REPLCONF".to_vec(),
+        b"listening-port".to_vec(),
+        listening_port.to_string().into_bytes(),
+    ]);
+    stream.write_all(&port_message).await?;
+    let reply = read_master_reply(&mut stream, &mut buffer).await?;
+    if first_reply(&reply) != "OK" {
+        return Err(handshake_error("REPLCONF listening-port", &reply));
+    }
+
+    set_stage(LinkState::ReceiveCapaReply);
+    let capa_message = to_command_array(&[
+        b"REPLCONF".to_vec(),
+        b"capa".to_vec(),
+        b"psync2".to_vec(),
+    ]);
+    stream.write_all(&capa_message).await?;
+    let reply = read_master_reply(&mut stream, &mut buffer).await?;
+    if first_reply(&reply) != "OK" {
+        return Err(handshake_error("REPLCONF capa", &reply));
+    }
+
+    set_stage(LinkState::ReceivePsyncReply);
+    let (replid, offset) = match cached {
+        Some((replid, offset)) => (replid.to_string(), (offset + 1).to_string()),
+        None => ("?".to_string(), "-1".to_string()),
+    };
+    let psync = to_command_array(&[b"PSYNC".to_vec(), replid.into_bytes(), offset.into_bytes()]);
+    stream.write_all(&psync).await?;
+    let reply = read_master_reply(&mut stream, &mut buffer).await?;
+    let answer = first_reply(&reply);
+    let mut parts = answer.split_whitespace();
+    let resync = match (parts.next(), parts.next(), parts.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset
+                .parse::<usize>()
+                .map_err(|_| handshake_error("PSYNC", &reply))?;
+            let replid = replid.to_string();
+            set_stage(LinkState::Transfer);
+            let rdb = read_rdb_payload(&mut stream, &mut buffer).await?;
+            Resync::Full {
+                replid,
+                offset,
+                rdb,
+            }
+        }
+        (Some("CONTINUE"), replid, None) => Resync::Partial {
+            replid: replid.map(str::to_string),
+        },
+        _ => return Err(handshake_error("PSYNC", &reply)),
+    };
+    println!("[INFO] : Handshake with master {}:{} completed", host, port);
+
+    Ok(MasterLink {
+        stream,
+        buffer,
+        resync,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A master with a backlog of `size` bytes holding `data`.
+    fn master_with_backlog(size: usize, data: &[u8]) -> RedisReplicationState {
+        let mut state = RedisReplicationState::new();
+        state.repl_backlog_size = size;
+        state.create_backlog();
+        state.proxy_master_stream(0, data);
+        state
+    }
+
+    #[test]
+    fn partial_resync_needs_a_backlog() {
+        let state = RedisReplicationState::new();
+        let replid = state.master_replid.clone();
+        assert_eq!(state.partial_resync(&replid, 1), None);
+    }
+
+    #[test]
+    fn partial_resync_offset_bounds() {
+        let state = master_with_backlog(16, b"abcdef");
+        let replid = state.master_replid.clone();
+        assert_eq!(state.repl_backlog_first_byte_offset, 1);
+        assert_eq!(state.partial_resync(&replid, 1), Some(b"abcdef".to_vec()));
+        assert_eq!(state.partial_resync(&replid, 4), Some(b"def".to_vec()));
+        // A replica that is fully caught up resumes with nothing to catch up on.
+        assert_eq!(state.partial_resync(&replid, 7), Some(vec![]));
+        assert_eq!(state.partial_resync(&replid, 8), None);
+        assert_eq!(state.partial_resync(&replid, 0), None);
+        assert_eq!(state.partial_resync(&new_replid(), 4), None);
+    }
+
+    #[test]
+    fn partial_resync_across_the_wrapped_buffer() {
+        let mut state = master_with_backlog(16, b"abcdefghij");
+        state.proxy_master_stream(0, b"klmnopqrstuvwxyz");
+        let replid = state.master_replid.clone();
+        assert_eq!(state.master_repl_offset, 26);
+        assert_eq!(state.repl_backlog_histlen, 16);
+        assert_eq!(state.repl_backlog_first_byte_offset, 11);
+        assert_eq!(
+            state.partial_resync(&replid, 11),
+            Some(b"klmnopqrstuvwxyz".to_vec())
+        );
+        assert_eq!(state.partial_resync(&replid, 20), Some(b"tuvwxyz".to_vec()));
+        assert_eq!(state.partial_resync(&replid, 10), None);
+    }
+
+    #[test]
+    fn previous_replid_is_accepted_up_to_the_switch() {
+        let mut state = master_with_backlog(16, b"abcdef");
+        let old = state.master_replid.clone();
+        state.promote();
+        state.proxy_master_stream(0, b"gh");
+        assert_eq!(state.second_replid, old);
+        assert_eq!(state.second_repl_offset, 7);
+        assert_eq!(state.partial_resync(&old, 7), Some(b"gh".to_vec()));
+        assert_eq!(state.partial_resync(&old, 8), None);
+        let replid = state.master_replid.clone();
+        assert_eq!(state.partial_resync(&replid, 8), Some(b"h".to_vec()));
+    }
+
+    #[test]
+    fn reset_backlog_drops_the_history() {
+        let mut state = master_with_backlog(16, b"abcdef");
+        state.reset_backlog();
+        let replid = state.master_replid.clone();
+        assert_eq!(state.partial_resync(&replid, 1), None);
+        assert_eq!(state.partial_resync(&replid, 7), Some(vec![]));
+    }
+
+    #[test]
+    fn feed_replicas_selects_the_database_on_change() {
+        let mut state = RedisReplicationState::new();
+        state.create_backlog();
+        let set = vec![b"SET".to_vec(), b"k".to_vec(), b"v".to_vec()];
+        state.feed_replicas(0, vec![set.clone()]);
+        state.feed_replicas(0, vec![set.clone()]);
+        state.feed_replicas(1, vec![set.clone()]);
+        let replid = state.master_replid.clone();
+        let stream = state.partial_resync(&replid, 1).unwrap();
+        let select = |db: &[u8]| to_command_array(&[b"SELECT".to_vec(), db.to_vec()]);
+        let set = to_command_array(&set);
+        let expected = [select(b"0"), set.clone(), set.clone(), select(b"1"), set].concat();
+        assert_eq!(stream, expected);
+        assert_eq!(state.master_repl_offset, expected.len());
+
+        state.role = Role::Slave;
+        state.feed_replicas(1, vec![vec![b"DEL".to_vec(), b"k".to_vec()]]);
+        assert_eq!(state.master_repl_offset, expected.len());
+    }
+}
@@ -1,4 +1,3 @@
-use std::{str::Lines, usize};
 
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
@@ -10,6 +9,88 @@ pub enum Command {
     Info,
     Replconf,
     Psync,
+    Wait,
+    Replicaof,
+    Config,
+    Save,
+    Bgsave,
+    Lastsave,
+    Bgrewriteaof,
+    Setbit,
+    Getbit,
+    Bitcount,
+    Bitpos,
+    Bitop,
+    Bitfield,
+    BitfieldRo,
+    Pfadd,
+    Pfcount,
+    Pfmerge,
+    Zadd,
+    Zscore,
+    Zrem,
+    Zcard,
+    Zrange,
+    Geoadd,
+    Geodist,
+    Geopos,
+    Geohash,
+    Geosearch,
+    Geosearchstore,
+    Hset,
+    Hget,
+    Hmget,
+    Hdel,
+    Hgetall,
+    Hlen,
+    Hexists,
+    Hexpire,
+    Hpexpire,
+    Hexpireat,
+    Hpexpireat,
+    Httl,
+    Hpttl,
+    Hexpiretime,
+    Hpexpiretime,
+    Hpersist,
+    Hgetex,
+    Hsetex,
+    Select,
+    Move,
+    Swapdb,
+    Flushdb,
+    Flushall,
+    Dbsize,
+    Del,
+    Exists,
+    Expire,
+    Pexpire,
+    Expireat,
+    Pexpireat,
+    Ttl,
+    Pttl,
+    Persist,
+    Type,
+    Dump,
+    Restore,
+    Migrate,
+    Multi,
+    Exec,
+    Discard,
+    Watch,
+    Unwatch,
+    Subscribe,
+    Unsubscribe,
+    Psubscribe,
+    Punsubscribe,
+    Ssubscribe,
+    Sunsubscribe,
+    Publish,
+    Spublish,
+    Pubsub,
+    Hello,
+    Quit,
+    Reset,
     None,
 }
 #[allow(dead_code)]
@@ -27,12 +108,26 @@ pub enum ContentType {
 #[derive(Debug, Clone)]
 pub struct Content {
     pub content: String,
+    /// Raw bytes of the argument; `content` is its lossy UTF-8 rendering.
+    pub bytes: Vec<u8>,
     pub content_type: ContentType,
 }
 
+impl Content {
+    pub fn new(bytes: &[u8], content_type: ContentType) -> Self {
+        Self {
+            content: String::from_utf8_lossy(bytes).into_owned(),
+            bytes: bytes.to_vec(),
+            content_type,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RespRequest {
     pub command: Command,
+    /// Upper-cased command name as sent by the client; empty for unknown commands.
+    pub name: String,
     pub arguments: Vec<Content>,
     pub single_content_type: ContentType,
 }
@@ -54,10 +149,17 @@ pub const ACCEPTED_TYPES: [&str; 14] = [
     ">", // Pushes
 ];
 
+impl Default for RespRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RespRequest {
     pub fn new() -> Self {
         Self {
             command: Command::None,
+            name: String::new(),
             single_content_type: ContentType::None,
             arguments: vec![],
         }
@@ -74,199 +176,571 @@ impl RespRequest {
         )
     }
 
+    /// The keys this request reads or writes, used to pick the shards to lock.
+    pub fn keys(&self) -> Vec<&String> {
+        let arguments = &self.arguments;
+        let positions = match self.command {
+            Command::Bitop => 1..arguments.len(),
+            Command::Pfcount
+            | Command::Pfmerge
+            | Command::Del
+            | Command::Exists
+            | Command::Watch => {
+                0..arguments.len()
+            }
+            Command::Geosearchstore => 0..arguments.len().min(2),
+            Command::Migrate => match migrate_keys_option(arguments) {
+                Some(index) => index + 1..arguments.len(),
+                None => arguments.len().min(2)..arguments.len().min(3),
+            },
+            Command::Ping
+            | Command::Echo
+            | Command::Info
+            | Command::Replconf
+            | Command::Psync
+            | Command::Wait
+            | Command::Replicaof
+            | Command::Config
+            | Command::Save
+            | Command::Bgsave
+            | Command::Lastsave
+            | Command::Bgrewriteaof
+            | Command::Select
+            | Command::Swapdb
+            | Command::Flushdb
+            | Command::Flushall
+            | Command::Dbsize
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Unwatch
+            | Command::Subscribe
+            | Command::Unsubscribe
+            | Command::Psubscribe
+            | Command::Punsubscribe
+            | Command::Ssubscribe
+            | Command::Sunsubscribe
+            | Command::Publish
+            | Command::Spublish
+            | Command::Pubsub
+            | Command::Hello
+            | Command::Quit
+            | Command::Reset
+            | Command::None => 0..0,
+            _ => 0..arguments.len().min(1),
+        };
+        arguments[positions]
+            .iter()
+            .map(|argument| &argument.content)
+            .collect()
+    }
+
+    /// Whether the command never modifies the keyspace, so shared locks suffice.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self.command,
+            Command::Get
+                | Command::Type
+                | Command::Getbit
+                | Command::Bitcount
+                | Command::Bitpos
+                | Command::BitfieldRo
+                | Command::Zscore
+                | Command::Zcard
+                | Command::Zrange
+                | Command::Geodist
+                | Command::Geopos
+                | Command::Geohash
+                | Command::Geosearch
+                | Command::Exists
+                | Command::Ttl
+                | Command::Pttl
+                | Command::Dump
+                | Command::Hget
+                | Command::Hmget
+                | Command::Hgetall
+                | Command::Hlen
+                | Command::Hexists
+                | Command::Httl
+                | Command::Hpttl
+                | Command::Hexpiretime
+                | Command::Hpexpiretime
+        )
+    }
+
+    /// Whether the command may modify the dataset and must reach replicas.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self.command,
+            Command::Set
+                | Command::Setbit
+                | Command::Bitop
+                | Command::Bitfield
+                | Command::Pfadd
+                | Command::Pfmerge
+                | Command::Zadd
+                | Command::Zrem
+                | Command::Geoadd
+                | Command::Geosearchstore
+                | Command::Hset
+                | Command::Hdel
+                | Command::Hexpire
+                | Command::Hpexpire
+                | Command::Hexpireat
+                | Command::Hpexpireat
+                | Command::Hpersist
+                | Command::Hgetex
+                | Command::Hsetex
+                | Command::Move
+                | Command::Swapdb
+                | Command::Flushdb
+                | Command::Flushall
+                | Command::Del
+                | Command::Expire
+                | Command::Pexpire
+                | Command::Expireat
+                | Command::Pexpireat
+                | Command::Persist
+                | Command::Restore
+                | Command::Migrate
+        )
+    }
+
+    /// Whether a replica may run the command while its data is stale, with the
+    /// master link down and `replica-serve-stale-data` off.
+    pub fn is_allowed_when_stale(&self) -> bool {
+        matches!(
+            self.command,
+            Command::Ping
+                | Command::Info
+                | Command::Replconf
+                | Command::Psync
+                | Command::Replicaof
+                | Command::Config
+                | Command::Lastsave
+                | Command::Select
+                | Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch
+                | Command::Unwatch
+                | Command::Subscribe
+                | Command::Unsubscribe
+                | Command::Psubscribe
+                | Command::Punsubscribe
+                | Command::Ssubscribe
+                | Command::Sunsubscribe
+                | Command::Publish
+                | Command::Spublish
+                | Command::Pubsub
+                | Command::Hello
+                | Command::Quit
+                | Command::Reset
+        )
+    }
+
+    /// Whether a RESP2 connection subscribed to channels may send the command.
+    pub fn is_allowed_when_subscribed(&self) -> bool {
+        matches!(
+            self.command,
+            Command::Subscribe
+                | Command::Unsubscribe
+                | Command::Psubscribe
+                | Command::Punsubscribe
+                | Command::Ssubscribe
+                | Command::Sunsubscribe
+                | Command::Ping
+                | Command::Quit
+                | Command::Reset
+        )
+    }
+
+    /// Whether a client inside MULTI queues the command rather than running it.
+    pub fn is_queued_in_transaction(&self) -> bool {
+        !matches!(
+            self.command,
+            Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch
+                | Command::Replconf
+                | Command::Quit
+                | Command::Reset
+        )
+    }
+
+    /// Whether the argument count fits the command, counting its name like the
+    /// Redis arity: exactly `arity` when positive, at least `-arity` otherwise.
+    pub fn has_valid_arity(&self) -> bool {
+        let arity = match self.command {
+            Command::Ping
+            | Command::Info
+            | Command::Replconf
+            | Command::Bgsave
+            | Command::Flushdb
+            | Command::Flushall
+            | Command::Unsubscribe
+            | Command::Punsubscribe
+            | Command::Sunsubscribe
+            | Command::Hello
+            | Command::Quit => -1,
+            Command::Save
+            | Command::Lastsave
+            | Command::Bgrewriteaof
+            | Command::Dbsize
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Unwatch
+            | Command::Reset => 1,
+            Command::Get
+            | Command::Echo
+            | Command::Zcard
+            | Command::Hgetall
+            | Command::Hlen
+            | Command::Select
+            | Command::Ttl
+            | Command::Pttl
+            | Command::Persist
+            | Command::Type
+            | Command::Dump => 2,
+            Command::Config
+            | Command::Bitcount
+            | Command::Bitfield
+            | Command::BitfieldRo
+            | Command::Pfadd
+            | Command::Pfcount
+            | Command::Pfmerge
+            | Command::Geopos
+            | Command::Geohash
+            | Command::Del
+            | Command::Exists
+            | Command::Watch
+            | Command::Subscribe
+            | Command::Psubscribe
+            | Command::Ssubscribe
+            | Command::Pubsub => -2,
+            Command::Wait
+            | Command::Replicaof
+            | Command::Getbit
+            | Command::Zscore
+            | Command::Hget
+            | Command::Hexists
+            | Command::Move
+            | Command::Swapdb
+            | Command::Publish
+            | Command::Spublish => 3,
+            Command::Set
+            | Command::Psync
+            | Command::Bitpos
+            | Command::Zrem
+            | Command::Hmget
+            | Command::Hdel
+            | Command::Expire
+            | Command::Pexpire
+            | Command::Expireat
+            | Command::Pexpireat => -3,
+            Command::Setbit => 4,
+            Command::Bitop
+            | Command::Zadd
+            | Command::Zrange
+            | Command::Geodist
+            | Command::Hset
+            | Command::Restore => -4,
+            Command::Geoadd
+            | Command::Httl
+            | Command::Hpttl
+            | Command::Hexpiretime
+            | Command::Hpexpiretime
+            | Command::Hpersist
+            | Command::Hgetex => -5,
+            Command::Hexpire
+            | Command::Hpexpire
+            | Command::Hexpireat
+            | Command::Hpexpireat
+            | Command::Hsetex
+            | Command::Migrate => -6,
+            Command::Geosearch => -7,
+            Command::Geosearchstore => -8,
+            Command::None => return false,
+        };
+        let count = self.arguments.len() as i64 + 1;
+        if arity > 0 {
+            count == arity
+        } else {
+            count >= -arity
+        }
+    }
+
     pub fn parse_command(mut resp_struct: RespRequest) -> RespRequest {
         if let Some(first_arg) = resp_struct.arguments.first() {
             if matches!(first_arg.content_type, ContentType::String)
                 || matches!(first_arg.content_type, ContentType::BulkString)
             {
-                if first_arg.content.to_ascii_uppercase() == "ECHO" {
-                    resp_struct.command = Command::Echo;
-                    resp_struct.arguments.remove(0);
-                } else if first_arg.content.to_ascii_uppercase() == "GET" {
-                    resp_struct.command = Command::Get;
-                    resp_struct.arguments.remove(0);
-                } else if first_arg.content.to_ascii_uppercase() == "SET" {
-                    println!("[INFO] Arguments LEN {}", resp_struct.arguments.len());
-                    resp_struct.command = Command::Set;
-                    resp_struct.arguments.remove(0);
-                    let mut expiry = String::from("MAX_VALUE");
-                    if let Some(param) = resp_struct.arguments.get(2) {
-                        if param.content == "px" {
-                            if let Ok(int_expiry) =
-                                resp_struct.arguments.get(3).unwrap().content.parse::<i64>()
-                            {
-                                expiry = int_expiry.to_string();
-                                resp_struct.arguments.remove(3);
-                            }
-                        }
-
-                        resp_struct.arguments.remove(2);
-                    }
-
-                    resp_struct.arguments.insert(
-                        2,
-                        Content {
-                            content: expiry,
-                            content_type: ContentType::BulkString,
-                        },
-                    );
-                } else if first_arg.content.to_ascii_uppercase() == "PING" {
-                    resp_struct.arguments.remove(0);
-                    resp_struct.command = Command::Ping;
-                } else if first_arg.content.to_ascii_uppercase() == "INFO" {
-                    resp_struct.arguments.remove(0);
-                    resp_struct.command = Command::Info;
-                } else if first_arg.content.to_ascii_uppercase() == "REPLCONF" {
-                    resp_struct.arguments.remove(0);
-                    resp_struct.command = Command::Replconf;
-                } else if first_arg.content.to_ascii_uppercase() == "PSYNC"
-                    && resp_struct.arguments.len() == 3
-                {
+                resp_struct.command = match first_arg.content.to_ascii_uppercase().as_str() {
+                    "ECHO" => Command::Echo,
+                    "GET" => Command::Get,
+                    "SET" => Command::Set,
+                    "PING" => Command::Ping,
+                    "INFO" => Command::Info,
+                    "REPLCONF" => Command::Replconf,
+                    "PSYNC" if resp_struct.arguments.len() == 3 => Command::Psync,
+                    "WAIT" => Command::Wait,
+                    "REPLICAOF" | "SLAVEOF" => Command::Replicaof,
+                    "CONFIG" => Command::Config,
+                    "SAVE" => Command::Save,
+                    "BGSAVE" => Command::Bgsave,
+                    "LASTSAVE" => Command::Lastsave,
+                    "BGREWRITEAOF" => Command::Bgrewriteaof,
+                    "SETBIT" => Command::Setbit,
+                    "GETBIT" => Command::Getbit,
+                    "BITCOUNT" => Command::Bitcount,
+                    "BITPOS" => Command::Bitpos,
+                    "BITOP" => Command::Bitop,
+                    "BITFIELD" => Command::Bitfield,
+                    "BITFIELD_RO" => Command::BitfieldRo,
+                    "PFADD" => Command::Pfadd,
+                    "PFCOUNT" => Command::Pfcount,
+                    "PFMERGE" => Command::Pfmerge,
+                    "ZADD" => Command::Zadd,
+                    "ZSCORE" => Command::Zscore,
+                    "ZREM" => Command::Zrem,
+                    "ZCARD" => Command::Zcard,
+                    "ZRANGE" => Command::Zrange,
+                    "GEOADD" => Command::Geoadd,
+                    "GEODIST" => Command::Geodist,
+                    "GEOPOS" => Command::Geopos,
+                    "GEOHASH" => Command::Geohash,
+                    "GEOSEARCH" => Command::Geosearch,
+                    "GEOSEARCHSTORE" => Command::Geosearchstore,
+                    "HSET" => Command::Hset,
+                    "HGET" => Command::Hget,
+                    "HMGET" => Command::Hmget,
+                    "HDEL" => Command::Hdel,
+                    "HGETALL" => Command::Hgetall,
+                    "HLEN" => Command::Hlen,
+                    "HEXISTS" => Command::Hexists,
+                    "HEXPIRE" => Command::Hexpire,
+                    "HPEXPIRE" => Command::Hpexpire,
+                    "HEXPIREAT" => Command::Hexpireat,
+                    "HPEXPIREAT" => Command::Hpexpireat,
+                    "HTTL" => Command::Httl,
+                    "HPTTL" => Command::Hpttl,
+                    "HEXPIRETIME" => Command::Hexpiretime,
+                    "HPEXPIRETIME" => Command::Hpexpiretime,
+                    "HPERSIST" => Command::Hpersist,
+                    "HGETEX" => Command::Hgetex,
+                    "HSETEX" => Command::Hsetex,
+                    "SELECT" => Command::Select,
+                    "MOVE" => Command::Move,
+                    "SWAPDB" => Command::Swapdb,
+                    "FLUSHDB" => Command::Flushdb,
+                    "FLUSHALL" => Command::Flushall,
+                    "DBSIZE" => Command::Dbsize,
+                    "DEL" => Command::Del,
+                    "EXISTS" => Command::Exists,
+                    "EXPIRE" => Command::Expire,
+                    "PEXPIRE" => Command::Pexpire,
+                    "EXPIREAT" => Command::Expireat,
+                    "PEXPIREAT" => Command::Pexpireat,
+                    "TTL" => Command::Ttl,
+                    "PTTL" => Command::Pttl,
+                    "PERSIST" => Command::Persist,
+                    "TYPE" => Command::Type,
+                    "DUMP" => Command::Dump,
+                    "RESTORE" => Command::Restore,
+                    "MIGRATE" => Command::Migrate,
+                    "MULTI" => Command::Multi,
+                    "EXEC" => Command::Exec,
+                    "DISCARD" => Command::Discard,
+                    "WATCH" => Command::Watch,
+                    "UNWATCH" => Command::Unwatch,
+                    "SUBSCRIBE" => Command::Subscribe,
+                    "UNSUBSCRIBE" => Command::Unsubscribe,
+                    "PSUBSCRIBE" => Command::Psubscribe,
+                    "PUNSUBSCRIBE" => Command::Punsubscribe,
+                    "SSUBSCRIBE" => Command::Ssubscribe,
+                    "SUNSUBSCRIBE" => Command::Sunsubscribe,
+                    "PUBLISH" => Command::Publish,
+                    "SPUBLISH" => Command::Spublish,
+                    "PUBSUB" => Command::Pubsub,
+                    "HELLO" => Command::Hello,
+                    "QUIT" => Command::Quit,
+                    "RESET" => Command::Reset,
+                    _ => Command::None,
+                };
+                if !matches!(resp_struct.command, Command::None) {
+                    resp_struct.name = first_arg.content.to_ascii_uppercase();
                     resp_struct.arguments.remove(0);
-                    resp_struct.command = Command::Psync;
                 }
             }
         }
 
+        if matches!(resp_struct.command, Command::Set) {
+            println!("[INFO] Arguments LEN {}", resp_struct.arguments.len());
+            let mut expiry = String::from("MAX_VALUE");
+            if let Some(param) = resp_struct.arguments.get(2) {
+                // Every form is stored as milliseconds relative to now.
+                let now = chrono::Utc::now().timestamp_millis();
+                let option = param.content.to_ascii_uppercase();
+                let relative_ms = resp_struct
+                    .arguments
+                    .get(3)
+                    .and_then(|value| value.content.parse::<i64>().ok())
+                    .and_then(|value| match option.as_str() {
+                        "PX" => Some(value),
+                        "EX" => value.checked_mul(1000),
+                        "PXAT" => Some(value - now),
+                        "EXAT" => value.checked_mul(1000).map(|value| value - now),
+                        _ => None,
+                    });
+                if let Some(int_expiry) = relative_ms {
+                    expiry = int_expiry.to_string();
+                    resp_struct.arguments.remove(3);
+                }
+
+                resp_struct.arguments.remove(2);
+            }
+
+            // Without a key and a value the arity check refuses the command.
+            if resp_struct.arguments.len() >= 2 {
+                resp_struct.arguments.insert(
+                    2,
+                    Content::new(expiry.as_bytes(), ContentType::BulkString),
+                );
+            }
+        }
+
         resp_struct
     }
 }
 
-pub fn handle_resp_request(resp_request: String) -> RespRequest {
-    let mut request_lines = resp_request.lines();
-    let current_line = request_lines.next().unwrap().to_string();
-    let mut is_simple = false;
-    if !current_line.starts_with('*') {
-        is_simple = true;
+/// Position of the KEYS option of MIGRATE, after which every argument is a key.
+/// Options are walked in order so an AUTH password spelling KEYS is skipped.
+pub fn migrate_keys_option(arguments: &[Content]) -> Option<usize> {
+    let mut index = 5;
+    while let Some(option) = arguments.get(index) {
+        match option.content.to_ascii_uppercase().as_str() {
+            "KEYS" => return Some(index),
+            "AUTH" => index += 2,
+            "AUTH2" => index += 3,
+            _ => index += 1,
+        }
     }
+    None
+}
 
-    let mut resp_request_struct = RespRequest {
-        command: Command::None,
-        arguments: Vec::new(),
-        single_content_type: ContentType::None,
-    };
-    let _: Lines;
+/// Parses one RESP frame from the start of `buffer`.
+///
+/// Returns the parsed request together with the number of bytes consumed, or
+/// `None` when the buffer does not hold a complete frame yet.
+pub fn handle_resp_request(buffer: &[u8]) -> Option<(RespRequest, usize)> {
+    let first_byte = *buffer.first()?;
+    let is_simple = first_byte != b'*';
+
+    let mut resp_request_struct = RespRequest::new();
 
-    (resp_request_struct, _) = parse_by_iter(
-        request_lines.clone(),
-        current_line,
-        is_simple,
-        resp_request_struct,
-    );
+    let consumed = if ACCEPTED_TYPES.contains(&(first_byte as char).to_string().as_str()) {
+        parse_by_bytes(buffer, 0, is_simple, &mut resp_request_struct)?
+    } else {
+        parse_inline(buffer, &mut resp_request_struct)?
+    };
 
     RespRequest::print_struct(resp_request_struct.clone());
 
     // finally parse if there is some command is present
-    let resp_parse = RespRequest::parse_command(resp_request_struct.clone());
+    let resp_parse = RespRequest::parse_command(resp_request_struct);
 
     RespRequest::print_struct(resp_parse.clone());
-    resp_parse
+    Some((resp_parse, consumed))
 }
 
-pub fn parse_by_iter(
-    request_line_iter: Lines,
-    current_line: String,
-    is_simple: bool,
-    mut resp_request: RespRequest,
-) -> (RespRequest, Lines) {
-    let mut line_iter = request_line_iter;
-    if let Some(_first_byte) = current_line.chars().next() {
-        let first_byte = _first_byte.to_string().as_str().to_owned();
-
-        if ACCEPTED_TYPES.contains(&first_byte.as_str()) {
-            match first_byte.as_str() {
-                "+" => {
-                    let content_str = current_line[1..].to_string();
-                    let content_struct: Content = Content {
-                        content: content_str,
-                        content_type: ContentType::String,
-                    };
-                    resp_request.arguments.push(content_struct);
-                    if is_simple {
-                        resp_request.single_content_type = ContentType::String;
-                    }
-                }
-                "-" => {
-                    let content_str = current_line[1..].to_string();
+/// Returns the line starting at `position` (without the trailing CRLF) and the
+/// position right after it.
+fn read_line(buffer: &[u8], position: usize) -> Option<(&[u8], usize)> {
+    let end = buffer
+        .get(position..)?
+        .windows(2)
+        .position(|window| window == b"\r\n")?
+        + position;
+    Some((&buffer[position..end], end + 2))
+}
 
-                    resp_request.arguments.push(Content {
-                        content: content_str,
-                        content_type: ContentType::Error,
-                    });
-                    if is_simple {
-                        resp_request.single_content_type = ContentType::Error;
-                    }
-                }
-                ":" => {
-                    // Not quite correct but it works for now :rolling_eyes:
-                    let content = current_line[1..].to_string();
-                    resp_request.arguments.push(Content {
-                        content,
-                        content_type: ContentType::Integer,
-                    });
-                    if is_simple {
-                        resp_request.single_content_type = ContentType::Integer;
-                    }
-                }
-                "_" => {
-                    if is_simple {
-                        resp_request.single_content_type = ContentType::None;
-                    }
-                }
-                "#" => {
-                    let content = current_line[1..].to_string();
-                    resp_request.arguments.push(Content {
-                        content,
-                        content_type: ContentType::Double,
-                    });
-                    if is_simple {
-                        resp_request.single_content_type = ContentType::Double;
-                    }
-                }
+/// Inline commands (`PING\r\n`) are split on whitespace, as telnet users expect.
+fn parse_inline(buffer: &[u8], resp_request: &mut RespRequest) -> Option<usize> {
+    let (line, next) = read_line(buffer, 0)?;
+    for word in line.split(|byte| byte.is_ascii_whitespace()) {
+        if !word.is_empty() {
+            resp_request
+                .arguments
+                .push(Content::new(word, ContentType::BulkString));
+        }
+    }
+    Some(next)
+}
 
-                "$" => {
-                    let string_length: usize = current_line[1..].parse::<usize>().unwrap_or(0);
+pub fn parse_by_bytes(
+    buffer: &[u8],
+    position: usize,
+    is_simple: bool,
+    resp_request: &mut RespRequest,
+) -> Option<usize> {
+    let (line, mut next) = read_line(buffer, position)?;
+    let Some(&first_byte) = line.first() else {
+        return Some(next);
+    };
+    let rest = &line[1..];
+    let simple_type = match first_byte {
+        b'+' => Some(ContentType::String),
+        b'-' => Some(ContentType::Error),
+        // Not quite correct but it works for now :rolling_eyes:
+        b':' => Some(ContentType::Integer),
+        b'#' | b',' => Some(ContentType::Double),
+        _ => None,
+    };
 
-                    let next_line = line_iter.next().unwrap();
-                    let mut content = next_line.to_string();
-                    content.truncate(string_length);
-                    resp_request.arguments.push(Content {
-                        content,
-                        content_type: ContentType::BulkString,
-                    });
-                    if is_simple {
-                        resp_request.single_content_type = ContentType::BulkString;
-                    }
-                }
-                "*" => {
-                    let mut item_count: usize = current_line[1..].parse::<usize>().unwrap_or(0);
-
-                    item_count += 1;
-                    while item_count != 0 {
-                        let resp_request_cloned: RespRequest = resp_request.clone();
-                        if let Some(next_line) = line_iter.next() {
-                            (resp_request, line_iter) = parse_by_iter(
-                                line_iter.clone(),
-                                next_line.to_string(),
-                                is_simple,
-                                resp_request_cloned,
-                            );
-                        }
-
-                        item_count -= 1;
-                    }
+    if let Some(content_type) = simple_type {
+        if is_simple {
+            resp_request.single_content_type = content_type.clone();
+        }
+        resp_request.arguments.push(Content::new(rest, content_type));
+        return Some(next);
+    }
+
+    match first_byte {
+        b'_' => {
+            if is_simple {
+                resp_request.single_content_type = ContentType::None;
+            }
+        }
+        b'$' => {
+            let string_length = String::from_utf8_lossy(rest).parse::<i64>().unwrap_or(0);
+            if string_length >= 0 {
+                let end = next + string_length as usize;
+                if buffer.len() < end + 2 {
+                    return None;
                 }
-                others => {
-                    println!("Parsing Not Yet Implemented for {}", others);
-                    todo!();
+                resp_request
+                    .arguments
+                    .push(Content::new(&buffer[next..end], ContentType::BulkString));
+                next = end + 2;
+                if is_simple {
+                    resp_request.single_content_type = ContentType::BulkString;
                 }
             }
-        } else {
-            println!("[PARSE] Invalid Command {}", first_byte);
+        }
+        b'*' => {
+            let item_count = String::from_utf8_lossy(rest).parse::<i64>().unwrap_or(0);
+            for _ in 0..item_count.max(0) {
+                next = parse_by_bytes(buffer, next, is_simple, resp_request)?;
+            }
+        }
+        others => {
+            println!("Parsing Not Yet Implemented for {}", others as char);
         }
     }
 
-    (resp_request, line_iter)
+    Some(next)
 }
 
 // ENCODING FUNCTIONS
@@ -275,6 +749,75 @@ pub fn to_bulk_string(content: String) -> String {
     format!("${}\r\n{}\r\n", content.len(), content)
 }
 
+/// Encodes a command as a RESP array of bulk strings, as sent over the wire.
+pub fn to_command_array(parts: &[Vec<u8>]) -> Vec<u8> {
+    let mut message = format!("*{}\r\n", parts.len()).into_bytes();
+    for part in parts {
+        message.extend(to_bulk_bytes(part));
+    }
+    message
+}
+
+/// Parses a command given as its parts, as a client would have sent it.
+#[cfg(test)]
+pub(crate) fn request<T: AsRef<[u8]>>(parts: &[T]) -> RespRequest {
+    let parts: Vec<Vec<u8>> = parts.iter().map(|part| part.as_ref().to_vec()).collect();
+    handle_resp_request(&to_command_array(&parts)).unwrap().0
+}
+
+pub fn to_bulk_bytes(content: &[u8]) -> Vec<u8> {
+    let mut message = format!("${}\r\n", content.len()).into_bytes();
+    message.extend_from_slice(content);
+    message.extend_from_slice(b"\r\n");
+    message
+}
+
 pub fn string_to_simple_resp(content: &str, prefix: char) -> String {
     format!("{}{}\r\n", prefix, content)
 }
+
+pub fn to_error(message: &str) -> Vec<u8> {
+    string_to_simple_resp(message, '-').into_bytes()
+}
+
+pub fn to_integer(value: i64) -> Vec<u8> {
+    string_to_simple_resp(&value.to_string(), ':').into_bytes()
+}
+
+pub fn wrong_arguments(command: &str) -> Vec<u8> {
+    to_error(&format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transaction_commands_run_immediately() {
+        for name in ["MULTI", "EXEC", "DISCARD"] {
+            assert!(!request(&[name]).is_queued_in_transaction());
+        }
+        assert!(!request(&["REPLCONF", "ACK", "0"]).is_queued_in_transaction());
+        assert!(request(&["SET", "a", "1"]).is_queued_in_transaction());
+        assert!(request(&["HGET", "h", "f"]).is_queued_in_transaction());
+    }
+
+    #[test]
+    fn arity_counts_the_command_name() {
+        assert!(request(&["MULTI"]).has_valid_arity());
+        assert!(!request(&["MULTI", "now"]).has_valid_arity());
+        assert!(!request(&["EXEC", "x"]).has_valid_arity());
+        assert!(request(&["GET", "a"]).has_valid_arity());
+        assert!(!request(&["GET"]).has_valid_arity());
+        assert!(!request(&["GET", "a", "b"]).has_valid_arity());
+        assert!(request(&["DEL", "a", "b", "c"]).has_valid_arity());
+        assert!(!request(&["DEL"]).has_valid_arity());
+        assert!(request(&["HSET", "h", "f", "v"]).has_valid_arity());
+        assert!(!request(&["HSET", "h", "f"]).has_valid_arity());
+        // Unknown commands are refused as such, not for their arity.
+        assert!(!request(&["NOSUCHCOMMAND"]).has_valid_arity());
+    }
+}
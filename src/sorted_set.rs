@@ -0,0 +1,366 @@
+use crate::resp_parser::{
+    string_to_simple_resp, to_bulk_bytes, to_bulk_string, to_error, to_integer, wrong_arguments,
+    Command, Content, RespRequest,
+};
+use crate::storage::{Keyspace, RedisValue};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+/// Wraps a score so that it can be used as an ordered key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then lexicographically, with O(1) score lookup.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of `member`, returning true if it was not present before.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        // -0.0 and 0.0 compare equal in Redis; keep a single representation.
+        let score = if score == 0.0 { 0.0 } else { score };
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous.is_none()
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.ordered.remove(&(Score(score), member.to_vec()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], f64)> {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+
+    /// Iterates over members with `min <= score < max`.
+    pub fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = (&[u8], f64)> {
+        self.ordered
+            .range((Score(min), vec![])..(Score(max), vec![]))
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub ch: bool,
+}
+
+pub fn handle_sorted_set_command(request: &RespRequest, storage: &mut Keyspace) -> Vec<u8> {
+    let arguments = &request.arguments;
+    match request.command {
+        Command::Zadd => zadd(storage, arguments),
+        Command::Zscore => zscore(storage, arguments),
+        Command::Zrem => zrem(storage, arguments),
+        Command::Zcard => zcard(storage, arguments),
+        Command::Zrange => zrange(storage, arguments),
+        _ => to_error("ERR unknown sorted set command"),
+    }
+}
+
+pub fn parse_score(argument: &Content) -> Result<f64, Vec<u8>> {
+    match argument.content.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => Err(to_error("ERR value is not a valid float")),
+    }
+}
+
+/// Adds `members` to the sorted set at `key`, creating it when needed, and
+/// returns the number of added (or, with CH, changed) members.
+pub fn add_members(
+    storage: &mut Keyspace,
+    key: &String,
+    members: Vec<(f64, Vec<u8>)>,
+    options: AddOptions,
+) -> Result<usize, Vec<u8>> {
+    let exists = match storage.get_sorted_set(key) {
+        Ok(set) => set.is_some(),
+        Err(error) => return Err(to_error(&error.to_string())),
+    };
+    if !exists {
+        if options.xx {
+            return Ok(0);
+        }
+        storage.update(
+            key.clone(),
+            RedisValue::SortedSet(SortedSet::new()),
+            i64::MAX,
+        );
+    }
+
+    let set = storage.get_sorted_set_mut(key).unwrap().unwrap();
+    let mut added = 0;
+    let mut changed = 0;
+    for (score, member) in members {
+        match set.score(&member) {
+            Some(_) if options.nx => {}
+            Some(previous) => {
+                if previous != score {
+                    set.insert(member, score);
+                    changed += 1;
+                }
+            }
+            None if options.xx => {}
+            None => {
+                set.insert(member, score);
+                added += 1;
+            }
+        }
+    }
+
+    if set.is_empty() {
+        storage.remove(key);
+    }
+    Ok(if options.ch { added + changed } else { added })
+}
+
+/// Formats a score the way Redis replies with it (shortest round-trip form).
+pub fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+fn zadd(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 3 {
+        return wrong_arguments("zadd");
+    }
+    let mut options = AddOptions::default();
+    let mut index = 1;
+    while let Some(argument) = arguments.get(index) {
+        match argument.content.to_ascii_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "CH" => options.ch = true,
+            _ => break,
+        }
+        index += 1;
+    }
+    if options.nx && options.xx {
+        return to_error("ERR XX and NX options at the same time are not compatible");
+    }
+
+    let pairs = &arguments[index..];
+    if pairs.is_empty() || pairs.len() % 2 == 1 {
+        return to_error("ERR syntax error");
+    }
+    let mut members = vec![];
+    for pair in pairs.chunks(2) {
+        match parse_score(&pair[0]) {
+            Ok(score) => members.push((score, pair[1].bytes.clone())),
+            Err(message) => return message,
+        }
+    }
+
+    match add_members(storage, &arguments[0].content, members, options) {
+        Ok(count) => to_integer(count as i64),
+        Err(message) => message,
+    }
+}
+
+fn zscore(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 2 {
+        return wrong_arguments("zscore");
+    }
+    match storage.get_sorted_set(&arguments[0].content) {
+        Ok(set) => match set.and_then(|set| set.score(&arguments[1].bytes)) {
+            Some(score) => to_bulk_string(format_score(score)).into_bytes(),
+            None => string_to_simple_resp("-1", '$').into_bytes(),
+        },
+        Err(error) => to_error(&error.to_string()),
+    }
+}
+
+fn zrem(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 2 {
+        return wrong_arguments("zrem");
+    }
+    let key = &arguments[0].content;
+    let set = match storage.get_sorted_set_mut(key) {
+        Ok(Some(set)) => set,
+        Ok(None) => return to_integer(0),
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    let removed = arguments[1..]
+        .iter()
+        .filter(|member| set.remove(&member.bytes))
+        .count();
+    if set.is_empty() {
+        storage.remove(key);
+    }
+    to_integer(removed as i64)
+}
+
+fn zcard(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() != 1 {
+        return wrong_arguments("zcard");
+    }
+    match storage.get_sorted_set(&arguments[0].content) {
+        Ok(set) => to_integer(set.map(SortedSet::len).unwrap_or(0) as i64),
+        Err(error) => to_error(&error.to_string()),
+    }
+}
+
+fn zrange(storage: &mut Keyspace, arguments: &[Content]) -> Vec<u8> {
+    if arguments.len() < 3 {
+        return wrong_arguments("zrange");
+    }
+    let with_scores = match arguments.get(3) {
+        None => false,
+        Some(option) if option.content.eq_ignore_ascii_case("WITHSCORES") => true,
+        Some(_) => return to_error("ERR syntax error"),
+    };
+    if arguments.len() > 4 {
+        return to_error("ERR syntax error");
+    }
+    let (Ok(start), Ok(stop)) = (
+        arguments[1].content.parse::<i64>(),
+        arguments[2].content.parse::<i64>(),
+    ) else {
+        return to_error("ERR value is not an integer or out of range");
+    };
+
+    let set = match storage.get_sorted_set(&arguments[0].content) {
+        Ok(Some(set)) => set,
+        Ok(None) => return b"*0\r\n".to_vec(),
+        Err(error) => return to_error(&error.to_string()),
+    };
+
+    let len = set.len() as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+    if start > stop {
+        return b"*0\r\n".to_vec();
+    }
+
+    let entries: Vec<(&[u8], f64)> = set
+        .iter()
+        .skip(start as usize)
+        .take((stop - start + 1) as usize)
+        .collect();
+    let item_count = if with_scores {
+        entries.len() * 2
+    } else {
+        entries.len()
+    };
+    let mut message = format!("*{}\r\n", item_count).into_bytes();
+    for (member, score) in entries {
+        message.extend(to_bulk_bytes(member));
+        if with_scores {
+            message.extend(to_bulk_string(format_score(score)).into_bytes());
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resp_parser::request;
+    use crate::storage::{Access, ShardedKeyspace};
+
+    fn run(storage: &mut Keyspace, parts: &[&str]) -> Vec<u8> {
+        handle_sorted_set_command(&request(parts), storage)
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(
+            run(
+                &mut storage,
+                &["ZADD", "z", "2", "b", "1", "c", "2", "a", "-inf", "d"]
+            ),
+            b":4\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["ZRANGE", "z", "0", "-1"]),
+            b"*4\r\n$1\r\nd\r\n$1\r\nc\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(&mut storage, &["ZRANGE", "z", "-2", "10", "WITHSCORES"]),
+            b"*4\r\n$1\r\na\r\n$1\r\n2\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(run(&mut storage, &["ZSCORE", "z", "d"]), b"$4\r\n-inf\r\n");
+        assert_eq!(run(&mut storage, &["ZRANGE", "z", "3", "1"]), b"*0\r\n");
+    }
+
+    #[test]
+    fn zadd_options() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        assert_eq!(run(&mut storage, &["ZADD", "z", "XX", "1", "a"]), b":0\r\n");
+        assert_eq!(run(&mut storage, &["ZCARD", "z"]), b":0\r\n");
+        run(&mut storage, &["ZADD", "z", "1", "a"]);
+        assert_eq!(
+            run(&mut storage, &["ZADD", "z", "NX", "5", "a", "2", "b"]),
+            b":1\r\n"
+        );
+        assert_eq!(run(&mut storage, &["ZSCORE", "z", "a"]), b"$1\r\n1\r\n");
+        assert_eq!(
+            run(
+                &mut storage,
+                &["ZADD", "z", "CH", "1.5", "a", "2", "b", "3", "c"]
+            ),
+            b":2\r\n"
+        );
+        assert_eq!(run(&mut storage, &["ZSCORE", "z", "a"]), b"$3\r\n1.5\r\n");
+        assert!(run(&mut storage, &["ZADD", "z", "NX", "XX", "1", "a"]).starts_with(b"-ERR"));
+        assert!(run(&mut storage, &["ZADD", "z", "nan", "a"]).starts_with(b"-ERR"));
+    }
+
+    #[test]
+    fn removing_the_last_member_deletes_the_key() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        run(&mut storage, &["ZADD", "z", "1", "a", "2", "b"]);
+        assert_eq!(run(&mut storage, &["ZREM", "z", "a", "missing"]), b":1\r\n");
+        assert_eq!(run(&mut storage, &["ZREM", "z", "b"]), b":1\r\n");
+        assert!(!storage.contains_key(&"z".to_string()));
+    }
+}
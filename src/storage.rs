@@ -1,10 +1,66 @@
+use crate::hash::Hash;
+use crate::rdb::Entry;
+use crate::sorted_set::SortedSet;
+use crate::stream::Stream;
 use chrono::{DateTime, Utc};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{Hash as _, Hasher};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use thiserror::Error;
+
+#[derive(Debug, Clone)]
+pub enum RedisValue {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    SortedSet(SortedSet),
+    Hash(Hash),
+    Stream(Stream),
+}
+
+impl RedisValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RedisValue::String(_) => "string",
+            RedisValue::List(_) => "list",
+            RedisValue::Set(_) => "set",
+            RedisValue::SortedSet(_) => "zset",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::Stream(_) => "stream",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+pub struct WrongTypeError;
+
+pub type Shard = TimeKeyValueStorage<String, RedisValue>;
 
 #[derive(Debug)]
 pub struct TimeKeyValueStorage<Key, Value> {
     map: BTreeMap<DateTime<Utc>, (i64, Value)>, // i64 used directly for expiry time
     key_index: HashMap<Key, DateTime<Utc>>,
+    volatile_keys: HashSet<Key>, // keys that may hold values with their own deadlines
+    watched: HashMap<Key, Watch>, // keys clients WATCH, with their versions
+}
+
+/// How often a watched key was modified, and by how many clients it is watched.
+/// Versions are only kept while someone watches the key.
+#[derive(Debug, Default)]
+struct Watch {
+    version: u64,
+    watchers: usize,
+}
+
+impl<Key, Value> Default for TimeKeyValueStorage<Key, Value>
+where
+    Key: std::cmp::Eq + std::hash::Hash + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<Key, Value> TimeKeyValueStorage<Key, Value>
@@ -15,10 +71,13 @@ where
         Self {
             map: BTreeMap::new(),
             key_index: HashMap::new(),
+            volatile_keys: HashSet::new(),
+            watched: HashMap::new(),
         }
     }
 
     pub fn insert(&mut self, key: Key, value: Value, expiry: i64) {
+        self.touch(&key);
         let now = Utc::now();
         self.map.insert(now, (expiry, value));
         self.key_index.insert(key, now);
@@ -38,6 +97,121 @@ where
         })
     }
 
+    /// The value of a live `key` for modification, which counts as modifying it.
+    pub fn get_mut(&mut self, key: &Key) -> Option<&mut Value> {
+        if self.get(key).is_some() {
+            self.touch(key);
+        }
+        self.live_value_mut(key)
+    }
+
+    fn live_value_mut(&mut self, key: &Key) -> Option<&mut Value> {
+        let timestamp = self.key_index.get(key)?;
+        self.map.get_mut(timestamp).and_then(|(expiry, value)| {
+            if *expiry == i64::MAX
+                || timestamp.timestamp_millis() + *expiry >= Utc::now().timestamp_millis()
+            {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Removes `key`, returning its value if it had not expired yet.
+    pub fn remove(&mut self, key: &Key) -> Option<Value> {
+        self.volatile_keys.remove(key);
+        let timestamp = self.key_index.remove(key)?;
+        self.touch(key);
+        let (expiry, value) = self.map.remove(&timestamp)?;
+        if expiry == i64::MAX
+            || timestamp.timestamp_millis() + expiry >= Utc::now().timestamp_millis()
+        {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Removes `key`, returning its value together with the remaining expiry in
+    /// milliseconds (`i64::MAX` when it does not expire).
+    pub fn remove_with_expiry(&mut self, key: &Key) -> Option<(Value, i64)> {
+        self.volatile_keys.remove(key);
+        let timestamp = self.key_index.remove(key)?;
+        self.touch(key);
+        let (expiry, value) = self.map.remove(&timestamp)?;
+        if expiry == i64::MAX {
+            return Some((value, expiry));
+        }
+        let remaining = timestamp.timestamp_millis() + expiry - Utc::now().timestamp_millis();
+        if remaining >= 0 {
+            Some((value, remaining))
+        } else {
+            None
+        }
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Absolute expiry deadline of a live `key` in unix milliseconds, if it has one.
+    pub fn deadline(&self, key: &Key) -> Option<i64> {
+        self.get(key)?;
+        let timestamp = self.key_index.get(key)?;
+        match self.map.get(timestamp) {
+            Some((expiry, _)) if *expiry != i64::MAX => Some(timestamp.timestamp_millis() + expiry),
+            _ => None,
+        }
+    }
+
+    /// Sets or clears the absolute deadline of a live `key`. Returns false if the
+    /// key does not exist.
+    pub fn set_deadline(&mut self, key: &Key, deadline: Option<i64>) -> bool {
+        if self.get(key).is_none() {
+            return false;
+        }
+        let Some(timestamp) = self.key_index.get(key) else {
+            return false;
+        };
+        let inserted_at = timestamp.timestamp_millis();
+        if let Some((expiry, _)) = self.map.get_mut(timestamp) {
+            *expiry = match deadline {
+                Some(deadline) => deadline - inserted_at,
+                None => i64::MAX,
+            };
+        }
+        self.touch(key);
+        true
+    }
+
+    /// Number of live keys, number of those with a deadline, and the sum of
+    /// their remaining milliseconds, for INFO keyspace.
+    pub fn expiry_stats(&self) -> (usize, usize, i64) {
+        let now = Utc::now().timestamp_millis();
+        let mut stats = (0, 0, 0);
+        for key in self.key_index.keys() {
+            if self.get(key).is_none() {
+                continue;
+            }
+            stats.0 += 1;
+            if let Some(deadline) = self.deadline(key) {
+                stats.1 += 1;
+                stats.2 += (deadline - now).max(0);
+            }
+        }
+        stats
+    }
+
+    /// Number of keys that have not expired yet.
+    pub fn key_count(&self) -> usize {
+        self.key_index
+            .keys()
+            .filter(|key| self.get(key).is_some())
+            .count()
+    }
+
+    #[allow(dead_code)]
     pub fn get_last_modified(&self, key: &Key) -> Option<&DateTime<Utc>> {
         self.key_index.get(key)
     }
@@ -49,7 +223,531 @@ where
         self.insert(key, value, expiry);
     }
 
+    /// Marks `key` as holding a value with internal deadlines, for active expiration.
+    pub fn track_volatile(&mut self, key: Key) {
+        self.volatile_keys.insert(key);
+    }
+
+    #[allow(dead_code)]
     pub fn get_by_time(&self, timestamp: &DateTime<Utc>) -> Option<&(i64, Value)> {
         self.map.get(timestamp)
     }
+
+    /// Starts keeping the version of `key` for one more watcher, returning it.
+    pub fn watch(&mut self, key: &Key) -> u64 {
+        let watch = self.watched.entry(key.clone()).or_default();
+        watch.watchers += 1;
+        watch.version
+    }
+
+    pub fn unwatch(&mut self, key: &Key) {
+        if let Some(watch) = self.watched.get_mut(key) {
+            watch.watchers -= 1;
+            if watch.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// Modifications of `key` since it was first watched, if it still is.
+    pub fn version(&self, key: &Key) -> Option<u64> {
+        self.watched.get(key).map(|watch| watch.version)
+    }
+
+    /// Moves every key out, leaving the watches behind with the watched keys
+    /// that existed marked as modified.
+    pub fn take_data(&mut self) -> Self {
+        self.touch_live();
+        let watched = std::mem::take(&mut self.watched);
+        let data = std::mem::take(self);
+        self.watched = watched;
+        data
+    }
+
+    /// Exchanges the keys with `other`, each keeping its own watches. Watched
+    /// keys that existed on either side count as modified.
+    pub fn swap_data(&mut self, other: &mut Self) {
+        self.touch_live();
+        other.touch_live();
+        std::mem::swap(&mut self.watched, &mut other.watched);
+        std::mem::swap(self, other);
+        self.touch_live();
+        other.touch_live();
+    }
+
+    fn touch(&mut self, key: &Key) {
+        if let Some(watch) = self.watched.get_mut(key) {
+            watch.version += 1;
+        }
+    }
+
+    fn touch_live(&mut self) {
+        let live: Vec<Key> = self
+            .watched
+            .keys()
+            .filter(|key| self.get(key).is_some())
+            .cloned()
+            .collect();
+        for key in live {
+            self.touch(&key);
+        }
+    }
+}
+
+impl Shard {
+    /// Deletes expired hash fields under every tracked key, dropping hashes left
+    /// empty and keys that no longer hold volatile fields. Returns the removed
+    /// fields by key.
+    pub fn expire_hash_fields(&mut self, now: i64) -> Vec<(String, Vec<Vec<u8>>)> {
+        let keys: Vec<String> = self.volatile_keys.iter().cloned().collect();
+        keys.into_iter()
+            .filter_map(|key| {
+                let expired = self.expire_fields_of(&key, now);
+                (!expired.is_empty()).then_some((key, expired))
+            })
+            .collect()
+    }
+
+    /// Deletes the expired fields of the hash at `key`, and the key once it is
+    /// left empty. Returns the removed fields; only removing some counts as
+    /// modifying the key.
+    pub fn expire_fields_of(&mut self, key: &String, now: i64) -> Vec<Vec<u8>> {
+        let (expired, emptied, volatile) = match self.live_value_mut(key) {
+            Some(RedisValue::Hash(hash)) => {
+                let expired = hash.expire_fields(now);
+                (expired, hash.is_empty(), hash.has_volatile_fields())
+            }
+            _ => (vec![], false, false),
+        };
+        if !expired.is_empty() {
+            self.touch(key);
+        }
+        if emptied {
+            self.remove(key);
+        } else if !volatile {
+            self.volatile_keys.remove(key);
+        }
+        expired
+    }
+
+    /// Copies of the live keys with their absolute deadlines.
+    pub fn snapshot(&self) -> Vec<Entry> {
+        self.key_index
+            .keys()
+            .filter_map(|key| {
+                let value = self.get(key)?.clone();
+                Some(Entry {
+                    key: key.clone(),
+                    value,
+                    deadline: self.deadline(key),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Number of lock-striped shards each database is split into.
+pub const SHARD_COUNT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// One database, split into independently locked shards by key hash.
+#[derive(Debug)]
+pub struct ShardedKeyspace {
+    shards: Vec<RwLock<Shard>>,
+}
+
+impl ShardedKeyspace {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| RwLock::new(Shard::new()))
+                .collect(),
+        }
+    }
+
+    pub fn shard_index(key: &String) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % SHARD_COUNT as u64) as usize
+    }
+
+    /// Locks the shards holding `keys`. Shards are always acquired in ascending
+    /// index order, so concurrent multi-key commands cannot deadlock.
+    pub fn lock(&self, keys: &[&String], access: Access) -> Keyspace<'_> {
+        let indexes: BTreeSet<usize> = keys.iter().map(|key| Self::shard_index(key)).collect();
+        self.lock_shards(indexes, access)
+    }
+
+    /// Locks every shard, for commands that operate on the whole database.
+    pub fn lock_all(&self, access: Access) -> Keyspace<'_> {
+        self.lock_shards(0..SHARD_COUNT, access)
+    }
+
+    fn lock_shards(
+        &self,
+        indexes: impl IntoIterator<Item = usize>,
+        access: Access,
+    ) -> Keyspace<'_> {
+        let guards = indexes
+            .into_iter()
+            .map(|index| {
+                let guard = match access {
+                    Access::Read => ShardGuard::Read(self.shards[index].read().unwrap()),
+                    Access::Write => ShardGuard::Write(self.shards[index].write().unwrap()),
+                };
+                (index, guard)
+            })
+            .collect();
+        Keyspace { guards }
+    }
+
+    /// Runs `apply` on each shard in turn, holding only one shard lock at a time.
+    pub fn for_each_shard(&self, mut apply: impl FnMut(&mut Shard)) {
+        for shard in &self.shards {
+            apply(&mut shard.write().unwrap());
+        }
+    }
+}
+
+impl Default for ShardedKeyspace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+enum ShardGuard<'a> {
+    Read(RwLockReadGuard<'a, Shard>),
+    Write(RwLockWriteGuard<'a, Shard>),
+}
+
+/// The shards of one database locked for a single command. Looking up a key
+/// whose shard was not locked is a bug in the command's key list and panics.
+#[derive(Debug)]
+pub struct Keyspace<'a> {
+    guards: Vec<(usize, ShardGuard<'a>)>,
+}
+
+impl<'a> Keyspace<'a> {
+    fn shard(&self, key: &String) -> &Shard {
+        let index = ShardedKeyspace::shard_index(key);
+        match self.guards.iter().find(|(locked, _)| *locked == index) {
+            Some((_, ShardGuard::Read(guard))) => guard,
+            Some((_, ShardGuard::Write(guard))) => guard,
+            None => panic!("shard {} was not locked for key '{}'", index, key),
+        }
+    }
+
+    fn shard_mut(&mut self, key: &String) -> &mut Shard {
+        let index = ShardedKeyspace::shard_index(key);
+        match self.guards.iter_mut().find(|(locked, _)| *locked == index) {
+            Some((_, ShardGuard::Write(guard))) => guard,
+            Some((_, ShardGuard::Read(_))) => {
+                panic!("shard {} was locked read-only for key '{}'", index, key)
+            }
+            None => panic!("shard {} was not locked for key '{}'", index, key),
+        }
+    }
+
+    fn shards_mut(&mut self) -> Vec<&mut Shard> {
+        self.guards
+            .iter_mut()
+            .map(|(index, guard)| match guard {
+                ShardGuard::Write(guard) => &mut **guard,
+                ShardGuard::Read(_) => panic!("shard {} was locked read-only", index),
+            })
+            .collect()
+    }
+
+    pub fn get(&self, key: &String) -> Option<&RedisValue> {
+        self.shard(key).get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &String) -> Option<&mut RedisValue> {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub fn update(&mut self, key: String, value: RedisValue, expiry: i64) {
+        self.shard_mut(&key).update(key, value, expiry)
+    }
+
+    pub fn remove(&mut self, key: &String) -> Option<RedisValue> {
+        self.shard_mut(key).remove(key)
+    }
+
+    pub fn remove_with_expiry(&mut self, key: &String) -> Option<(RedisValue, i64)> {
+        self.shard_mut(key).remove_with_expiry(key)
+    }
+
+    pub fn contains_key(&self, key: &String) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    pub fn deadline(&self, key: &String) -> Option<i64> {
+        self.shard(key).deadline(key)
+    }
+
+    pub fn set_deadline(&mut self, key: &String, deadline: Option<i64>) -> bool {
+        self.shard_mut(key).set_deadline(key, deadline)
+    }
+
+    pub fn track_volatile(&mut self, key: String) {
+        self.shard_mut(&key).track_volatile(key)
+    }
+
+    pub fn expire_fields_of(&mut self, key: &String, now: i64) -> Vec<Vec<u8>> {
+        self.shard_mut(key).expire_fields_of(key, now)
+    }
+
+    pub fn watch(&mut self, key: &String) -> u64 {
+        self.shard_mut(key).watch(key)
+    }
+
+    pub fn unwatch(&mut self, key: &String) {
+        self.shard_mut(key).unwatch(key)
+    }
+
+    pub fn version(&self, key: &String) -> Option<u64> {
+        self.shard(key).version(key)
+    }
+
+    /// Stores a loaded key, expiring at the absolute `deadline` if it has one.
+    pub fn restore(&mut self, entry: Entry) {
+        let volatile = matches!(&entry.value, RedisValue::Hash(hash) if hash.has_volatile_fields());
+        let key = entry.key;
+        self.update(key.clone(), entry.value, i64::MAX);
+        if entry.deadline.is_some() {
+            self.set_deadline(&key, entry.deadline);
+        }
+        if volatile {
+            self.track_volatile(key);
+        }
+    }
+
+    /// Copies of the live keys across the locked shards.
+    pub fn snapshot(&self) -> Vec<Entry> {
+        self.guards
+            .iter()
+            .flat_map(|(_, guard)| match guard {
+                ShardGuard::Read(guard) => guard.snapshot(),
+                ShardGuard::Write(guard) => guard.snapshot(),
+            })
+            .collect()
+    }
+
+    /// Live keys, keys with a deadline and their summed remaining milliseconds
+    /// across the locked shards.
+    pub fn expiry_stats(&self) -> (usize, usize, i64) {
+        self.guards
+            .iter()
+            .map(|(_, guard)| match guard {
+                ShardGuard::Read(guard) => guard.expiry_stats(),
+                ShardGuard::Write(guard) => guard.expiry_stats(),
+            })
+            .fold((0, 0, 0), |total, stats| {
+                (total.0 + stats.0, total.1 + stats.1, total.2 + stats.2)
+            })
+    }
+
+    /// Number of live keys across the locked shards.
+    pub fn key_count(&self) -> usize {
+        self.guards
+            .iter()
+            .map(|(_, guard)| match guard {
+                ShardGuard::Read(guard) => guard.key_count(),
+                ShardGuard::Write(guard) => guard.key_count(),
+            })
+            .sum()
+    }
+
+    /// Exchanges the contents of the locked shards with those of `other`, which
+    /// must have locked the same shards for writing.
+    pub fn swap_with(&mut self, other: &mut Keyspace) {
+        for (shard, other_shard) in self.shards_mut().into_iter().zip(other.shards_mut()) {
+            shard.swap_data(other_shard);
+        }
+    }
+
+    /// Empties the locked shards, returning their previous contents.
+    pub fn take_all(&mut self) -> Vec<Shard> {
+        self.shards_mut().into_iter().map(Shard::take_data).collect()
+    }
+
+    pub fn get_string(&self, key: &String) -> Result<Option<&Vec<u8>>, WrongTypeError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue::String(value)) => Ok(Some(value)),
+            Some(_) => Err(WrongTypeError),
+        }
+    }
+
+    pub fn get_string_mut(&mut self, key: &String) -> Result<Option<&mut Vec<u8>>, WrongTypeError> {
+        match self.get_mut(key) {
+            None => Ok(None),
+            Some(RedisValue::String(value)) => Ok(Some(value)),
+            Some(_) => Err(WrongTypeError),
+        }
+    }
+
+    pub fn get_sorted_set(&self, key: &String) -> Result<Option<&SortedSet>, WrongTypeError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue::SortedSet(value)) => Ok(Some(value)),
+            Some(_) => Err(WrongTypeError),
+        }
+    }
+
+    pub fn get_sorted_set_mut(
+        &mut self,
+        key: &String,
+    ) -> Result<Option<&mut SortedSet>, WrongTypeError> {
+        match self.get_mut(key) {
+            None => Ok(None),
+            Some(RedisValue::SortedSet(value)) => Ok(Some(value)),
+            Some(_) => Err(WrongTypeError),
+        }
+    }
+
+    pub fn get_hash(&self, key: &String) -> Result<Option<&Hash>, WrongTypeError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(RedisValue::Hash(value)) => Ok(Some(value)),
+            Some(_) => Err(WrongTypeError),
+        }
+    }
+
+    pub fn get_hash_mut(&mut self, key: &String) -> Result<Option<&mut Hash>, WrongTypeError> {
+        match self.get_mut(key) {
+            None => Ok(None),
+            Some(RedisValue::Hash(value)) => Ok(Some(value)),
+            Some(_) => Err(WrongTypeError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> RedisValue {
+        RedisValue::String(value.as_bytes().to_vec())
+    }
+
+    /// Two keys that live in different shards.
+    fn keys_in_different_shards() -> (String, String) {
+        let first = "key:0".to_string();
+        let second = (1..)
+            .map(|n| format!("key:{}", n))
+            .find(|key| ShardedKeyspace::shard_index(key) != ShardedKeyspace::shard_index(&first))
+            .unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn expired_keys_are_invisible() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        let (live, expired) = ("live".to_string(), "expired".to_string());
+        let now = Utc::now().timestamp_millis();
+        storage.update(live.clone(), string("1"), i64::MAX);
+        storage.update(expired.clone(), string("2"), i64::MAX);
+        assert!(storage.set_deadline(&live, Some(now + 60_000)));
+        assert!(storage.set_deadline(&expired, Some(now - 1)));
+        assert!(!storage.set_deadline(&"missing".to_string(), Some(now)));
+
+        assert_eq!(storage.deadline(&live), Some(now + 60_000));
+        assert!(storage.get(&expired).is_none() && !storage.contains_key(&expired));
+        assert!(storage.get_mut(&expired).is_none());
+        assert_eq!(storage.key_count(), 1);
+        let (keys, volatile, _) = storage.expiry_stats();
+        assert_eq!((keys, volatile), (1, 1));
+        let snapshot = storage.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].deadline, Some(now + 60_000));
+
+        assert!(storage.set_deadline(&live, None));
+        assert_eq!(storage.deadline(&live), None);
+        assert!(storage.remove(&expired).is_none());
+        assert!(storage.remove(&live).is_some());
+        assert_eq!(storage.key_count(), 0);
+    }
+
+    #[test]
+    fn typed_accessors_refuse_other_types() {
+        let keyspace = ShardedKeyspace::new();
+        let mut storage = keyspace.lock_all(Access::Write);
+        let key = "s".to_string();
+        storage.update(key.clone(), string("v"), i64::MAX);
+        assert_eq!(storage.get_string(&key).unwrap(), Some(&b"v".to_vec()));
+        assert!(storage.get_hash(&key).is_err());
+        assert!(storage.get_sorted_set_mut(&key).is_err());
+        assert!(matches!(storage.get_hash(&"missing".to_string()), Ok(None)));
+    }
+
+    #[test]
+    fn locks_only_the_shards_of_the_keys() {
+        let keyspace = ShardedKeyspace::new();
+        let (first, second) = keys_in_different_shards();
+        let mut storage = keyspace.lock(&[&first], Access::Write);
+        storage.update(first.clone(), string("1"), i64::MAX);
+        // The other key's shard is free for another command meanwhile.
+        let mut other = keyspace.lock(&[&second], Access::Write);
+        other.update(second.clone(), string("2"), i64::MAX);
+        drop(other);
+        drop(storage);
+
+        let storage = keyspace.lock(&[&first, &second, &first], Access::Read);
+        assert_eq!(storage.key_count(), 2);
+        assert!(storage.contains_key(&first) && storage.contains_key(&second));
+    }
+
+    #[test]
+    #[should_panic(expected = "was not locked")]
+    fn panics_on_a_key_outside_the_locked_shards() {
+        let keyspace = ShardedKeyspace::new();
+        let (first, second) = keys_in_different_shards();
+        keyspace.lock(&[&first], Access::Read).get(&second);
+    }
+
+    #[test]
+    #[should_panic(expected = "locked read-only")]
+    fn panics_on_writing_through_a_read_lock() {
+        let keyspace = ShardedKeyspace::new();
+        let key = "key".to_string();
+        keyspace.lock(&[&key], Access::Read).remove(&key);
+    }
+
+    #[test]
+    fn swaps_and_takes_whole_databases() {
+        let (first, second) = (ShardedKeyspace::new(), ShardedKeyspace::new());
+        let key = "k".to_string();
+        first
+            .lock_all(Access::Write)
+            .update(key.clone(), string("first"), i64::MAX);
+
+        let mut watched = second.lock_all(Access::Write);
+        let version = watched.watch(&key);
+        drop(watched);
+
+        let (mut left, mut right) = (
+            first.lock_all(Access::Write),
+            second.lock_all(Access::Write),
+        );
+        left.swap_with(&mut right);
+        assert!(left.get(&key).is_none());
+        assert!(matches!(right.get(&key), Some(RedisValue::String(value)) if value == b"first"));
+        // The key appeared under its watcher, which stayed with its database.
+        assert!(right.version(&key) > Some(version));
+        assert_eq!(left.version(&key), None);
+
+        let taken = right.take_all();
+        assert_eq!(taken.iter().map(Shard::key_count).sum::<usize>(), 1);
+        assert_eq!(right.key_count(), 0);
+        assert!(right.version(&key).is_some());
+    }
 }
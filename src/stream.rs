@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, fmt};
+
+/// A stream entry id, `<milliseconds>-<sequence>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// The 128-bit big endian form Redis uses as radix tree keys and in RDB files.
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.ms.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; 16]) -> Self {
+        let (ms, seq) = bytes.split_at(8);
+        Self {
+            ms: u64::from_be_bytes(ms.try_into().unwrap()),
+            seq: u64::from_be_bytes(seq.try_into().unwrap()),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The field-value pairs of one stream entry, in the order they were added.
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// An entry delivered to a consumer of a group but not acknowledged yet.
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    /// Unix milliseconds of the last delivery.
+    pub delivery_time: i64,
+    pub delivery_count: u64,
+    pub consumer: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    pub name: Vec<u8>,
+    /// Unix milliseconds of the consumer's last interaction and last successful read.
+    pub seen_time: i64,
+    pub active_time: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    pub name: Vec<u8>,
+    pub last_id: StreamId,
+    /// Entries the group read, or -1 when unknown.
+    pub entries_read: i64,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+/// An append-only log of field-value entries, ordered by id.
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Fields>,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    /// Largest id ever deleted from the stream.
+    pub max_deleted_id: StreamId,
+    /// Entries ever added, including deleted ones.
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_sort_by_their_big_endian_bytes() {
+        let ids = [
+            StreamId { ms: 0, seq: 1 },
+            StreamId { ms: 1, seq: 0 },
+            StreamId {
+                ms: 1_700_000_000_000,
+                seq: u64::MAX,
+            },
+        ];
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!(pair[0].to_bytes() < pair[1].to_bytes());
+        }
+        for id in ids {
+            assert_eq!(StreamId::from_bytes(&id.to_bytes()), id);
+        }
+        assert_eq!(ids[2].to_string(), "1700000000000-18446744073709551615");
+    }
+}
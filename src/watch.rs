@@ -0,0 +1,169 @@
+//! Optimistic locking for transactions: WATCH records the version of each key,
+//! and EXEC only runs if none of them was modified or expired since.
+
+use crate::database::Databases;
+use crate::resp_parser::{string_to_simple_resp, wrong_arguments, RespRequest};
+use crate::storage::{Access, Keyspace};
+
+/// A key a client watches, as it was when WATCH ran.
+#[derive(Debug)]
+pub struct WatchedKey {
+    db: usize,
+    key: String,
+    version: u64,
+    existed: bool,
+}
+
+/// WATCH key [key ...] on database `db`, whose shards for the keys are locked
+/// for writing in `keyspace`.
+pub fn watch(
+    request: &RespRequest,
+    keyspace: &mut Keyspace,
+    db: usize,
+    watched: &mut Vec<WatchedKey>,
+) -> Vec<u8> {
+    if request.arguments.is_empty() {
+        return wrong_arguments("watch");
+    }
+    for argument in &request.arguments {
+        let key = &argument.content;
+        if watched
+            .iter()
+            .any(|watched| watched.db == db && &watched.key == key)
+        {
+            continue;
+        }
+        watched.push(WatchedKey {
+            db,
+            key: key.clone(),
+            version: keyspace.watch(key),
+            existed: keyspace.contains_key(key),
+        });
+    }
+    string_to_simple_resp("OK", '+').into_bytes()
+}
+
+/// Stops watching every key, as UNWATCH, EXEC, DISCARD and disconnecting do.
+pub fn unwatch_all(storage: &Databases, watched: &mut Vec<WatchedKey>) {
+    for watched in watched.drain(..) {
+        storage
+            .get(watched.db)
+            .lock(&[&watched.key], Access::Write)
+            .unwatch(&watched.key);
+    }
+}
+
+/// Whether any watched key was written, deleted or expired since WATCH.
+pub fn any_modified(storage: &Databases, watched: &[WatchedKey]) -> bool {
+    watched.iter().any(|watched| {
+        let keyspace = storage.get(watched.db).lock(&[&watched.key], Access::Read);
+        keyspace.version(&watched.key) != Some(watched.version)
+            || keyspace.contains_key(&watched.key) != watched.existed
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{handle_hash_command, Hash};
+    use crate::rdb::Entry;
+    use crate::resp_parser::request;
+    use crate::storage::RedisValue;
+
+    fn watch_keys(storage: &Databases, keys: &[&str], watched: &mut Vec<WatchedKey>) {
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        let locked: Vec<&String> = keys.iter().collect();
+        let mut keyspace = storage.get(0).lock(&locked, Access::Write);
+        let mut parts = vec!["WATCH"];
+        parts.extend(keys.iter().map(String::as_str));
+        assert_eq!(
+            watch(&request(&parts), &mut keyspace, 0, watched),
+            b"+OK\r\n"
+        );
+    }
+
+    fn run(storage: &Databases, parts: &[&str]) -> Vec<u8> {
+        let key = parts[1].to_string();
+        let mut keyspace = storage.get(0).lock(&[&key], Access::Write);
+        handle_hash_command(&request(parts), &mut keyspace)
+    }
+
+    #[test]
+    fn writes_to_a_watched_key_are_seen() {
+        let storage = Databases::new(1);
+        let mut watched = vec![];
+        watch_keys(&storage, &["h", "h"], &mut watched);
+        assert_eq!(watched.len(), 1);
+        assert!(!any_modified(&storage, &watched));
+
+        run(&storage, &["HSET", "h", "f", "v"]);
+        assert!(any_modified(&storage, &watched));
+
+        unwatch_all(&storage, &mut watched);
+        assert!(watched.is_empty());
+        watch_keys(&storage, &["h"], &mut watched);
+        run(&storage, &["HDEL", "h", "f"]);
+        assert!(any_modified(&storage, &watched));
+    }
+
+    #[test]
+    fn hash_reads_leave_watched_keys_alone() {
+        let storage = Databases::new(1);
+        run(&storage, &["HSET", "h", "f", "v"]);
+        let mut watched = vec![];
+        watch_keys(&storage, &["h"], &mut watched);
+        for read in [
+            &["HGET", "h", "f"][..],
+            &["HGETALL", "h"],
+            &["HLEN", "h"],
+            &["HEXISTS", "h", "f"],
+            &["HTTL", "h", "FIELDS", "1", "f"],
+        ] {
+            run(&storage, read);
+        }
+        assert!(!any_modified(&storage, &watched));
+        // Nor do writes to other keys.
+        run(&storage, &["HSET", "other", "f", "v"]);
+        assert!(!any_modified(&storage, &watched));
+    }
+
+    #[test]
+    fn field_expiry_modifies_a_watched_hash() {
+        let storage = Databases::new(1);
+        let mut hash = Hash::new();
+        hash.insert(b"due".to_vec(), b"1".to_vec());
+        hash.insert(b"kept".to_vec(), b"2".to_vec());
+        hash.set_expires_at(b"due", Some(1_000));
+        storage.get(0).lock_all(Access::Write).restore(Entry {
+            key: "h".to_string(),
+            value: RedisValue::Hash(hash),
+            deadline: None,
+        });
+        let mut watched = vec![];
+        watch_keys(&storage, &["h"], &mut watched);
+
+        let key = "h".to_string();
+        let removed = storage
+            .get(0)
+            .lock(&[&key], Access::Write)
+            .expire_fields_of(&key, 999);
+        assert!(removed.is_empty());
+        assert!(!any_modified(&storage, &watched));
+
+        let removed = storage
+            .get(0)
+            .lock(&[&key], Access::Write)
+            .expire_fields_of(&key, 1_000);
+        assert_eq!(removed, vec![b"due".to_vec()]);
+        assert!(any_modified(&storage, &watched));
+    }
+
+    #[test]
+    fn creating_a_missing_watched_key_is_seen() {
+        let storage = Databases::new(1);
+        let mut watched = vec![];
+        watch_keys(&storage, &["h"], &mut watched);
+        run(&storage, &["HSET", "h", "f", "v"]);
+        assert!(any_modified(&storage, &watched));
+    }
+}